@@ -1,8 +1,24 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use dispatchers::*;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use dispatchers::{heap::Heap, host::Host, workloads::Workload, *};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+/// With the `profile` feature enabled, prints how much work each backend performs next to the
+/// time it takes. Note that the instrumentation inflates the timings.
+#[cfg(feature = "profile")]
+fn counts<C>(profile: fn(C) -> profile::Profile, code: C) {
+    let profile = profile(code);
+    // Mimic criterion's layout, which moves long names onto their own line.
+    if profile.backend.len() < 24 {
+        println!("{:<24}{}", profile.backend, profile.counts);
+    } else {
+        println!("{}\n{:<24}{}", profile.backend, "", profile.counts);
+    }
+}
 
 fn native(c: &mut Criterion) {
-    c.bench_function("native", |b| b.iter(|| native::run()));
+    c.bench_function("native", |b| b.iter(native::run));
 }
 
 fn treewalk(c: &mut Criterion) {
@@ -12,6 +28,8 @@ fn treewalk(c: &mut Criterion) {
 
 fn compact_treewalk_dtable(c: &mut Criterion) {
     let code = compact_treewalk_dtable::code();
+    #[cfg(feature = "profile")]
+    counts(compact_treewalk_dtable::profile, &code);
     c.bench_function("compact treewalk (dtable)", |b| {
         b.iter(|| compact_treewalk_dtable::run(&code))
     });
@@ -19,6 +37,8 @@ fn compact_treewalk_dtable(c: &mut Criterion) {
 
 fn compact_treewalk_switch(c: &mut Criterion) {
     let code = compact_treewalk_switch::code();
+    #[cfg(feature = "profile")]
+    counts(compact_treewalk_switch::profile, &code);
     c.bench_function("compact treewalk (switch)", |b| {
         b.iter(|| compact_treewalk_switch::run(&code))
     });
@@ -26,26 +46,90 @@ fn compact_treewalk_switch(c: &mut Criterion) {
 
 fn stack_dtable(c: &mut Criterion) {
     let code = stack_dtable::code();
+    #[cfg(feature = "profile")]
+    counts(stack_dtable::profile, &code);
     c.bench_function("stack (dtable)", |b| b.iter(|| stack_dtable::run(&code)));
 }
 
 fn stack_switch(c: &mut Criterion) {
-    let code = stack_switch::code();
-    c.bench_function("stack (switch)", |b| b.iter(|| stack_switch::run(&code)));
+    let mut code = stack_switch::code();
+    #[cfg(feature = "profile")]
+    // Profiling quickens the code, so profile a copy.
+    counts(stack_switch::profile, &mut code.clone());
+    c.bench_function("stack (switch)", |b| {
+        b.iter(|| stack_switch::run(&mut code))
+    });
 }
 
 fn register_dtable(c: &mut Criterion) {
     let code = register_dtable::code();
+    #[cfg(feature = "profile")]
+    counts(register_dtable::profile, &code);
     c.bench_function("register (dtable)", |b| {
         b.iter(|| register_dtable::run(&code))
     });
 }
 
 fn register_switch(c: &mut Criterion) {
-    let code = register_switch::code();
+    let mut code = register_switch::code();
+    #[cfg(feature = "profile")]
+    // Profiling quickens the code, so profile a copy.
+    counts(register_switch::profile, &mut code.clone());
     c.bench_function("register (switch)", |b| {
-        b.iter(|| register_switch::run(&code))
+        b.iter(|| register_switch::run(&mut code))
+    });
+}
+
+fn register_fixed(c: &mut Criterion) {
+    let code = register_fixed::code();
+    #[cfg(feature = "profile")]
+    counts(register_fixed::profile, &code);
+    c.bench_function("register (fixed)", |b| {
+        b.iter(|| register_fixed::run(&code))
+    });
+}
+
+/// Benchmarks every workload on every backend, in a group per workload. Workloads that use the
+/// heap get a second group, where it has a generational collector.
+fn workloads(c: &mut Criterion) {
+    for workload in workloads::WORKLOADS {
+        bench_workload(c, workload, workload.name, Heap::new);
+        if workload.heap {
+            let name = format!("{} (generational)", workload.name);
+            bench_workload(c, workload, &name, || {
+                Heap::generational(heap::DEFAULT_NURSERY)
+            });
+        }
+    }
+}
+
+fn bench_workload(c: &mut Criterion, workload: &Workload, group: &str, new_heap: fn() -> Heap) {
+    let mut group = c.benchmark_group(group);
+    group.bench_function("native", |b| {
+        b.iter(|| (workload.native)(black_box(workload.arguments)))
     });
+    // The tree walkers have no coroutines, and the optimizer isn't one of the methods compared.
+    let backends = if workload.coroutines {
+        common::BYTECODE
+    } else {
+        common::ALL
+    };
+    let backends: Vec<_> = (backends.iter().copied())
+        .filter(|&backend| backend != common::Backend::StackOptimized)
+        .collect();
+    for mut compiled in common::compile(&workload.program(), &backends) {
+        group.bench_function(compiled.name, |b| {
+            b.iter(|| {
+                compiled.run(
+                    &mut workload.variables(),
+                    &mut workload.memory(),
+                    &mut new_heap(),
+                    &Host::new(),
+                )
+            })
+        });
+    }
+    group.finish();
 }
 
 criterion_group!(
@@ -58,5 +142,7 @@ criterion_group!(
     stack_switch,
     register_dtable,
     register_switch,
+    register_fixed,
+    workloads,
 );
 criterion_main!(benches);
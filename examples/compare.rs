@@ -0,0 +1,212 @@
+//! Compares two benchmark reports written by `examples/report.rs` with `--format json`, eg. from
+//! before and after a change to a backend, and fails if any benchmark got significantly slower.
+//!
+//! ```text
+//! cargo bench && cargo run --release --example report -- --format json --output before.json
+//! # make a change
+//! cargo bench && cargo run --release --example report -- --format json --output after.json
+//! cargo run --release --example compare -- before.json after.json
+//! ```
+//!
+//! A change is significant if a Mann-Whitney U test on the samples rejects the hypothesis that
+//! both runs are equally fast. Unlike a t-test, it doesn't assume that times are normally
+//! distributed, and isn't thrown off by the outliers that interrupts and frequency scaling
+//! cause.
+
+use std::{env, fs, process};
+
+use serde_json::Value;
+
+const USAGE: &str = "\
+usage: compare [--threshold <percent>] [--alpha <level>] <before.json> <after.json>
+
+  --threshold <percent>  slowdowns smaller than this are tolerated (default: 5)
+  --alpha <level>        the significance level of the test (default: 0.01)";
+
+struct Benchmark {
+    workload: String,
+    backend: String,
+    median: f64,
+    samples: Vec<f64>,
+}
+
+struct Report {
+    machine: Value,
+    benchmarks: Vec<Benchmark>,
+}
+
+fn load(path: &str) -> Result<Report, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+    let invalid = || format!("{path}: not a JSON report from examples/report.rs");
+
+    let benchmarks = json["results"]
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|result| {
+            Some(Benchmark {
+                workload: result["workload"].as_str()?.to_owned(),
+                backend: result["backend"].as_str()?.to_owned(),
+                median: result["median_ns"].as_f64()?,
+                samples: result["samples_ns"]
+                    .as_array()?
+                    .iter()
+                    .map(Value::as_f64)
+                    .collect::<Option<_>>()?,
+            })
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+    Ok(Report {
+        machine: json["machine"].clone(),
+        benchmarks,
+    })
+}
+
+/// The two-sided p-value of a Mann-Whitney U test, using the normal approximation, which is
+/// accurate for the hundred or so samples criterion takes.
+fn mann_whitney(a: &[f64], b: &[f64]) -> f64 {
+    let (n1, n2) = (a.len() as f64, b.len() as f64);
+    if a.is_empty() || b.is_empty() {
+        return 1.0;
+    }
+
+    let mut all: Vec<(f64, bool)> = a
+        .iter()
+        .map(|&x| (x, true))
+        .chain(b.iter().map(|&x| (x, false)))
+        .collect();
+    all.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    // Sum the ranks of `a`, giving tied values the average of their ranks.
+    let mut rank_sum = 0.0;
+    let mut i = 0;
+    while i < all.len() {
+        let mut j = i;
+        while j < all.len() && all[j].0 == all[i].0 {
+            j += 1;
+        }
+        let rank = (i + j + 1) as f64 / 2.0;
+        rank_sum += rank * all[i..j].iter().filter(|x| x.1).count() as f64;
+        i = j;
+    }
+
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let mean = n1 * n2 / 2.0;
+    let sd = (n1 * n2 * (n1 + n2 + 1.0) / 12.0).sqrt();
+    let z = (u - mean).abs() / sd;
+    erfc(z / std::f64::consts::SQRT_2)
+}
+
+/// The complementary error function, with a maximum error of 1.2e-7 (Numerical Recipes'
+/// `erfcc`).
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+fn run() -> Result<bool, String> {
+    let mut threshold = 5.0;
+    let mut alpha = 0.01;
+    let mut paths = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| -> Result<f64, String> {
+            args.next()
+                .and_then(|v| v.parse().ok())
+                .ok_or(format!("{name} needs a number"))
+        };
+        match arg.as_str() {
+            "--threshold" => threshold = value("--threshold")?,
+            "--alpha" => alpha = value("--alpha")?,
+            _ if arg.starts_with("--") => return Err(USAGE.into()),
+            _ => paths.push(arg),
+        }
+    }
+    let [before, after] = &paths[..] else {
+        return Err(USAGE.into());
+    };
+    let (before, after) = (load(before)?, load(after)?);
+
+    for field in ["cpu", "rustc"] {
+        if before.machine[field] != after.machine[field] {
+            println!(
+                "note: the reports differ in {field}: {} vs {}",
+                before.machine[field], after.machine[field]
+            );
+        }
+    }
+
+    println!(
+        "{:<14} {:<26} {:>12} {:>12} {:>8} {:>8}",
+        "workload", "backend", "before", "after", "change", "p"
+    );
+    let mut regressed = false;
+    for old in &before.benchmarks {
+        let Some(new) = after
+            .benchmarks
+            .iter()
+            .find(|b| b.workload == old.workload && b.backend == old.backend)
+        else {
+            println!("{:<14} {:<26} removed", old.workload, old.backend);
+            continue;
+        };
+
+        let change = (new.median / old.median - 1.0) * 100.0;
+        let p = mann_whitney(&old.samples, &new.samples);
+        let verdict = if p >= alpha {
+            ""
+        } else if change > threshold {
+            regressed = true;
+            "regressed"
+        } else if change < -threshold {
+            "improved"
+        } else {
+            "within threshold"
+        };
+        println!(
+            "{:<14} {:<26} {:>9.1} ns {:>9.1} ns {:>+7.1}% {:>8.4} {verdict}",
+            old.workload, old.backend, old.median, new.median, change, p
+        );
+    }
+    for new in &after.benchmarks {
+        if !before
+            .benchmarks
+            .iter()
+            .any(|b| b.workload == new.workload && b.backend == new.backend)
+        {
+            println!("{:<14} {:<26} added", new.workload, new.backend);
+        }
+    }
+    Ok(regressed)
+}
+
+fn main() {
+    match run() {
+        Ok(false) => (),
+        Ok(true) => {
+            eprintln!("some benchmarks regressed");
+            process::exit(1);
+        }
+        Err(message) => {
+            eprintln!("{message}");
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Runs the workloads that use the heap on every backend with each collector, and prints how
+//! long the collections paused them for and how fast they allocated.
+//!
+//! Run with `cargo run --release --example gc`.
+
+use std::time::{Duration, Instant};
+
+use dispatchers::{
+    heap::{self, Collector, GcStats, Heap},
+    host::Host,
+    value::Value,
+    workloads::{Workload, WORKLOADS},
+    *,
+};
+
+type Run<'a> = &'a mut dyn FnMut(&mut Heap) -> Value;
+
+/// Runs are repeated, and the one with the least time spent collecting is reported.
+const RUNS: usize = 20;
+
+fn best(workload: &Workload, collector: Collector, run: Run) -> (Duration, GcStats) {
+    (0..RUNS)
+        .map(|_| {
+            let mut heap = Heap::with_collector(collector, heap::DEFAULT_THRESHOLD);
+            let start = Instant::now();
+            let result = run(&mut heap);
+            let elapsed = start.elapsed();
+            assert_eq!(result, workload.run_native(), "{}", workload.name);
+            (elapsed, heap.stats().clone())
+        })
+        .min_by_key(|(_, stats)| stats.total_pause())
+        .unwrap()
+}
+
+fn main() {
+    let collectors = [
+        Collector::MarkSweep,
+        Collector::Generational {
+            nursery: heap::DEFAULT_NURSERY,
+        },
+    ];
+    println!(
+        "{:<28}{:<14}{:>10}{:>8}{:>12}{:>12}{:>10}",
+        "backend", "collector", "time", "pauses", "total", "max", "MB/s"
+    );
+    for workload in WORKLOADS.iter().filter(|w| w.heap) {
+        println!("\n{}", workload.name);
+        let program = workload.program();
+        let compact = compact_treewalk_switch::compile(&program).unwrap();
+        let stack = stack_switch::compile(&program).unwrap();
+        let register = register_switch::compile(&program).unwrap();
+        let fixed = register_fixed::compile(&program).unwrap();
+        let host = Host::new();
+
+        for collector in collectors {
+            let mut backends: [(&str, Run); 8] = [
+                ("treewalk", &mut |h| {
+                    treewalk::execute(
+                        &program,
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                    .unwrap()
+                }),
+                ("compact treewalk (dtable)", &mut |h| {
+                    compact_treewalk_dtable::execute(
+                        &compact,
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                }),
+                ("compact treewalk (switch)", &mut |h| {
+                    compact_treewalk_switch::execute(
+                        &compact,
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                }),
+                ("stack (dtable)", &mut |h| {
+                    stack_dtable::execute(
+                        &stack,
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                    .unwrap()
+                }),
+                ("stack (switch)", &mut |h| {
+                    stack_switch::execute(
+                        &mut stack.clone(),
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                    .unwrap()
+                }),
+                ("register (dtable)", &mut |h| {
+                    register_dtable::execute(
+                        &register,
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                    .unwrap()
+                }),
+                ("register (switch)", &mut |h| {
+                    register_switch::execute(
+                        &mut register.clone(),
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                    .unwrap()
+                }),
+                ("register (fixed)", &mut |h| {
+                    register_fixed::execute(
+                        &fixed,
+                        &mut workload.variables(),
+                        &mut workload.memory(),
+                        h,
+                        &host,
+                    )
+                    .unwrap()
+                }),
+            ];
+            let name = match collector {
+                Collector::MarkSweep => "mark-sweep",
+                Collector::Generational { .. } => "generational",
+            };
+            for (backend, run) in &mut backends {
+                let (elapsed, stats) = best(workload, collector, *run);
+                println!(
+                    "{backend:<28}{name:<14}{:>10.2?}{:>8}{:>12.2?}{:>12.2?}{:>10.0}",
+                    elapsed,
+                    stats.pauses.len(),
+                    stats.total_pause(),
+                    stats.max_pause(),
+                    stats.allocation_rate(elapsed) / 1e6
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! Profiles every bytecode backend on the benchmark program and explains where the time goes.
+//!
+//! Run with `cargo run --release --example profile --features profile`.
+
+use dispatchers::*;
+
+/// Single runs are dominated by cold caches and branch predictors, so the fastest of a number
+/// of runs is reported.
+fn best(profile: impl Fn() -> profile::Profile) -> profile::Profile {
+    (0..1000)
+        .map(|_| profile())
+        .min_by_key(|p| p.cycles())
+        .unwrap()
+}
+
+fn main() {
+    let profiles = [
+        best(|| compact_treewalk_dtable::profile(&compact_treewalk_dtable::code())),
+        best(|| compact_treewalk_switch::profile(&compact_treewalk_switch::code())),
+        best(|| stack_dtable::profile(&stack_dtable::code())),
+        best(|| stack_switch::profile(&mut stack_switch::code())),
+        best(|| register_dtable::profile(&register_dtable::code())),
+        best(|| register_fixed::profile(&register_fixed::code())),
+        best(|| register_switch::profile(&mut register_switch::code())),
+    ];
+
+    for profile in &profiles {
+        println!("{profile}");
+    }
+
+    let baseline = profiles.last().unwrap();
+    for profile in &profiles[..profiles.len() - 1] {
+        println!("{}", profile.compare(baseline));
+    }
+}
@@ -0,0 +1,343 @@
+//! Turns the results criterion saved under `target/criterion` into a table of medians with their
+//! confidence intervals and slowdowns relative to native code, together with a description of
+//! the machine they were measured on.
+//!
+//! Run `cargo bench` first, then eg. `cargo run --release --example report -- --format markdown`.
+//! The JSON format keeps the samples, so that `examples/compare.rs` can compare two reports.
+
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use dispatchers::workloads::WORKLOADS;
+use serde_json::{json, Value};
+
+const USAGE: &str = "\
+usage: report [--criterion <dir>] [--format markdown|csv|json] [--output <path>]
+
+  --criterion <dir>  where criterion saved its results (default: target/criterion)
+  --format <format>  the format of the report (default: markdown)
+  --output <path>    where to write the report (default: standard output)";
+
+/// The order in which backends are listed, from the fastest in principle to the slowest.
+const BACKENDS: &[&str] = &[
+    "native",
+    "treewalk",
+    "compact treewalk (dtable)",
+    "compact treewalk (switch)",
+    "stack (dtable)",
+    "stack (switch)",
+    "register (dtable)",
+    "register (switch)",
+    "register (fixed)",
+];
+
+/// The ungrouped benchmarks all run the factorial.
+const FACTORIAL: &str = "factorial";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Markdown,
+    Csv,
+    Json,
+}
+
+struct Measurement {
+    workload: String,
+    backend: String,
+    /// Median time per iteration with its 95% confidence interval, in nanoseconds.
+    median: f64,
+    lower: f64,
+    upper: f64,
+    /// Time per iteration of each sample, in nanoseconds.
+    samples: Vec<f64>,
+}
+
+struct Machine {
+    cpu: String,
+    os: String,
+    rustc: String,
+    commit: String,
+}
+
+fn read_json(path: &Path) -> io::Result<Value> {
+    let text = fs::read_to_string(path)?;
+    serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn number(value: &Value, path: &[&str]) -> Option<f64> {
+    path.iter().try_fold(value, |v, key| v.get(key))?.as_f64()
+}
+
+/// Reads a single benchmark from its directory, which holds criterion's latest results in `new`.
+fn read_measurement(directory: &Path) -> io::Result<Measurement> {
+    let invalid = |what| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: missing {what}", directory.display()),
+        )
+    };
+    let benchmark = read_json(&directory.join("benchmark.json"))?;
+    let estimates = read_json(&directory.join("estimates.json"))?;
+    let sample = read_json(&directory.join("sample.json"))?;
+
+    let group = benchmark["group_id"]
+        .as_str()
+        .ok_or_else(|| invalid("group"))?;
+    let (workload, backend) = match benchmark["function_id"].as_str() {
+        Some(function) => (group, function),
+        None => (FACTORIAL, group),
+    };
+
+    let median = &estimates["median"];
+    let iters = sample["iters"].as_array().ok_or_else(|| invalid("iters"))?;
+    let times = sample["times"].as_array().ok_or_else(|| invalid("times"))?;
+    let samples = iters
+        .iter()
+        .zip(times)
+        .map(|(iters, time)| Some(time.as_f64()? / iters.as_f64()?))
+        .collect::<Option<_>>()
+        .ok_or_else(|| invalid("samples"))?;
+
+    Ok(Measurement {
+        workload: workload.to_owned(),
+        backend: backend.to_owned(),
+        median: number(median, &["point_estimate"]).ok_or_else(|| invalid("median"))?,
+        lower: number(median, &["confidence_interval", "lower_bound"])
+            .ok_or_else(|| invalid("lower bound"))?,
+        upper: number(median, &["confidence_interval", "upper_bound"])
+            .ok_or_else(|| invalid("upper bound"))?,
+        samples,
+    })
+}
+
+/// Finds every benchmark under the criterion directory, skipping its HTML reports.
+fn find_benchmarks(directory: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    if directory.join("new").join("benchmark.json").is_file() {
+        found.push(directory.join("new"));
+        return Ok(());
+    }
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() && path.file_name().is_some_and(|name| name != "report") {
+            find_benchmarks(&path, found)?;
+        }
+    }
+    Ok(())
+}
+
+fn sort_key(result: &Measurement) -> (usize, usize, String, String) {
+    let workload = if result.workload == FACTORIAL {
+        0
+    } else {
+        WORKLOADS
+            .iter()
+            .position(|w| w.name == result.workload)
+            .map_or(usize::MAX, |i| i + 1)
+    };
+    let backend = BACKENDS
+        .iter()
+        .position(|&b| b == result.backend)
+        .unwrap_or(usize::MAX);
+    (
+        workload,
+        backend,
+        result.workload.clone(),
+        result.backend.clone(),
+    )
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn machine() -> Machine {
+    let cpu = fs::read_to_string("/proc/cpuinfo")
+        .ok()
+        .and_then(|info| {
+            info.lines()
+                .find(|line| line.starts_with("model name"))
+                .and_then(|line| line.split_once(':'))
+                .map(|(_, model)| model.trim().to_owned())
+        })
+        .unwrap_or_else(|| "unknown".into());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    Machine {
+        cpu,
+        os: format!("{} {}", env::consts::OS, env::consts::ARCH),
+        rustc: command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into()),
+        commit: command_output("git", &["describe", "--always", "--dirty"])
+            .unwrap_or_else(|| "unknown".into()),
+    }
+}
+
+/// The slowdown of each result relative to native code running the same workload.
+fn relative_to_native(results: &[Measurement]) -> Vec<Option<f64>> {
+    results
+        .iter()
+        .map(|result| {
+            results
+                .iter()
+                .find(|r| r.workload == result.workload && r.backend == "native")
+                .map(|native| result.median / native.median)
+        })
+        .collect()
+}
+
+struct Time(f64);
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (value, unit) = match self.0 {
+            t if t < 1e3 => (t, "ns"),
+            t if t < 1e6 => (t / 1e3, "µs"),
+            t if t < 1e9 => (t / 1e6, "ms"),
+            t => (t / 1e9, "s"),
+        };
+        write!(f, "{value:.2} {unit}")
+    }
+}
+
+fn markdown(out: &mut impl Write, machine: &Machine, results: &[Measurement]) -> io::Result<()> {
+    writeln!(out, "CPU: {}  ", machine.cpu)?;
+    writeln!(out, "OS: {}  ", machine.os)?;
+    writeln!(out, "Compiler: {}  ", machine.rustc)?;
+    writeln!(out, "Commit: {}", machine.commit)?;
+
+    let relative = relative_to_native(results);
+    let mut workload = None;
+    for (result, relative) in results.iter().zip(relative) {
+        if workload != Some(&result.workload) {
+            workload = Some(&result.workload);
+            writeln!(out, "\n### {}\n", result.workload)?;
+            writeln!(out, "| Backend | Median | 95% CI | vs native |")?;
+            writeln!(out, "|---|--:|--:|--:|")?;
+        }
+        let relative = relative.map_or_else(String::new, |r| format!("{r:.1}x"));
+        writeln!(
+            out,
+            "| {} | {} | {} – {} | {} |",
+            result.backend,
+            Time(result.median),
+            Time(result.lower),
+            Time(result.upper),
+            relative
+        )?;
+    }
+    Ok(())
+}
+
+fn csv(out: &mut impl Write, results: &[Measurement]) -> io::Result<()> {
+    writeln!(
+        out,
+        "workload,backend,median_ns,lower_ns,upper_ns,relative_to_native"
+    )?;
+    for (result, relative) in results.iter().zip(relative_to_native(results)) {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            result.workload,
+            result.backend,
+            result.median,
+            result.lower,
+            result.upper,
+            relative.map_or_else(String::new, |r| r.to_string())
+        )?;
+    }
+    Ok(())
+}
+
+fn to_json(machine: &Machine, results: &[Measurement]) -> Value {
+    let results: Vec<_> = results
+        .iter()
+        .zip(relative_to_native(results))
+        .map(|(result, relative)| {
+            json!({
+                "workload": result.workload,
+                "backend": result.backend,
+                "median_ns": result.median,
+                "lower_ns": result.lower,
+                "upper_ns": result.upper,
+                "relative_to_native": relative,
+                "samples_ns": result.samples,
+            })
+        })
+        .collect();
+    json!({
+        "machine": {
+            "cpu": machine.cpu,
+            "os": machine.os,
+            "rustc": machine.rustc,
+            "commit": machine.commit,
+        },
+        "results": results,
+    })
+}
+
+fn run() -> Result<(), String> {
+    let mut criterion = PathBuf::from("target/criterion");
+    let mut format = Format::Markdown;
+    let mut output = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--criterion" => criterion = value()?.into(),
+            "--format" => {
+                format = match value()?.as_str() {
+                    "markdown" | "md" => Format::Markdown,
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    other => return Err(format!("unknown format `{other}`")),
+                }
+            }
+            "--output" => output = Some(value()?),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let mut directories = vec![];
+    find_benchmarks(&criterion, &mut directories)
+        .map_err(|e| format!("cannot read {}: {e}", criterion.display()))?;
+    if directories.is_empty() {
+        return Err(format!(
+            "no results in {}; run `cargo bench` first",
+            criterion.display()
+        ));
+    }
+    let mut results = directories
+        .iter()
+        .map(|directory| read_measurement(directory).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    results.sort_by_key(sort_key);
+
+    let machine = machine();
+    let mut report = vec![];
+    match format {
+        Format::Markdown => markdown(&mut report, &machine, &results),
+        Format::Csv => csv(&mut report, &results),
+        Format::Json => writeln!(report, "{:#}", to_json(&machine, &results)),
+    }
+    .unwrap();
+
+    match output {
+        Some(path) => fs::write(&path, report).map_err(|e| format!("cannot write {path}: {e}")),
+        None => io::stdout()
+            .write_all(&report)
+            .map_err(|e| format!("cannot write the report: {e}")),
+    }
+}
+
+fn main() {
+    if let Err(message) = run() {
+        eprintln!("{message}");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,27 @@
+; Bubble sorts n pseudo-random 16-bit integers from a linear congruential generator in memory,
+; and returns the sum of each integer times its position. There are no conditionals, so every
+; pair is written back, and swapped by adding the difference between them times 0 or 1.
+(args n seed)
+(let x seed)
+(let i 0)
+(while (<= (+ i 1) n)
+  (let x (+ (* x 1103515245) 12345))
+  (store16 (* i 2) (/ x 65536))
+  (let i (+ i 1)))
+(let end n)
+(while (<= 2 end)
+  (let j 0)
+  (while (<= (+ j 2) end)
+    (let a (load16 (* j 2)))
+    (let b (load16 (+ (* j 2) 2)))
+    (let swap (- 1 (<= a b)))
+    (store16 (* j 2) (+ a (* swap (- b a))))
+    (store16 (+ (* j 2) 2) (+ b (* swap (- a b))))
+    (let j (+ j 1)))
+  (let end (- end 1)))
+(let sum 0)
+(let i 0)
+(while (<= (+ i 1) n)
+  (let sum (+ sum (* (load16 (* i 2)) (+ i 1))))
+  (let i (+ i 1)))
+sum
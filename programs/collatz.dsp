@@ -0,0 +1,14 @@
+; The total number of steps it takes for every start from 1 to n to reach 1 in the Collatz
+; sequence. There are no conditionals, so both branches of a step are computed and the unwanted
+; one is multiplied by 0.
+(args n)
+(let total 0)
+(let start 1)
+(while (<= start n)
+  (let x start)
+  (while (<= 2 x)
+    (let odd (% x 2))
+    (let x (+ (* (- 1 odd) (/ x 2)) (* odd (+ (* 3 x) 1))))
+    (let total (+ total 1)))
+  (let start (+ start 1)))
+total
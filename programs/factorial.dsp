@@ -0,0 +1,8 @@
+; The program benchmarked by every backend: computes n! with wrapping 32-bit arithmetic.
+(args n)
+(let i 1)
+(let x 1)
+(while (<= i n)
+  (let x (* x i))
+  (let i (+ i 1)))
+x
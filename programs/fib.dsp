@@ -0,0 +1,25 @@
+; The n-th Fibonacci number, by the naive recursion fib(k) = fib(k - 1) + fib(k - 2), which
+; makes a call for every node of a tree of fib(n + 1) leaves. Programs can't call functions, so
+; the tree is walked depth first with an explicit stack: bit i of `stack` says whether the call
+; at depth i is its parent's second call, on top of a 1 marking the bottom. That holds the 25
+; levels of fib(25), and up to 31.
+(args n)
+(let k n)
+(let stack 1)
+(let sum 0)
+(let running 1)
+(while running
+  ; Call fib(k - 1) until reaching a leaf, which returns k.
+  (while (<= 2 k)
+    (let stack (* stack 2))
+    (let k (- k 1)))
+  (let sum (+ sum k))
+  ; Return from second calls, whose parents were fib(k + 2).
+  (while (* (% stack 2) (<= 2 stack))
+    (let stack (/ stack 2))
+    (let k (+ k 2)))
+  ; Then from the first call, to make the second, unless the outermost call has returned.
+  (let running (<= 2 stack))
+  (let k (- k running))
+  (let stack (+ stack running)))
+sum
@@ -0,0 +1,22 @@
+; Adds up the squares of 1 to n, which one coroutine yields by squaring the numbers that another
+; yields, so that each number takes four switches between frames. Each coroutine returns 0 once
+; it runs out.
+(args n)
+(let numbers (coroutine
+  (let i 0)
+  (while (<= (+ i 1) n)
+    (let i (+ i 1))
+    (yield i))
+  0))
+(let squares (coroutine
+  (let x (resume numbers 0))
+  (while x
+    (yield (* x x))
+    (let x (resume numbers 0)))
+  0))
+(let total 0)
+(let x (resume squares 0))
+(while x
+  (let total (+ total x))
+  (let x (resume squares 0)))
+total
@@ -0,0 +1,21 @@
+; Builds a linked list of n records, each holding its index plus the round, then walks it to add
+; up the values, for a number of rounds. Each round's list is garbage by the next.
+(args rounds n)
+(let total 0)
+(let r 0)
+(while (<= (+ r 1) rounds)
+  (let list 0)
+  (let i 0)
+  (while (<= (+ i 1) n)
+    (let node (record 2))
+    (set node 0 (+ i r))
+    (set node 1 list)
+    (let list node)
+    (let i (+ i 1)))
+  (let i 0)
+  (while (<= (+ i 1) n)
+    (let total (+ total (get list 0)))
+    (let list (get list 1))
+    (let i (+ i 1)))
+  (let r (+ r 1)))
+total
@@ -0,0 +1,27 @@
+; The number of points on a size by size grid over [-2, 1] x [-1, 1] that are in the Mandelbrot
+; set, in 20.12 fixed point. Negative numbers are stored in two's complement, so adding 2^31
+; before dividing by 2^12 and subtracting 2^19 afterwards is an arithmetic shift right by 12.
+(args size iterations)
+(let inside 0)
+(let y 0)
+(while (<= (+ y 1) size)
+  (let ci (- (/ (* y 8192) size) 4096))
+  (let x 0)
+  (while (<= (+ x 1) size)
+    (let cr (- (/ (* x 12288) size) 8192))
+    (let zr 0)
+    (let zi 0)
+    (let rr 0)
+    (let ii 0)
+    (let i 0)
+    ; |z|^2 <= 4 and i < iterations
+    (while (* (<= (+ rr ii) 16384) (<= (+ i 1) iterations))
+      (let zi (+ (- (/ (+ (* (* 2 zr) zi) 2147483648) 4096) 524288) ci))
+      (let zr (+ (- rr ii) cr))
+      (let rr (/ (* zr zr) 4096))
+      (let ii (/ (* zi zi) 4096))
+      (let i (+ i 1)))
+    (let inside (+ inside (<= iterations i)))
+    (let x (+ x 1)))
+  (let y (+ y 1)))
+inside
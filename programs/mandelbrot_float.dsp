@@ -0,0 +1,27 @@
+; `mandelbrot` in floating point: the number of points on a size by size grid over
+; [-2, 1] x [-1, 1] that are in the Mandelbrot set.
+(args size iterations)
+(let inside 0)
+(let fsize (float size))
+(let y 0)
+(while (<= (+ y 1) size)
+  (let ci (f- (f/ (f* (float y) 2.0) fsize) 1.0))
+  (let x 0)
+  (while (<= (+ x 1) size)
+    (let cr (f- (f/ (f* (float x) 3.0) fsize) 2.0))
+    (let zr 0.0)
+    (let zi 0.0)
+    (let rr 0.0)
+    (let ii 0.0)
+    (let i 0)
+    ; not |z|^2 > 4 and i < iterations
+    (while (* (- 1 (f< 4.0 (f+ rr ii))) (<= (+ i 1) iterations))
+      (let zi (f+ (f* (f* 2.0 zr) zi) ci))
+      (let zr (f+ (f- rr ii) cr))
+      (let rr (f* zr zr))
+      (let ii (f* zi zi))
+      (let i (+ i 1)))
+    (let inside (+ inside (<= iterations i)))
+    (let x (+ x 1)))
+  (let y (+ y 1)))
+inside
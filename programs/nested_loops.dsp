@@ -0,0 +1,11 @@
+; The sum of i * j over all pairs of i and j below n.
+(args n)
+(let sum 0)
+(let i 0)
+(while (<= (+ i 1) n)
+  (let j 0)
+  (while (<= (+ j 1) n)
+    (let sum (+ sum (* i j)))
+    (let j (+ j 1)))
+  (let i (+ i 1)))
+sum
@@ -0,0 +1,21 @@
+; The number of primes up to n, found with the sieve of Eratosthenes. Byte k of memory is set
+; once k is known to be composite. There are no conditionals, but the loop crossing off the
+; multiples of i runs no iterations when i is itself composite.
+(args n)
+(let k 0)
+(while (<= k n)
+  (store8 k 0)
+  (let k (+ k 1)))
+(let i 2)
+(while (<= (* i i) n)
+  (let j (* i i))
+  (while (* (<= j n) (- 1 (load8 i)))
+    (store8 j 1)
+    (let j (+ j i)))
+  (let i (+ i 1)))
+(let count 0)
+(let k 2)
+(while (<= k n)
+  (let count (+ count (- 1 (load8 k))))
+  (let k (+ k 1)))
+count
@@ -0,0 +1,255 @@
+//! Types shared by the compilers and verifiers of the bytecode backends.
+
+use std::{error, fmt};
+
+use crate::{exception::Handler, value::Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// The compact treewalk encoding can only express loops and sequences as statements, not
+    /// as operands of other instructions.
+    StatementInExpression,
+    /// The program needs more than the 65536 variable slots (including temporaries) that
+    /// operands can address.
+    TooManyVariables,
+    /// Evaluating the program needs more stack space than the backend has.
+    ExpressionTooDeep,
+    /// The program doesn't fit in the 4 GiB addressable by the program counter.
+    CodeTooLarge,
+    /// The program's strings don't fit in the part of the constant pool that operands can
+    /// address.
+    TooManyConstants,
+    /// A call to a host function has more than the 255 arguments an operand can count.
+    TooManyArguments,
+    /// The compact treewalk encoding, like the tree walker, cannot suspend an evaluation to
+    /// switch to a coroutine.
+    CoroutinesUnsupported,
+    /// The compact treewalk encoding has no exception tables, and can't find where a handler
+    /// starts without evaluating the code before it.
+    ExceptionsUnsupported,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::StatementInExpression => {
+                "loops and sequences cannot be used as operands in this backend"
+            }
+            Self::TooManyVariables => "program uses too many variables",
+            Self::ExpressionTooDeep => "expressions are nested too deeply",
+            Self::CodeTooLarge => "program is too large",
+            Self::TooManyConstants => "program has too many constants",
+            Self::TooManyArguments => "call has too many arguments",
+            Self::CoroutinesUnsupported => "coroutines need a stack or register backend",
+            Self::ExceptionsUnsupported => {
+                "exceptions need a tree walker, stack or register backend"
+            }
+        })
+    }
+}
+
+impl error::Error for CompileError {}
+
+/// Bytecode that would make the interpreter misbehave, given that it doesn't perform any checks
+/// at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub pc: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at pc {}: {}", self.pc, self.message)
+    }
+}
+
+impl error::Error for VerifyError {}
+
+/// Compiled bytecode together with its constant pool, which holds the integers too large to be
+/// encoded as small immediates and the contents of strings. Each is stored only once.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<u32>,
+    /// The number of variable slots the code needs, including temporaries and, for the stack
+    /// machine, the operand stack.
+    pub slots: usize,
+    /// The exception table.
+    pub handlers: Vec<Handler>,
+}
+
+/// Disassembled instructions, paired with their program counters.
+pub type Disassembly = Vec<(u32, String)>;
+
+/// Runs `f` on a frame of `slots` variable slots, the first of which are initialized from
+/// `variables` and copied back afterwards. The rest start out as the integer zero. Frames of up
+/// to 256 slots are kept on the stack.
+pub(crate) fn with_slots<R>(
+    slots: usize,
+    variables: &mut [Value],
+    f: impl FnOnce(&mut [Value]) -> R,
+) -> R {
+    let mut small = [Value::int(0); 256];
+    let mut large = vec![];
+    let frame = if slots <= small.len() {
+        &mut small[..slots]
+    } else {
+        large.resize(slots, Value::int(0));
+        &mut large[..]
+    };
+    let n = variables.len().min(slots);
+    frame[..n].copy_from_slice(&variables[..n]);
+    let result = f(frame);
+    variables[..n].copy_from_slice(&frame[..n]);
+    result
+}
+
+/// How far a relative jump reaches, depending on whether its offset is one, two or four bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Reach {
+    Short,
+    Long,
+    Wide,
+}
+
+impl Reach {
+    fn fits(self, offset: i64) -> bool {
+        match self {
+            Reach::Short => i8::try_from(offset).is_ok(),
+            Reach::Long => i16::try_from(offset).is_ok(),
+            Reach::Wide => i32::try_from(offset).is_ok(),
+        }
+    }
+}
+
+/// A position in the code that jumps can target, which can be used before it's placed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Label(usize);
+
+/// Jumps whose offsets are only resolved once all code has been written, so that each can be
+/// given the shortest encoding that reaches its target. Positions are in the code written so
+/// far, which doesn't include the jumps themselves.
+pub(crate) struct Jumps<T> {
+    /// The position of each label, and the number of jumps written before it.
+    labels: Vec<Option<(usize, usize)>>,
+    jumps: Vec<Jump<T>>,
+}
+
+struct Jump<T> {
+    at: usize,
+    target: Label,
+    /// The shortest reach the jump may be encoded with.
+    reach: Reach,
+    /// The size of the jump with each reach.
+    sizes: [usize; 3],
+    /// What the backend needs to encode the jump.
+    data: T,
+}
+
+impl<T> Default for Jumps<T> {
+    fn default() -> Self {
+        Self {
+            labels: vec![],
+            jumps: vec![],
+        }
+    }
+}
+
+impl<T> Jumps<T> {
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Places `label` at `at`, after the jumps added so far.
+    pub fn place(&mut self, label: Label, at: usize) {
+        self.labels[label.0] = Some((at, self.jumps.len()));
+    }
+
+    pub fn add(&mut self, at: usize, target: Label, reach: Reach, sizes: [usize; 3], data: T) {
+        self.jumps.push(Jump {
+            at,
+            target,
+            reach,
+            sizes,
+            data,
+        });
+    }
+
+    /// Inserts the jumps into `code`, calling `encode` with the reach of each and its offset
+    /// from the end of the jump to its target. Every jump starts out as short as it may be and
+    /// is lengthened until it reaches its target; since jumps only ever grow, this settles.
+    pub fn link(
+        self,
+        code: &[u8],
+        mut encode: impl FnMut(&mut Vec<u8>, &T, Reach, i32),
+    ) -> Result<Linked, CompileError> {
+        let mut reaches: Vec<Reach> = self.jumps.iter().map(|jump| jump.reach).collect();
+        let mut offsets = vec![0; self.jumps.len()];
+        // The number of bytes taken up by the jumps before each one.
+        let mut growth = Vec::with_capacity(self.jumps.len() + 1);
+        loop {
+            growth.clear();
+            growth.push(0);
+            for (jump, &reach) in self.jumps.iter().zip(&reaches) {
+                growth.push(growth.last().unwrap() + jump.sizes[reach as usize]);
+            }
+
+            let mut settled = true;
+            for (i, jump) in self.jumps.iter().enumerate() {
+                let (at, jumps_before) = self.labels[jump.target.0].expect("label is placed");
+                let target = (at + growth[jumps_before]) as i64;
+                let offset = target - (jump.at + growth[i + 1]) as i64;
+                while !reaches[i].fits(offset) {
+                    reaches[i] = match reaches[i] {
+                        Reach::Short => Reach::Long,
+                        Reach::Long => Reach::Wide,
+                        Reach::Wide => return Err(CompileError::CodeTooLarge),
+                    };
+                    settled = false;
+                }
+                offsets[i] = offset as i32;
+            }
+            if settled {
+                break;
+            }
+        }
+
+        let mut linked = Vec::with_capacity(code.len());
+        let mut copied = 0;
+        for ((jump, reach), offset) in self.jumps.iter().zip(reaches).zip(offsets) {
+            linked.extend_from_slice(&code[copied..jump.at]);
+            copied = jump.at;
+            encode(&mut linked, &jump.data, reach, offset);
+        }
+        linked.extend_from_slice(&code[copied..]);
+        let labels = self
+            .labels
+            .iter()
+            .map(|label| label.map(|(at, jumps_before)| at + growth[jumps_before]))
+            .collect();
+        Ok(Linked {
+            code: linked,
+            labels,
+        })
+    }
+}
+
+/// Code with its jumps inserted, and where its labels ended up.
+pub(crate) struct Linked {
+    pub code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+}
+
+impl Linked {
+    pub fn position(&self, label: Label) -> u32 {
+        self.labels[label.0].expect("label is placed") as u32
+    }
+}
+
+/// Sign-extends an operand of `size` bytes.
+pub(crate) fn sign_extend(x: u32, size: usize) -> i32 {
+    let shift = 32 - 8 * size as u32;
+    (x << shift) as i32 >> shift
+}
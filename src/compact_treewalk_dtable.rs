@@ -1,7 +1,27 @@
+use crate::{
+    bytecode::{with_slots, Chunk},
+    heap::{self, Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    profile::Profiler,
+    value::Value,
+};
+
+/// `compact treewalk (dtable)` shares its encoding with `compact treewalk (switch)`, so it
+/// reuses its tooling.
+pub use crate::compact_treewalk_switch::{compile, disassemble, verify};
+
+// Must be kept in sync with `compact_treewalk_switch::Opcode`, whose compiler emits the opcodes
+// that `code` doesn't use.
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 enum Opcode {
     Int,
+    SmallInt,
+    Const8,
+    Const16,
+    Float,
 
     Var,
     Let,
@@ -9,21 +29,60 @@ enum Opcode {
     LessEq,
     Add,
     Multiply,
-
+    Subtract,
+    Divide,
+    Remainder,
+
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+    IntToFloat,
+    FloatToInt,
+
+    Load8,
+    Load16,
+    Load32,
+    Store8,
+    Store16,
+    Store32,
+
+    NewArray,
+    NewRecord,
+    NewString,
+    Get,
+    Set,
+    Len,
+
+    CallNative,
+
+    JumpIfNot8,
+    Jump8,
     JumpIfNot,
     Jump,
+    Wide,
     Halt,
 }
 
 struct Frame<'c> {
-    variables: [u32; 256],
+    variables: &'c mut [Value],
+    memory: &'c mut [u8],
+    heap: &'c mut Heap,
+    host: &'c Host,
+    /// Objects whose fields are being accessed and arguments of host functions being called,
+    /// which the garbage collector must keep alive.
+    roots: Vec<Value>,
     bytecode: &'c [u8],
-    pc: u16,
+    constants: &'c [u32],
+    pc: u32,
+    profiler: Profiler,
 }
 
 impl<'c> Frame<'c> {
     fn read_u8(&mut self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         let x = *unsafe { self.bytecode.get_unchecked(self.pc as usize) };
         self.pc += 1;
         x
@@ -31,13 +90,15 @@ impl<'c> Frame<'c> {
 
     fn peek_u8(&self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         *unsafe { self.bytecode.get_unchecked(self.pc as usize) }
     }
 
     fn read_u16(&mut self) -> u16 {
         debug_assert!((self.pc as usize + 1) < self.bytecode.len());
+        self.profiler.read_bytecode(2);
         let x = unsafe {
-            *std::mem::transmute::<_, &u16>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u16>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 2;
         u16::from_le(x)
@@ -45,97 +106,370 @@ impl<'c> Frame<'c> {
 
     fn read_u32(&mut self) -> u32 {
         debug_assert!((self.pc as usize + 3) < self.bytecode.len());
+        self.profiler.read_bytecode(4);
         let x = unsafe {
-            *std::mem::transmute::<_, &u32>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u32>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 4;
         u32::from_le(x)
     }
 
-    fn var(&self, i: u8) -> u32 {
+    fn read_u64(&mut self) -> u64 {
+        debug_assert!((self.pc as usize + 7) < self.bytecode.len());
+        self.profiler.read_bytecode(8);
+        let x = unsafe {
+            *std::mem::transmute::<&u8, &u64>(self.bytecode.get_unchecked(self.pc as usize))
+        };
+        self.pc += 8;
+        u64::from_le(x)
+    }
+
+    /// Evaluates the condition of a `JumpIfNot`, and jumps by `offset` from the end of the
+    /// jump's operand if it's zero.
+    fn jump_if_not(&mut self, offset: i32) -> Value {
+        let base = self.pc;
+        let condition = self.step();
+        if !condition.is_truthy() {
+            self.pc = base.wrapping_add_signed(offset);
+        }
+        Value::int(0)
+    }
+
+    fn load(&mut self, width: Width) -> Value {
+        let address = self.step().as_int();
+        Value::int(memory::load(self.memory, address, width))
+    }
+
+    fn store(&mut self, width: Width) -> Value {
+        let address = self.step().as_int();
+        let value = self.step().as_int();
+        memory::store(self.memory, address, width, value);
+        Value::int(value)
+    }
+
+    fn alloc(&mut self, object: Object) -> Value {
+        self.heap.alloc(object, &[self.variables, &self.roots])
+    }
+
+    fn constant(&self, i: u16) -> u32 {
+        debug_assert!((i as usize) < self.constants.len());
+        self.profiler.read_bytecode(4);
+        *unsafe { self.constants.get_unchecked(i as usize) }
+    }
+
+    fn string(&self, i: u16) -> Box<[u8]> {
+        heap::read_string_constant(self.constants, i as usize).expect("verified string constant")
+    }
+
+    fn var(&self, i: u16) -> Value {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.read_variable();
         unsafe { *self.variables.get_unchecked(i as usize) }
     }
 
-    fn set_var(&mut self, i: u8, val: u32) {
+    fn set_var(&mut self, i: u16, val: Value) {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.write_variable();
         unsafe {
             *self.variables.get_unchecked_mut(i as usize) = val;
         }
     }
 }
 
-static DISPATCH_TABLE: [fn(&mut Frame) -> u32; 8] = [
+static DISPATCH_TABLE: [fn(&mut Frame) -> Value; 38] = [
     exec_int,
+    exec_small_int,
+    exec_const8,
+    exec_const16,
+    exec_float,
     exec_var,
     exec_let,
     exec_less_eq,
     exec_add,
     exec_multiply,
+    exec_subtract,
+    exec_divide,
+    exec_remainder,
+    exec_f_less,
+    exec_f_add,
+    exec_f_multiply,
+    exec_f_subtract,
+    exec_f_divide,
+    exec_int_to_float,
+    exec_float_to_int,
+    exec_load8,
+    exec_load16,
+    exec_load32,
+    exec_store8,
+    exec_store16,
+    exec_store32,
+    exec_new_array,
+    exec_new_record,
+    exec_new_string,
+    exec_get,
+    exec_set,
+    exec_len,
+    exec_call_native,
+    exec_jump_if_not8,
+    exec_jump8,
     exec_jump_if_not,
     exec_jump,
+    exec_wide,
 ];
 
 impl<'c> Frame<'c> {
-    fn step(&mut self) -> u32 {
+    fn step(&mut self) -> Value {
+        let pc = self.pc;
         let opcode = self.read_u8();
-        unsafe { DISPATCH_TABLE.get_unchecked(opcode as usize)(self) }
+        self.profiler.enter(pc, opcode);
+        let value = unsafe { DISPATCH_TABLE.get_unchecked(opcode as usize)(self) };
+        self.profiler.leave();
+        value
     }
 
-    fn eval(&mut self) {
+    /// Evaluates statements until `Halt`, and returns the value of the last one.
+    fn eval(&mut self) -> Value {
+        let mut value = Value::int(0);
         while self.peek_u8() != Opcode::Halt as u8 {
-            self.step();
+            value = self.step();
         }
+        value
     }
 }
 
-fn exec_int(frame: &mut Frame) -> u32 {
-    frame.read_u32()
+fn exec_int(frame: &mut Frame) -> Value {
+    Value::int(frame.read_u32())
+}
+
+fn exec_small_int(frame: &mut Frame) -> Value {
+    Value::int(frame.read_u8() as u32)
 }
 
-fn exec_var(frame: &mut Frame) -> u32 {
+fn exec_const8(frame: &mut Frame) -> Value {
     let i = frame.read_u8();
-    frame.var(i)
+    Value::int(frame.constant(i as u16))
+}
+
+fn exec_const16(frame: &mut Frame) -> Value {
+    let i = frame.read_u16();
+    Value::int(frame.constant(i))
 }
 
-fn exec_let(frame: &mut Frame) -> u32 {
+fn exec_float(frame: &mut Frame) -> Value {
+    Value::float(f64::from_bits(frame.read_u64()))
+}
+
+fn exec_var(frame: &mut Frame) -> Value {
+    let i = frame.read_u8();
+    frame.var(i as u16)
+}
+
+fn exec_let(frame: &mut Frame) -> Value {
     let i = frame.read_u8();
     let val = frame.step();
-    frame.set_var(i, val);
+    frame.set_var(i as u16, val);
     val
 }
 
-fn exec_less_eq(frame: &mut Frame) -> u32 {
-    let a = frame.step();
-    let b = frame.step();
-    (a <= b) as u32
+fn exec_less_eq(frame: &mut Frame) -> Value {
+    let a = frame.step().as_int();
+    let b = frame.step().as_int();
+    Value::int((a <= b) as u32)
 }
 
-fn exec_add(frame: &mut Frame) -> u32 {
+fn exec_add(frame: &mut Frame) -> Value {
     let a = frame.step();
-    let b = frame.step();
-    a + b
+    a + frame.step()
 }
 
-fn exec_multiply(frame: &mut Frame) -> u32 {
-    let a = frame.step();
-    let b = frame.step();
-    a * b
+fn exec_multiply(frame: &mut Frame) -> Value {
+    let a = frame.step().as_int();
+    let b = frame.step().as_int();
+    Value::int(a.wrapping_mul(b))
+}
+
+fn exec_subtract(frame: &mut Frame) -> Value {
+    let a = frame.step().as_int();
+    let b = frame.step().as_int();
+    Value::int(a.wrapping_sub(b))
+}
+
+fn exec_divide(frame: &mut Frame) -> Value {
+    let a = frame.step().as_int();
+    let b = frame.step().as_int();
+    Value::int(a / b)
+}
+
+fn exec_remainder(frame: &mut Frame) -> Value {
+    let a = frame.step().as_int();
+    let b = frame.step().as_int();
+    Value::int(a % b)
+}
+
+fn exec_f_less(frame: &mut Frame) -> Value {
+    let a = frame.step().as_float();
+    let b = frame.step().as_float();
+    Value::int((a < b) as u32)
+}
+
+fn exec_f_add(frame: &mut Frame) -> Value {
+    let a = frame.step().as_float();
+    let b = frame.step().as_float();
+    Value::float(a + b)
+}
+
+fn exec_f_multiply(frame: &mut Frame) -> Value {
+    let a = frame.step().as_float();
+    let b = frame.step().as_float();
+    Value::float(a * b)
+}
+
+fn exec_f_subtract(frame: &mut Frame) -> Value {
+    let a = frame.step().as_float();
+    let b = frame.step().as_float();
+    Value::float(a - b)
+}
+
+fn exec_f_divide(frame: &mut Frame) -> Value {
+    let a = frame.step().as_float();
+    let b = frame.step().as_float();
+    Value::float(a / b)
+}
+
+fn exec_int_to_float(frame: &mut Frame) -> Value {
+    Value::float(frame.step().as_int() as f64)
+}
+
+fn exec_float_to_int(frame: &mut Frame) -> Value {
+    Value::int(frame.step().as_float() as u32)
 }
 
-fn exec_jump_if_not(frame: &mut Frame) -> u32 {
-    let offset = frame.read_u16();
-    let condition = frame.step();
-    if condition == 0 {
-        frame.pc = offset;
+fn exec_load8(frame: &mut Frame) -> Value {
+    frame.load(Width::U8)
+}
+
+fn exec_load16(frame: &mut Frame) -> Value {
+    frame.load(Width::U16)
+}
+
+fn exec_load32(frame: &mut Frame) -> Value {
+    frame.load(Width::U32)
+}
+
+fn exec_store8(frame: &mut Frame) -> Value {
+    frame.store(Width::U8)
+}
+
+fn exec_store16(frame: &mut Frame) -> Value {
+    frame.store(Width::U16)
+}
+
+fn exec_store32(frame: &mut Frame) -> Value {
+    frame.store(Width::U32)
+}
+
+fn exec_new_array(frame: &mut Frame) -> Value {
+    let len = frame.step().as_int();
+    frame.alloc(Object::Array(vec![Value::int(0); len as usize]))
+}
+
+fn exec_new_record(frame: &mut Frame) -> Value {
+    let fields = frame.read_u16();
+    frame.alloc(Object::Record(vec![Value::int(0); fields as usize].into()))
+}
+
+fn exec_new_string(frame: &mut Frame) -> Value {
+    let i = frame.read_u16();
+    let string = frame.string(i);
+    frame.alloc(Object::String(string))
+}
+
+fn exec_get(frame: &mut Frame) -> Value {
+    let object = frame.step();
+    frame.roots.push(object);
+    let index = frame.step().as_int();
+    frame.roots.pop();
+    frame.heap.get(object, index)
+}
+
+fn exec_set(frame: &mut Frame) -> Value {
+    let object = frame.step();
+    frame.roots.push(object);
+    let index = frame.step().as_int();
+    let value = frame.step();
+    frame.roots.pop();
+    frame.heap.set(object, index, value);
+    value
+}
+
+fn exec_len(frame: &mut Frame) -> Value {
+    let object = frame.step();
+    Value::int(frame.heap.len(object))
+}
+
+fn exec_call_native(frame: &mut Frame) -> Value {
+    let function = frame.read_u16();
+    let argc = frame.read_u8();
+    let base = frame.roots.len();
+    for _ in 0..argc {
+        let argument = frame.step();
+        frame.roots.push(argument);
     }
-    0
+    let (roots, args) = frame.roots.split_at(base);
+    let roots = [&*frame.variables, roots];
+    let result = frame
+        .host
+        .call(function, &mut Call::new(args, frame.heap, &roots));
+    frame.roots.truncate(base);
+    result
 }
 
-fn exec_jump(frame: &mut Frame) -> u32 {
-    let offset = frame.read_u16();
-    frame.pc = offset;
-    0
+fn exec_jump_if_not8(frame: &mut Frame) -> Value {
+    let offset = frame.read_u8() as i8;
+    frame.jump_if_not(offset as i32)
+}
+
+fn exec_jump8(frame: &mut Frame) -> Value {
+    let offset = frame.read_u8() as i8;
+    frame.pc = frame.pc.wrapping_add_signed(offset as i32);
+    Value::int(0)
+}
+
+fn exec_jump_if_not(frame: &mut Frame) -> Value {
+    let offset = frame.read_u16() as i16;
+    frame.jump_if_not(offset as i32)
+}
+
+fn exec_jump(frame: &mut Frame) -> Value {
+    let offset = frame.read_u16() as i16;
+    frame.pc = frame.pc.wrapping_add_signed(offset as i32);
+    Value::int(0)
+}
+
+#[cold]
+fn exec_wide(frame: &mut Frame) -> Value {
+    match unsafe { std::mem::transmute::<u8, Opcode>(frame.read_u8()) } {
+        Opcode::Var => {
+            let i = frame.read_u16();
+            frame.var(i)
+        }
+        Opcode::Let => {
+            let i = frame.read_u16();
+            let val = frame.step();
+            frame.set_var(i, val);
+            val
+        }
+        Opcode::JumpIfNot => {
+            let offset = frame.read_u32() as i32;
+            frame.jump_if_not(offset)
+        }
+        Opcode::Jump => {
+            let offset = frame.read_u32() as i32;
+            frame.pc = frame.pc.wrapping_add_signed(offset);
+            Value::int(0)
+        }
+        _ => unsafe { std::hint::unreachable_unchecked() },
+    }
 }
 
 #[derive(Default)]
@@ -150,8 +484,8 @@ impl Writer {
         i
     }
 
-    fn pc(&self) -> u16 {
-        self.bytecode.len() as u16
+    fn pc(&self) -> u32 {
+        self.bytecode.len() as u32
     }
 
     fn write_u16(&mut self, x: u16) -> usize {
@@ -201,6 +535,7 @@ pub fn code() -> Vec<u8> {
     let loop_start = w.pc();
     w.write_opcode(Opcode::JumpIfNot);
     let loop_jump_hole = w.write_u16(0);
+    let loop_jump_base = w.pc();
     {
         w.write_opcode(Opcode::LessEq);
         {
@@ -242,22 +577,88 @@ pub fn code() -> Vec<u8> {
     }
 
     w.write_opcode(Opcode::Jump);
-    w.write_u16(loop_start);
+    w.write_u16((loop_start as i32 - (w.pc() as i32 + 2)) as u16);
 
     let loop_end = w.pc();
-    w.patch_u16(loop_jump_hole, loop_end);
+    w.patch_u16(loop_jump_hole, (loop_end - loop_jump_base) as u16);
     w.write_opcode(Opcode::Halt);
 
     w.bytecode
 }
 
 pub fn run(code: &[u8]) -> u32 {
+    let mut variables = [Value::int(0); 256];
     let mut frame = Frame {
-        variables: [0; 256],
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        roots: vec![],
         bytecode: code,
+        constants: &[],
         pc: 0,
+        profiler: Profiler::new(),
     };
-    frame.variables[0] = 10;
+    frame.variables[0] = Value::int(10);
     frame.eval();
-    frame.variables[2]
+    frame.variables[2].as_int()
+}
+
+#[cfg(feature = "profile")]
+pub fn profile(code: &[u8]) -> crate::profile::Profile {
+    let mut variables = [Value::int(0); 256];
+    let mut frame = Frame {
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        roots: vec![],
+        bytecode: code,
+        constants: &[],
+        pc: 0,
+        profiler: Profiler::new(),
+    };
+    frame.variables[0] = Value::int(10);
+    frame.eval();
+    frame
+        .profiler
+        .finish("compact treewalk (dtable)", |opcode| {
+            format!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) })
+        })
+}
+
+/// See [`compact_treewalk_switch::execute`][crate::compact_treewalk_switch::execute].
+pub fn execute(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Value {
+    execute_with(code, variables, memory, heap, host, Profiler::new()).0
+}
+
+pub fn execute_with(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    profiler: Profiler,
+) -> (Value, Profiler) {
+    with_slots(code.slots, variables, |variables| {
+        let mut frame = Frame {
+            variables,
+            memory,
+            heap,
+            host,
+            roots: vec![],
+            bytecode: &code.code,
+            constants: &code.constants,
+            pc: 0,
+            profiler,
+        };
+        let result = frame.eval();
+        (result, frame.profiler)
+    })
 }
@@ -1,7 +1,28 @@
+use std::collections::HashMap;
+
+use crate::{
+    bytecode::{
+        sign_extend, with_slots, Chunk, CompileError, Disassembly, Jumps, Label, Reach, VerifyError,
+    },
+    heap::{self, Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    profile::Profiler,
+    treewalk::Instruction,
+    value::Value,
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 enum Opcode {
     Int,
+    /// Evaluates to its one-byte operand, for the many small integers in programs.
+    SmallInt,
+    /// Evaluate to the constant at a one- or two-byte index in the constant pool.
+    Const8,
+    Const16,
+    /// Evaluates to its eight-byte operand.
+    Float,
 
     Var,
     Let,
@@ -9,21 +30,76 @@ enum Opcode {
     LessEq,
     Add,
     Multiply,
+    Subtract,
+    Divide,
+    Remainder,
+
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+    IntToFloat,
+    FloatToInt,
+
+    /// Evaluate to the integer in linear memory at the address their child evaluates to.
+    Load8,
+    Load16,
+    Load32,
+    /// Store the integer their second child evaluates to at the address the first evaluates to,
+    /// and evaluate to the integer.
+    Store8,
+    Store16,
+    Store32,
 
+    /// Evaluates to a new array, with as many elements as its child evaluates to.
+    NewArray,
+    /// Evaluates to a new record, with as many fields as its two-byte operand.
+    NewRecord,
+    /// Evaluates to a new string, copied from the constant at its two-byte operand.
+    NewString,
+    /// Evaluates to element, field or byte number (second child) of an object (first child).
+    Get,
+    /// Writes the value of its third child to an element or field, like `Get`, and evaluates to
+    /// it.
+    Set,
+    Len,
+
+    /// Calls the host function at its two-byte operand with as many children as its one-byte
+    /// operand after that, and evaluates to its result.
+    CallNative,
+
+    /// Jump by a signed offset from the end of their operand, so code can be moved without
+    /// patching. `JumpIfNot` evaluates its condition after the operand. The offset is one byte in
+    /// the `8` forms, and two bytes otherwise.
+    JumpIfNot8,
+    Jump8,
     JumpIfNot,
     Jump,
+    /// Prefix giving the following `Var` or `Let` a two-byte variable operand, or the following
+    /// `JumpIfNot` or `Jump` a four-byte offset.
+    Wide,
     Halt,
 }
 
 struct Frame<'c> {
-    variables: [u32; 256],
+    variables: &'c mut [Value],
+    memory: &'c mut [u8],
+    heap: &'c mut Heap,
+    host: &'c Host,
+    /// Objects whose fields are being accessed and arguments of host functions being called,
+    /// which the garbage collector must keep alive.
+    roots: Vec<Value>,
     bytecode: &'c [u8],
-    pc: u16,
+    constants: &'c [u32],
+    pc: u32,
+    profiler: Profiler,
 }
 
 impl<'c> Frame<'c> {
     fn read_u8(&mut self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         let x = *unsafe { self.bytecode.get_unchecked(self.pc as usize) };
         self.pc += 1;
         x
@@ -31,13 +107,15 @@ impl<'c> Frame<'c> {
 
     fn peek_u8(&self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         *unsafe { self.bytecode.get_unchecked(self.pc as usize) }
     }
 
     fn read_u16(&mut self) -> u16 {
         debug_assert!((self.pc as usize + 1) < self.bytecode.len());
+        self.profiler.read_bytecode(2);
         let x = unsafe {
-            *std::mem::transmute::<_, &u16>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u16>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 2;
         u16::from_le(x)
@@ -45,20 +123,105 @@ impl<'c> Frame<'c> {
 
     fn read_u32(&mut self) -> u32 {
         debug_assert!((self.pc as usize + 3) < self.bytecode.len());
+        self.profiler.read_bytecode(4);
         let x = unsafe {
-            *std::mem::transmute::<_, &u32>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u32>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 4;
         u32::from_le(x)
     }
 
-    fn var(&self, i: u8) -> u32 {
+    fn read_u64(&mut self) -> u64 {
+        debug_assert!((self.pc as usize + 7) < self.bytecode.len());
+        self.profiler.read_bytecode(8);
+        let x = unsafe {
+            *std::mem::transmute::<&u8, &u64>(self.bytecode.get_unchecked(self.pc as usize))
+        };
+        self.pc += 8;
+        u64::from_le(x)
+    }
+
+    /// Evaluates the condition of a `JumpIfNot`, and jumps by `offset` from the end of the
+    /// jump's operand if it's zero.
+    fn jump_if_not(&mut self, offset: i32) -> Value {
+        let base = self.pc;
+        let condition = self.step();
+        if !condition.is_truthy() {
+            self.pc = base.wrapping_add_signed(offset);
+        }
+        Value::int(0)
+    }
+
+    fn load(&mut self, width: Width) -> Value {
+        let address = self.step().as_int();
+        Value::int(memory::load(self.memory, address, width))
+    }
+
+    fn store(&mut self, width: Width) -> Value {
+        let address = self.step().as_int();
+        let value = self.step().as_int();
+        memory::store(self.memory, address, width, value);
+        Value::int(value)
+    }
+
+    fn alloc(&mut self, object: Object) -> Value {
+        self.heap.alloc(object, &[self.variables, &self.roots])
+    }
+
+    fn get(&mut self) -> Value {
+        let object = self.step();
+        self.roots.push(object);
+        let index = self.step().as_int();
+        self.roots.pop();
+        self.heap.get(object, index)
+    }
+
+    fn set(&mut self) -> Value {
+        let object = self.step();
+        self.roots.push(object);
+        let index = self.step().as_int();
+        let value = self.step();
+        self.roots.pop();
+        self.heap.set(object, index, value);
+        value
+    }
+
+    fn call_native(&mut self) -> Value {
+        let function = self.read_u16();
+        let argc = self.read_u8();
+        let base = self.roots.len();
+        for _ in 0..argc {
+            let argument = self.step();
+            self.roots.push(argument);
+        }
+        let (roots, args) = self.roots.split_at(base);
+        let roots = [&*self.variables, roots];
+        let result = self
+            .host
+            .call(function, &mut Call::new(args, self.heap, &roots));
+        self.roots.truncate(base);
+        result
+    }
+
+    fn constant(&self, i: u16) -> u32 {
+        debug_assert!((i as usize) < self.constants.len());
+        self.profiler.read_bytecode(4);
+        *unsafe { self.constants.get_unchecked(i as usize) }
+    }
+
+    fn string(&self, i: u16) -> Box<[u8]> {
+        heap::read_string_constant(self.constants, i as usize).expect("verified string constant")
+    }
+
+    fn var(&self, i: u16) -> Value {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.read_variable();
         unsafe { *self.variables.get_unchecked(i as usize) }
     }
 
-    fn set_var(&mut self, i: u8, val: u32) {
+    fn set_var(&mut self, i: u16, val: Value) {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.write_variable();
         unsafe {
             *self.variables.get_unchecked_mut(i as usize) = val;
         }
@@ -66,62 +229,183 @@ impl<'c> Frame<'c> {
 }
 
 impl<'c> Frame<'c> {
-    fn step(&mut self) -> u32 {
-        let opcode = unsafe { std::mem::transmute::<_, Opcode>(self.read_u8()) };
-        match opcode {
-            Opcode::Int => self.read_u32(),
+    fn step(&mut self) -> Value {
+        let pc = self.pc;
+        let opcode = unsafe { std::mem::transmute::<u8, Opcode>(self.read_u8()) };
+        self.profiler.enter(pc, opcode as u8);
+        let value = match opcode {
+            Opcode::Int => Value::int(self.read_u32()),
+            Opcode::SmallInt => Value::int(self.read_u8() as u32),
+            Opcode::Const8 => {
+                let i = self.read_u8();
+                Value::int(self.constant(i as u16))
+            }
+            Opcode::Const16 => {
+                let i = self.read_u16();
+                Value::int(self.constant(i))
+            }
+            Opcode::Float => Value::float(f64::from_bits(self.read_u64())),
             Opcode::Var => {
                 let i = self.read_u8();
-                self.var(i)
+                self.var(i as u16)
             }
             Opcode::Let => {
                 let i = self.read_u8();
                 let val = self.step();
-                self.set_var(i, val);
+                self.set_var(i as u16, val);
                 val
             }
             Opcode::LessEq => {
-                let a = self.step();
-                let b = self.step();
-                (a <= b) as u32
+                let a = self.step().as_int();
+                let b = self.step().as_int();
+                Value::int((a <= b) as u32)
             }
             Opcode::Add => {
                 let a = self.step();
-                let b = self.step();
-                a + b
+                a + self.step()
             }
             Opcode::Multiply => {
-                let a = self.step();
-                let b = self.step();
-                a * b
+                let a = self.step().as_int();
+                let b = self.step().as_int();
+                Value::int(a.wrapping_mul(b))
+            }
+            Opcode::Subtract => {
+                let a = self.step().as_int();
+                let b = self.step().as_int();
+                Value::int(a.wrapping_sub(b))
+            }
+            Opcode::Divide => {
+                let a = self.step().as_int();
+                let b = self.step().as_int();
+                Value::int(a / b)
+            }
+            Opcode::Remainder => {
+                let a = self.step().as_int();
+                let b = self.step().as_int();
+                Value::int(a % b)
+            }
+            Opcode::FLess => {
+                let a = self.step().as_float();
+                let b = self.step().as_float();
+                Value::int((a < b) as u32)
+            }
+            Opcode::FAdd => {
+                let a = self.step().as_float();
+                let b = self.step().as_float();
+                Value::float(a + b)
+            }
+            Opcode::FMultiply => {
+                let a = self.step().as_float();
+                let b = self.step().as_float();
+                Value::float(a * b)
+            }
+            Opcode::FSubtract => {
+                let a = self.step().as_float();
+                let b = self.step().as_float();
+                Value::float(a - b)
+            }
+            Opcode::FDivide => {
+                let a = self.step().as_float();
+                let b = self.step().as_float();
+                Value::float(a / b)
+            }
+            Opcode::IntToFloat => Value::float(self.step().as_int() as f64),
+            Opcode::FloatToInt => Value::int(self.step().as_float() as u32),
+            Opcode::Load8 => self.load(Width::U8),
+            Opcode::Load16 => self.load(Width::U16),
+            Opcode::Load32 => self.load(Width::U32),
+            Opcode::Store8 => self.store(Width::U8),
+            Opcode::Store16 => self.store(Width::U16),
+            Opcode::Store32 => self.store(Width::U32),
+            Opcode::NewArray => {
+                let len = self.step().as_int();
+                self.alloc(Object::Array(vec![Value::int(0); len as usize]))
+            }
+            Opcode::NewRecord => {
+                let fields = self.read_u16();
+                self.alloc(Object::Record(vec![Value::int(0); fields as usize].into()))
+            }
+            Opcode::NewString => {
+                let i = self.read_u16();
+                let string = self.string(i);
+                self.alloc(Object::String(string))
+            }
+            Opcode::Get => self.get(),
+            Opcode::Set => self.set(),
+            Opcode::Len => {
+                let object = self.step();
+                Value::int(self.heap.len(object))
+            }
+            Opcode::CallNative => self.call_native(),
+            Opcode::JumpIfNot8 => {
+                let offset = self.read_u8() as i8;
+                self.jump_if_not(offset as i32)
+            }
+            Opcode::Jump8 => {
+                let offset = self.read_u8() as i8;
+                self.pc = self.pc.wrapping_add_signed(offset as i32);
+                Value::int(0)
             }
             Opcode::JumpIfNot => {
-                let offset = self.read_u16();
-                let condition = self.step();
-                if condition == 0 {
-                    self.pc = offset;
-                }
-                0
+                let offset = self.read_u16() as i16;
+                self.jump_if_not(offset as i32)
             }
             Opcode::Jump => {
-                let offset = self.read_u16();
-                self.pc = offset;
-                0
+                let offset = self.read_u16() as i16;
+                self.pc = self.pc.wrapping_add_signed(offset as i32);
+                Value::int(0)
             }
-            Opcode::Halt => 0,
+            Opcode::Wide => self.wide(),
+            Opcode::Halt => Value::int(0),
+        };
+        self.profiler.leave();
+        value
+    }
+
+    /// Executes the instruction following a `Wide` prefix.
+    #[cold]
+    fn wide(&mut self) -> Value {
+        match unsafe { std::mem::transmute::<u8, Opcode>(self.read_u8()) } {
+            Opcode::Var => {
+                let i = self.read_u16();
+                self.var(i)
+            }
+            Opcode::Let => {
+                let i = self.read_u16();
+                let val = self.step();
+                self.set_var(i, val);
+                val
+            }
+            Opcode::JumpIfNot => {
+                let offset = self.read_u32() as i32;
+                self.jump_if_not(offset)
+            }
+            Opcode::Jump => {
+                let offset = self.read_u32() as i32;
+                self.pc = self.pc.wrapping_add_signed(offset);
+                Value::int(0)
+            }
+            _ => unsafe { std::hint::unreachable_unchecked() },
         }
     }
 
-    fn eval(&mut self) {
+    /// Evaluates statements until `Halt`, and returns the value of the last one.
+    fn eval(&mut self) -> Value {
+        let mut value = Value::int(0);
         while self.peek_u8() != Opcode::Halt as u8 {
-            self.step();
+            value = self.step();
         }
+        value
     }
 }
 
 #[derive(Default)]
 struct Writer {
     bytecode: Vec<u8>,
+    constants: Vec<u32>,
+    constant_indices: HashMap<u32, u16>,
+    /// Jumps written by the compiler, which are only inserted into `bytecode` by `link`.
+    jumps: Jumps<Opcode>,
 }
 
 impl Writer {
@@ -131,8 +415,8 @@ impl Writer {
         i
     }
 
-    fn pc(&self) -> u16 {
-        self.bytecode.len() as u16
+    fn pc(&self) -> u32 {
+        self.bytecode.len() as u32
     }
 
     fn write_u16(&mut self, x: u16) -> usize {
@@ -153,9 +437,60 @@ impl Writer {
         i
     }
 
+    fn write_u64(&mut self, x: u64) -> usize {
+        let i = self.bytecode.len();
+        self.bytecode.extend_from_slice(&x.to_le_bytes());
+        i
+    }
+
     fn write_opcode(&mut self, opcode: Opcode) {
         self.write_u8(opcode as u8);
     }
+
+    /// Returns the index of `x` in the constant pool, adding it if it isn't there yet, or `None`
+    /// if the pool is full.
+    fn constant(&mut self, x: u32) -> Option<u16> {
+        if let Some(&i) = self.constant_indices.get(&x) {
+            return Some(i);
+        }
+        let i = u16::try_from(self.constants.len()).ok()?;
+        self.constants.push(x);
+        self.constant_indices.insert(x, i);
+        Some(i)
+    }
+
+    fn label(&mut self) -> Label {
+        self.jumps.label()
+    }
+
+    fn place(&mut self, label: Label) {
+        self.jumps.place(label, self.bytecode.len());
+    }
+
+    /// Writes a `JumpIfNot` or `Jump` to `target`, in whichever form reaches it once the code
+    /// is linked.
+    fn write_jump(&mut self, opcode: Opcode, target: Label) {
+        let at = self.bytecode.len();
+        self.jumps.add(at, target, Reach::Short, [2, 3, 6], opcode);
+    }
+
+    /// Returns the bytecode with the jumps inserted.
+    fn link(&mut self) -> Result<Vec<u8>, CompileError> {
+        let jumps = std::mem::take(&mut self.jumps);
+        jumps
+            .link(&self.bytecode, |code, &opcode, reach, offset| match reach {
+                Reach::Short => code.extend([opcode.short_jump() as u8, offset as i8 as u8]),
+                Reach::Long => {
+                    code.push(opcode as u8);
+                    code.extend((offset as i16).to_le_bytes());
+                }
+                Reach::Wide => {
+                    code.extend([Opcode::Wide as u8, opcode as u8]);
+                    code.extend(offset.to_le_bytes());
+                }
+            })
+            .map(|linked| linked.code)
+    }
 }
 
 const VAR_N: u8 = 0;
@@ -182,6 +517,7 @@ pub fn code() -> Vec<u8> {
     let loop_start = w.pc();
     w.write_opcode(Opcode::JumpIfNot);
     let loop_jump_hole = w.write_u16(0);
+    let loop_jump_base = w.pc();
     {
         w.write_opcode(Opcode::LessEq);
         {
@@ -223,22 +559,679 @@ pub fn code() -> Vec<u8> {
     }
 
     w.write_opcode(Opcode::Jump);
-    w.write_u16(loop_start);
+    w.write_u16((loop_start as i32 - (w.pc() as i32 + 2)) as u16);
 
     let loop_end = w.pc();
-    w.patch_u16(loop_jump_hole, loop_end);
+    w.patch_u16(loop_jump_hole, (loop_end - loop_jump_base) as u16);
     w.write_opcode(Opcode::Halt);
 
     w.bytecode
 }
 
 pub fn run(code: &[u8]) -> u32 {
+    let mut variables = [Value::int(0); 256];
+    let mut frame = Frame {
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        roots: vec![],
+        bytecode: code,
+        constants: &[],
+        pc: 0,
+        profiler: Profiler::new(),
+    };
+    frame.variables[0] = Value::int(10);
+    frame.eval();
+    frame.variables[2].as_int()
+}
+
+#[cfg(feature = "profile")]
+pub fn profile(code: &[u8]) -> crate::profile::Profile {
+    let mut variables = [Value::int(0); 256];
     let mut frame = Frame {
-        variables: [0; 256],
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        roots: vec![],
         bytecode: code,
+        constants: &[],
         pc: 0,
+        profiler: Profiler::new(),
     };
-    frame.variables[0] = 10;
+    frame.variables[0] = Value::int(10);
     frame.eval();
-    frame.variables[2]
+    frame
+        .profiler
+        .finish("compact treewalk (switch)", |opcode| {
+            format!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) })
+        })
+}
+
+/// Runs a program produced by [`compile`] with the given initial variables, linear memory, heap
+/// and host functions, and returns its result. Afterwards `variables` holds the final values of
+/// the variables; those not used by the program may have been overwritten with temporaries.
+/// Variables the program uses beyond the end of `variables` start out as zero.
+pub fn execute(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Value {
+    execute_with(code, variables, memory, heap, host, Profiler::new()).0
+}
+
+/// Like [`execute`], but runs the program under the given profiler and hands it back.
+pub fn execute_with(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    profiler: Profiler,
+) -> (Value, Profiler) {
+    with_slots(code.slots, variables, |variables| {
+        let mut frame = Frame {
+            variables,
+            memory,
+            heap,
+            host,
+            roots: vec![],
+            bytecode: &code.code,
+            constants: &code.constants,
+            pc: 0,
+            profiler,
+        };
+        let result = frame.eval();
+        (result, frame.profiler)
+    })
+}
+
+impl Opcode {
+    fn from_u8(x: u8) -> Option<Self> {
+        (x <= Opcode::Halt as u8).then(|| unsafe { std::mem::transmute::<u8, Opcode>(x) })
+    }
+
+    /// The size of the instruction's immediate operand in bytes.
+    fn operand_size(self) -> usize {
+        match self {
+            Opcode::Float => 8,
+            Opcode::Int => 4,
+            Opcode::SmallInt
+            | Opcode::Const8
+            | Opcode::Var
+            | Opcode::Let
+            | Opcode::JumpIfNot8
+            | Opcode::Jump8 => 1,
+            Opcode::Const16
+            | Opcode::NewRecord
+            | Opcode::NewString
+            | Opcode::JumpIfNot
+            | Opcode::Jump => 2,
+            Opcode::CallNative => 3,
+            Opcode::LessEq
+            | Opcode::Add
+            | Opcode::Multiply
+            | Opcode::Subtract
+            | Opcode::Divide
+            | Opcode::Remainder
+            | Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide
+            | Opcode::IntToFloat
+            | Opcode::FloatToInt
+            | Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Load32
+            | Opcode::Store8
+            | Opcode::Store16
+            | Opcode::Store32
+            | Opcode::NewArray
+            | Opcode::Get
+            | Opcode::Set
+            | Opcode::Len
+            | Opcode::Wide
+            | Opcode::Halt => 0,
+        }
+    }
+
+    /// The size of the operand after a `Wide` prefix, or `None` if the instruction cannot be
+    /// widened.
+    fn wide_operand_size(self) -> Option<usize> {
+        match self {
+            Opcode::Var | Opcode::Let => Some(2),
+            Opcode::JumpIfNot | Opcode::Jump => Some(4),
+            _ => None,
+        }
+    }
+
+    fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JumpIfNot8 | Opcode::Jump8 | Opcode::JumpIfNot | Opcode::Jump
+        )
+    }
+
+    /// The form of a `JumpIfNot` or `Jump` with a one-byte offset.
+    fn short_jump(self) -> Opcode {
+        match self {
+            Opcode::JumpIfNot => Opcode::JumpIfNot8,
+            Opcode::Jump => Opcode::Jump8,
+            _ => unreachable!("{self:?} is not a jump with a short form"),
+        }
+    }
+
+    /// The number of instructions nested inside this one, given its operand.
+    fn children(self, operand: u64) -> usize {
+        match self {
+            Opcode::CallNative => (operand >> 16) as usize,
+            Opcode::Let
+            | Opcode::IntToFloat
+            | Opcode::FloatToInt
+            | Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Load32
+            | Opcode::NewArray
+            | Opcode::Len
+            | Opcode::JumpIfNot8
+            | Opcode::JumpIfNot => 1,
+            Opcode::LessEq
+            | Opcode::Add
+            | Opcode::Multiply
+            | Opcode::Subtract
+            | Opcode::Divide
+            | Opcode::Remainder
+            | Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide
+            | Opcode::Store8
+            | Opcode::Store16
+            | Opcode::Store32
+            | Opcode::Get => 2,
+            Opcode::Set => 3,
+            Opcode::Int
+            | Opcode::SmallInt
+            | Opcode::Const8
+            | Opcode::Const16
+            | Opcode::Float
+            | Opcode::Var
+            | Opcode::NewRecord
+            | Opcode::NewString
+            | Opcode::Jump8
+            | Opcode::Jump
+            | Opcode::Wide
+            | Opcode::Halt => 0,
+        }
+    }
+}
+
+/// Walks the instruction at `pc` and the instructions nested inside it, calling `visit` with
+/// the pc, nesting depth, opcode and operand of each, and whether it has a `Wide` prefix. The
+/// operands of jumps are resolved to their targets. Returns the pc after the instruction.
+fn walk(
+    code: &[u8],
+    pc: usize,
+    depth: usize,
+    visit: &mut impl FnMut(usize, usize, Opcode, u64, bool) -> Result<(), VerifyError>,
+) -> Result<usize, VerifyError> {
+    let error = |message| VerifyError { pc, message };
+    let opcode = |pc: usize| {
+        let opcode = code.get(pc).ok_or_else(|| error("truncated instruction"))?;
+        Opcode::from_u8(*opcode).ok_or_else(|| error("invalid opcode"))
+    };
+    let (opcode, start, size, wide) = match opcode(pc)? {
+        Opcode::Wide => {
+            let opcode = opcode(pc + 1)?;
+            let size = opcode
+                .wide_operand_size()
+                .ok_or_else(|| error("instruction cannot be widened"))?;
+            (opcode, pc + 2, size, true)
+        }
+        opcode => (opcode, pc + 1, opcode.operand_size(), false),
+    };
+    let mut operand = code
+        .get(start..start + size)
+        .ok_or_else(|| error("truncated instruction"))?
+        .iter()
+        .rev()
+        .fold(0, |acc, &byte| acc << 8 | byte as u64);
+    if opcode.is_jump() {
+        let target = (start + size) as i64 + sign_extend(operand as u32, size) as i64;
+        operand = u32::try_from(target).map_err(|_| error("jump target out of bounds"))? as u64;
+    }
+    visit(pc, depth, opcode, operand, wide)?;
+
+    let mut next = start + size;
+    for _ in 0..opcode.children(operand) {
+        next = walk(code, next, depth + 1, visit)?;
+    }
+    Ok(next)
+}
+
+pub fn disassemble(chunk: &Chunk) -> Disassembly {
+    let code = &chunk.code;
+    let mut disassembly = vec![];
+    let mut pc = 0;
+    while pc < code.len() {
+        let result = walk(code, pc, 0, &mut |pc, depth, opcode, operand, wide| {
+            let indent = "  ".repeat(depth) + if wide { "Wide " } else { "" };
+            let text = match (opcode, chunk.constants.get(operand as usize)) {
+                (Opcode::Const8 | Opcode::Const16, Some(k)) => {
+                    format!("{indent}{opcode:?} {operand} ({k})")
+                }
+                (Opcode::Float, _) => format!("{indent}Float {:?}", f64::from_bits(operand)),
+                (Opcode::CallNative, _) => {
+                    format!("{indent}CallNative {}, {}", operand & 0xffff, operand >> 16)
+                }
+                (Opcode::NewString, _) => {
+                    match heap::read_string_constant(&chunk.constants, operand as usize) {
+                        Some(s) => format!(
+                            "{indent}NewString {operand} ({:?})",
+                            String::from_utf8_lossy(&s)
+                        ),
+                        None => format!("{indent}NewString {operand}"),
+                    }
+                }
+                _ if opcode.operand_size() > 0 => format!("{indent}{opcode:?} {operand}"),
+                _ => format!("{indent}{opcode:?}"),
+            };
+            disassembly.push((pc as u32, text));
+            Ok(())
+        });
+        match result {
+            Ok(next) => pc = next,
+            Err(error) => {
+                disassembly.push((error.pc as u32, format!("<{}>", error.message)));
+                break;
+            }
+        }
+    }
+    disassembly
+}
+
+/// Checks that the bytecode cannot read or jump out of bounds. Jumps may only appear as
+/// statements, and may only target statements.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    let code = &chunk.code;
+    if code.len() > u32::MAX as usize {
+        return Err(VerifyError {
+            pc: u32::MAX as usize,
+            message: "bytecode is larger than 4 GiB",
+        });
+    }
+    // `compile` rejects exceptions, so no handler can be right.
+    if let Some(handler) = chunk.handlers.first() {
+        return Err(VerifyError {
+            pc: handler.target as usize,
+            message: "exception handlers are not supported",
+        });
+    }
+
+    let mut statements = vec![false; code.len()];
+    let mut jumps = vec![];
+    let mut last = None;
+    let mut pc = 0;
+    while pc < code.len() {
+        statements[pc] = true;
+        pc = walk(code, pc, 0, &mut |pc, depth, opcode, operand, _| {
+            if let Opcode::Var | Opcode::Let = opcode {
+                if operand as usize >= chunk.slots {
+                    return Err(VerifyError {
+                        pc,
+                        message: "variable out of bounds",
+                    });
+                }
+            }
+            if let Opcode::Const8 | Opcode::Const16 = opcode {
+                if operand as usize >= chunk.constants.len() {
+                    return Err(VerifyError {
+                        pc,
+                        message: "constant out of bounds",
+                    });
+                }
+            }
+            if opcode == Opcode::NewString
+                && heap::read_string_constant(&chunk.constants, operand as usize).is_none()
+            {
+                return Err(VerifyError {
+                    pc,
+                    message: "string constant out of bounds",
+                });
+            }
+            if opcode.is_jump() || opcode == Opcode::Halt {
+                if depth > 0 {
+                    return Err(VerifyError {
+                        pc,
+                        message: "control flow inside an expression",
+                    });
+                }
+                if opcode != Opcode::Halt {
+                    jumps.push((pc, operand as usize));
+                }
+            }
+            if depth == 0 {
+                last = Some(opcode);
+            }
+            Ok(())
+        })?;
+    }
+    if !matches!(last, Some(Opcode::Halt | Opcode::Jump8 | Opcode::Jump)) {
+        return Err(VerifyError {
+            pc,
+            message: "execution can run past the end of the bytecode",
+        });
+    }
+    for (pc, target) in jumps {
+        if target >= code.len() || !statements[target] {
+            return Err(VerifyError {
+                pc,
+                message: "jump target is not the start of a statement",
+            });
+        }
+    }
+    Ok(())
+}
+
+struct Compiler {
+    w: Writer,
+    /// The next free variable slot for temporaries.
+    temps: usize,
+    /// The number of variable slots used so far.
+    slots: usize,
+}
+
+fn is_statement(insn: &Instruction) -> bool {
+    matches!(insn, Instruction::Sequence(_) | Instruction::While { .. })
+}
+
+impl Compiler {
+    fn alloc_temp(&mut self) -> Result<u16, CompileError> {
+        let temp = u16::try_from(self.temps).map_err(|_| CompileError::TooManyVariables)?;
+        self.temps += 1;
+        self.slots = self.slots.max(self.temps);
+        Ok(temp)
+    }
+
+    fn free_temp(&mut self) {
+        self.temps -= 1;
+    }
+
+    /// Writes an integer, using the shortest encoding available for it.
+    fn write_int(&mut self, i: u32) {
+        if let Ok(small) = u8::try_from(i) {
+            self.w.write_opcode(Opcode::SmallInt);
+            self.w.write_u8(small);
+        } else {
+            match self.w.constant(i) {
+                Some(k) if k <= u8::MAX as u16 => {
+                    self.w.write_opcode(Opcode::Const8);
+                    self.w.write_u8(k as u8);
+                }
+                Some(k) => {
+                    self.w.write_opcode(Opcode::Const16);
+                    self.w.write_u16(k);
+                }
+                None => {
+                    self.w.write_opcode(Opcode::Int);
+                    self.w.write_u32(i);
+                }
+            }
+        }
+    }
+
+    /// Writes a `Var` or `Let` of the given variable, widening it if needed.
+    fn write_variable(&mut self, opcode: Opcode, variable: u16) {
+        match u8::try_from(variable) {
+            Ok(variable) => {
+                self.w.write_opcode(opcode);
+                self.w.write_u8(variable);
+            }
+            Err(_) => {
+                self.w.write_opcode(Opcode::Wide);
+                self.w.write_opcode(opcode);
+                self.w.write_u16(variable);
+            }
+        }
+    }
+
+    fn write_let_var(&mut self, variable: u16, source: u16) {
+        self.write_variable(Opcode::Let, variable);
+        self.write_variable(Opcode::Var, source);
+    }
+
+    /// Compiles an instruction to statements whose values are not needed.
+    fn statement(&mut self, insn: &Instruction) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Sequence(s) => {
+                for insn in s {
+                    self.statement(insn)?;
+                }
+            }
+            Instruction::While { condition, body } => {
+                let loop_start = self.w.label();
+                let loop_end = self.w.label();
+                self.w.place(loop_start);
+                self.w.write_jump(Opcode::JumpIfNot, loop_end);
+                self.expression(condition)?;
+                self.statement(body)?;
+                self.w.write_jump(Opcode::Jump, loop_start);
+                self.w.place(loop_end);
+            }
+            Instruction::Let { variable, value } if is_statement(value) => {
+                let temp = self.alloc_temp()?;
+                self.store(value, temp)?;
+                self.write_let_var(*variable, temp);
+                self.free_temp();
+            }
+            _ => self.expression(insn)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles an instruction to statements, the last of which evaluates to its value.
+    fn value(&mut self, insn: &Instruction) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Sequence(s) => match s.split_last() {
+                Some((last, init)) => {
+                    for insn in init {
+                        self.statement(insn)?;
+                    }
+                    self.value(last)?;
+                }
+                None => self.expression(&Instruction::Int(0))?,
+            },
+            Instruction::While { .. } => {
+                let temp = self.alloc_temp()?;
+                self.store(insn, temp)?;
+                self.write_variable(Opcode::Var, temp);
+                self.free_temp();
+            }
+            _ => self.statement(insn)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles an instruction to statements that store its value in the temporary `temp`.
+    fn store(&mut self, insn: &Instruction, temp: u16) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Sequence(s) => match s.split_last() {
+                Some((last, init)) => {
+                    for insn in init {
+                        self.statement(insn)?;
+                    }
+                    self.store(last, temp)?;
+                }
+                None => self.store(&Instruction::Int(0), temp)?,
+            },
+            Instruction::While { condition, body } => {
+                // The value of a loop is the value of its body in the last iteration.
+                self.store(&Instruction::Int(0), temp)?;
+                let loop_start = self.w.label();
+                let loop_end = self.w.label();
+                self.w.place(loop_start);
+                self.w.write_jump(Opcode::JumpIfNot, loop_end);
+                self.expression(condition)?;
+                self.store(body, temp)?;
+                self.w.write_jump(Opcode::Jump, loop_start);
+                self.w.place(loop_end);
+            }
+            Instruction::Let { variable, value } if is_statement(value) => {
+                self.store(value, temp)?;
+                self.write_let_var(*variable, temp);
+            }
+            _ => {
+                self.write_variable(Opcode::Let, temp);
+                self.expression(insn)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles an instruction to a single tree of instructions evaluating to its value.
+    fn expression(&mut self, insn: &Instruction) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Int(i) => self.write_int(*i),
+            Instruction::Float(f) => {
+                self.w.write_opcode(Opcode::Float);
+                self.w.write_u64(f.to_bits());
+            }
+            Instruction::Var(v) => self.write_variable(Opcode::Var, *v),
+            Instruction::Let { variable, value } => {
+                self.write_variable(Opcode::Let, *variable);
+                self.expression(value)?;
+            }
+            Instruction::LessEq(a, b) => self.binary(Opcode::LessEq, a, b)?,
+            Instruction::Add(a, b) => self.binary(Opcode::Add, a, b)?,
+            Instruction::Multiply(a, b) => self.binary(Opcode::Multiply, a, b)?,
+            Instruction::Subtract(a, b) => self.binary(Opcode::Subtract, a, b)?,
+            Instruction::Divide(a, b) => self.binary(Opcode::Divide, a, b)?,
+            Instruction::Remainder(a, b) => self.binary(Opcode::Remainder, a, b)?,
+            Instruction::FLess(a, b) => self.binary(Opcode::FLess, a, b)?,
+            Instruction::FAdd(a, b) => self.binary(Opcode::FAdd, a, b)?,
+            Instruction::FMultiply(a, b) => self.binary(Opcode::FMultiply, a, b)?,
+            Instruction::FSubtract(a, b) => self.binary(Opcode::FSubtract, a, b)?,
+            Instruction::FDivide(a, b) => self.binary(Opcode::FDivide, a, b)?,
+            Instruction::IntToFloat(a) => {
+                self.w.write_opcode(Opcode::IntToFloat);
+                self.expression(a)?;
+            }
+            Instruction::FloatToInt(a) => {
+                self.w.write_opcode(Opcode::FloatToInt);
+                self.expression(a)?;
+            }
+            Instruction::Index { width, address } => {
+                self.w.write_opcode(match width {
+                    Width::U8 => Opcode::Load8,
+                    Width::U16 => Opcode::Load16,
+                    Width::U32 => Opcode::Load32,
+                });
+                self.expression(address)?;
+            }
+            Instruction::StoreIndex {
+                width,
+                address,
+                value,
+            } => {
+                let opcode = match width {
+                    Width::U8 => Opcode::Store8,
+                    Width::U16 => Opcode::Store16,
+                    Width::U32 => Opcode::Store32,
+                };
+                self.binary(opcode, address, value)?;
+            }
+            Instruction::NewArray(len) => {
+                self.w.write_opcode(Opcode::NewArray);
+                self.expression(len)?;
+            }
+            Instruction::NewRecord(fields) => {
+                self.w.write_opcode(Opcode::NewRecord);
+                self.w.write_u16(*fields);
+            }
+            Instruction::NewString(s) => {
+                let k = u16::try_from(heap::add_string_constant(&mut self.w.constants, s))
+                    .map_err(|_| CompileError::TooManyConstants)?;
+                self.w.write_opcode(Opcode::NewString);
+                self.w.write_u16(k);
+            }
+            Instruction::Get(object, index) => self.binary(Opcode::Get, object, index)?,
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => {
+                self.binary(Opcode::Set, object, index)?;
+                self.expression(value)?;
+            }
+            Instruction::Len(object) => {
+                self.w.write_opcode(Opcode::Len);
+                self.expression(object)?;
+            }
+            Instruction::CallNative {
+                function,
+                arguments,
+            } => {
+                let argc =
+                    u8::try_from(arguments.len()).map_err(|_| CompileError::TooManyArguments)?;
+                self.w.write_opcode(Opcode::CallNative);
+                self.w.write_u16(*function);
+                self.w.write_u8(argc);
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+            }
+            // Compact trees aren't metered, so they carry straight on.
+            Instruction::Yield(value) => self.expression(value)?,
+            Instruction::Coroutine(_) | Instruction::Resume(..) => {
+                return Err(CompileError::CoroutinesUnsupported)
+            }
+            Instruction::Try { .. } | Instruction::Throw(_) => {
+                return Err(CompileError::ExceptionsUnsupported)
+            }
+            Instruction::Sequence(_) | Instruction::While { .. } => {
+                return Err(CompileError::StatementInExpression)
+            }
+        }
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        opcode: Opcode,
+        a: &Instruction,
+        b: &Instruction,
+    ) -> Result<(), CompileError> {
+        self.w.write_opcode(opcode);
+        self.expression(a)?;
+        self.expression(b)
+    }
+}
+
+/// Compiles a program to bytecode that [`execute`] can run. Loops and sequences may only appear
+/// where their value is either unused or assigned to a variable.
+pub fn compile(program: &Instruction) -> Result<Chunk, CompileError> {
+    let mut c = Compiler {
+        w: Writer::default(),
+        temps: program.variable_count(),
+        slots: program.variable_count(),
+    };
+
+    c.value(program)?;
+    c.w.write_opcode(Opcode::Halt);
+
+    let code = c.w.link()?;
+    if code.len() > u32::MAX as usize {
+        return Err(CompileError::CodeTooLarge);
+    }
+    Ok(Chunk {
+        code,
+        constants: c.w.constants,
+        slots: c.slots,
+        handlers: vec![],
+    })
 }
@@ -0,0 +1,126 @@
+//! Coroutines, which the stack and register backends run. `(coroutine body...)` creates one
+//! without running it, and `(resume c x)` runs `c` until its body yields a value with
+//! `(yield y)`, which the `resume` evaluates to, or ends, in which case it evaluates to the
+//! body's value and `c` is dead. `x` is what the `yield` that last stopped `c` evaluates to; the
+//! first resume has nothing to hand it to.
+//!
+//! A coroutine is an object on the heap that owns a frame, which starts out as a copy of its
+//! creator's, so the body sees the variables as they were when it was created, and assigning
+//! to them doesn't affect its creator. Its body is compiled inline, in the same code. Resuming
+//! a coroutine swaps the running frame with the coroutine's, so that while it runs, it holds
+//! the frame of whoever resumed it, and yielding swaps them back. A coroutine must only be
+//! resumed by the code that created it.
+
+use std::mem;
+
+use crate::{
+    heap::{Heap, Object},
+    value::Value,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    NotStarted,
+    /// Stopped at a yield.
+    Suspended,
+    /// Running, or resuming another coroutine.
+    Running,
+    Dead,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coroutine {
+    pub(crate) status: Status,
+    /// The registers to carry on with, or those of its resumer while it's running.
+    pub(crate) registers: Registers,
+    pub(crate) frame: Box<[Value]>,
+}
+
+impl Coroutine {
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// The live slots of the frame, which the garbage collector traces.
+    pub(crate) fn values(&self) -> &[Value] {
+        &self.frame[..self.registers.sp as usize]
+    }
+}
+
+/// The registers of a frame other than its slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Registers {
+    pub pc: u32,
+    /// The stack pointer of the stack backends, below which slots are live. Every slot of a
+    /// register backend's frame is live.
+    pub sp: u32,
+    /// The register that receives the value the frame is switched back to with, in the
+    /// register backends.
+    pub target: u16,
+}
+
+/// Allocates a coroutine that starts at `registers` with a copy of `frame`.
+pub(crate) fn new(
+    heap: &mut Heap,
+    frame: &[Value],
+    registers: Registers,
+    roots: &[&[Value]],
+) -> Value {
+    let coroutine = Coroutine {
+        status: Status::NotStarted,
+        registers,
+        frame: frame.into(),
+    };
+    heap.alloc(Object::Coroutine(Box::new(coroutine)), roots)
+}
+
+/// Switches from the running frame to `coroutine`'s. Returns whether it had yielded, in which
+/// case it's owed the value it's resumed with. Panics if it's running or dead.
+pub(crate) fn resume(
+    heap: &mut Heap,
+    coroutine: Value,
+    frame: &mut [Value],
+    registers: &mut Registers,
+) -> bool {
+    let coroutine = heap.coroutine_mut(coroutine);
+    let yielded = match coroutine.status {
+        Status::NotStarted => false,
+        Status::Suspended => true,
+        Status::Running => panic!("cannot resume a running coroutine"),
+        Status::Dead => panic!("cannot resume a dead coroutine"),
+    };
+    coroutine.status = Status::Running;
+    switch(coroutine, frame, registers);
+    yielded
+}
+
+/// Switches from the running `coroutine` back to the frame that resumed it, at a yield or, if
+/// `returned`, at the end of its body.
+pub(crate) fn suspend(
+    heap: &mut Heap,
+    coroutine: Value,
+    frame: &mut [Value],
+    registers: &mut Registers,
+    returned: bool,
+) {
+    let coroutine = heap.coroutine_mut(coroutine);
+    switch(coroutine, frame, registers);
+    if returned {
+        // Its frame is of no more use, and would keep its objects alive.
+        coroutine.status = Status::Dead;
+        coroutine.frame = Box::default();
+        coroutine.registers.sp = 0;
+    } else {
+        coroutine.status = Status::Suspended;
+    }
+}
+
+fn switch(coroutine: &mut Coroutine, frame: &mut [Value], registers: &mut Registers) {
+    assert_eq!(
+        coroutine.frame.len(),
+        frame.len(),
+        "coroutine resumed by different code"
+    );
+    frame.swap_with_slice(&mut coroutine.frame);
+    mem::swap(&mut coroutine.registers, registers);
+}
@@ -0,0 +1,109 @@
+//! Exceptions. `(throw x)` throws any value, and `(try body... (catch e handler...))` evaluates
+//! to the value of its body, unless the body throws, in which case the thrown value is
+//! assigned to `e` and it evaluates to the handler instead. Runtime errors throw too: dividing
+//! an integer by zero throws [`DIVISION_BY_ZERO`]. An exception that nothing catches ends the
+//! run with [`VmError::Uncaught`][crate::fuel::VmError::Uncaught], while the errors that don't
+//! throw, such as indexing out of bounds, panic.
+//!
+//! The tree walker keeps its state on the Rust stack, so it returns what was thrown up that to
+//! the innermost `try`. The stack and register backends look up the instruction that threw in
+//! an exception table instead, whose entries map ranges of code to the handler that catches
+//! what they throw and the stack depth to cut the stack back to. An exception that escapes a
+//! coroutine's body kills the coroutine, and is thrown again by the `resume` that was running
+//! it.
+
+use crate::value::Value;
+
+/// What dividing an integer by zero, or taking the remainder of it, throws.
+pub const DIVISION_BY_ZERO: Value = Value::int(1);
+
+/// An entry of an exception table. Tables are ordered innermost first, and the first entry
+/// whose range holds the instruction that threw catches the exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handler {
+    /// The code covered, from `start` up to but not including `end`.
+    pub start: u32,
+    pub end: u32,
+    /// Where the handler starts.
+    pub target: u32,
+    /// The height the stack backends cut the stack back to, before pushing the thrown value.
+    /// The register backends have no stack, and put the thrown value in this register instead.
+    pub depth: u32,
+}
+
+/// Finds the handler for an exception thrown by the instruction at `pc`.
+pub fn find(handlers: &[Handler], pc: u32) -> Option<&Handler> {
+    handlers.iter().find(|h| h.start <= pc && pc < h.end)
+}
+
+/// A value thrown in the tree walker, which returns it up the Rust stack to the innermost `try`.
+#[derive(Debug)]
+pub(crate) struct Thrown(pub Value);
+
+/// The exception table a compiler builds, with positions in the code of type `P` until they're
+/// known.
+pub(crate) struct Table<P> {
+    handlers: Vec<(P, P, P, u32)>,
+    /// The bodies of coroutines written so far, and how many others each is nested in, in the
+    /// order they were finished. The handlers of the code around a body don't cover it, since
+    /// it runs in another frame.
+    coroutines: Vec<(P, P, usize)>,
+    nesting: usize,
+}
+
+impl<P> Default for Table<P> {
+    fn default() -> Self {
+        Self {
+            handlers: vec![],
+            coroutines: vec![],
+            nesting: 0,
+        }
+    }
+}
+
+impl<P: Copy> Table<P> {
+    /// Notes that the body of a coroutine is being written.
+    pub fn enter_coroutine(&mut self) {
+        self.nesting += 1;
+    }
+
+    /// Notes that the body of a coroutine was written from `start` to `end`.
+    pub fn leave_coroutine(&mut self, start: P, end: P) {
+        self.nesting -= 1;
+        self.coroutines.push((start, end, self.nesting));
+    }
+
+    /// Marks the start of the body of a `try`, for `add`.
+    pub fn mark(&self) -> usize {
+        self.coroutines.len()
+    }
+
+    /// Adds a handler at `target` for the code from `start` to `end`, whose writing started at
+    /// `mark`, leaving out the bodies of the coroutines in it. Handlers must be added innermost
+    /// first.
+    pub fn add(&mut self, mark: usize, start: P, end: P, target: P, depth: u32) {
+        let mut from = start;
+        for &(body_start, body_end, nesting) in &self.coroutines[mark..] {
+            if nesting == self.nesting {
+                self.handlers.push((from, body_start, target, depth));
+                from = body_end;
+            }
+        }
+        self.handlers.push((from, end, target, depth));
+    }
+
+    /// The table, with the positions given by `position` and `base` added to the depths,
+    /// leaving out the entries that cover no code.
+    pub fn resolve(&self, position: impl Fn(P) -> u32, base: u32) -> Vec<Handler> {
+        self.handlers
+            .iter()
+            .map(|&(start, end, target, depth)| Handler {
+                start: position(start),
+                end: position(end),
+                target: position(target),
+                depth: depth + base,
+            })
+            .filter(|handler| handler.start < handler.end)
+            .collect()
+    }
+}
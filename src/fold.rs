@@ -0,0 +1,175 @@
+//! Constant folding and algebraic simplification of [`Instruction`] trees, which can be run on
+//! a program before compiling it for any backend. Folding keeps what a program does, including
+//! what it throws, so a division by a constant zero is left for the run to throw. Variables that
+//! a program no longer uses once folded count as temporaries for the register backends.
+
+use crate::treewalk::Instruction;
+
+/// Returns a program that does the same as `code`, with constant subexpressions evaluated,
+/// identities like `x * 1` and `x + 0` simplified, and loops whose condition is a constant zero
+/// removed.
+pub fn fold(code: &Instruction) -> Instruction {
+    use Instruction::*;
+
+    let fold_box = |insn: &Instruction| Box::new(fold(insn));
+    let fold_pair = |a: &Instruction, b: &Instruction| (fold(a), fold(b));
+    match code {
+        Int(_) | Float(_) | Var(_) | NewRecord(_) | NewString(_) => code.clone(),
+        Let { variable, value } => Let {
+            variable: *variable,
+            value: fold_box(value),
+        },
+
+        LessEq(a, b) => match fold_pair(a, b) {
+            (Int(a), Int(b)) => Int((a <= b) as u32),
+            (a, b) => LessEq(Box::new(a), Box::new(b)),
+        },
+        // Adding an integer to a float is unspecified, so `x + 0` is `x` whatever `x` is.
+        Add(a, b) => match fold_pair(a, b) {
+            (Int(a), Int(b)) => Int(a.wrapping_add(b)),
+            (Float(a), Float(b)) => Float(a + b),
+            (Int(0), x) | (x, Int(0)) => x,
+            (a, b) => Add(Box::new(a), Box::new(b)),
+        },
+        Multiply(a, b) => match fold_pair(a, b) {
+            (Int(a), Int(b)) => Int(a.wrapping_mul(b)),
+            (Int(1), x) | (x, Int(1)) => x,
+            (Int(0), x) | (x, Int(0)) if is_pure(&x) => Int(0),
+            (a, b) => Multiply(Box::new(a), Box::new(b)),
+        },
+        Subtract(a, b) => match fold_pair(a, b) {
+            (Int(a), Int(b)) => Int(a.wrapping_sub(b)),
+            (x, Int(0)) => x,
+            (a, b) => Subtract(Box::new(a), Box::new(b)),
+        },
+        Divide(a, b) => match fold_pair(a, b) {
+            (Int(a), Int(b)) if b != 0 => Int(a / b),
+            (x, Int(1)) => x,
+            (a, b) => Divide(Box::new(a), Box::new(b)),
+        },
+        Remainder(a, b) => match fold_pair(a, b) {
+            (Int(a), Int(b)) if b != 0 => Int(a % b),
+            (x, Int(1)) if is_pure(&x) => Int(0),
+            (a, b) => Remainder(Box::new(a), Box::new(b)),
+        },
+
+        // Float identities don't hold for every float: `-0.0 + 0.0` is `0.0`.
+        FLess(a, b) => match fold_pair(a, b) {
+            (Float(a), Float(b)) => Int((a < b) as u32),
+            (a, b) => FLess(Box::new(a), Box::new(b)),
+        },
+        FAdd(a, b) => match fold_pair(a, b) {
+            (Float(a), Float(b)) => Float(a + b),
+            (a, b) => FAdd(Box::new(a), Box::new(b)),
+        },
+        FMultiply(a, b) => match fold_pair(a, b) {
+            (Float(a), Float(b)) => Float(a * b),
+            (a, b) => FMultiply(Box::new(a), Box::new(b)),
+        },
+        FSubtract(a, b) => match fold_pair(a, b) {
+            (Float(a), Float(b)) => Float(a - b),
+            (a, b) => FSubtract(Box::new(a), Box::new(b)),
+        },
+        FDivide(a, b) => match fold_pair(a, b) {
+            (Float(a), Float(b)) => Float(a / b),
+            (a, b) => FDivide(Box::new(a), Box::new(b)),
+        },
+        IntToFloat(a) => match fold(a) {
+            Int(a) => Float(a as f64),
+            a => IntToFloat(Box::new(a)),
+        },
+        FloatToInt(a) => match fold(a) {
+            Float(a) => Int(a as u32),
+            a => FloatToInt(Box::new(a)),
+        },
+
+        Index { width, address } => Index {
+            width: *width,
+            address: fold_box(address),
+        },
+        StoreIndex {
+            width,
+            address,
+            value,
+        } => StoreIndex {
+            width: *width,
+            address: fold_box(address),
+            value: fold_box(value),
+        },
+        NewArray(len) => NewArray(fold_box(len)),
+        Get(object, index) => Get(fold_box(object), fold_box(index)),
+        Set {
+            object,
+            index,
+            value,
+        } => Set {
+            object: fold_box(object),
+            index: fold_box(index),
+            value: fold_box(value),
+        },
+        Len(object) => Len(fold_box(object)),
+        CallNative {
+            function,
+            arguments,
+        } => CallNative {
+            function: *function,
+            arguments: arguments.iter().map(fold).collect(),
+        },
+        Yield(value) => Yield(fold_box(value)),
+        Coroutine(body) => Coroutine(fold_box(body)),
+        Resume(coroutine, value) => Resume(fold_box(coroutine), fold_box(value)),
+
+        Try {
+            body,
+            variable,
+            handler,
+        } => match fold(body) {
+            // Nothing in the body can throw.
+            body if is_pure(&body) => body,
+            body => Try {
+                body: Box::new(body),
+                variable: *variable,
+                handler: fold_box(handler),
+            },
+        },
+        Throw(value) => Throw(fold_box(value)),
+
+        Sequence(s) => {
+            let mut folded = vec![];
+            for insn in s {
+                // Folded sequences have at least two instructions, so the last one's value is
+                // still the value of the sequence once it's spliced in.
+                match fold(insn) {
+                    Sequence(inner) => folded.extend(inner),
+                    insn => folded.push(insn),
+                }
+            }
+            // Only the value of the last instruction is used.
+            let last = folded.pop();
+            folded.retain(|insn| !is_pure(insn));
+            folded.extend(last);
+            match folded.len() {
+                0 => Int(0),
+                1 => folded.pop().unwrap(),
+                _ => Sequence(folded),
+            }
+        }
+        While { condition, body } => match fold(condition) {
+            // The value of a loop that never runs is zero.
+            Int(0) => Int(0),
+            condition => While {
+                condition: Box::new(condition),
+                body: fold_box(body),
+            },
+        },
+    }
+}
+
+/// Whether evaluating the instruction has no effect besides producing its value, and can't
+/// throw or panic.
+fn is_pure(insn: &Instruction) -> bool {
+    matches!(
+        insn,
+        Instruction::Int(_) | Instruction::Float(_) | Instruction::Var(_)
+    )
+}
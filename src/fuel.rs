@@ -0,0 +1,495 @@
+//! Bounding how long programs run. Every backward jump, which each iteration of a loop takes
+//! once, costs a unit of [`Fuel`], so a program given a finite amount cannot run forever, while
+//! straight-line code costs nothing. An [`Interrupter`] stops a run from another thread, which
+//! notices it at its next backward jump, a program stops itself with `(yield x)`, and the host
+//! stops it at a given pc with a breakpoint.
+//!
+//! A run that stops early returns a [`VmError`] holding where it stopped, which the backend's
+//! `resume` carries on from, given the same code and more fuel, or which can be saved as a
+//! [snapshot][crate::snapshot]. Resuming first pays for the jump the run stopped at, if any, so
+//! a program takes the same fuel however often it's stopped. Resuming at a breakpoint runs the
+//! instruction under it before writing the breakpoint back. Unmetered runs carry on through
+//! yields.
+//!
+//! Only the stack and register backends are metered: the tree walkers keep their state on the
+//! Rust stack, so they couldn't be resumed.
+
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    error, fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{bytecode::with_slots, module::Backend, value::Value};
+
+/// The number of backward jumps a run may still take, a flag that interrupts it, and the pcs it
+/// stops at.
+#[derive(Debug)]
+pub struct Fuel {
+    remaining: u64,
+    interrupt: Arc<AtomicBool>,
+    /// Sorted.
+    breakpoints: Vec<u32>,
+}
+
+impl Fuel {
+    pub fn new(remaining: u64) -> Self {
+        Self {
+            remaining,
+            interrupt: Arc::default(),
+            breakpoints: vec![],
+        }
+    }
+
+    /// Fuel that never runs out, for runs that should only stop when interrupted.
+    pub fn unlimited() -> Self {
+        Self::new(u64::MAX)
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Adds fuel, for example before resuming a run that ran out.
+    pub fn refuel(&mut self, amount: u64) {
+        self.remaining = self.remaining.saturating_add(amount);
+    }
+
+    /// A handle, which can be sent to other threads, that interrupts runs using this fuel.
+    pub fn interrupter(&self) -> Interrupter {
+        Interrupter(self.interrupt.clone())
+    }
+
+    /// Stops runs using the fuel when they reach `pc`, before the instruction there runs, which
+    /// must be the start of one. A run resumed at a breakpoint runs that instruction, and stops
+    /// the next time it comes back.
+    pub fn set_breakpoint(&mut self, pc: u32) {
+        if let Err(i) = self.breakpoints.binary_search(&pc) {
+            self.breakpoints.insert(i, pc);
+        }
+    }
+
+    pub fn clear_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.retain(|&at| at != pc);
+    }
+
+    pub fn breakpoints(&self) -> &[u32] {
+        &self.breakpoints
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Interrupter(Arc<AtomicBool>);
+
+impl Interrupter {
+    /// Stops the run using the fuel at its next backward jump, or the next run to use it if
+    /// none is in progress.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where a run stopped, for a backend with the same encoding to resume from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suspended {
+    pub(crate) backend: Backend,
+    /// The checksum of the code that was running, as its backend's `checksum` computes it.
+    pub(crate) code: u32,
+    pub(crate) at: StoppedAt,
+    pub(crate) state: State,
+    /// Every slot of the frame, including temporaries.
+    pub(crate) frame: Vec<Value>,
+}
+
+impl Suspended {
+    /// The bytecode encoding of the code that was running.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// The checksum of the code that was running, which must match the code it resumes.
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    /// The instruction the run will carry on with.
+    pub fn pc(&self) -> u32 {
+        self.state.pc
+    }
+
+    /// The number of slots of the frame, which must match the code it resumes.
+    pub fn slots(&self) -> usize {
+        self.frame.len()
+    }
+}
+
+/// What a run stopped at, which decides how it carries on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StoppedAt {
+    /// The instruction after a yield.
+    Yield,
+    /// A backward jump it hasn't paid for yet.
+    Jump,
+    /// A breakpoint, which it steps over.
+    Breakpoint,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    /// The run reached a backward jump without any fuel left.
+    OutOfFuel(Suspended),
+    Interrupted(Suspended),
+    /// The program yielded a value.
+    Yielded(Value, Suspended),
+    /// The run reached a breakpoint.
+    Breakpoint(Suspended),
+    /// The program threw an [exception][crate::exception] that nothing caught. The run is over,
+    /// so it can't be resumed.
+    Uncaught(Value),
+}
+
+impl VmError {
+    /// Where the run stopped, unless it threw an exception that nothing caught.
+    pub fn into_suspended(self) -> Option<Suspended> {
+        match self {
+            Self::OutOfFuel(suspended)
+            | Self::Interrupted(suspended)
+            | Self::Yielded(_, suspended)
+            | Self::Breakpoint(suspended) => Some(suspended),
+            Self::Uncaught(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfFuel(suspended) => write!(f, "ran out of fuel at pc {}", suspended.pc()),
+            Self::Interrupted(suspended) => write!(f, "interrupted at pc {}", suspended.pc()),
+            Self::Yielded(value, suspended) => {
+                write!(f, "yielded {value} at pc {}", suspended.pc())
+            }
+            Self::Breakpoint(suspended) => write!(f, "reached breakpoint at pc {}", suspended.pc()),
+            Self::Uncaught(value) => write!(f, "uncaught exception {value}"),
+        }
+    }
+}
+
+impl error::Error for VmError {}
+
+/// The registers of a frame other than its slots.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct State {
+    pub pc: u32,
+    /// The stack pointer of the stack backends.
+    pub sp: usize,
+    /// The value a register backend's `Return` has set so far.
+    pub result: Value,
+    /// The [coroutines][crate::coroutine] that are running, innermost last. The frame is the
+    /// innermost one's.
+    pub running: Vec<Value>,
+}
+
+impl State {
+    pub const START: State = State {
+        pc: 0,
+        sp: 0,
+        result: Value::int(0),
+        running: vec![],
+    };
+}
+
+/// Why a frame stopped before the end of its code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Stop {
+    OutOfFuel,
+    Interrupted,
+    Yielded(Value),
+    Breakpoint,
+    Uncaught(Value),
+}
+
+static NEVER: AtomicBool = AtomicBool::new(false);
+
+/// The fuel a running frame draws from.
+pub(crate) struct Meter<'a> {
+    remaining: Cell<u64>,
+    interrupt: &'a AtomicBool,
+    /// Whether yields stop the run.
+    metered: bool,
+    breakpoints: &'a [u32],
+    /// The breakpoint the run resumed at, which it carries on past by running the instruction
+    /// under it before the breakpoint is written back.
+    stepping: Cell<Option<u32>>,
+}
+
+impl Meter<'static> {
+    /// The meter of unmetered runs, which never stops them.
+    pub fn unlimited() -> Self {
+        Meter {
+            remaining: Cell::new(u64::MAX),
+            interrupt: &NEVER,
+            metered: false,
+            breakpoints: &[],
+            stepping: Cell::new(None),
+        }
+    }
+}
+
+impl Meter<'_> {
+    /// Pays for a backward jump.
+    #[inline(always)]
+    pub fn tick(&self) -> Result<(), Stop> {
+        let remaining = self.remaining.get();
+        if remaining == 0 || self.interrupt.load(Ordering::Relaxed) {
+            return Err(self.stop());
+        }
+        self.remaining.set(remaining - 1);
+        Ok(())
+    }
+
+    /// Stops a metered run at a yield of `value`.
+    #[inline(always)]
+    pub fn yield_value(&self, value: Value) -> Result<(), Stop> {
+        if self.metered {
+            return Err(Stop::Yielded(value));
+        }
+        Ok(())
+    }
+
+    /// Whether there's a breakpoint at `pc`.
+    pub fn is_breakpoint(&self, pc: u32) -> bool {
+        self.breakpoints.binary_search(&pc).is_ok()
+    }
+
+    /// Whether the run resumed at a breakpoint, whose instruction the backend runs before
+    /// writing the breakpoint into the code.
+    pub fn stepping(&self) -> bool {
+        self.stepping.get().is_some()
+    }
+
+    /// Checks that the breakpoints are at pcs in `starts`, where instructions start.
+    fn check_breakpoints(&self, starts: Vec<u32>) {
+        for pc in self.breakpoints {
+            assert!(
+                starts.binary_search(pc).is_ok(),
+                "breakpoint at {pc}, which isn't the start of an instruction"
+            );
+        }
+    }
+
+    /// Writes `halt` over each breakpoint in `code`, which a switch backend runs in place,
+    /// except the one the run resumed at. `starts` lists the pcs that instructions start at.
+    pub fn patch<'c, T: Copy>(
+        &self,
+        code: &'c mut [T],
+        halt: T,
+        starts: fn(&[T]) -> Vec<u32>,
+    ) -> Patched<'c, T> {
+        if self.breakpoints.is_empty() {
+            return Patched::clean(code);
+        }
+        self.check_breakpoints(starts(code));
+        let stepping = self.stepping.get();
+        let replaced = self
+            .breakpoints
+            .iter()
+            .filter(|&&pc| Some(pc) != stepping)
+            .map(|&pc| (pc, std::mem::replace(&mut code[pc as usize], halt)))
+            .collect();
+        Patched {
+            code,
+            replaced,
+            stepping,
+        }
+    }
+
+    /// A copy of `code` with `halt` written over every breakpoint, for a backend that doesn't
+    /// change the code it runs. Borrows `code` if there are no breakpoints.
+    pub fn armed<'c, T: Copy>(
+        &self,
+        code: &'c [T],
+        halt: T,
+        starts: fn(&[T]) -> Vec<u32>,
+    ) -> Cow<'c, [T]> {
+        if self.breakpoints.is_empty() {
+            return Cow::Borrowed(code);
+        }
+        self.check_breakpoints(starts(code));
+        let mut armed = code.to_vec();
+        for &pc in self.breakpoints {
+            armed[pc as usize] = halt;
+        }
+        Cow::Owned(armed)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn stop(&self) -> Stop {
+        // The flag is cleared so that the run can be resumed.
+        if self.interrupt.swap(false, Ordering::Relaxed) {
+            Stop::Interrupted
+        } else {
+            Stop::OutOfFuel
+        }
+    }
+}
+
+/// Code that a switch backend runs in place, with breakpoints written over it. Dropping it puts
+/// back the instructions they replaced, even if the run panics.
+pub(crate) struct Patched<'c, T: Copy> {
+    code: &'c mut [T],
+    replaced: Vec<(u32, T)>,
+    /// The breakpoint the run resumed at, which isn't written yet.
+    stepping: Option<u32>,
+}
+
+impl<'c, T: Copy> Patched<'c, T> {
+    /// Code without breakpoints.
+    pub fn clean(code: &'c mut [T]) -> Self {
+        Self {
+            code,
+            replaced: vec![],
+            stepping: None,
+        }
+    }
+
+    /// Whether the run resumed at a breakpoint that isn't written yet.
+    pub fn stepping(&self) -> bool {
+        self.stepping.is_some()
+    }
+
+    /// Whether a breakpoint is written at `pc`.
+    pub fn is_breakpoint(&self, pc: u32) -> bool {
+        self.replaced.iter().any(|&(at, _)| at == pc)
+    }
+
+    /// Writes `halt` over the breakpoint the run resumed at, once it has run the instruction
+    /// there.
+    pub fn rearm(&mut self, halt: T) {
+        if let Some(pc) = self.stepping.take() {
+            let insn = std::mem::replace(&mut self.code[pc as usize], halt);
+            self.replaced.push((pc, insn));
+        }
+    }
+}
+
+impl<T: Copy> Deref for Patched<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.code
+    }
+}
+
+impl<T: Copy> DerefMut for Patched<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.code
+    }
+}
+
+impl<T: Copy> Drop for Patched<'_, T> {
+    fn drop(&mut self) {
+        for &(pc, insn) in &self.replaced {
+            self.code[pc as usize] = insn;
+        }
+    }
+}
+
+/// The result of an unmetered run, which only stops early at an exception that nothing caught.
+pub(crate) fn unmetered(result: Result<(), Stop>) -> Result<(), VmError> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(Stop::Uncaught(value)) => Err(VmError::Uncaught(value)),
+        Err(stop) => unreachable!("unmetered runs don't stop at {stop:?}"),
+    }
+}
+
+/// Runs `f` on a frame of `slots` slots like [`with_slots`], drawing from `fuel`. It starts
+/// from the beginning with the slots initialized from `variables`, or where `suspended` stopped
+/// with its slots, which must have been taken of code with the checksum `code`; either way
+/// `variables` receives the final contents. `f` gets the frame, the
+/// meter and the state to start from, and returns the result or the state it stopped in.
+pub(crate) fn run(
+    backend: Backend,
+    code: u32,
+    slots: usize,
+    variables: &mut [Value],
+    fuel: &mut Fuel,
+    suspended: Option<Suspended>,
+    f: impl FnOnce(&mut [Value], &Meter, State) -> Result<Value, (Stop, State)>,
+) -> Result<Value, VmError> {
+    let meter = Meter {
+        remaining: Cell::new(fuel.remaining),
+        interrupt: &fuel.interrupt,
+        metered: true,
+        breakpoints: &fuel.breakpoints,
+        stepping: Cell::new(None),
+    };
+    let suspend = |stop, state, frame: &mut [Value]| {
+        let at = match stop {
+            Stop::OutOfFuel | Stop::Interrupted => StoppedAt::Jump,
+            Stop::Yielded(_) => StoppedAt::Yield,
+            Stop::Breakpoint => StoppedAt::Breakpoint,
+            // The run is over, so there's nothing to resume.
+            Stop::Uncaught(value) => return VmError::Uncaught(value),
+        };
+        let suspended = Suspended {
+            backend,
+            code,
+            at,
+            state,
+            frame: frame.to_vec(),
+        };
+        match stop {
+            Stop::OutOfFuel => VmError::OutOfFuel(suspended),
+            Stop::Interrupted => VmError::Interrupted(suspended),
+            Stop::Yielded(value) => VmError::Yielded(value, suspended),
+            Stop::Breakpoint => VmError::Breakpoint(suspended),
+            Stop::Uncaught(_) => unreachable!("uncaught exceptions aren't suspended"),
+        }
+    };
+    let run = |frame: &mut [Value], state| {
+        f(frame, &meter, state).map_err(|(stop, state)| suspend(stop, state, frame))
+    };
+    let result = match suspended {
+        None => with_slots(slots, variables, |frame| run(frame, State::START)),
+        Some(Suspended {
+            backend: resumed,
+            code: checksum,
+            at,
+            state,
+            mut frame,
+        }) => {
+            assert_eq!(resumed, backend, "resumed with a different encoding");
+            assert_eq!(checksum, code, "resumed with different code");
+            assert_eq!(frame.len(), slots, "resumed with different code");
+            let result = with_slots(slots, &mut frame, |frame| {
+                match at {
+                    StoppedAt::Yield => (),
+                    // The jump that stopped the run has been taken, but not paid for.
+                    StoppedAt::Jump => {
+                        if let Err(stop) = meter.tick() {
+                            return Err(suspend(stop, state, frame));
+                        }
+                    }
+                    StoppedAt::Breakpoint if meter.is_breakpoint(state.pc) => {
+                        meter.stepping.set(Some(state.pc));
+                    }
+                    StoppedAt::Breakpoint => (),
+                }
+                run(frame, state)
+            });
+            let n = variables.len().min(slots);
+            variables[..n].copy_from_slice(&frame[..n]);
+            result
+        }
+    };
+    fuel.remaining = meter.remaining.get();
+    result
+}
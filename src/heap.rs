@@ -0,0 +1,477 @@
+//! The heap of objects that values refer to: arrays, whose length is chosen at runtime, records,
+//! whose number of fields is fixed by the program, immutable byte strings, and
+//! [coroutines][crate::coroutine]. Fields and elements start out as the integer zero, and
+//! reading a string yields its bytes as integers. Accesses are bounds-checked, and panic when
+//! out of bounds.
+//!
+//! Objects are freed by a garbage collector, which runs before an allocation once enough memory
+//! has been allocated since the last collection. Its roots are the values the interpreter hands
+//! to [`Heap::alloc`]: the variables, registers or stack of the running frame, and the
+//! coroutines that are running, which hold the frames that resumed them. Values held outside of
+//! a run are not roots, so objects they refer to may be freed by the next run that shares the
+//! heap. The [`Collector`] is chosen when the heap is created, and the heap keeps [`GcStats`]
+//! on how much was allocated and how long collections paused the program for.
+
+use std::{
+    fmt, mem,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    coroutine::Coroutine,
+    value::{Unpacked, Value},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    Array(Vec<Value>),
+    Record(Box<[Value]>),
+    String(Box<[u8]>),
+    /// A coroutine, which has no elements, but keeps the objects its frame refers to alive.
+    Coroutine(Box<Coroutine>),
+}
+
+impl Object {
+    /// An approximation of the memory the object takes up, which paces the collector.
+    fn size(&self) -> usize {
+        mem::size_of::<Object>()
+            + match self {
+                Object::Array(elements) => elements.len() * mem::size_of::<Value>(),
+                Object::Record(fields) => fields.len() * mem::size_of::<Value>(),
+                Object::String(bytes) => bytes.len(),
+                Object::Coroutine(coroutine) => {
+                    mem::size_of::<Coroutine>() + coroutine.frame.len() * mem::size_of::<Value>()
+                }
+            }
+    }
+
+    fn values(&self) -> &[Value] {
+        match self {
+            Object::Array(elements) => elements,
+            Object::Record(fields) => fields,
+            Object::String(_) => &[],
+            Object::Coroutine(coroutine) => coroutine.values(),
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        match self {
+            Object::Array(elements) => elements.len() as u32,
+            Object::Record(fields) => fields.len() as u32,
+            Object::String(bytes) => bytes.len() as u32,
+            Object::Coroutine(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The number of bytes allocated between collections when few objects survive them.
+pub const DEFAULT_THRESHOLD: usize = 1 << 20;
+
+/// The size of the nursery of a generational heap created with [`Heap::generational`].
+pub const DEFAULT_NURSERY: usize = 64 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collector {
+    /// Marks everything reachable from the roots and sweeps the whole heap every time.
+    MarkSweep,
+    /// Collects the objects allocated since the last collection, the nursery, on their own once
+    /// they take up `nursery` bytes, and promotes those that survive to the old generation. The
+    /// whole heap is only collected once the old generation outgrows the threshold.
+    ///
+    /// Old objects aren't traced by nursery collections, so writing a reference to a young
+    /// object into an old one records the old one in a remembered set, whose objects are roots
+    /// of the next nursery collection.
+    Generational { nursery: usize },
+}
+
+/// A pause of the program for a garbage collection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pause {
+    pub duration: Duration,
+    /// Whether the whole heap was collected, rather than just the nursery.
+    pub full: bool,
+    pub freed: usize,
+}
+
+/// What a heap has allocated and collected so far.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcStats {
+    pub objects: usize,
+    pub bytes: usize,
+    pub pauses: Vec<Pause>,
+}
+
+impl GcStats {
+    pub fn total_pause(&self) -> Duration {
+        self.pauses.iter().map(|p| p.duration).sum()
+    }
+
+    pub fn max_pause(&self) -> Duration {
+        self.pauses
+            .iter()
+            .map(|p| p.duration)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Allocated bytes per second over a run of the given length.
+    pub fn allocation_rate(&self, elapsed: Duration) -> f64 {
+        self.bytes as f64 / elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let full = self.pauses.iter().filter(|p| p.full).count();
+        write!(
+            f,
+            "{} objects, {} bytes allocated; ",
+            self.objects, self.bytes
+        )?;
+        write!(
+            f,
+            "{} collections ({full} full), {:?} paused, {:?} at most",
+            self.pauses.len(),
+            self.total_pause(),
+            self.max_pause()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Heap {
+    /// Objects by index, with `None` in the slots of freed objects.
+    objects: Vec<Option<Object>>,
+    free: Vec<u32>,
+    marks: Vec<bool>,
+    collector: Collector,
+    /// Whether each object is in the old generation, and whether it's in the remembered set.
+    old: Vec<bool>,
+    remembered: Vec<bool>,
+    /// The objects in the nursery and the remembered set of a generational heap.
+    young: Vec<u32>,
+    remembered_set: Vec<u32>,
+    /// Bytes allocated since the last collection.
+    allocated: usize,
+    /// Bytes taken up by the old generation.
+    old_bytes: usize,
+    threshold: usize,
+    min_threshold: usize,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn out_of_bounds(index: u32, len: u32) -> ! {
+    panic!("index {index} is out of bounds of an object of length {len}")
+}
+
+fn reference(value: Value) -> Option<u32> {
+    match value.unpack() {
+        Unpacked::Ref(r) => Some(r),
+        _ => None,
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::with_threshold(DEFAULT_THRESHOLD)
+    }
+
+    /// Creates a mark-sweep heap that collects garbage once `threshold` bytes have been
+    /// allocated since the last collection, or twice what survived it if that is more.
+    pub fn with_threshold(threshold: usize) -> Self {
+        Self::with_collector(Collector::MarkSweep, threshold)
+    }
+
+    /// Creates a generational heap with the given nursery size and the default threshold.
+    pub fn generational(nursery: usize) -> Self {
+        Self::with_collector(Collector::Generational { nursery }, DEFAULT_THRESHOLD)
+    }
+
+    /// Creates a heap with the given collector. A generational heap's threshold applies to its
+    /// old generation.
+    pub fn with_collector(collector: Collector, threshold: usize) -> Self {
+        Self {
+            objects: vec![],
+            free: vec![],
+            marks: vec![],
+            collector,
+            old: vec![],
+            remembered: vec![],
+            young: vec![],
+            remembered_set: vec![],
+            allocated: 0,
+            old_bytes: 0,
+            threshold,
+            min_threshold: threshold,
+            stats: GcStats::default(),
+        }
+    }
+
+    /// Adds an object to the heap and returns a reference to it, collecting garbage first if
+    /// it's time to.
+    pub fn alloc(&mut self, object: Object, roots: &[&[Value]]) -> Value {
+        match self.collector {
+            Collector::MarkSweep if self.allocated >= self.threshold => self.collect(roots),
+            Collector::Generational { nursery } if self.allocated >= nursery => {
+                if self.old_bytes >= self.threshold {
+                    self.collect(roots);
+                } else {
+                    self.collect_nursery(roots);
+                }
+            }
+            _ => (),
+        }
+        let size = object.size();
+        self.allocated += size;
+        self.stats.objects += 1;
+        self.stats.bytes += size;
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.objects[index as usize] = Some(object);
+                index
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.old.push(false);
+                self.remembered.push(false);
+                (self.objects.len() - 1) as u32
+            }
+        };
+        if let Collector::Generational { .. } = self.collector {
+            self.young.push(index);
+        }
+        Value::reference(index)
+    }
+
+    /// Marks the objects reachable from `grey` that `is_traced` accepts, and returns their
+    /// total size.
+    fn mark(&mut self, mut grey: Vec<u32>, is_traced: impl Fn(&Self, u32) -> bool) -> usize {
+        self.marks.clear();
+        self.marks.resize(self.objects.len(), false);
+        let mut live = 0;
+        while let Some(index) = grey.pop() {
+            if !is_traced(self, index) || mem::replace(&mut self.marks[index as usize], true) {
+                continue;
+            }
+            let object = self.objects[index as usize]
+                .as_ref()
+                .expect("reachable objects are never freed");
+            live += object.size();
+            grey.extend(object.values().iter().filter_map(|&value| reference(value)));
+        }
+        live
+    }
+
+    /// Frees every object that cannot be reached from `roots`.
+    pub fn collect(&mut self, roots: &[&[Value]]) {
+        let start = Instant::now();
+        let live = self.mark(references(roots), |_, _| true);
+
+        let mut freed = 0;
+        for (index, (object, &marked)) in self.objects.iter_mut().zip(&self.marks).enumerate() {
+            if !marked && object.take().is_some() {
+                self.free.push(index as u32);
+                freed += 1;
+            }
+        }
+        // Every survivor is old now.
+        if let Collector::Generational { .. } = self.collector {
+            self.old.copy_from_slice(&self.marks);
+            self.young.clear();
+        }
+        for index in self.remembered_set.drain(..) {
+            self.remembered[index as usize] = false;
+        }
+        self.allocated = 0;
+        self.old_bytes = live;
+        self.threshold = self.min_threshold.max(2 * live);
+        self.pause(start, true, freed);
+    }
+
+    /// Frees every object in the nursery that cannot be reached from `roots` or the remembered
+    /// set, and promotes the others.
+    fn collect_nursery(&mut self, roots: &[&[Value]]) {
+        let start = Instant::now();
+        let mut grey = references(roots);
+        for index in self.remembered_set.drain(..) {
+            self.remembered[index as usize] = false;
+            if let Some(object) = &self.objects[index as usize] {
+                grey.extend(object.values().iter().filter_map(|&value| reference(value)));
+            }
+        }
+        let promoted = self.mark(grey, |heap, index| !heap.old[index as usize]);
+
+        let mut freed = 0;
+        for index in mem::take(&mut self.young) {
+            let index = index as usize;
+            if self.marks[index] {
+                self.old[index] = true;
+            } else {
+                self.objects[index] = None;
+                self.free.push(index as u32);
+                freed += 1;
+            }
+        }
+        self.allocated = 0;
+        self.old_bytes += promoted;
+        self.pause(start, false, freed);
+    }
+
+    fn pause(&mut self, start: Instant, full: bool, freed: usize) {
+        self.stats.pauses.push(Pause {
+            duration: start.elapsed(),
+            full,
+            freed,
+        });
+    }
+
+    /// The object `value` refers to. Panics if it isn't a reference to a live object.
+    pub fn object(&self, value: Value) -> &Object {
+        reference(value)
+            .and_then(|index| self.objects.get(index as usize)?.as_ref())
+            .unwrap_or_else(|| panic!("{value:?} is not a reference to a live object"))
+    }
+
+    fn object_mut(&mut self, value: Value) -> &mut Object {
+        reference(value)
+            .and_then(|index| self.objects.get_mut(index as usize)?.as_mut())
+            .unwrap_or_else(|| panic!("{value:?} is not a reference to a live object"))
+    }
+
+    /// Reads the element, field or byte at `index` of an object.
+    pub fn get(&self, object: Value, index: u32) -> Value {
+        let object = self.object(object);
+        let value = match object {
+            Object::Array(elements) => elements.get(index as usize).copied(),
+            Object::Record(fields) => fields.get(index as usize).copied(),
+            Object::String(bytes) => bytes.get(index as usize).map(|&b| Value::int(b as u32)),
+            Object::Coroutine(_) => None,
+        };
+        value.unwrap_or_else(|| out_of_bounds(index, object.len()))
+    }
+
+    /// Writes the element or field at `index` of an array or record. Panics on strings, which
+    /// are immutable.
+    pub fn set(&mut self, object: Value, index: u32, value: Value) {
+        self.write_barrier(object, value);
+        let object = self.object_mut(object);
+        let len = object.len();
+        let slot = match object {
+            Object::Array(elements) => elements.get_mut(index as usize),
+            Object::Record(fields) => fields.get_mut(index as usize),
+            Object::String(_) => panic!("strings are immutable"),
+            Object::Coroutine(_) => None,
+        };
+        *slot.unwrap_or_else(|| out_of_bounds(index, len)) = value;
+    }
+
+    /// Adds an old object to the remembered set if it's about to refer to a young one.
+    #[inline(always)]
+    fn write_barrier(&mut self, object: Value, value: Value) {
+        let (Some(object), Some(value)) = (reference(object), reference(value)) else {
+            return;
+        };
+        if self.old.get(value as usize) == Some(&false) {
+            self.remember(object);
+        }
+    }
+
+    /// Adds an object to the remembered set if it's old.
+    fn remember(&mut self, object: u32) {
+        let index = object as usize;
+        if self.old.get(index) == Some(&true) && !mem::replace(&mut self.remembered[index], true) {
+            self.remembered_set.push(object);
+        }
+    }
+
+    /// The coroutine `value` refers to, for switching frames with it. Panics if it refers to
+    /// something else.
+    pub(crate) fn coroutine_mut(&mut self, value: Value) -> &mut Coroutine {
+        // The frames it's switched with may refer to young objects.
+        if let Some(object) = reference(value) {
+            self.remember(object);
+        }
+        match self.object_mut(value) {
+            Object::Coroutine(coroutine) => coroutine,
+            _ => panic!("{value:?} is not a coroutine"),
+        }
+    }
+
+    /// The number of elements, fields or bytes in an object.
+    pub fn len(&self, object: Value) -> u32 {
+        self.object(object).len()
+    }
+
+    /// The number of objects that have not been freed.
+    pub fn live(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    /// The number of collections so far.
+    pub fn collections(&self) -> usize {
+        self.stats.pauses.len()
+    }
+
+    pub fn collector(&self) -> Collector {
+        self.collector
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+}
+
+fn references(roots: &[&[Value]]) -> Vec<u32> {
+    roots
+        .iter()
+        .flat_map(|values| values.iter())
+        .filter_map(|&value| reference(value))
+        .collect()
+}
+
+/// Encodes a string for a constant pool: its length, followed by its bytes packed four to a
+/// word, little endian.
+pub(crate) fn string_constant(bytes: &[u8]) -> Vec<u32> {
+    let mut words = vec![bytes.len() as u32];
+    words.extend(bytes.chunks(4).map(|chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        u32::from_le_bytes(word)
+    }));
+    words
+}
+
+/// Decodes the string constant starting at `index`, or returns `None` if it runs past the end
+/// of the pool.
+pub(crate) fn read_string_constant(constants: &[u32], index: usize) -> Option<Box<[u8]>> {
+    let len = *constants.get(index)? as usize;
+    let words = constants.get(index + 1..)?.get(..len.div_ceil(4))?;
+    let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    bytes.truncate(len);
+    Some(bytes.into())
+}
+
+/// Returns the index of a string in a constant pool, adding it if it isn't there yet. Its words
+/// may also be parts of other constants.
+pub(crate) fn add_string_constant(constants: &mut Vec<u32>, bytes: &[u8]) -> usize {
+    let words = string_constant(bytes);
+    match constants.windows(words.len()).position(|w| w == words) {
+        Some(i) => i,
+        None => {
+            constants.extend(&words);
+            constants.len() - words.len()
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Functions that embedders expose to programs, such as I/O, math or logging, without adding
+//! opcodes for them. A [`Host`] holds Rust closures by index, each with a name the parser knows
+//! it by and a number of arguments. The parser lowers a call to one into
+//! [`Instruction::CallNative`], which every backend compiles into a `CallNative` instruction
+//! carrying the function's index and the number of arguments.
+//!
+//! Bytecode refers to functions by index, so a module must be run with a host that registered
+//! the same functions in the same order as the one it was compiled with. A call with the wrong
+//! number of arguments, or to a function the host doesn't have, panics.
+//!
+//! [`Instruction::CallNative`]: crate::treewalk::Instruction::CallNative
+
+use std::fmt;
+
+use crate::{
+    heap::{Heap, Object},
+    value::Value,
+};
+
+/// What a host function gets to work with: its arguments and the heap.
+pub struct Call<'a> {
+    args: &'a [Value],
+    heap: &'a mut Heap,
+    /// The values of the calling frame, other than the arguments, that must survive a
+    /// collection.
+    roots: &'a [&'a [Value]],
+}
+
+impl<'a> Call<'a> {
+    pub fn new(args: &'a [Value], heap: &'a mut Heap, roots: &'a [&'a [Value]]) -> Self {
+        Self { args, heap, roots }
+    }
+
+    pub fn args(&self) -> &[Value] {
+        self.args
+    }
+
+    pub fn heap(&self) -> &Heap {
+        self.heap
+    }
+
+    /// The heap, for changing objects. Allocate with [`Call::alloc`] instead, which knows the
+    /// caller's roots.
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        self.heap
+    }
+
+    /// Allocates an object, keeping the caller's values and the arguments alive.
+    pub fn alloc(&mut self, object: Object) -> Value {
+        let mut roots = self.roots.to_vec();
+        roots.push(self.args);
+        self.heap.alloc(object, &roots)
+    }
+}
+
+pub type Function = dyn Fn(&mut Call) -> Value;
+
+struct Entry {
+    name: String,
+    arity: u8,
+    function: Box<Function>,
+}
+
+/// The functions programs can call, by index.
+#[derive(Default)]
+pub struct Host {
+    functions: Vec<Entry>,
+}
+
+impl fmt::Debug for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.functions.iter().map(|e| (&e.name, e.arity)))
+            .finish()
+    }
+}
+
+impl Host {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a function taking `arity` arguments, and returns its index. Panics if there are
+    /// already 65536 functions or one with the same name.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        arity: u8,
+        function: impl Fn(&mut Call) -> Value + 'static,
+    ) -> u16 {
+        let name = name.into();
+        assert!(self.find(&name).is_none(), "`{name}` is already registered");
+        let index = u16::try_from(self.functions.len()).expect("too many host functions");
+        self.functions.push(Entry {
+            name,
+            arity,
+            function: Box::new(function),
+        });
+        index
+    }
+
+    /// The index and arity of the function with the given name.
+    pub fn find(&self, name: &str) -> Option<(u16, u8)> {
+        let index = self.functions.iter().position(|e| e.name == name)?;
+        Some((index as u16, self.functions[index].arity))
+    }
+
+    pub fn name(&self, index: u16) -> Option<&str> {
+        Some(&self.functions.get(index as usize)?.name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    /// Calls function `index` with the arguments in `call`.
+    pub fn call(&self, index: u16, call: &mut Call) -> Value {
+        let Some(entry) = self.functions.get(index as usize) else {
+            unknown_function(index)
+        };
+        if call.args.len() != entry.arity as usize {
+            wrong_arity(entry, call.args.len());
+        }
+        (entry.function)(call)
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn unknown_function(index: u16) -> ! {
+    panic!("host function {index} is not registered")
+}
+
+#[cold]
+#[inline(never)]
+fn wrong_arity(entry: &Entry, given: usize) -> ! {
+    panic!(
+        "`{}` takes {} arguments, but {given} were given",
+        entry.name, entry.arity
+    )
+}
@@ -1,8 +1,23 @@
+pub mod bytecode;
 pub mod compact_treewalk_dtable;
 pub mod compact_treewalk_switch;
+pub mod coroutine;
+pub mod exception;
+pub mod fold;
+pub mod fuel;
+pub mod heap;
+pub mod host;
+pub mod memory;
+pub mod module;
 pub mod native;
+pub mod parser;
+pub mod profile;
 pub mod register_dtable;
+pub mod register_fixed;
 pub mod register_switch;
+pub mod snapshot;
 pub mod stack_dtable;
 pub mod stack_switch;
 pub mod treewalk;
+pub mod value;
+pub mod workloads;
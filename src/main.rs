@@ -0,0 +1,664 @@
+use std::{env, fs, process::ExitCode, time::Instant};
+
+use dispatchers::{
+    bytecode::{CompileError, Disassembly},
+    fuel::{Fuel, Suspended, VmError},
+    heap::{self, Collector, Heap, Object},
+    host::Host,
+    module::{self, Module},
+    parser,
+    profile::Profiler,
+    treewalk::Instruction,
+    value::{Unpacked, Value},
+    *,
+};
+
+const USAGE: &str = "\
+usage: dispatchers [options] <file> [arguments...]
+
+Runs a source file (see src/parser.rs for the syntax) or a compiled module, passing the
+arguments in the first variables. Arguments are integers, or floats if they have a decimal
+point or an exponent. Programs can call `(print x)`, which prints a value and returns it, and
+`(sqrt x)` on floats.
+
+options:
+  --backend <name>  the interpreter to use: treewalk, compact-treewalk-dtable,
+                    compact-treewalk-switch, stack-dtable, stack-switch, register-dtable,
+                    register-switch (the default for source files), register-fixed, or
+                    native
+  --trace           print every instruction as it's executed; needs the `profile` feature
+  --disasm          print the bytecode before running it
+  --time            print how long the program took to run
+  --verify          check that the bytecode is well-formed before running it; modules are
+                    always verified
+  --fold            fold constants and simplify the program before compiling it
+  --peephole        optimize the bytecode before running it; needs a stack backend
+  --emit <path>     save the compiled bytecode as a module
+  --memory <bytes>  the size of the program's linear memory, 64 KiB by default
+  --gc <collector>  the heap's garbage collector: mark-sweep (the default) or generational
+  --gc-stats        print how much the program allocated and how long collections took
+  --fuel <n>        stop the program after <n> backward jumps; needs a stack or register
+                    backend, as do the three options below
+  --break <pc>      stop the program at the instruction at <pc>, as --disasm numbers them;
+                    may be given more than once
+  --snapshot <path> save the state of a program that ran out of fuel, yielded or reached a
+                    breakpoint, for --resume to carry on from
+  --resume <path>   carry on from a snapshot of the same program, rather than starting it;
+                    the snapshot doesn't hold the linear memory or the heap";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Native,
+    Treewalk,
+    CompactTreewalkDtable,
+    CompactTreewalkSwitch,
+    StackDtable,
+    StackSwitch,
+    RegisterDtable,
+    RegisterSwitch,
+    RegisterFixed,
+}
+
+const BACKENDS: &[(&str, Backend)] = &[
+    ("native", Backend::Native),
+    ("treewalk", Backend::Treewalk),
+    ("compact-treewalk-dtable", Backend::CompactTreewalkDtable),
+    ("compact-treewalk-switch", Backend::CompactTreewalkSwitch),
+    ("stack-dtable", Backend::StackDtable),
+    ("stack-switch", Backend::StackSwitch),
+    ("register-dtable", Backend::RegisterDtable),
+    ("register-switch", Backend::RegisterSwitch),
+    ("register-fixed", Backend::RegisterFixed),
+];
+
+impl Backend {
+    /// The bytecode encoding of the backend, or `None` if it doesn't interpret bytecode.
+    fn encoding(self) -> Option<module::Backend> {
+        match self {
+            Backend::Native | Backend::Treewalk => None,
+            Backend::CompactTreewalkDtable | Backend::CompactTreewalkSwitch => {
+                Some(module::Backend::CompactTreewalk)
+            }
+            Backend::StackDtable | Backend::StackSwitch => Some(module::Backend::Stack),
+            Backend::RegisterDtable | Backend::RegisterSwitch => Some(module::Backend::Register),
+            Backend::RegisterFixed => Some(module::Backend::RegisterFixed),
+        }
+    }
+
+    fn for_encoding(encoding: module::Backend) -> Self {
+        match encoding {
+            module::Backend::CompactTreewalk => Backend::CompactTreewalkSwitch,
+            module::Backend::Stack => Backend::StackSwitch,
+            module::Backend::Register => Backend::RegisterSwitch,
+            module::Backend::RegisterFixed => Backend::RegisterFixed,
+        }
+    }
+
+    fn compile(self, program: &Instruction) -> Result<Module, CompileError> {
+        let encoding = self.encoding().expect("backend must interpret bytecode");
+        let chunk = match encoding {
+            module::Backend::CompactTreewalk => compact_treewalk_switch::compile(program)?,
+            module::Backend::Stack => stack_switch::compile(program)?,
+            module::Backend::Register => register_switch::compile(program)?,
+            module::Backend::RegisterFixed => {
+                return Ok(register_fixed::compile(program)?.to_module())
+            }
+        };
+        Ok(Module::from_chunk(encoding, chunk))
+    }
+
+    fn disassemble(self, module: &Module) -> Disassembly {
+        let code = &module.chunk();
+        match module.backend {
+            module::Backend::CompactTreewalk => compact_treewalk_switch::disassemble(code),
+            module::Backend::Stack => stack_switch::disassemble(code),
+            module::Backend::Register => register_switch::disassemble(code),
+            module::Backend::RegisterFixed => fixed(module).map_or_else(
+                |e| vec![(0, format!("<{e}>"))],
+                |program| register_fixed::disassemble(&program),
+            ),
+        }
+    }
+
+    fn verify(self, module: &Module) -> Result<(), String> {
+        let code = &module.chunk();
+        match module.backend {
+            module::Backend::CompactTreewalk => compact_treewalk_switch::verify(code),
+            module::Backend::Stack => stack_switch::verify(code),
+            module::Backend::Register => register_switch::verify(code),
+            module::Backend::RegisterFixed => register_fixed::verify(&fixed(module)?),
+        }
+        .map_err(|e| format!("invalid bytecode {e}"))
+    }
+
+    fn execute_with(
+        self,
+        module: &Module,
+        variables: &mut [Value; 256],
+        memory: &mut [u8],
+        heap: &mut Heap,
+        host: &Host,
+        profiler: Profiler,
+    ) -> (Result<Value, VmError>, Profiler) {
+        let code = &mut module.chunk();
+        match self {
+            Backend::Native | Backend::Treewalk => unreachable!("backend must interpret bytecode"),
+            Backend::CompactTreewalkDtable => {
+                let (result, profiler) = compact_treewalk_dtable::execute_with(
+                    code, variables, memory, heap, host, profiler,
+                );
+                (Ok(result), profiler)
+            }
+            Backend::CompactTreewalkSwitch => {
+                let (result, profiler) = compact_treewalk_switch::execute_with(
+                    code, variables, memory, heap, host, profiler,
+                );
+                (Ok(result), profiler)
+            }
+            Backend::StackDtable => {
+                stack_dtable::execute_with(code, variables, memory, heap, host, profiler)
+            }
+            Backend::StackSwitch => {
+                stack_switch::execute_with(code, variables, memory, heap, host, profiler)
+            }
+            Backend::RegisterDtable => {
+                register_dtable::execute_with(code, variables, memory, heap, host, profiler)
+            }
+            Backend::RegisterSwitch => {
+                register_switch::execute_with(code, variables, memory, heap, host, profiler)
+            }
+            Backend::RegisterFixed => {
+                let program = fixed(module).expect("module must have been verified");
+                register_fixed::execute_with(&program, variables, memory, heap, host, profiler)
+            }
+        }
+    }
+
+    /// Runs with `fuel`, from the start or from where `suspended` stopped.
+    #[allow(clippy::too_many_arguments)]
+    fn execute_metered(
+        self,
+        module: &Module,
+        suspended: Option<Suspended>,
+        variables: &mut [Value; 256],
+        memory: &mut [u8],
+        heap: &mut Heap,
+        host: &Host,
+        fuel: &mut Fuel,
+    ) -> Result<Value, VmError> {
+        let code = &mut module.chunk();
+        match (self, suspended) {
+            (Backend::StackDtable, None) => {
+                stack_dtable::execute_metered(code, variables, memory, heap, host, fuel)
+            }
+            (Backend::StackDtable, Some(s)) => {
+                stack_dtable::resume(code, s, variables, memory, heap, host, fuel)
+            }
+            (Backend::StackSwitch, None) => {
+                stack_switch::execute_metered(code, variables, memory, heap, host, fuel)
+            }
+            (Backend::StackSwitch, Some(s)) => {
+                stack_switch::resume(code, s, variables, memory, heap, host, fuel)
+            }
+            (Backend::RegisterDtable, None) => {
+                register_dtable::execute_metered(code, variables, memory, heap, host, fuel)
+            }
+            (Backend::RegisterDtable, Some(s)) => {
+                register_dtable::resume(code, s, variables, memory, heap, host, fuel)
+            }
+            (Backend::RegisterSwitch, None) => {
+                register_switch::execute_metered(code, variables, memory, heap, host, fuel)
+            }
+            (Backend::RegisterSwitch, Some(s)) => {
+                register_switch::resume(code, s, variables, memory, heap, host, fuel)
+            }
+            (Backend::RegisterFixed, s) => {
+                let program = fixed(module).expect("module must have been verified");
+                match s {
+                    None => register_fixed::execute_metered(
+                        &program, variables, memory, heap, host, fuel,
+                    ),
+                    Some(s) => {
+                        register_fixed::resume(&program, s, variables, memory, heap, host, fuel)
+                    }
+                }
+            }
+            _ => unreachable!("backend must be metered"),
+        }
+    }
+
+    /// Whether the backend can stop and resume runs.
+    fn is_metered(self) -> bool {
+        matches!(
+            self.encoding(),
+            Some(
+                module::Backend::Stack | module::Backend::Register | module::Backend::RegisterFixed
+            )
+        )
+    }
+
+    /// The number of slots a frame running `module` has.
+    fn slots(self, module: &Module) -> usize {
+        match module.backend {
+            module::Backend::RegisterFixed => fixed(module).map_or(0, |program| program.slots),
+            _ => module.chunk().slots,
+        }
+    }
+
+    /// The checksum of `module`'s code that snapshots of runs of it record.
+    fn checksum(self, module: &Module) -> u32 {
+        match module.backend {
+            module::Backend::Stack => stack_switch::checksum(&module.chunk()),
+            module::Backend::Register => register_switch::checksum(&module.chunk()),
+            module::Backend::RegisterFixed => {
+                fixed(module).map_or(0, |p| register_fixed::checksum(&p))
+            }
+            module::Backend::CompactTreewalk => unreachable!("backend must be metered"),
+        }
+    }
+}
+
+/// Unpacks the instructions of a `register (fixed)` module.
+fn fixed(module: &Module) -> Result<register_fixed::Program, String> {
+    register_fixed::Program::from_module(module).map_err(|e| e.to_string())
+}
+
+struct Options {
+    backend: Option<Backend>,
+    trace: bool,
+    disasm: bool,
+    time: bool,
+    verify: bool,
+    fold: bool,
+    peephole: bool,
+    emit: Option<String>,
+    memory: usize,
+    collector: Collector,
+    gc_stats: bool,
+    fuel: Option<u64>,
+    breakpoints: Vec<u32>,
+    snapshot: Option<String>,
+    resume: Option<String>,
+    file: Option<String>,
+    arguments: Vec<Value>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            backend: None,
+            trace: false,
+            disasm: false,
+            time: false,
+            verify: false,
+            fold: false,
+            peephole: false,
+            emit: None,
+            memory: 64 * 1024,
+            collector: Collector::MarkSweep,
+            gc_stats: false,
+            fuel: None,
+            breakpoints: vec![],
+            snapshot: None,
+            resume: None,
+            file: None,
+            arguments: vec![],
+        }
+    }
+}
+
+fn parse_argument(arg: &str) -> Option<Value> {
+    match arg.parse() {
+        Ok(i) => Some(Value::int(i)),
+        Err(_) => arg.parse().ok().map(Value::float),
+    }
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backend" => {
+                let name = args.next().ok_or("--backend needs a value")?;
+                let (_, backend) = BACKENDS
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .ok_or_else(|| format!("unknown backend `{name}`"))?;
+                options.backend = Some(*backend);
+            }
+            "--trace" => options.trace = true,
+            "--disasm" => options.disasm = true,
+            "--time" => options.time = true,
+            "--verify" => options.verify = true,
+            "--fold" => options.fold = true,
+            "--peephole" => options.peephole = true,
+            "--emit" => options.emit = Some(args.next().ok_or("--emit needs a path")?),
+            "--memory" => {
+                let size = args.next().ok_or("--memory needs a size")?;
+                options.memory = size
+                    .parse()
+                    .map_err(|_| format!("memory size `{size}` is not a valid number of bytes"))?;
+            }
+            "--gc" => {
+                options.collector = match args.next().ok_or("--gc needs a collector")?.as_str() {
+                    "mark-sweep" => Collector::MarkSweep,
+                    "generational" => Collector::Generational {
+                        nursery: heap::DEFAULT_NURSERY,
+                    },
+                    name => return Err(format!("unknown collector `{name}`")),
+                };
+            }
+            "--gc-stats" => options.gc_stats = true,
+            "--fuel" => {
+                let fuel = args.next().ok_or("--fuel needs an amount")?;
+                options.fuel = Some(
+                    fuel.parse()
+                        .map_err(|_| format!("fuel `{fuel}` is not a valid number of jumps"))?,
+                );
+            }
+            "--break" => {
+                let pc = args.next().ok_or("--break needs a pc")?;
+                options.breakpoints.push(
+                    pc.parse()
+                        .map_err(|_| format!("`{pc}` is not a valid pc"))?,
+                );
+            }
+            "--snapshot" => options.snapshot = Some(args.next().ok_or("--snapshot needs a path")?),
+            "--resume" => options.resume = Some(args.next().ok_or("--resume needs a path")?),
+            "-h" | "--help" => return Err(USAGE.into()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{arg}`")),
+            _ if options.file.is_none() => options.file = Some(arg),
+            _ => options.arguments.push(
+                parse_argument(&arg)
+                    .ok_or_else(|| format!("argument `{arg}` is not a valid u32 or float"))?,
+            ),
+        }
+    }
+    Ok(options)
+}
+
+/// What was loaded from the input file.
+enum Input {
+    Source(parser::Program),
+    Module(Module),
+}
+
+fn run() -> Result<(), String> {
+    let options = parse_options()?;
+    let file = options.file.as_deref().ok_or(USAGE)?;
+    let bytes = fs::read(file).map_err(|e| format!("cannot read {file}: {e}"))?;
+    let host = host();
+
+    let input = if bytes.starts_with(&module::MAGIC) {
+        if options.fold {
+            return Err("--fold needs a source file rather than a module".into());
+        }
+        Input::Module(Module::from_bytes(&bytes).map_err(|e| format!("{file}: {e}"))?)
+    } else {
+        let source = String::from_utf8(bytes).map_err(|_| format!("{file}: not valid UTF-8"))?;
+        let mut program = parser::parse_with(&source, &host).map_err(|e| format!("{file}:{e}"))?;
+        if options.fold {
+            program.code = fold::fold(&program.code);
+        }
+        Input::Source(program)
+    };
+
+    let backend = match (&input, options.backend) {
+        (_, Some(Backend::Native)) => {
+            return Err("`native` is compiled Rust code and cannot run programs".into())
+        }
+        (Input::Module(module), None) => Backend::for_encoding(module.backend),
+        (Input::Module(module), Some(backend)) if backend.encoding() != Some(module.backend) => {
+            return Err(format!(
+                "{file} contains {} bytecode, which the selected backend cannot run",
+                module.backend
+            ));
+        }
+        (_, Some(backend)) => backend,
+        (Input::Source(_), None) => Backend::RegisterSwitch,
+    };
+    if options.peephole && backend.encoding() != Some(module::Backend::Stack) {
+        return Err("--peephole needs a stack backend".into());
+    }
+    let metered = options.fuel.is_some()
+        || !options.breakpoints.is_empty()
+        || options.snapshot.is_some()
+        || options.resume.is_some();
+    if metered && !backend.is_metered() {
+        return Err(
+            "--fuel, --break, --snapshot and --resume need a stack or register backend".into(),
+        );
+    }
+    if metered && options.trace {
+        return Err(
+            "tracing cannot be combined with --fuel, --break, --snapshot or --resume".into(),
+        );
+    }
+
+    let mut variables = [Value::int(0); 256];
+    match &input {
+        // A resumed run carries on with the variables in the snapshot.
+        _ if options.resume.is_some() && !options.arguments.is_empty() => {
+            return Err("a resumed program takes no arguments".into());
+        }
+        _ if options.resume.is_some() => {}
+        Input::Source(program) if options.arguments.len() != program.arguments => {
+            return Err(format!(
+                "{file} takes {} arguments, but {} were given",
+                program.arguments,
+                options.arguments.len()
+            ));
+        }
+        _ if options.arguments.len() > variables.len() => return Err("too many arguments".into()),
+        _ => variables[..options.arguments.len()].copy_from_slice(&options.arguments),
+    }
+
+    let mut memory = vec![0; options.memory];
+    let mut heap = Heap::with_collector(options.collector, heap::DEFAULT_THRESHOLD);
+    let start = Instant::now();
+    let (result, elapsed) = if backend == Backend::Treewalk {
+        let Input::Source(program) = &input else {
+            unreachable!("modules cannot select the treewalk backend")
+        };
+        if options.trace || options.emit.is_some() {
+            return Err("tracing and emitting modules needs a bytecode backend".into());
+        }
+        if options.disasm {
+            println!("{:#?}", program.code);
+        }
+        let result =
+            treewalk::execute(&program.code, &mut variables, &mut memory, &mut heap, &host);
+        (result, start.elapsed())
+    } else {
+        let mut module = match &input {
+            Input::Source(program) => backend
+                .compile(&program.code)
+                .map_err(|e| format!("{file}: {e}"))?,
+            Input::Module(module) => {
+                if module.entry_point("main") != Some(0) {
+                    return Err(format!(
+                        "{file}: `main` must be at the start of the bytecode"
+                    ));
+                }
+                module.clone()
+            }
+        };
+        if options.verify || matches!(input, Input::Module(_)) {
+            backend
+                .verify(&module)
+                .map_err(|e| format!("{file}: {e}"))?;
+        }
+        if options.peephole {
+            let chunk =
+                stack_switch::optimize(&module.chunk()).map_err(|e| format!("{file}: {e}"))?;
+            module = Module::from_chunk(module::Backend::Stack, chunk);
+        }
+        if let Some(path) = &options.emit {
+            module
+                .save(fs::File::create(path).map_err(|e| format!("cannot create {path}: {e}"))?)
+                .map_err(|e| format!("cannot write {path}: {e}"))?;
+        }
+
+        let disassembly = backend.disassemble(&module);
+        if options.disasm {
+            for (pc, text) in &disassembly {
+                println!("{pc:5}  {text}");
+            }
+        }
+        if let Some(pc) = options
+            .breakpoints
+            .iter()
+            .find(|&&at| !disassembly.iter().any(|(pc, _)| *pc == at))
+        {
+            return Err(format!("no instruction starts at pc {pc}"));
+        }
+        let suspended = match &options.resume {
+            Some(path) => Some(load_snapshot(path, backend, &module, &disassembly)?),
+            None => None,
+        };
+        let profiler = if options.trace {
+            tracer(disassembly)?
+        } else {
+            Profiler::new()
+        };
+        // Don't include compilation and verification in the measurement.
+        let start = Instant::now();
+        let result = if metered {
+            let mut fuel = options.fuel.map_or_else(Fuel::unlimited, Fuel::new);
+            for &pc in &options.breakpoints {
+                fuel.set_breakpoint(pc);
+            }
+            backend.execute_metered(
+                &module,
+                suspended,
+                &mut variables,
+                &mut memory,
+                &mut heap,
+                &host,
+                &mut fuel,
+            )
+        } else {
+            let (result, _) = backend.execute_with(
+                &module,
+                &mut variables,
+                &mut memory,
+                &mut heap,
+                &host,
+                profiler,
+            );
+            result
+        };
+        (result, start.elapsed())
+    };
+    if options.time {
+        println!("time: {elapsed:?}");
+    }
+    if options.gc_stats {
+        let stats = heap.stats();
+        println!("gc: {stats}");
+        println!(
+            "allocation rate: {:.1} MB/s",
+            stats.allocation_rate(elapsed) / 1e6
+        );
+    }
+
+    match result {
+        Ok(result) => println!("result: {}", show(&heap, result)),
+        Err(VmError::Uncaught(value)) => {
+            return Err(format!("uncaught exception {}", show(&heap, value)));
+        }
+        Err(error) => {
+            println!("stopped: {error}");
+            if let Some(path) = &options.snapshot {
+                let file =
+                    fs::File::create(path).map_err(|e| format!("cannot create {path}: {e}"))?;
+                error
+                    .into_suspended()
+                    .expect("a run that stopped can be resumed")
+                    .save(file)
+                    .map_err(|e| format!("cannot write {path}: {e}"))?;
+            }
+        }
+    }
+    if let Input::Source(program) = &input {
+        for (name, value) in program.variables.iter().zip(variables) {
+            println!("{name} = {}", show(&heap, value));
+        }
+    }
+    Ok(())
+}
+
+/// Loads a snapshot, checking that it was taken of `module`.
+fn load_snapshot(
+    path: &str,
+    backend: Backend,
+    module: &Module,
+    disassembly: &Disassembly,
+) -> Result<Suspended, String> {
+    let file = fs::File::open(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+    let suspended = Suspended::load(file).map_err(|e| format!("{path}: {e}"))?;
+    if Some(suspended.backend()) != backend.encoding()
+        || suspended.slots() != backend.slots(module)
+        || !disassembly.iter().any(|(pc, _)| *pc == suspended.pc())
+    {
+        return Err(format!("{path} is a snapshot of a different program"));
+    }
+    suspended
+        .check_code(backend.checksum(module))
+        .map_err(|e| format!("{path}: {e}"))?;
+    Ok(suspended)
+}
+
+/// The functions programs run from the command line can call. Modules must have been compiled
+/// against the same ones.
+fn host() -> Host {
+    let mut host = Host::new();
+    host.register("print", 1, |call| {
+        let value = call.args()[0];
+        println!("{}", show(call.heap(), value));
+        value
+    });
+    host.register("sqrt", 1, |call| {
+        Value::float(call.args()[0].as_float().sqrt())
+    });
+    host
+}
+
+/// Formats a value, spelling out the contents of strings it refers to.
+fn show(heap: &Heap, value: Value) -> String {
+    match value.unpack() {
+        Unpacked::Ref(_) => match heap.object(value) {
+            Object::String(bytes) => format!("{:?}", String::from_utf8_lossy(bytes)),
+            Object::Coroutine(coroutine) => {
+                format!("{value} (coroutine, {:?})", coroutine.status())
+            }
+            object => format!("{value} (length {})", object.len()),
+        },
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(feature = "profile")]
+fn tracer(disassembly: Disassembly) -> Result<Profiler, String> {
+    let instructions: std::collections::HashMap<_, _> = disassembly.into_iter().collect();
+    Ok(Profiler::with_trace(move |pc, depth| {
+        let text = instructions.get(&pc).map(|t| t.trim_start()).unwrap_or("?");
+        println!("{pc:5}  {}{text}", "  ".repeat(depth));
+    }))
+}
+
+#[cfg(not(feature = "profile"))]
+fn tracer(_disassembly: Disassembly) -> Result<Profiler, String> {
+    Err("--trace needs dispatchers to be built with `--features profile`".into())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}
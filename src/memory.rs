@@ -0,0 +1,59 @@
+//! Linear memory: a byte array handed to each run, which programs read and write with loads and
+//! stores of 8, 16 and 32-bit unsigned integers at byte addresses. Integers are little-endian
+//! and need not be aligned. Accesses are bounds-checked, and panic when out of bounds.
+
+/// The width of a load or store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    U8,
+    U16,
+    U32,
+}
+
+impl Width {
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::U8 => 1,
+            Width::U16 => 2,
+            Width::U32 => 4,
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn out_of_bounds(address: u32, width: Width, size: usize) -> ! {
+    panic!(
+        "{}-byte access at address {address} is out of bounds of {size} bytes of memory",
+        width.bytes()
+    )
+}
+
+/// Reads the unsigned integer of the given width at `address`.
+#[inline(always)]
+pub fn load(memory: &[u8], address: u32, width: Width) -> u32 {
+    let Some(bytes) = memory
+        .get(address as usize..)
+        .and_then(|m| m.get(..width.bytes()))
+    else {
+        out_of_bounds(address, width, memory.len())
+    };
+    match width {
+        Width::U8 => bytes[0] as u32,
+        Width::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+        Width::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+/// Writes the low bytes of `value` that fit the given width at `address`.
+#[inline(always)]
+pub fn store(memory: &mut [u8], address: u32, width: Width, value: u32) {
+    let size = memory.len();
+    let Some(bytes) = memory
+        .get_mut(address as usize..)
+        .and_then(|m| m.get_mut(..width.bytes()))
+    else {
+        out_of_bounds(address, width, size)
+    };
+    bytes.copy_from_slice(&value.to_le_bytes()[..width.bytes()]);
+}
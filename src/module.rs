@@ -0,0 +1,359 @@
+//! Binary module files, so that compiled bytecode can be cached and shared between tools.
+//!
+//! All integers are little endian. A module is laid out as follows:
+//!
+//! ```text
+//! magic         [u8; 4]   "DSPM"
+//! version       u16       must be equal to VERSION
+//! backend       u8        see Backend
+//! slots         u32       the number of variable slots the code needs
+//! constants     u32 count, followed by that many u32s
+//! entry points  u32 count, followed by (u16 name length, UTF-8 name, u32 pc) for each
+//! handlers      u32 count, followed by (u32 start, u32 end, u32 target, u32 depth) for each
+//! bytecode      u32 length, followed by that many bytes
+//! checksum      u32       FNV-1a of everything above
+//! ```
+
+use std::{
+    error, fmt,
+    io::{self, Read, Write},
+};
+
+use crate::{bytecode::Chunk, exception::Handler};
+
+pub const MAGIC: [u8; 4] = *b"DSPM";
+
+/// The version of the format. Bumped whenever the layout or the bytecode encoding of any backend
+/// changes, as modules are not portable between versions.
+pub const VERSION: u16 = 1;
+
+/// The bytecode encoding a module uses. The `dtable` and `switch` variants of each backend share
+/// their encoding, so either can run a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Backend {
+    CompactTreewalk,
+    Stack,
+    Register,
+    /// `register (fixed)`, whose instructions are stored as little endian `u32`s.
+    RegisterFixed,
+}
+
+impl Backend {
+    pub(crate) fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            0 => Some(Self::CompactTreewalk),
+            1 => Some(Self::Stack),
+            2 => Some(Self::Register),
+            3 => Some(Self::RegisterFixed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::CompactTreewalk => "compact treewalk",
+            Self::Stack => "stack",
+            Self::Register => "register",
+            Self::RegisterFixed => "register (fixed)",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub name: String,
+    pub pc: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub backend: Backend,
+    pub slots: u32,
+    pub constants: Vec<u32>,
+    pub entry_points: Vec<EntryPoint>,
+    /// The [exception table][crate::exception].
+    pub handlers: Vec<Handler>,
+    pub bytecode: Vec<u8>,
+}
+
+impl Module {
+    /// Creates a module with no constants or exception handlers, whose only entry point is
+    /// `main` at the start of the bytecode. It has 256 variable slots, which is what code
+    /// without `Wide` operands can address.
+    pub fn new(backend: Backend, bytecode: Vec<u8>) -> Self {
+        Self {
+            backend,
+            slots: 256,
+            constants: vec![],
+            entry_points: vec![EntryPoint {
+                name: "main".into(),
+                pc: 0,
+            }],
+            handlers: vec![],
+            bytecode,
+        }
+    }
+
+    /// Creates a module from compiled bytecode, its constant pool and exception table, with
+    /// `main` at the start of the bytecode.
+    pub fn from_chunk(backend: Backend, chunk: Chunk) -> Self {
+        Self {
+            slots: chunk.slots as u32,
+            constants: chunk.constants,
+            handlers: chunk.handlers,
+            ..Self::new(backend, chunk.code)
+        }
+    }
+
+    /// The module's bytecode, constant pool and exception table, as the bytecode backends take
+    /// them.
+    pub fn chunk(&self) -> Chunk {
+        Chunk {
+            code: self.bytecode.clone(),
+            constants: self.constants.clone(),
+            slots: self.slots as usize,
+            handlers: self.handlers.clone(),
+        }
+    }
+
+    pub fn entry_point(&self, name: &str) -> Option<u32> {
+        self.entry_points
+            .iter()
+            .find(|e| e.name == name)
+            .map(|e| e.pc)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(self.backend as u8);
+        bytes.extend_from_slice(&self.slots.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.constants.len() as u32).to_le_bytes());
+        for constant in &self.constants {
+            bytes.extend_from_slice(&constant.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.entry_points.len() as u32).to_le_bytes());
+        for entry in &self.entry_points {
+            bytes.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(entry.name.as_bytes());
+            bytes.extend_from_slice(&entry.pc.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.handlers.len() as u32).to_le_bytes());
+        for handler in &self.handlers {
+            for field in [handler.start, handler.end, handler.target, handler.depth] {
+                bytes.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        bytes.extend_from_slice(&(self.bytecode.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.bytecode);
+
+        let checksum = fnv1a(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// The checksum the module is saved with, which tells modules with different code apart.
+    pub fn checksum(&self) -> u32 {
+        let bytes = self.to_bytes();
+        u32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        let mut r = Reader { bytes, pos: 0 };
+
+        if r.take(4).map_err(|_| LoadError::BadMagic)? != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+
+        // Verify the checksum before interpreting anything else, so that corruption isn't
+        // reported as some other, more confusing error.
+        let Some(body_len) = bytes.len().checked_sub(4) else {
+            return Err(LoadError::Truncated);
+        };
+        let (body, checksum) = bytes.split_at(body_len);
+        let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+        let actual = fnv1a(body);
+        if expected != actual {
+            return Err(LoadError::ChecksumMismatch { expected, actual });
+        }
+        r.bytes = body;
+
+        let backend = r.u8()?;
+        let backend = Backend::from_u8(backend).ok_or(LoadError::UnknownBackend(backend))?;
+        let slots = r.u32()?;
+        if slots > 1 << 16 {
+            return Err(LoadError::Malformed(
+                "more variable slots than operands can address",
+            ));
+        }
+
+        let constant_count = r.u32()?;
+        let constants = (0..constant_count)
+            .map(|_| r.u32())
+            .collect::<Result<_, _>>()?;
+
+        let entry_count = r.u32()?;
+        let mut entry_points = vec![];
+        for _ in 0..entry_count {
+            let name_len = r.u16()?;
+            let name = std::str::from_utf8(r.take(name_len as usize)?)
+                .map_err(|_| LoadError::Malformed("entry point name is not valid UTF-8"))?
+                .to_owned();
+            let pc = r.u32()?;
+            entry_points.push(EntryPoint { name, pc });
+        }
+
+        let handler_count = r.u32()?;
+        let handlers: Vec<Handler> = (0..handler_count)
+            .map(|_| {
+                Ok(Handler {
+                    start: r.u32()?,
+                    end: r.u32()?,
+                    target: r.u32()?,
+                    depth: r.u32()?,
+                })
+            })
+            .collect::<Result<_, Truncated>>()?;
+
+        let bytecode_len = r.u32()?;
+        let bytecode = r.take(bytecode_len as usize)?.to_vec();
+
+        if r.pos != body.len() {
+            return Err(LoadError::Malformed("trailing data after bytecode"));
+        }
+        if entry_points.iter().any(|e| e.pc as usize >= bytecode.len()) {
+            return Err(LoadError::Malformed("entry point outside of bytecode"));
+        }
+        if handlers.iter().any(|h| {
+            h.start > h.end
+                || h.end as usize > bytecode.len()
+                || h.target as usize >= bytecode.len()
+        }) {
+            return Err(LoadError::Malformed(
+                "exception handler outside of bytecode",
+            ));
+        }
+
+        Ok(Self {
+            backend,
+            slots,
+            constants,
+            entry_points,
+            handlers,
+            bytecode,
+        })
+    }
+
+    pub fn save(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn load(mut reader: impl Read) -> Result<Self, LoadError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Reads little endian integers, for modules and [snapshots][crate::snapshot].
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub pos: usize,
+}
+
+/// The error of reading past the end of a [`Reader`].
+pub(crate) struct Truncated;
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], Truncated> {
+        let slice = self.bytes.get(self.pos..self.pos + n).ok_or(Truncated)?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Truncated> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Truncated> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Truncated> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Truncated> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownBackend(u8),
+    ChecksumMismatch { expected: u32, actual: u32 },
+    Truncated,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{error}"),
+            Self::BadMagic => write!(f, "not a module file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "module has format version {version}, but only version {VERSION} is supported"
+            ),
+            Self::UnknownBackend(backend) => write!(f, "unknown backend {backend}"),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch (expected {expected:08x}, got {actual:08x})"
+            ),
+            Self::Truncated => write!(f, "module is truncated"),
+            Self::Malformed(reason) => write!(f, "malformed module: {reason}"),
+        }
+    }
+}
+
+impl error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<Truncated> for LoadError {
+    fn from(_: Truncated) -> Self {
+        Self::Truncated
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
@@ -9,3 +9,146 @@ pub fn factorial(n: u32) -> u32 {
 pub fn run() -> u32 {
     factorial(10)
 }
+
+// Native implementations of the programs in `workloads`, which perform the same operations.
+
+pub fn fib(n: u32) -> u32 {
+    match n {
+        0 | 1 => n,
+        _ => fib(n - 1).wrapping_add(fib(n - 2)),
+    }
+}
+
+pub fn collatz(n: u32) -> u32 {
+    let mut total = 0;
+    for start in 1..=n {
+        let mut x = start;
+        while x >= 2 {
+            x = if x % 2 == 0 { x / 2 } else { 3 * x + 1 };
+            total += 1;
+        }
+    }
+    total
+}
+
+pub fn nested_loops(n: u32) -> u32 {
+    let mut sum = 0u32;
+    for i in 0..n {
+        for j in 0..n {
+            sum = sum.wrapping_add(i * j);
+        }
+    }
+    sum
+}
+
+pub fn mandelbrot(size: u32, iterations: u32) -> u32 {
+    let mut inside = 0;
+    for y in 0..size {
+        let ci = (y * 8192 / size) as i32 - 4096;
+        for x in 0..size {
+            let cr = (x * 12288 / size) as i32 - 8192;
+            let (mut zr, mut zi, mut rr, mut ii) = (0i32, 0i32, 0i32, 0i32);
+            let mut i = 0;
+            while rr + ii <= 16384 && i < iterations {
+                zi = ((2 * zr * zi) >> 12) + ci;
+                zr = rr - ii + cr;
+                rr = (zr * zr) >> 12;
+                ii = (zi * zi) >> 12;
+                i += 1;
+            }
+            if i == iterations {
+                inside += 1;
+            }
+        }
+    }
+    inside
+}
+
+pub fn mandelbrot_float(size: u32, iterations: u32) -> u32 {
+    let mut inside = 0;
+    for y in 0..size {
+        let ci = y as f64 * 2.0 / size as f64 - 1.0;
+        for x in 0..size {
+            let cr = x as f64 * 3.0 / size as f64 - 2.0;
+            let (mut zr, mut zi, mut rr, mut ii) = (0.0, 0.0, 0.0, 0.0);
+            let mut i = 0;
+            while rr + ii <= 4.0 && i < iterations {
+                zi = 2.0 * zr * zi + ci;
+                zr = rr - ii + cr;
+                rr = zr * zr;
+                ii = zi * zi;
+                i += 1;
+            }
+            if i == iterations {
+                inside += 1;
+            }
+        }
+    }
+    inside
+}
+
+pub fn sieve(n: u32) -> u32 {
+    let mut composite = vec![false; n as usize + 1];
+    let mut i = 2;
+    while i * i <= n {
+        if !composite[i as usize] {
+            let mut j = i * i;
+            while j <= n {
+                composite[j as usize] = true;
+                j += i;
+            }
+        }
+        i += 1;
+    }
+    (2..=n).filter(|&k| !composite[k as usize]).count() as u32
+}
+
+pub fn bubble_sort(n: u32, seed: u32) -> u32 {
+    let mut x = seed;
+    let mut data: Vec<u16> = (0..n)
+        .map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u16
+        })
+        .collect();
+    for end in (2..=data.len()).rev() {
+        for j in 0..end - 1 {
+            if data[j] > data[j + 1] {
+                data.swap(j, j + 1);
+            }
+        }
+    }
+    data.iter()
+        .zip(1..)
+        .fold(0u32, |sum, (&d, i)| sum.wrapping_add(d as u32 * i))
+}
+
+pub fn linked_list(rounds: u32, n: u32) -> u32 {
+    struct Node {
+        value: u32,
+        next: Option<Box<Node>>,
+    }
+
+    let mut total = 0u32;
+    for r in 0..rounds {
+        let mut list = None;
+        for i in 0..n {
+            list = Some(Box::new(Node {
+                value: i.wrapping_add(r),
+                next: list,
+            }));
+        }
+        let mut node = &list;
+        while let Some(n) = node {
+            total = total.wrapping_add(n.value);
+            node = &n.next;
+        }
+    }
+    total
+}
+
+pub fn generator(n: u32) -> u32 {
+    let numbers = 1..=n;
+    let squares = numbers.map(|x| x.wrapping_mul(x));
+    squares.fold(0u32, |total, x| total.wrapping_add(x))
+}
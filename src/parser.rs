@@ -0,0 +1,519 @@
+//! Parser for a small s-expression language that maps directly onto [`Instruction`]s, so that
+//! programs other than the benchmark can be fed to the interpreters.
+//!
+//! ```text
+//! ; Comments run until the end of the line.
+//! (args n)        ; Names the arguments, which are passed in the first variables.
+//! (let i 1)
+//! (let x 1)
+//! (while (<= i n)
+//!   (let x (* x i))
+//!   (let i (+ i 1)))
+//! x               ; The value of the last form is the result of the program.
+//! ```
+//!
+//! Variables are allocated in order of first appearance. `(do forms...)` groups forms into a
+//! sequence. The operators are `<=`, `+`, `-`, `*`, `/` and `%`, all on unsigned 32-bit integers.
+//! Numbers with a decimal point or an exponent, such as `0.5` or `1e9`, are floats, which have
+//! the operators `f<`, `f+`, `f-`, `f*` and `f/`. `+` adds two floats as well. `(float x)`
+//! converts the integer `x` to a float, and `(int x)` a float to an integer.
+//!
+//! `(load8 a)`, `(load16 a)` and `(load32 a)` read an unsigned integer from linear memory at the
+//! byte address `a`, and `(store8 a x)`, `(store16 a x)` and `(store32 a x)` write the low bytes
+//! of the integer `x` there, evaluating to `x`.
+//!
+//! Objects live on a garbage-collected heap. `(array n)` allocates an array of `n` elements,
+//! `(record 3)` a record of three fields, and a string literal such as `"hi\n"` a string, with
+//! the escapes `\"`, `\\` and `\n`. `(get o i)` reads element, field or byte `i` of the object
+//! `o`, `(set o i x)` writes `x` to element or field `i`, evaluating to `x`, and `(len o)` is the
+//! number of elements, fields or bytes in `o`.
+//!
+//! Programs parsed with [`parse_with`] can also call the functions of a [`Host`] by name, as in
+//! `(print x)`, with as many arguments as the function takes. `(yield x)` evaluates to `x`, but
+//! first stops a [metered][crate::fuel] run, handing `x` to the host.
+//!
+//! `(coroutine forms...)` creates a [coroutine][crate::coroutine] that runs the forms, and
+//! `(resume c x)` runs it until it yields or ends, passing `x` to the `yield` it stopped at.
+//! Inside a coroutine, `yield` hands its value to `resume` rather than to the host.
+//!
+//! `(try forms... (catch e handler...))` evaluates to the value of the forms, unless they throw
+//! an [exception][crate::exception], in which case the thrown value is assigned to `e` and it
+//! evaluates to the handler instead. `(throw x)` throws `x`.
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{host::Host, memory::Width, treewalk::Instruction};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub code: Instruction,
+    /// The names of variables, indexed by their slot.
+    pub variables: Vec<String>,
+    /// The number of arguments, which occupy the first variable slots.
+    pub arguments: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+impl Position {
+    fn error(self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+}
+
+enum Expr<'s> {
+    Atom(&'s str, Position),
+    String(String, Position),
+    List(Vec<Expr<'s>>, Position),
+}
+
+impl Expr<'_> {
+    fn position(&self) -> Position {
+        match self {
+            Expr::Atom(_, position) | Expr::String(_, position) | Expr::List(_, position) => {
+                *position
+            }
+        }
+    }
+}
+
+struct Reader<'s> {
+    source: &'s str,
+    pos: usize,
+    line: usize,
+    line_start: usize,
+}
+
+impl<'s> Reader<'s> {
+    fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.pos - self.line_start + 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn advance(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+            if c == '\n' {
+                self.line += 1;
+                self.line_start = self.pos;
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ';' {
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.advance();
+                }
+            } else if c.is_whitespace() {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Reads the next expression, or returns `None` at the end of a list or the input.
+    fn read(&mut self) -> Result<Option<Expr<'s>>, ParseError> {
+        self.skip_whitespace();
+        let position = self.position();
+        match self.peek() {
+            None | Some(')') => Ok(None),
+            Some('(') => {
+                self.advance();
+                let mut list = vec![];
+                while let Some(expr) = self.read()? {
+                    list.push(expr);
+                }
+                if self.peek() != Some(')') {
+                    return Err(position.error("unclosed parenthesis"));
+                }
+                self.advance();
+                Ok(Some(Expr::List(list, position)))
+            }
+            Some('"') => {
+                self.advance();
+                let mut string = String::new();
+                loop {
+                    let escape = self.position();
+                    match self.peek() {
+                        None => return Err(position.error("unclosed string")),
+                        Some('"') => break,
+                        Some('\\') => {
+                            self.advance();
+                            string.push(match self.peek() {
+                                Some('"') => '"',
+                                Some('\\') => '\\',
+                                Some('n') => '\n',
+                                _ => return Err(escape.error("invalid escape sequence")),
+                            });
+                        }
+                        Some(c) => string.push(c),
+                    }
+                    self.advance();
+                }
+                self.advance();
+                Ok(Some(Expr::String(string, position)))
+            }
+            Some(_) => {
+                let start = self.pos;
+                while !matches!(self.peek(), None | Some('(' | ')' | ';'))
+                    && !self.peek().unwrap().is_whitespace()
+                {
+                    self.advance();
+                }
+                Ok(Some(Expr::Atom(&self.source[start..self.pos], position)))
+            }
+        }
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "args",
+    "do",
+    "let",
+    "while",
+    "<=",
+    "+",
+    "*",
+    "-",
+    "/",
+    "%",
+    "f<",
+    "f+",
+    "f*",
+    "f-",
+    "f/",
+    "float",
+    "int",
+    "load8",
+    "load16",
+    "load32",
+    "store8",
+    "store16",
+    "store32",
+    "array",
+    "record",
+    "get",
+    "set",
+    "len",
+    "yield",
+    "coroutine",
+    "resume",
+    "try",
+    "catch",
+    "throw",
+];
+
+/// The width of a load or store, from the number of bits at the end of its name.
+fn width(bits: &str) -> Width {
+    match bits {
+        "8" => Width::U8,
+        "16" => Width::U16,
+        _ => Width::U32,
+    }
+}
+
+struct Lowerer<'h> {
+    slots: HashMap<String, u16>,
+    variables: Vec<String>,
+    host: &'h Host,
+}
+
+impl Lowerer<'_> {
+    fn variable(&mut self, name: &str, position: Position) -> Result<u16, ParseError> {
+        if let Some(&slot) = self.slots.get(name) {
+            return Ok(slot);
+        }
+        if KEYWORDS.contains(&name) {
+            return Err(position.error(format!("`{name}` cannot be used as a variable")));
+        }
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(position.error(format!("invalid number `{name}`")));
+        }
+        let slot = u16::try_from(self.variables.len())
+            .map_err(|_| position.error("too many variables"))?;
+        self.slots.insert(name.to_owned(), slot);
+        self.variables.push(name.to_owned());
+        Ok(slot)
+    }
+
+    fn sequence(&mut self, exprs: &[Expr]) -> Result<Instruction, ParseError> {
+        let mut instructions = exprs
+            .iter()
+            .map(|expr| self.lower(expr))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(if instructions.len() == 1 {
+            instructions.pop().unwrap()
+        } else {
+            Instruction::Sequence(instructions)
+        })
+    }
+
+    fn lower(&mut self, expr: &Expr) -> Result<Instruction, ParseError> {
+        match expr {
+            Expr::Atom(atom, position) => {
+                if let Ok(i) = atom.parse::<u32>() {
+                    Ok(Instruction::Int(i))
+                } else if let (true, Ok(f)) = (atom.contains(['.', 'e']), atom.parse::<f64>()) {
+                    Ok(Instruction::Float(f))
+                } else {
+                    Ok(Instruction::Var(self.variable(atom, *position)?))
+                }
+            }
+            Expr::String(string, _) => Ok(Instruction::NewString(string.as_bytes().into())),
+            Expr::List(list, position) => {
+                let Some(Expr::Atom(head, _)) = list.first() else {
+                    return Err(position.error("expected a form name"));
+                };
+                let args = &list[1..];
+                let arity = |n: usize| {
+                    if args.len() == n {
+                        Ok(())
+                    } else {
+                        Err(position.error(format!("`{head}` takes {n} operands")))
+                    }
+                };
+                match *head {
+                    "let" => {
+                        arity(2)?;
+                        let Expr::Atom(name, name_position) = &args[0] else {
+                            return Err(args[0].position().error("expected a variable name"));
+                        };
+                        let variable = self.variable(name, *name_position)?;
+                        Ok(Instruction::Let {
+                            variable,
+                            value: Box::new(self.lower(&args[1])?),
+                        })
+                    }
+                    "while" => {
+                        let Some((condition, body)) = args.split_first() else {
+                            return Err(position.error("`while` needs a condition"));
+                        };
+                        Ok(Instruction::While {
+                            condition: Box::new(self.lower(condition)?),
+                            body: Box::new(self.sequence(body)?),
+                        })
+                    }
+                    "do" => Ok(Instruction::Sequence(
+                        args.iter()
+                            .map(|expr| self.lower(expr))
+                            .collect::<Result<_, _>>()?,
+                    )),
+                    "<=" | "+" | "*" | "-" | "/" | "%" => {
+                        arity(2)?;
+                        let a = Box::new(self.lower(&args[0])?);
+                        let b = Box::new(self.lower(&args[1])?);
+                        Ok(match *head {
+                            "<=" => Instruction::LessEq(a, b),
+                            "+" => Instruction::Add(a, b),
+                            "*" => Instruction::Multiply(a, b),
+                            "-" => Instruction::Subtract(a, b),
+                            "/" => Instruction::Divide(a, b),
+                            _ => Instruction::Remainder(a, b),
+                        })
+                    }
+                    "f<" | "f+" | "f*" | "f-" | "f/" => {
+                        arity(2)?;
+                        let a = Box::new(self.lower(&args[0])?);
+                        let b = Box::new(self.lower(&args[1])?);
+                        Ok(match *head {
+                            "f<" => Instruction::FLess(a, b),
+                            "f+" => Instruction::FAdd(a, b),
+                            "f*" => Instruction::FMultiply(a, b),
+                            "f-" => Instruction::FSubtract(a, b),
+                            _ => Instruction::FDivide(a, b),
+                        })
+                    }
+                    "float" | "int" => {
+                        arity(1)?;
+                        let a = Box::new(self.lower(&args[0])?);
+                        Ok(match *head {
+                            "float" => Instruction::IntToFloat(a),
+                            _ => Instruction::FloatToInt(a),
+                        })
+                    }
+                    "load8" | "load16" | "load32" => {
+                        arity(1)?;
+                        Ok(Instruction::Index {
+                            width: width(&head[4..]),
+                            address: Box::new(self.lower(&args[0])?),
+                        })
+                    }
+                    "store8" | "store16" | "store32" => {
+                        arity(2)?;
+                        Ok(Instruction::StoreIndex {
+                            width: width(&head[5..]),
+                            address: Box::new(self.lower(&args[0])?),
+                            value: Box::new(self.lower(&args[1])?),
+                        })
+                    }
+                    "array" => {
+                        arity(1)?;
+                        Ok(Instruction::NewArray(Box::new(self.lower(&args[0])?)))
+                    }
+                    "record" => {
+                        arity(1)?;
+                        match &args[0] {
+                            Expr::Atom(fields, _) if fields.parse::<u16>().is_ok() => {
+                                Ok(Instruction::NewRecord(fields.parse().unwrap()))
+                            }
+                            arg => Err(arg.position().error("expected a number of fields")),
+                        }
+                    }
+                    "get" => {
+                        arity(2)?;
+                        Ok(Instruction::Get(
+                            Box::new(self.lower(&args[0])?),
+                            Box::new(self.lower(&args[1])?),
+                        ))
+                    }
+                    "set" => {
+                        arity(3)?;
+                        Ok(Instruction::Set {
+                            object: Box::new(self.lower(&args[0])?),
+                            index: Box::new(self.lower(&args[1])?),
+                            value: Box::new(self.lower(&args[2])?),
+                        })
+                    }
+                    "len" => {
+                        arity(1)?;
+                        Ok(Instruction::Len(Box::new(self.lower(&args[0])?)))
+                    }
+                    "yield" => {
+                        arity(1)?;
+                        Ok(Instruction::Yield(Box::new(self.lower(&args[0])?)))
+                    }
+                    "coroutine" => Ok(Instruction::Coroutine(Box::new(self.sequence(args)?))),
+                    "resume" => {
+                        arity(2)?;
+                        Ok(Instruction::Resume(
+                            Box::new(self.lower(&args[0])?),
+                            Box::new(self.lower(&args[1])?),
+                        ))
+                    }
+                    "try" => {
+                        let (catch, body) = match args.split_last() {
+                            Some((Expr::List(catch, _), body))
+                                if matches!(catch.first(), Some(Expr::Atom("catch", _))) =>
+                            {
+                                (catch, body)
+                            }
+                            _ => return Err(position.error("`try` must end with a `catch`")),
+                        };
+                        let Some(Expr::Atom(name, name_position)) = catch.get(1) else {
+                            return Err(position.error("`catch` needs a variable name"));
+                        };
+                        let variable = self.variable(name, *name_position)?;
+                        Ok(Instruction::Try {
+                            body: Box::new(self.sequence(body)?),
+                            variable,
+                            handler: Box::new(self.sequence(&catch[2..])?),
+                        })
+                    }
+                    "catch" => Err(position.error("`catch` must end a `try`")),
+                    "throw" => {
+                        arity(1)?;
+                        Ok(Instruction::Throw(Box::new(self.lower(&args[0])?)))
+                    }
+                    "args" => Err(position.error("`args` must be the first form")),
+                    _ => {
+                        let Some((function, n)) = self.host.find(head) else {
+                            return Err(position.error(format!("unknown form `{head}`")));
+                        };
+                        if args.len() != n as usize {
+                            return Err(position.error(format!("`{head}` takes {n} arguments")));
+                        }
+                        Ok(Instruction::CallNative {
+                            function,
+                            arguments: args
+                                .iter()
+                                .map(|expr| self.lower(expr))
+                                .collect::<Result<_, _>>()?,
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Program, ParseError> {
+    parse_with(source, &Host::new())
+}
+
+/// Parses a program that may call the functions of `host`.
+pub fn parse_with(source: &str, host: &Host) -> Result<Program, ParseError> {
+    let mut reader = Reader {
+        source,
+        pos: 0,
+        line: 1,
+        line_start: 0,
+    };
+    let mut exprs = vec![];
+    while let Some(expr) = reader.read()? {
+        exprs.push(expr);
+    }
+    if reader.peek() == Some(')') {
+        return Err(reader.position().error("unexpected `)`"));
+    }
+
+    let mut lowerer = Lowerer {
+        slots: HashMap::new(),
+        variables: vec![],
+        host,
+    };
+    let mut body = &exprs[..];
+    if let Some(Expr::List(list, _)) = exprs.first() {
+        if let Some(Expr::Atom("args", _)) = list.first() {
+            for arg in &list[1..] {
+                let Expr::Atom(name, position) = arg else {
+                    return Err(arg.position().error("expected an argument name"));
+                };
+                if lowerer.slots.contains_key(*name) {
+                    return Err(position.error(format!("duplicate argument `{name}`")));
+                }
+                lowerer.variable(name, *position)?;
+            }
+            body = &exprs[1..];
+        }
+    }
+    let arguments = lowerer.variables.len();
+    let code = lowerer.sequence(body)?;
+
+    Ok(Program {
+        code,
+        variables: lowerer.variables,
+        arguments,
+    })
+}
@@ -0,0 +1,403 @@
+//! Per-opcode execution profiler for the bytecode backends.
+//!
+//! Every bytecode backend's `Frame` carries a [`Profiler`] and notifies it when an
+//! instruction is entered and left, and whenever it touches bytecode, variables, or the stack.
+//! Without the `profile` feature the profiler is a zero-sized
+//! type whose hooks compile to nothing, so the benchmarks measure the same code as before.
+//! With the feature enabled, each backend gains a `profile` function returning a [`Profile`].
+
+use std::fmt;
+
+#[cfg(feature = "profile")]
+pub use enabled::Profiler;
+
+#[cfg(not(feature = "profile"))]
+pub use disabled::Profiler;
+
+#[cfg(not(feature = "profile"))]
+mod disabled {
+    #[derive(Default)]
+    pub struct Profiler;
+
+    impl Profiler {
+        pub fn new() -> Self {
+            Profiler
+        }
+
+        #[inline(always)]
+        pub fn enter(&mut self, _pc: u32, _opcode: u8) {}
+
+        #[inline(always)]
+        pub fn leave(&mut self) {}
+
+        #[inline(always)]
+        pub fn read_bytecode(&self, _bytes: u64) {}
+
+        #[inline(always)]
+        pub fn read_variable(&self) {}
+
+        #[inline(always)]
+        pub fn write_variable(&self) {}
+
+        #[inline(always)]
+        pub fn read_stack(&self) {}
+
+        #[inline(always)]
+        pub fn write_stack(&self) {}
+    }
+}
+
+#[cfg(feature = "profile")]
+mod enabled {
+    use std::cell::Cell;
+
+    use super::{Counts, OpcodeProfile, PcProfile, Profile};
+
+    struct Active {
+        pc: u32,
+        opcode: u8,
+        start: u64,
+        /// Cycles spent in nested instructions, which the compact treewalk backends execute
+        /// from within their parent. These are subtracted so that each opcode is only charged
+        /// for its own work.
+        children: u64,
+    }
+
+    pub struct Profiler {
+        opcode_counts: [u64; 256],
+        opcode_cycles: [u64; 256],
+        pc_counts: Vec<u64>,
+        pc_cycles: Vec<u64>,
+        active: Vec<Active>,
+        overhead: u64,
+        // Cells, because reading variables and peeking at bytecode only borrow the frame
+        // immutably.
+        bytecode_bytes: Cell<u64>,
+        variable_reads: Cell<u64>,
+        variable_writes: Cell<u64>,
+        stack_reads: Cell<u64>,
+        stack_writes: Cell<u64>,
+        trace: Option<Box<dyn FnMut(u32, usize)>>,
+    }
+
+    impl Default for Profiler {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Profiler {
+        pub fn new() -> Self {
+            Self {
+                opcode_counts: [0; 256],
+                opcode_cycles: [0; 256],
+                pc_counts: vec![],
+                pc_cycles: vec![],
+                active: vec![],
+                overhead: calibrate(),
+                bytecode_bytes: Cell::new(0),
+                variable_reads: Cell::new(0),
+                variable_writes: Cell::new(0),
+                stack_reads: Cell::new(0),
+                stack_writes: Cell::new(0),
+                trace: None,
+            }
+        }
+
+        /// Creates a profiler that additionally calls `trace` with the pc and nesting depth
+        /// of every instruction before it's executed.
+        pub fn with_trace(trace: impl FnMut(u32, usize) + 'static) -> Self {
+            Self {
+                trace: Some(Box::new(trace)),
+                ..Self::new()
+            }
+        }
+
+        #[inline(always)]
+        pub fn enter(&mut self, pc: u32, opcode: u8) {
+            if let Some(trace) = &mut self.trace {
+                trace(pc, self.active.len());
+            }
+            self.active.push(Active {
+                pc,
+                opcode,
+                start: now(),
+                children: 0,
+            });
+        }
+
+        #[inline(always)]
+        pub fn leave(&mut self) {
+            let end = now();
+            let active = self.active.pop().expect("leave() without matching enter()");
+            let elapsed = end.saturating_sub(active.start);
+            if let Some(parent) = self.active.last_mut() {
+                parent.children += elapsed;
+            }
+            let cycles = elapsed
+                .saturating_sub(active.children)
+                .saturating_sub(self.overhead);
+
+            let pc = active.pc as usize;
+            if pc >= self.pc_counts.len() {
+                self.pc_counts.resize(pc + 1, 0);
+                self.pc_cycles.resize(pc + 1, 0);
+            }
+            self.pc_counts[pc] += 1;
+            self.pc_cycles[pc] += cycles;
+            self.opcode_counts[active.opcode as usize] += 1;
+            self.opcode_cycles[active.opcode as usize] += cycles;
+        }
+
+        #[inline(always)]
+        pub fn read_bytecode(&self, bytes: u64) {
+            self.bytecode_bytes.set(self.bytecode_bytes.get() + bytes);
+        }
+
+        #[inline(always)]
+        pub fn read_variable(&self) {
+            self.variable_reads.set(self.variable_reads.get() + 1);
+        }
+
+        #[inline(always)]
+        pub fn write_variable(&self) {
+            self.variable_writes.set(self.variable_writes.get() + 1);
+        }
+
+        #[inline(always)]
+        pub fn read_stack(&self) {
+            self.stack_reads.set(self.stack_reads.get() + 1);
+        }
+
+        #[inline(always)]
+        pub fn write_stack(&self) {
+            self.stack_writes.set(self.stack_writes.get() + 1);
+        }
+
+        /// Turns the collected samples into a report. `opcode_name` maps raw opcode bytes back
+        /// to the backend's `Opcode` names.
+        pub fn finish(self, backend: &'static str, opcode_name: impl Fn(u8) -> String) -> Profile {
+            let opcodes = (0..=u8::MAX)
+                .filter(|&op| self.opcode_counts[op as usize] != 0)
+                .map(|op| OpcodeProfile {
+                    name: opcode_name(op),
+                    count: self.opcode_counts[op as usize],
+                    cycles: self.opcode_cycles[op as usize],
+                })
+                .collect();
+            let pcs = self
+                .pc_counts
+                .iter()
+                .zip(&self.pc_cycles)
+                .enumerate()
+                .filter(|(_, (&count, _))| count != 0)
+                .map(|(pc, (&count, &cycles))| PcProfile {
+                    pc: pc as u32,
+                    count,
+                    cycles,
+                })
+                .collect();
+            let counts = Counts {
+                dispatches: self.opcode_counts.iter().sum(),
+                bytecode_bytes: self.bytecode_bytes.get(),
+                variable_reads: self.variable_reads.get(),
+                variable_writes: self.variable_writes.get(),
+                stack_reads: self.stack_reads.get(),
+                stack_writes: self.stack_writes.get(),
+            };
+            Profile {
+                backend,
+                opcodes,
+                pcs,
+                counts,
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    fn now() -> u64 {
+        unsafe { std::arch::x86_64::_rdtsc() }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline(always)]
+    fn now() -> u64 {
+        use std::{sync::OnceLock, time::Instant};
+
+        static EPOCH: OnceLock<Instant> = OnceLock::new();
+        EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+
+    /// Measures the cost of taking a timestamp, so that it's not attributed to the instructions.
+    fn calibrate() -> u64 {
+        (0..1000)
+            .map(|_| {
+                let start = now();
+                now().saturating_sub(start)
+            })
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Execution statistics of a single opcode.
+#[derive(Debug, Clone)]
+pub struct OpcodeProfile {
+    pub name: String,
+    pub count: u64,
+    pub cycles: u64,
+}
+
+/// Execution statistics of the instruction at a single program counter.
+#[derive(Debug, Clone)]
+pub struct PcProfile {
+    pub pc: u32,
+    pub count: u64,
+    pub cycles: u64,
+}
+
+/// How much work a program performed, independently of how long it took.
+///
+/// In the stack backends, variables live on the stack, but accesses to them are still counted
+/// as variable reads and writes rather than pushes and pops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub dispatches: u64,
+    pub bytecode_bytes: u64,
+    pub variable_reads: u64,
+    pub variable_writes: u64,
+    pub stack_reads: u64,
+    pub stack_writes: u64,
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dispatches: {}, bytecode bytes read: {}, variables: {} reads/{} writes, \
+             stack: {} reads/{} writes",
+            self.dispatches,
+            self.bytecode_bytes,
+            self.variable_reads,
+            self.variable_writes,
+            self.stack_reads,
+            self.stack_writes
+        )
+    }
+}
+
+/// The result of profiling a single run of a program.
+///
+/// Cycles are measured with `rdtsc` on x86_64 and are nanoseconds on other architectures.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub backend: &'static str,
+    pub opcodes: Vec<OpcodeProfile>,
+    pub pcs: Vec<PcProfile>,
+    pub counts: Counts,
+}
+
+impl Profile {
+    /// The number of instructions dispatched.
+    pub fn dispatches(&self) -> u64 {
+        self.counts.dispatches
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.opcodes.iter().map(|o| o.cycles).sum()
+    }
+
+    pub fn cycles_per_dispatch(&self) -> f64 {
+        self.cycles() as f64 / self.dispatches().max(1) as f64
+    }
+
+    /// Breaks down the slowdown of `self` relative to `baseline` into instruction count and
+    /// cost per instruction.
+    pub fn compare<'a>(&'a self, baseline: &'a Profile) -> Comparison<'a> {
+        Comparison {
+            profile: self,
+            baseline,
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dispatches = self.dispatches();
+        let cycles = self.cycles();
+        writeln!(
+            f,
+            "{}: {} dispatches, {} cycles, {:.1} cycles/dispatch",
+            self.backend,
+            dispatches,
+            cycles,
+            self.cycles_per_dispatch()
+        )?;
+        writeln!(f, "  {}", self.counts)?;
+        writeln!(
+            f,
+            "  {:<12} {:>8} {:>7} {:>10} {:>7} {:>8}",
+            "opcode", "count", "count%", "cycles", "cycles%", "avg"
+        )?;
+        for opcode in &self.opcodes {
+            writeln!(
+                f,
+                "  {:<12} {:>8} {:>6.1}% {:>10} {:>6.1}% {:>8.1}",
+                opcode.name,
+                opcode.count,
+                percent(opcode.count, dispatches),
+                opcode.cycles,
+                percent(opcode.cycles, cycles),
+                opcode.cycles as f64 / opcode.count as f64,
+            )?;
+        }
+        writeln!(f, "  {:<12} {:>8} {:>10}", "pc", "count", "cycles")?;
+        for pc in &self.pcs {
+            writeln!(f, "  {:<12} {:>8} {:>10}", pc.pc, pc.count, pc.cycles)?;
+        }
+        Ok(())
+    }
+}
+
+/// See [`Profile::compare`].
+pub struct Comparison<'a> {
+    profile: &'a Profile,
+    baseline: &'a Profile,
+}
+
+impl Comparison<'_> {
+    /// How many times more instructions `profile` dispatches than `baseline`.
+    pub fn dispatch_ratio(&self) -> f64 {
+        self.profile.dispatches() as f64 / self.baseline.dispatches().max(1) as f64
+    }
+
+    /// How many times more expensive a single instruction of `profile` is than one of
+    /// `baseline`.
+    pub fn cost_ratio(&self) -> f64 {
+        self.profile.cycles_per_dispatch() / self.baseline.cycles_per_dispatch().max(f64::EPSILON)
+    }
+
+    /// The overall slowdown, equal to the product of the two ratios above.
+    pub fn slowdown(&self) -> f64 {
+        self.profile.cycles() as f64 / self.baseline.cycles().max(1) as f64
+    }
+}
+
+impl fmt::Display for Comparison<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} vs {}: {:.2}x = {:.2}x instructions * {:.2}x cycles/instruction",
+            self.profile.backend,
+            self.baseline.backend,
+            self.slowdown(),
+            self.dispatch_ratio(),
+            self.cost_ratio()
+        )
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    part as f64 / whole.max(1) as f64 * 100.0
+}
@@ -1,26 +1,104 @@
+use crate::{
+    bytecode::{with_slots, Chunk},
+    coroutine::{self, Registers},
+    exception::{self, Handler, DIVISION_BY_ZERO},
+    fuel::{self, Fuel, Meter, State, Stop, Suspended, VmError},
+    heap::{self, Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    module,
+    profile::Profiler,
+    register_switch::starts,
+    value::Value,
+};
+
+/// `register (dtable)` shares its encoding with `register (switch)`, so it reuses its tooling.
+pub use crate::register_switch::{checksum, compile, disassemble, verify};
+
+// Must be kept in sync with `register_switch::Opcode`, whose compiler emits the opcodes that
+// `code` doesn't use.
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 enum Opcode {
     Int,
+    SmallInt,
+    Const8,
+    Const16,
+    Float,
+    Move,
 
     LessEq,
     Add,
+    AddInt,
+    AddFloat,
     Multiply,
+    Subtract,
+    Divide,
+    Remainder,
+
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+    IntToFloat,
+    FloatToInt,
+
+    Load8,
+    Load16,
+    Load32,
+    Store8,
+    Store16,
+    Store32,
 
+    NewArray,
+    NewRecord,
+    NewString,
+    Get,
+    Set,
+    Len,
+
+    CallNative,
+    Yield,
+    NewCoroutine,
+    Resume,
+    Throw,
+
+    JumpIfNot8,
+    Jump8,
     JumpIfNot,
     Jump,
+    Return,
+    Wide,
     Halt,
 }
 
 struct Frame<'c> {
-    variables: [u32; 256],
+    variables: &'c mut [Value],
+    memory: &'c mut [u8],
+    heap: &'c mut Heap,
+    host: &'c Host,
+    meter: &'c Meter<'c>,
+    /// Where the meter stopped the run, and why.
+    stopped: Option<(Stop, u32)>,
     bytecode: &'c [u8],
-    pc: u16,
+    /// The code with a `Halt` over every breakpoint, which a run resumed at a breakpoint
+    /// switches to once it has run the instruction under it.
+    armed: Option<&'c [u8]>,
+    constants: &'c [u32],
+    handlers: &'c [Handler],
+    pc: u32,
+    result: Value,
+    /// The coroutines that are running, innermost last, which hold the frames that resumed them.
+    running: Vec<Value>,
+    profiler: Profiler,
 }
 
 impl<'c> Frame<'c> {
     fn read_u8(&mut self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         let x = *unsafe { self.bytecode.get_unchecked(self.pc as usize) };
         self.pc += 1;
         x
@@ -28,13 +106,15 @@ impl<'c> Frame<'c> {
 
     fn peek_u8(&self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         *unsafe { self.bytecode.get_unchecked(self.pc as usize) }
     }
 
     fn read_u16(&mut self) -> u16 {
         debug_assert!((self.pc as usize + 1) < self.bytecode.len());
+        self.profiler.read_bytecode(2);
         let x = unsafe {
-            *std::mem::transmute::<_, &u16>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u16>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 2;
         u16::from_le(x)
@@ -42,47 +122,315 @@ impl<'c> Frame<'c> {
 
     fn read_u32(&mut self) -> u32 {
         debug_assert!((self.pc as usize + 3) < self.bytecode.len());
+        self.profiler.read_bytecode(4);
         let x = unsafe {
-            *std::mem::transmute::<_, &u32>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u32>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 4;
         u32::from_le(x)
     }
 
-    fn var(&self, i: u8) -> u32 {
+    fn read_u64(&mut self) -> u64 {
+        debug_assert!((self.pc as usize + 7) < self.bytecode.len());
+        self.profiler.read_bytecode(8);
+        let x = unsafe {
+            *std::mem::transmute::<&u8, &u64>(self.bytecode.get_unchecked(self.pc as usize))
+        };
+        self.pc += 8;
+        u64::from_le(x)
+    }
+
+    fn constant(&self, i: u16) -> u32 {
+        debug_assert!((i as usize) < self.constants.len());
+        self.profiler.read_bytecode(4);
+        *unsafe { self.constants.get_unchecked(i as usize) }
+    }
+
+    fn var(&self, i: u16) -> Value {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.read_variable();
         unsafe { *self.variables.get_unchecked(i as usize) }
     }
 
-    fn set_var(&mut self, i: u8, val: u32) {
+    fn set_var(&mut self, i: u16, val: Value) {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.write_variable();
         unsafe {
             *self.variables.get_unchecked_mut(i as usize) = val;
         }
     }
+
+    fn load(&mut self, source: u16, target: u16, width: Width) {
+        let address = self.var(source).as_int();
+        self.set_var(
+            target,
+            Value::int(memory::load(self.memory, address, width)),
+        );
+    }
+
+    fn store(&mut self, ra: u16, rb: u16, width: Width) {
+        let address = self.var(ra).as_int();
+        let value = self.var(rb).as_int();
+        memory::store(self.memory, address, width, value);
+    }
+
+    /// Allocates an object into `target`. All registers and the running coroutines are the
+    /// garbage collector's roots.
+    fn alloc(&mut self, target: u16, object: Object) {
+        let object = self.heap.alloc(object, &[self.variables, &self.running]);
+        self.set_var(target, object);
+    }
+
+    fn new_array(&mut self, source: u16, target: u16) {
+        let len = self.var(source).as_int();
+        self.alloc(target, Object::Array(vec![Value::int(0); len as usize]));
+    }
+
+    fn new_record(&mut self, target: u16, fields: u16) {
+        self.alloc(
+            target,
+            Object::Record(vec![Value::int(0); fields as usize].into()),
+        );
+    }
+
+    fn new_string(&mut self, target: u16, i: u16) {
+        let string = heap::read_string_constant(self.constants, i as usize)
+            .expect("verified string constant");
+        self.alloc(target, Object::String(string));
+    }
+
+    fn get(&mut self, ra: u16, rb: u16, target: u16) {
+        let object = self.var(ra);
+        let index = self.var(rb).as_int();
+        self.set_var(target, self.heap.get(object, index));
+    }
+
+    fn set(&mut self, ra: u16, rb: u16, rc: u16) {
+        let object = self.var(ra);
+        let index = self.var(rb).as_int();
+        let value = self.var(rc);
+        self.heap.set(object, index, value);
+    }
+
+    fn len(&mut self, source: u16, target: u16) {
+        let object = self.var(source);
+        self.set_var(target, Value::int(self.heap.len(object)));
+    }
+
+    /// Calls a host function. All registers, including the arguments, are the garbage
+    /// collector's roots.
+    fn call_native(&mut self, first: u16, target: u16, argc: u8, function: u16) {
+        for _ in 0..argc {
+            self.profiler.read_variable();
+        }
+        let args = &self.variables[first as usize..][..argc as usize];
+        let result = self.host.call(
+            function,
+            &mut Call::new(args, self.heap, &[self.variables, &self.running]),
+        );
+        self.set_var(target, result);
+    }
+
+    /// Puts a new coroutine whose body starts at the next instruction, with a copy of the
+    /// registers, into `target`, and jumps past the body.
+    fn new_coroutine(&mut self, offset: i32, target: u16) {
+        let registers = self.registers(0);
+        let roots = [&*self.variables, &self.running];
+        let coroutine = coroutine::new(self.heap, self.variables, registers, &roots);
+        self.set_var(target, coroutine);
+        self.pc = self.pc.wrapping_add_signed(offset);
+    }
+
+    fn resume(&mut self, rc: u16, rv: u16, target: u16) {
+        let coroutine = self.var(rc);
+        let value = self.var(rv);
+        let mut registers = self.registers(target);
+        let yielded = coroutine::resume(self.heap, coroutine, self.variables, &mut registers);
+        self.running.push(coroutine);
+        self.pc = registers.pc;
+        if yielded {
+            self.set_var(registers.target, value);
+        }
+    }
+
+    /// Hands `source` back to whoever resumed the running coroutine, at a `Yield` whose target
+    /// is `target` or, if `returned`, at the end of its body.
+    fn suspend(&mut self, source: u16, target: u16, returned: bool) {
+        let value = self.var(source);
+        let target = self.switch_back(target, returned);
+        self.set_var(target, value);
+    }
+
+    /// Switches from the running coroutine back to whoever resumed it, and returns the register
+    /// the resumer expects its value in.
+    fn switch_back(&mut self, target: u16, returned: bool) -> u16 {
+        let coroutine = self.running.pop().expect("a coroutine is running");
+        let mut registers = self.registers(target);
+        coroutine::suspend(
+            self.heap,
+            coroutine,
+            self.variables,
+            &mut registers,
+            returned,
+        );
+        self.pc = registers.pc;
+        registers.target
+    }
+
+    /// Throws a value from the instruction just executed to its handler, which receives it in
+    /// the handler's register. A coroutine without a handler for it dies, and the `Resume`
+    /// running it throws the value again. Nothing else catching it stops the run.
+    #[cold]
+    #[inline(never)]
+    fn throw(&mut self, value: Value) {
+        loop {
+            if let Some(handler) = exception::find(self.handlers, self.pc - 1) {
+                self.pc = handler.target;
+                self.set_var(handler.depth as u16, value);
+                return;
+            }
+            if self.running.is_empty() {
+                self.stop(Stop::Uncaught(value));
+                return;
+            }
+            self.switch_back(0, true);
+        }
+    }
+
+    /// Puts the quotient or remainder of a division in `target`, or throws if the divisor was
+    /// zero.
+    fn divide(&mut self, result: Option<u32>, target: u16) {
+        match result {
+            Some(result) => self.set_var(target, Value::int(result)),
+            None => self.throw(DIVISION_BY_ZERO),
+        }
+    }
+
+    fn registers(&self, target: u16) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.variables.len() as u32,
+            target,
+        }
+    }
 }
 
-static DISPATCH_TABLE: [fn(&mut Frame); 6] = [
+static HALT: [u8; 1] = [Opcode::Halt as u8];
+
+static DISPATCH_TABLE: [fn(&mut Frame); 44] = [
     exec_int,
+    exec_small_int,
+    exec_const8,
+    exec_const16,
+    exec_float,
+    exec_move,
     exec_less_eq,
     exec_add,
+    // Code isn't quickened here, but it may have been by `register (switch)`.
+    exec_add,
+    exec_add,
     exec_multiply,
+    exec_subtract,
+    exec_divide,
+    exec_remainder,
+    exec_f_less,
+    exec_f_add,
+    exec_f_multiply,
+    exec_f_subtract,
+    exec_f_divide,
+    exec_int_to_float,
+    exec_float_to_int,
+    exec_load8,
+    exec_load16,
+    exec_load32,
+    exec_store8,
+    exec_store16,
+    exec_store32,
+    exec_new_array,
+    exec_new_record,
+    exec_new_string,
+    exec_get,
+    exec_set,
+    exec_len,
+    exec_call_native,
+    exec_yield,
+    exec_new_coroutine,
+    exec_resume,
+    exec_throw,
+    exec_jump_if_not8,
+    exec_jump8,
     exec_jump_if_not,
     exec_jump,
+    exec_return,
+    exec_wide,
 ];
 
 impl<'c> Frame<'c> {
     fn step(&mut self) {
         // print!("{:4} | ", self.pc);
+        let pc = self.pc;
         let opcode = self.read_u8();
-        // println!("{:?}", unsafe { std::mem::transmute::<_, Opcode>(opcode) });
+        // println!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) });
+        self.profiler.enter(pc, opcode);
         unsafe { DISPATCH_TABLE.get_unchecked(opcode as usize)(self) };
+        self.profiler.leave();
     }
 
-    fn eval(&mut self) {
+    /// Runs until `Halt`, or until the meter stops it at a backward jump or a breakpoint.
+    fn eval(&mut self) -> Result<(), Stop> {
+        // Resumed at a breakpoint, the run carries on with the instruction under it in the code
+        // without breakpoints, and switches to the code with them straight after.
+        if let Some(armed) = self.armed.take() {
+            if self.peek_u8() == Opcode::Halt as u8 {
+                return Ok(());
+            }
+            self.step();
+            if self.stopped.is_none() {
+                self.bytecode = armed;
+            }
+        }
         while self.peek_u8() != Opcode::Halt as u8 {
             self.step();
         }
+        if self.stopped.is_none() && self.meter.is_breakpoint(self.pc) {
+            self.stopped = Some((Stop::Breakpoint, self.pc));
+        }
+        match self.stopped.take() {
+            Some((stop, pc)) => {
+                self.pc = pc;
+                Err(stop)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Jumps by `offset` from the end of the instruction. Jumping backward costs fuel, and when
+    /// the meter stops the run, the frame switches to code that halts straight away, so that
+    /// the dispatch loop needn't check for it.
+    #[inline(always)]
+    fn jump(&mut self, offset: i32) {
+        self.pc = self.pc.wrapping_add_signed(offset);
+        if offset < 0 {
+            if let Err(stop) = self.meter.tick() {
+                self.stop(stop);
+            }
+        }
+    }
+
+    /// Stops a metered run at a `Yield`, like a jump without fuel.
+    #[inline(always)]
+    fn yield_value(&mut self, value: Value) {
+        if let Err(stop) = self.meter.yield_value(value) {
+            self.stop(stop);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn stop(&mut self, stop: Stop) {
+        self.stopped = Some((stop, self.pc));
+        self.bytecode = &HALT;
+        self.pc = 0;
     }
 
     fn dump(&self) {
@@ -91,54 +439,522 @@ impl<'c> Frame<'c> {
 }
 
 fn exec_int(frame: &mut Frame) {
-    let target = frame.read_u8();
+    let target = frame.read_u8() as u16;
     let i = frame.read_u32();
-    frame.set_var(target, i);
+    frame.set_var(target, Value::int(i));
     frame.dump();
 }
 
+fn exec_small_int(frame: &mut Frame) {
+    let target = frame.read_u8() as u16;
+    let i = frame.read_u8();
+    frame.set_var(target, Value::int(i as u32));
+}
+
+fn exec_const8(frame: &mut Frame) {
+    let target = frame.read_u8() as u16;
+    let i = frame.read_u8();
+    let k = frame.constant(i as u16);
+    frame.set_var(target, Value::int(k));
+}
+
+fn exec_const16(frame: &mut Frame) {
+    let target = frame.read_u8() as u16;
+    let i = frame.read_u16();
+    let k = frame.constant(i);
+    frame.set_var(target, Value::int(k));
+}
+
+fn exec_float(frame: &mut Frame) {
+    let target = frame.read_u8() as u16;
+    let f = f64::from_bits(frame.read_u64());
+    frame.set_var(target, Value::float(f));
+}
+
+fn exec_move(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    let val = frame.var(source);
+    frame.set_var(target, val);
+}
+
 fn exec_less_eq(frame: &mut Frame) {
-    let ra = frame.read_u8();
-    let rb = frame.read_u8();
-    let a = frame.var(ra);
-    let b = frame.var(rb);
-    let target = frame.read_u8();
-    frame.set_var(target, (a <= b) as u32);
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_int();
+    let b = frame.var(rb).as_int();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::int((a <= b) as u32));
     frame.dump();
 }
 
 fn exec_add(frame: &mut Frame) {
-    let ra = frame.read_u8();
-    let rb = frame.read_u8();
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
     let a = frame.var(ra);
     let b = frame.var(rb);
-    let target = frame.read_u8();
+    let target = frame.read_u8() as u16;
     frame.set_var(target, a + b);
     frame.dump();
 }
 
 fn exec_multiply(frame: &mut Frame) {
-    let ra = frame.read_u8();
-    let rb = frame.read_u8();
-    let a = frame.var(ra);
-    let b = frame.var(rb);
-    let target = frame.read_u8();
-    frame.set_var(target, a * b);
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_int();
+    let b = frame.var(rb).as_int();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::int(a.wrapping_mul(b)));
+    frame.dump();
+}
+
+fn exec_subtract(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_int();
+    let b = frame.var(rb).as_int();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::int(a.wrapping_sub(b)));
+    frame.dump();
+}
+
+fn exec_divide(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_int();
+    let b = frame.var(rb).as_int();
+    let target = frame.read_u8() as u16;
+    frame.divide(a.checked_div(b), target);
+    frame.dump();
+}
+
+fn exec_remainder(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_int();
+    let b = frame.var(rb).as_int();
+    let target = frame.read_u8() as u16;
+    frame.divide(a.checked_rem(b), target);
+    frame.dump();
+}
+
+fn exec_f_less(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_float();
+    let b = frame.var(rb).as_float();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::int((a < b) as u32));
+    frame.dump();
+}
+
+fn exec_f_add(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_float();
+    let b = frame.var(rb).as_float();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::float(a + b));
+    frame.dump();
+}
+
+fn exec_f_multiply(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_float();
+    let b = frame.var(rb).as_float();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::float(a * b));
+    frame.dump();
+}
+
+fn exec_f_subtract(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_float();
+    let b = frame.var(rb).as_float();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::float(a - b));
+    frame.dump();
+}
+
+fn exec_f_divide(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let a = frame.var(ra).as_float();
+    let b = frame.var(rb).as_float();
+    let target = frame.read_u8() as u16;
+    frame.set_var(target, Value::float(a / b));
     frame.dump();
 }
 
+fn exec_int_to_float(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    let a = frame.var(source).as_int();
+    frame.set_var(target, Value::float(a as f64));
+}
+
+fn exec_float_to_int(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    let a = frame.var(source).as_float();
+    frame.set_var(target, Value::int(a as u32));
+}
+
+fn exec_load8(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    frame.load(source, target, Width::U8);
+}
+
+fn exec_load16(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    frame.load(source, target, Width::U16);
+}
+
+fn exec_load32(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    frame.load(source, target, Width::U32);
+}
+
+fn exec_store8(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    frame.store(ra, rb, Width::U8);
+}
+
+fn exec_store16(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    frame.store(ra, rb, Width::U16);
+}
+
+fn exec_store32(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    frame.store(ra, rb, Width::U32);
+}
+
+fn exec_new_array(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    frame.new_array(source, target);
+}
+
+fn exec_new_record(frame: &mut Frame) {
+    let target = frame.read_u8() as u16;
+    let fields = frame.read_u16();
+    frame.new_record(target, fields);
+}
+
+fn exec_new_string(frame: &mut Frame) {
+    let target = frame.read_u8() as u16;
+    let i = frame.read_u16();
+    frame.new_string(target, i);
+}
+
+fn exec_get(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    frame.get(ra, rb, target);
+}
+
+fn exec_set(frame: &mut Frame) {
+    let ra = frame.read_u8() as u16;
+    let rb = frame.read_u8() as u16;
+    let rc = frame.read_u8() as u16;
+    frame.set(ra, rb, rc);
+}
+
+fn exec_len(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    frame.len(source, target);
+}
+
+fn exec_call_native(frame: &mut Frame) {
+    let first = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    let argc = frame.read_u8();
+    let function = frame.read_u16();
+    frame.call_native(first, target, argc, function);
+}
+
+fn exec_yield(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    if !frame.running.is_empty() {
+        return frame.suspend(source, target, false);
+    }
+    let val = frame.var(source);
+    frame.set_var(target, val);
+    frame.yield_value(val);
+}
+
+fn exec_new_coroutine(frame: &mut Frame) {
+    let offset = frame.read_u16() as i16;
+    let target = frame.read_u8() as u16;
+    frame.new_coroutine(offset as i32, target);
+}
+
+fn exec_resume(frame: &mut Frame) {
+    let rc = frame.read_u8() as u16;
+    let rv = frame.read_u8() as u16;
+    let target = frame.read_u8() as u16;
+    frame.resume(rc, rv, target);
+}
+
+fn exec_throw(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    frame.throw(frame.var(source));
+}
+
+fn exec_jump_if_not8(frame: &mut Frame) {
+    let offset = frame.read_u8() as i8;
+    let source = frame.read_u8() as u16;
+    let condition = frame.var(source);
+    if !condition.is_truthy() {
+        frame.jump(offset as i32);
+    }
+}
+
+fn exec_jump8(frame: &mut Frame) {
+    let offset = frame.read_u8() as i8;
+    frame.jump(offset as i32);
+}
+
 fn exec_jump_if_not(frame: &mut Frame) {
-    let offset = frame.read_u16();
-    let source = frame.read_u8();
+    let offset = frame.read_u16() as i16;
+    let source = frame.read_u8() as u16;
     let condition = frame.var(source);
-    if condition == 0 {
-        frame.pc = offset;
+    if !condition.is_truthy() {
+        frame.jump(offset as i32);
     }
 }
 
 fn exec_jump(frame: &mut Frame) {
-    let offset = frame.read_u16();
-    frame.pc = offset;
+    let offset = frame.read_u16() as i16;
+    frame.jump(offset as i32);
+}
+
+fn exec_return(frame: &mut Frame) {
+    let source = frame.read_u8() as u16;
+    if frame.running.is_empty() {
+        frame.result = frame.var(source);
+    } else {
+        frame.suspend(source, 0, true);
+    }
+}
+
+#[cold]
+fn exec_wide(frame: &mut Frame) {
+    let opcode = unsafe { std::mem::transmute::<u8, Opcode>(frame.read_u8()) };
+    match opcode {
+        Opcode::Int => {
+            let target = frame.read_u16();
+            let i = frame.read_u32();
+            frame.set_var(target, Value::int(i));
+        }
+        Opcode::SmallInt => {
+            let target = frame.read_u16();
+            let i = frame.read_u8();
+            frame.set_var(target, Value::int(i as u32));
+        }
+        Opcode::Const8 => {
+            let target = frame.read_u16();
+            let i = frame.read_u8();
+            let k = frame.constant(i as u16);
+            frame.set_var(target, Value::int(k));
+        }
+        Opcode::Const16 => {
+            let target = frame.read_u16();
+            let i = frame.read_u16();
+            let k = frame.constant(i);
+            frame.set_var(target, Value::int(k));
+        }
+        Opcode::Float => {
+            let target = frame.read_u16();
+            let f = f64::from_bits(frame.read_u64());
+            frame.set_var(target, Value::float(f));
+        }
+        Opcode::Move => {
+            let source = frame.read_u16();
+            let target = frame.read_u16();
+            let val = frame.var(source);
+            frame.set_var(target, val);
+        }
+        Opcode::Add | Opcode::AddInt | Opcode::AddFloat => {
+            let ra = frame.read_u16();
+            let rb = frame.read_u16();
+            let a = frame.var(ra);
+            let b = frame.var(rb);
+            let target = frame.read_u16();
+            frame.set_var(target, a + b);
+        }
+        Opcode::LessEq | Opcode::Multiply | Opcode::Subtract => {
+            let ra = frame.read_u16();
+            let rb = frame.read_u16();
+            let a = frame.var(ra).as_int();
+            let b = frame.var(rb).as_int();
+            let target = frame.read_u16();
+            let val = match opcode {
+                Opcode::LessEq => (a <= b) as u32,
+                Opcode::Multiply => a.wrapping_mul(b),
+                _ => a.wrapping_sub(b),
+            };
+            frame.set_var(target, Value::int(val));
+        }
+        Opcode::Divide | Opcode::Remainder => {
+            let ra = frame.read_u16();
+            let rb = frame.read_u16();
+            let a = frame.var(ra).as_int();
+            let b = frame.var(rb).as_int();
+            let target = frame.read_u16();
+            let val = match opcode {
+                Opcode::Divide => a.checked_div(b),
+                _ => a.checked_rem(b),
+            };
+            frame.divide(val, target);
+        }
+        Opcode::FLess | Opcode::FAdd | Opcode::FMultiply | Opcode::FSubtract | Opcode::FDivide => {
+            let ra = frame.read_u16();
+            let rb = frame.read_u16();
+            let a = frame.var(ra).as_float();
+            let b = frame.var(rb).as_float();
+            let target = frame.read_u16();
+            let val = match opcode {
+                Opcode::FLess => Value::int((a < b) as u32),
+                Opcode::FAdd => Value::float(a + b),
+                Opcode::FMultiply => Value::float(a * b),
+                Opcode::FSubtract => Value::float(a - b),
+                _ => Value::float(a / b),
+            };
+            frame.set_var(target, val);
+        }
+        Opcode::IntToFloat => {
+            let source = frame.read_u16();
+            let target = frame.read_u16();
+            let a = frame.var(source).as_int();
+            frame.set_var(target, Value::float(a as f64));
+        }
+        Opcode::FloatToInt => {
+            let source = frame.read_u16();
+            let target = frame.read_u16();
+            let a = frame.var(source).as_float();
+            frame.set_var(target, Value::int(a as u32));
+        }
+        Opcode::Load8 | Opcode::Load16 | Opcode::Load32 => {
+            let source = frame.read_u16();
+            let target = frame.read_u16();
+            let width = match opcode {
+                Opcode::Load8 => Width::U8,
+                Opcode::Load16 => Width::U16,
+                _ => Width::U32,
+            };
+            frame.load(source, target, width);
+        }
+        Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => {
+            let ra = frame.read_u16();
+            let rb = frame.read_u16();
+            let width = match opcode {
+                Opcode::Store8 => Width::U8,
+                Opcode::Store16 => Width::U16,
+                _ => Width::U32,
+            };
+            frame.store(ra, rb, width);
+        }
+        Opcode::NewArray => {
+            let source = frame.read_u16();
+            let target = frame.read_u16();
+            frame.new_array(source, target);
+        }
+        Opcode::NewRecord => {
+            let target = frame.read_u16();
+            let fields = frame.read_u16();
+            frame.new_record(target, fields);
+        }
+        Opcode::NewString => {
+            let target = frame.read_u16();
+            let i = frame.read_u16();
+            frame.new_string(target, i);
+        }
+        Opcode::Get => {
+            let ra = frame.read_u16();
+            let rb = frame.read_u16();
+            let target = frame.read_u16();
+            frame.get(ra, rb, target);
+        }
+        Opcode::Set => {
+            let ra = frame.read_u16();
+            let rb = frame.read_u16();
+            let rc = frame.read_u16();
+            frame.set(ra, rb, rc);
+        }
+        Opcode::Len => {
+            let source = frame.read_u16();
+            let target = frame.read_u16();
+            frame.len(source, target);
+        }
+        Opcode::CallNative => {
+            let first = frame.read_u16();
+            let target = frame.read_u16();
+            let argc = frame.read_u8();
+            let function = frame.read_u16();
+            frame.call_native(first, target, argc, function);
+        }
+        Opcode::Yield => {
+            let source = frame.read_u16();
+            let target = frame.read_u16();
+            if !frame.running.is_empty() {
+                return frame.suspend(source, target, false);
+            }
+            let val = frame.var(source);
+            frame.set_var(target, val);
+            frame.yield_value(val);
+        }
+        Opcode::NewCoroutine => {
+            let offset = frame.read_u32() as i32;
+            let target = frame.read_u16();
+            frame.new_coroutine(offset, target);
+        }
+        Opcode::Resume => {
+            let rc = frame.read_u16();
+            let rv = frame.read_u16();
+            let target = frame.read_u16();
+            frame.resume(rc, rv, target);
+        }
+        Opcode::Throw => {
+            let source = frame.read_u16();
+            frame.throw(frame.var(source));
+        }
+        Opcode::JumpIfNot => {
+            let offset = frame.read_u32() as i32;
+            let source = frame.read_u16();
+            let condition = frame.var(source);
+            if !condition.is_truthy() {
+                frame.jump(offset);
+            }
+        }
+        Opcode::Jump => {
+            let offset = frame.read_u32() as i32;
+            frame.jump(offset);
+        }
+        Opcode::Return => {
+            let source = frame.read_u16();
+            if frame.running.is_empty() {
+                frame.result = frame.var(source);
+            } else {
+                frame.suspend(source, 0, true);
+            }
+        }
+        Opcode::JumpIfNot8 | Opcode::Jump8 | Opcode::Wide | Opcode::Halt => unsafe {
+            std::hint::unreachable_unchecked()
+        },
+    }
 }
 
 #[derive(Default)]
@@ -153,8 +969,8 @@ impl Writer {
         i
     }
 
-    fn pc(&self) -> u16 {
-        self.bytecode.len() as u16
+    fn pc(&self) -> u32 {
+        self.bytecode.len() as u32
     }
 
     fn write_u16(&mut self, x: u16) -> usize {
@@ -206,6 +1022,7 @@ pub fn code() -> Vec<u8> {
     w.write_opcode(Opcode::JumpIfNot);
     let loop_jump_hole = w.write_u16(0);
     w.write_u8(TEMP);
+    let loop_jump_base = w.pc();
 
     w.write_opcode(Opcode::Multiply);
     w.write_u8(VAR_X);
@@ -222,22 +1039,181 @@ pub fn code() -> Vec<u8> {
     w.write_u8(VAR_I);
 
     w.write_opcode(Opcode::Jump);
-    w.write_u16(loop_start);
+    w.write_u16((loop_start as i32 - (w.pc() as i32 + 2)) as u16);
 
     let loop_end = w.pc();
-    w.patch_u16(loop_jump_hole, loop_end);
+    w.patch_u16(loop_jump_hole, (loop_end - loop_jump_base) as u16);
     w.write_opcode(Opcode::Halt);
 
     w.bytecode
 }
 
 pub fn run(code: &[u8]) -> u32 {
+    let mut variables = [Value::int(0); 256];
     let mut frame = Frame {
-        variables: [0; 256],
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
         bytecode: code,
+        armed: None,
+        constants: &[],
+        handlers: &[],
         pc: 0,
+        result: Value::int(0),
+        running: vec![],
+        profiler: Profiler::new(),
     };
-    frame.variables[VAR_N as usize] = 10;
-    frame.eval();
-    frame.variables[VAR_X as usize]
+    frame.variables[VAR_N as usize] = Value::int(10);
+    frame.eval().expect("unmetered runs don't stop");
+    frame.variables[VAR_X as usize].as_int()
+}
+
+#[cfg(feature = "profile")]
+pub fn profile(code: &[u8]) -> crate::profile::Profile {
+    let mut variables = [Value::int(0); 256];
+    let mut frame = Frame {
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        bytecode: code,
+        armed: None,
+        constants: &[],
+        handlers: &[],
+        pc: 0,
+        result: Value::int(0),
+        running: vec![],
+        profiler: Profiler::new(),
+    };
+    frame.variables[VAR_N as usize] = Value::int(10);
+    frame.eval().expect("unmetered runs don't stop");
+    frame.profiler.finish("register (dtable)", |opcode| {
+        format!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) })
+    })
+}
+
+/// See [`register_switch::execute`][crate::register_switch::execute].
+pub fn execute(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Result<Value, VmError> {
+    execute_with(code, variables, memory, heap, host, Profiler::new()).0
+}
+
+pub fn execute_with(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    profiler: Profiler,
+) -> (Result<Value, VmError>, Profiler) {
+    with_slots(code.slots, variables, |variables| {
+        let mut frame = Frame {
+            variables,
+            memory,
+            heap,
+            host,
+            meter: &Meter::unlimited(),
+            stopped: None,
+            bytecode: &code.code,
+            armed: None,
+            constants: &code.constants,
+            handlers: &code.handlers,
+            pc: 0,
+            result: Value::int(0),
+            running: vec![],
+            profiler,
+        };
+        let result = fuel::unmetered(frame.eval()).map(|()| frame.result);
+        (result, frame.profiler)
+    })
+}
+
+/// See [`register_switch::execute_metered`][crate::register_switch::execute_metered].
+pub fn execute_metered(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    execute_from(code, None, variables, memory, heap, host, fuel)
+}
+
+/// See [`register_switch::resume`][crate::register_switch::resume].
+pub fn resume(
+    code: &Chunk,
+    suspended: Suspended,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    assert!(
+        (suspended.pc() as usize) < code.code.len(),
+        "resumed with different code"
+    );
+    execute_from(code, Some(suspended), variables, memory, heap, host, fuel)
+}
+
+fn execute_from(
+    code: &Chunk,
+    suspended: Option<Suspended>,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    fuel::run(
+        module::Backend::Register,
+        checksum(code),
+        code.slots,
+        variables,
+        fuel,
+        suspended,
+        |variables, meter, state| {
+            let armed = meter.armed(&code.code, Opcode::Halt as u8, starts);
+            let stepping = meter.stepping();
+            let mut frame = Frame {
+                variables,
+                memory,
+                heap,
+                host,
+                meter,
+                stopped: None,
+                bytecode: if stepping { &code.code } else { &armed },
+                armed: stepping.then_some(&armed),
+                constants: &code.constants,
+                handlers: &code.handlers,
+                pc: state.pc,
+                result: state.result,
+                running: state.running,
+                profiler: Profiler::new(),
+            };
+            match frame.eval() {
+                Ok(()) => Ok(frame.result),
+                Err(stop) => Err((
+                    stop,
+                    State {
+                        pc: frame.pc,
+                        result: frame.result,
+                        running: frame.running,
+                        ..state
+                    },
+                )),
+            }
+        },
+    )
 }
@@ -0,0 +1,1945 @@
+//! `register (fixed)`: the register machine with Lua's instruction encoding. Every instruction is
+//! a single 32-bit word, so decoding is a few shifts and masks instead of a series of byte
+//! reads, and integers that don't fit in an operand live in a table of constants.
+//!
+//! ```text
+//!  31       23 22       14 13      6 5      0
+//! +-----------+-----------+---------+--------+
+//! |     C     |     B     |    A    | opcode |
+//! +-----------+-----------+---------+--------+
+//! |          Bx / sBx     |    A    | opcode |
+//! +-----------------------+---------+--------+
+//! ```
+//!
+//! `A` is always a register. `B` and `C` are "RK" operands: a register, or a constant if their
+//! high bit is set. `Bx` indexes the constant table and `sBx` is a jump offset, relative to the
+//! next instruction, stored in excess-131071 like in Lua.
+//!
+//! A `Wide` word in front of an instruction supplies the high bits of its fields: the high
+//! bytes of its registers, or the top 18 bits of a 36-bit `Bx` or `sBx`.
+//!
+//! Constants are 32-bit words. A float constant takes up two consecutive words, low bits first,
+//! and can only be loaded into a register with `LoadF` rather than used as an RK operand. A
+//! string constant is its length followed by its bytes, and is only used by `NewString`.
+
+use crate::{
+    bytecode::{with_slots, CompileError, Disassembly, VerifyError},
+    coroutine::{self, Registers},
+    exception::{self, Handler, DIVISION_BY_ZERO},
+    fuel::{self, Fuel, Meter, State, Stop, Suspended, VmError},
+    heap::{self, Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    module::{self, LoadError, Module},
+    profile::Profiler,
+    treewalk::Instruction,
+    value::Value,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Opcode {
+    LoadK,
+    LoadF,
+    Move,
+
+    LessEq,
+    Add,
+    Multiply,
+    Subtract,
+    Divide,
+    Remainder,
+
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+    /// Convert between integers and floats, with a `B` register like `Move`.
+    IntToFloat,
+    FloatToInt,
+
+    /// Load the integer in linear memory at the address in RK `B` into `A`.
+    Load8,
+    Load16,
+    Load32,
+    /// Store the integer in `A` at the address in RK `B`.
+    Store8,
+    Store16,
+    Store32,
+
+    /// Allocates an array into `A`, with as many elements as RK `B`.
+    NewArray,
+    /// Allocate a record into `A` with `Bx` fields, or a string copied from constant `Bx`.
+    NewRecord,
+    NewString,
+    /// `A` := element, field or byte RK `C` of the object in RK `B`.
+    Get,
+    /// Element or field RK `B` of the object in `A` := RK `C`.
+    Set,
+    /// `A` := the length of the object in RK `B`.
+    Len,
+
+    /// `A` := host function `C` called with the `B` registers from `A` on. `B` and `C` are
+    /// plain numbers rather than RK operands, and `Wide` supplies the high byte of `C`.
+    CallNative,
+    /// `A` := RK `B`, and stops a metered run, yielding it. Inside a
+    /// [coroutine][crate::coroutine], it hands RK `B` to whoever resumed it instead, and `A`
+    /// receives the value it's resumed with.
+    Yield,
+    /// `A` := a new coroutine whose body starts at the next instruction, and jumps by `sBx`
+    /// past the body, without costing fuel.
+    NewCoroutine,
+    /// `A` := what the coroutine in RK `B` yields or returns when resumed with RK `C`.
+    Resume,
+    /// Throws RK `B` to the handler in the exception table.
+    Throw,
+
+    JumpIfNot,
+    Jump,
+    /// Ends the program with `A` as its result, or the running coroutine.
+    Return,
+    Wide,
+}
+
+const MAX_BX: u32 = (1 << 18) - 1;
+const MAX_SBX: i32 = (MAX_BX >> 1) as i32;
+/// The excess of an `sBx` widened to 36 bits.
+const MAX_WIDE_SBX: i64 = ((1 << 36) - 1) >> 1;
+/// Set in an RK operand that refers to a constant rather than a register.
+const CONSTANT: u32 = 1 << 8;
+
+/// The code a stopped frame switches to. Its `Return` leaves the result alone.
+static HALT: [u32; 1] = [Opcode::Return as u32];
+
+fn abc(opcode: Opcode, a: u8, b: u32, c: u32) -> u32 {
+    debug_assert!(b < 1 << 9 && c < 1 << 9);
+    opcode as u32 | (a as u32) << 6 | b << 14 | c << 23
+}
+
+fn abx(opcode: Opcode, a: u8, bx: u32) -> u32 {
+    debug_assert!(bx <= MAX_BX);
+    opcode as u32 | (a as u32) << 6 | bx << 14
+}
+
+fn asbx(opcode: Opcode, a: u8, sbx: i32) -> u32 {
+    abx(opcode, a, (sbx + MAX_SBX) as u32)
+}
+
+fn opcode_bits(insn: u32) -> u8 {
+    (insn & 0x3f) as u8
+}
+
+fn arg_a(insn: u32) -> u16 {
+    (insn >> 6) as u8 as u16
+}
+
+fn arg_b(insn: u32) -> u32 {
+    (insn >> 14) & 0x1ff
+}
+
+fn arg_c(insn: u32) -> u32 {
+    insn >> 23
+}
+
+fn arg_bx(insn: u32) -> u32 {
+    insn >> 14
+}
+
+fn arg_sbx(insn: u32) -> i32 {
+    arg_bx(insn) as i32 - MAX_SBX
+}
+
+/// The `A` register of `insn`, with the high byte taken from its `Wide` prefix.
+fn wide_a(prefix: u32, insn: u32) -> u16 {
+    arg_a(prefix) << 8 | arg_a(insn)
+}
+
+fn wide_bx(prefix: u32, insn: u32) -> u64 {
+    (arg_bx(prefix) as u64) << 18 | arg_bx(insn) as u64
+}
+
+fn wide_sbx(prefix: u32, insn: u32) -> i64 {
+    wide_bx(prefix, insn) as i64 - MAX_WIDE_SBX
+}
+
+/// An RK operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rk {
+    Register(u16),
+    Constant(u32),
+}
+
+impl Rk {
+    /// Decodes an RK field, with the high byte of a register taken from `hi`.
+    fn decode(hi: u32, operand: u32) -> Self {
+        if operand & CONSTANT == 0 {
+            Rk::Register((hi << 8 | operand) as u16)
+        } else {
+            Rk::Constant(operand & !CONSTANT)
+        }
+    }
+
+    /// Splits the operand into the bits for a `Wide` prefix and those for the instruction.
+    fn encode(self) -> (u32, u32) {
+        match self {
+            Rk::Register(r) => (r as u32 >> 8, r as u32 & 0xff),
+            Rk::Constant(k) => (0, CONSTANT | k),
+        }
+    }
+}
+
+/// A compiled program: its instructions, the constants they refer to and its exception table.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Program {
+    pub code: Vec<u32>,
+    pub constants: Vec<u32>,
+    pub handlers: Vec<Handler>,
+    /// The number of registers the code needs.
+    pub slots: usize,
+}
+
+impl Program {
+    pub fn to_module(&self) -> Module {
+        let bytecode = self
+            .code
+            .iter()
+            .flat_map(|insn| insn.to_le_bytes())
+            .collect();
+        Module {
+            constants: self.constants.clone(),
+            slots: self.slots as u32,
+            handlers: self.handlers.clone(),
+            ..Module::new(module::Backend::RegisterFixed, bytecode)
+        }
+    }
+
+    pub fn from_module(module: &Module) -> Result<Self, LoadError> {
+        if module.backend != module::Backend::RegisterFixed {
+            return Err(LoadError::Malformed(
+                "module does not contain register (fixed) code",
+            ));
+        }
+        if !module.bytecode.len().is_multiple_of(4) {
+            return Err(LoadError::Malformed(
+                "bytecode is not a whole number of instructions",
+            ));
+        }
+        Ok(Self {
+            code: module
+                .bytecode
+                .chunks_exact(4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                .collect(),
+            constants: module.constants.clone(),
+            handlers: module.handlers.clone(),
+            slots: module.slots as usize,
+        })
+    }
+}
+
+struct Frame<'c> {
+    variables: &'c mut [Value],
+    memory: &'c mut [u8],
+    heap: &'c mut Heap,
+    host: &'c Host,
+    meter: &'c Meter<'c>,
+    /// Where the meter stopped the run, and why.
+    stopped: Option<(Stop, u32)>,
+    code: &'c [u32],
+    /// The code with the `Return` of [`HALT`] over every breakpoint, which a run resumed at a
+    /// breakpoint switches to once it has run the instruction under it.
+    armed: Option<&'c [u32]>,
+    constants: &'c [u32],
+    handlers: &'c [Handler],
+    pc: u32,
+    result: Value,
+    /// The coroutines that are running, innermost last, which hold the frames that resumed them.
+    running: Vec<Value>,
+    profiler: Profiler,
+}
+
+impl<'c> Frame<'c> {
+    fn fetch(&mut self) -> u32 {
+        debug_assert!((self.pc as usize) < self.code.len());
+        self.profiler.read_bytecode(4);
+        let insn = *unsafe { self.code.get_unchecked(self.pc as usize) };
+        self.pc += 1;
+        insn
+    }
+
+    /// Jumps by `offset` from the next instruction. Jumping backward costs fuel, and when the
+    /// meter stops the run, the frame switches to code that returns straight away, so that the
+    /// dispatch loop needn't check for it.
+    #[inline(always)]
+    fn jump(&mut self, offset: i64) {
+        self.pc = (self.pc as i64 + offset) as u32;
+        if offset < 0 {
+            if let Err(stop) = self.meter.tick() {
+                self.stop(stop);
+            }
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn stop(&mut self, stop: Stop) {
+        self.stopped = Some((stop, self.pc));
+        self.code = &HALT;
+        self.pc = 0;
+    }
+
+    /// Stops a metered run at a `Yield`, like a jump without fuel.
+    #[inline(always)]
+    fn yield_value(&mut self, value: Value) {
+        if let Err(stop) = self.meter.yield_value(value) {
+            self.stop(stop);
+        }
+    }
+
+    fn constant(&self, i: u32) -> u32 {
+        debug_assert!((i as usize) < self.constants.len());
+        self.profiler.read_bytecode(4);
+        *unsafe { self.constants.get_unchecked(i as usize) }
+    }
+
+    fn float_constant(&self, i: u32) -> f64 {
+        let lo = self.constant(i);
+        let hi = self.constant(i + 1);
+        f64::from_bits((hi as u64) << 32 | lo as u64)
+    }
+
+    fn var(&self, i: u16) -> Value {
+        debug_assert!((i as usize) < self.variables.len());
+        self.profiler.read_variable();
+        unsafe { *self.variables.get_unchecked(i as usize) }
+    }
+
+    fn set_var(&mut self, i: u16, val: Value) {
+        debug_assert!((i as usize) < self.variables.len());
+        self.profiler.write_variable();
+        unsafe {
+            *self.variables.get_unchecked_mut(i as usize) = val;
+        }
+    }
+
+    fn rk(&self, operand: u32) -> Value {
+        if operand & CONSTANT == 0 {
+            self.var(operand as u16)
+        } else {
+            Value::int(self.constant(operand & !CONSTANT))
+        }
+    }
+
+    fn wide_rk(&self, hi: u32, operand: u32) -> Value {
+        match Rk::decode(hi, operand) {
+            Rk::Register(r) => self.var(r),
+            Rk::Constant(k) => Value::int(self.constant(k)),
+        }
+    }
+
+    fn load(&mut self, a: u16, address: Value, width: Width) {
+        let val = memory::load(self.memory, address.as_int(), width);
+        self.set_var(a, Value::int(val));
+    }
+
+    fn store(&mut self, a: u16, address: Value, width: Width) {
+        let val = self.var(a).as_int();
+        memory::store(self.memory, address.as_int(), width, val);
+    }
+
+    /// Allocates an object into `a`. All registers and the running coroutines are the garbage
+    /// collector's roots.
+    fn alloc(&mut self, a: u16, object: Object) {
+        let object = self.heap.alloc(object, &[self.variables, &self.running]);
+        self.set_var(a, object);
+    }
+
+    fn new_array(&mut self, a: u16, len: Value) {
+        let len = len.as_int() as usize;
+        self.alloc(a, Object::Array(vec![Value::int(0); len]));
+    }
+
+    fn new_record(&mut self, a: u16, fields: u64) {
+        self.alloc(
+            a,
+            Object::Record(vec![Value::int(0); fields as usize].into()),
+        );
+    }
+
+    fn new_string(&mut self, a: u16, k: u64) {
+        let string = heap::read_string_constant(self.constants, k as usize)
+            .expect("verified string constant");
+        self.alloc(a, Object::String(string));
+    }
+
+    fn get(&mut self, a: u16, object: Value, index: Value) {
+        let val = self.heap.get(object, index.as_int());
+        self.set_var(a, val);
+    }
+
+    fn set(&mut self, a: u16, index: Value, val: Value) {
+        let object = self.var(a);
+        self.heap.set(object, index.as_int(), val);
+    }
+
+    fn len(&mut self, a: u16, object: Value) {
+        let len = self.heap.len(object);
+        self.set_var(a, Value::int(len));
+    }
+
+    /// Calls a host function. All registers, including the arguments, are the garbage
+    /// collector's roots.
+    fn call_native(&mut self, a: u16, argc: u32, function: u32) {
+        for _ in 0..argc {
+            self.profiler.read_variable();
+        }
+        let args = &self.variables[a as usize..][..argc as usize];
+        let result = self.host.call(
+            function as u16,
+            &mut Call::new(args, self.heap, &[self.variables, &self.running]),
+        );
+        self.set_var(a, result);
+    }
+
+    /// `a` := a new coroutine whose body starts at the next instruction, with a copy of the
+    /// registers, and jumps past the body.
+    fn new_coroutine(&mut self, a: u16, offset: i64) {
+        let registers = self.registers(0);
+        let roots = [&*self.variables, &self.running];
+        let coroutine = coroutine::new(self.heap, self.variables, registers, &roots);
+        self.set_var(a, coroutine);
+        self.pc = (self.pc as i64 + offset) as u32;
+    }
+
+    #[inline(never)]
+    fn resume(&mut self, a: u16, coroutine: Value, value: Value) {
+        let mut registers = self.registers(a);
+        let yielded = coroutine::resume(self.heap, coroutine, self.variables, &mut registers);
+        self.running.push(coroutine);
+        self.pc = registers.pc;
+        if yielded {
+            self.set_var(registers.target, value);
+        }
+    }
+
+    /// Hands `value` back to whoever resumed the running coroutine, at a `Yield` into `a` or,
+    /// if `returned`, at the end of its body.
+    #[inline(never)]
+    fn suspend(&mut self, a: u16, value: Value, returned: bool) {
+        let target = self.switch_back(a, returned);
+        self.set_var(target, value);
+    }
+
+    /// Switches from the running coroutine back to whoever resumed it, and returns the register
+    /// the resumer expects its value in.
+    fn switch_back(&mut self, a: u16, returned: bool) -> u16 {
+        let coroutine = self.running.pop().expect("a coroutine is running");
+        let mut registers = self.registers(a);
+        coroutine::suspend(
+            self.heap,
+            coroutine,
+            self.variables,
+            &mut registers,
+            returned,
+        );
+        self.pc = registers.pc;
+        registers.target
+    }
+
+    /// Throws a value from the instruction just executed to its handler, which receives it in
+    /// the handler's register. A coroutine without a handler for it dies, and the `Resume`
+    /// running it throws the value again. Nothing else catching it stops the run.
+    #[cold]
+    #[inline(never)]
+    fn throw(&mut self, value: Value) {
+        loop {
+            if let Some(handler) = exception::find(self.handlers, self.pc - 1) {
+                self.pc = handler.target;
+                self.set_var(handler.depth as u16, value);
+                return;
+            }
+            if self.running.is_empty() {
+                self.stop(Stop::Uncaught(value));
+                return;
+            }
+            self.switch_back(0, true);
+        }
+    }
+
+    /// `a` := the quotient or remainder of a division, or throws if the divisor was zero.
+    fn divide(&mut self, a: u16, result: Option<u32>) {
+        match result {
+            Some(result) => self.set_var(a, Value::int(result)),
+            None => self.throw(DIVISION_BY_ZERO),
+        }
+    }
+
+    fn registers(&self, target: u16) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.variables.len() as u32,
+            target,
+        }
+    }
+}
+
+impl<'c> Frame<'c> {
+    /// Runs until a `Return` outside a coroutine, or until the meter stops it at a backward
+    /// jump or a breakpoint.
+    #[inline(never)]
+    fn eval(&mut self) -> Result<(), Stop> {
+        // Resumed at a breakpoint, the run carries on with the instruction under it in the code
+        // without breakpoints, and switches to the code with them straight after.
+        let mut running = true;
+        if let Some(armed) = self.armed {
+            running = self.step();
+            self.armed = None;
+            if self.stopped.is_none() {
+                self.code = armed;
+            }
+        }
+        if running {
+            while self.step() {}
+        }
+        match self.stopped.take() {
+            Some((stop, pc)) => {
+                self.pc = pc;
+                Err(stop)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs an instruction, and returns whether the run carries on.
+    #[inline(always)]
+    fn step(&mut self) -> bool {
+        let pc = self.pc;
+        let insn = self.fetch();
+        let opcode = unsafe { std::mem::transmute::<u8, Opcode>(opcode_bits(insn)) };
+        self.profiler.enter(pc, opcode as u8);
+        match opcode {
+            Opcode::LoadK => {
+                let k = self.constant(arg_bx(insn));
+                self.set_var(arg_a(insn), Value::int(k));
+            }
+            Opcode::LoadF => {
+                let f = self.float_constant(arg_bx(insn));
+                self.set_var(arg_a(insn), Value::float(f));
+            }
+            Opcode::Move => {
+                let val = self.var(arg_b(insn) as u16);
+                self.set_var(arg_a(insn), val);
+            }
+            Opcode::LessEq => {
+                let b = self.rk(arg_b(insn)).as_int();
+                let c = self.rk(arg_c(insn)).as_int();
+                self.set_var(arg_a(insn), Value::int((b <= c) as u32));
+            }
+            Opcode::Add => {
+                let b = self.rk(arg_b(insn));
+                let c = self.rk(arg_c(insn));
+                self.set_var(arg_a(insn), b + c);
+            }
+            Opcode::Multiply => {
+                let b = self.rk(arg_b(insn)).as_int();
+                let c = self.rk(arg_c(insn)).as_int();
+                self.set_var(arg_a(insn), Value::int(b.wrapping_mul(c)));
+            }
+            Opcode::Subtract => {
+                let b = self.rk(arg_b(insn)).as_int();
+                let c = self.rk(arg_c(insn)).as_int();
+                self.set_var(arg_a(insn), Value::int(b.wrapping_sub(c)));
+            }
+            Opcode::Divide => {
+                let b = self.rk(arg_b(insn)).as_int();
+                let c = self.rk(arg_c(insn)).as_int();
+                self.divide(arg_a(insn), b.checked_div(c));
+            }
+            Opcode::Remainder => {
+                let b = self.rk(arg_b(insn)).as_int();
+                let c = self.rk(arg_c(insn)).as_int();
+                self.divide(arg_a(insn), b.checked_rem(c));
+            }
+            Opcode::FLess => {
+                let b = self.rk(arg_b(insn)).as_float();
+                let c = self.rk(arg_c(insn)).as_float();
+                self.set_var(arg_a(insn), Value::int((b < c) as u32));
+            }
+            Opcode::FAdd => {
+                let b = self.rk(arg_b(insn)).as_float();
+                let c = self.rk(arg_c(insn)).as_float();
+                self.set_var(arg_a(insn), Value::float(b + c));
+            }
+            Opcode::FMultiply => {
+                let b = self.rk(arg_b(insn)).as_float();
+                let c = self.rk(arg_c(insn)).as_float();
+                self.set_var(arg_a(insn), Value::float(b * c));
+            }
+            Opcode::FSubtract => {
+                let b = self.rk(arg_b(insn)).as_float();
+                let c = self.rk(arg_c(insn)).as_float();
+                self.set_var(arg_a(insn), Value::float(b - c));
+            }
+            Opcode::FDivide => {
+                let b = self.rk(arg_b(insn)).as_float();
+                let c = self.rk(arg_c(insn)).as_float();
+                self.set_var(arg_a(insn), Value::float(b / c));
+            }
+            Opcode::IntToFloat => {
+                let b = self.var(arg_b(insn) as u16).as_int();
+                self.set_var(arg_a(insn), Value::float(b as f64));
+            }
+            Opcode::FloatToInt => {
+                let b = self.var(arg_b(insn) as u16).as_float();
+                self.set_var(arg_a(insn), Value::int(b as u32));
+            }
+            Opcode::Load8 | Opcode::Load16 | Opcode::Load32 => {
+                let b = self.rk(arg_b(insn));
+                self.load(arg_a(insn), b, opcode.width());
+            }
+            Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => {
+                let b = self.rk(arg_b(insn));
+                self.store(arg_a(insn), b, opcode.width());
+            }
+            Opcode::NewArray => {
+                let b = self.rk(arg_b(insn));
+                self.new_array(arg_a(insn), b);
+            }
+            Opcode::NewRecord => self.new_record(arg_a(insn), arg_bx(insn) as u64),
+            Opcode::NewString => self.new_string(arg_a(insn), arg_bx(insn) as u64),
+            Opcode::Get => {
+                let b = self.rk(arg_b(insn));
+                let c = self.rk(arg_c(insn));
+                self.get(arg_a(insn), b, c);
+            }
+            Opcode::Set => {
+                let b = self.rk(arg_b(insn));
+                let c = self.rk(arg_c(insn));
+                self.set(arg_a(insn), b, c);
+            }
+            Opcode::Len => {
+                let b = self.rk(arg_b(insn));
+                self.len(arg_a(insn), b);
+            }
+            Opcode::CallNative => self.call_native(arg_a(insn), arg_b(insn), arg_c(insn)),
+            Opcode::Yield => {
+                let val = self.rk(arg_b(insn));
+                if self.running.is_empty() {
+                    self.set_var(arg_a(insn), val);
+                    self.yield_value(val);
+                } else {
+                    self.suspend(arg_a(insn), val, false);
+                }
+            }
+            Opcode::NewCoroutine => self.new_coroutine(arg_a(insn), arg_sbx(insn) as i64),
+            Opcode::Resume => {
+                let b = self.rk(arg_b(insn));
+                let c = self.rk(arg_c(insn));
+                self.resume(arg_a(insn), b, c);
+            }
+            Opcode::Throw => {
+                let b = self.rk(arg_b(insn));
+                self.throw(b);
+            }
+            Opcode::JumpIfNot => {
+                if !self.var(arg_a(insn)).is_truthy() {
+                    self.jump(arg_sbx(insn) as i64);
+                }
+            }
+            Opcode::Jump => self.jump(arg_sbx(insn) as i64),
+            Opcode::Return
+                if self.stopped.is_none()
+                    && self.armed.is_none()
+                    && self.meter.is_breakpoint(pc) =>
+            {
+                self.stopped = Some((Stop::Breakpoint, pc));
+                self.profiler.leave();
+                return false;
+            }
+            Opcode::Return if self.stopped.is_none() && !self.running.is_empty() => {
+                let val = self.var(arg_a(insn));
+                self.suspend(0, val, true);
+            }
+            Opcode::Return => {
+                if self.stopped.is_none() {
+                    self.result = self.var(arg_a(insn));
+                }
+                self.profiler.leave();
+                return false;
+            }
+            Opcode::Wide => {
+                if self.wide(insn) {
+                    self.profiler.leave();
+                    return false;
+                }
+            }
+        }
+        self.profiler.leave();
+        true
+    }
+
+    /// Executes the instruction following the `Wide` prefix `prefix`, and returns whether it
+    /// was a `Return` that ends the program.
+    #[cold]
+    fn wide(&mut self, prefix: u32) -> bool {
+        let insn = self.fetch();
+        let opcode = unsafe { std::mem::transmute::<u8, Opcode>(opcode_bits(insn)) };
+        let a = wide_a(prefix, insn);
+        match opcode {
+            Opcode::LoadK => {
+                let k = self.constant(wide_bx(prefix, insn) as u32);
+                self.set_var(a, Value::int(k));
+            }
+            Opcode::LoadF => {
+                let f = self.float_constant(wide_bx(prefix, insn) as u32);
+                self.set_var(a, Value::float(f));
+            }
+            Opcode::Move => {
+                let val = self.wide_rk(arg_b(prefix), arg_b(insn));
+                self.set_var(a, val);
+            }
+            Opcode::IntToFloat => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn)).as_int();
+                self.set_var(a, Value::float(b as f64));
+            }
+            Opcode::FloatToInt => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn)).as_float();
+                self.set_var(a, Value::int(b as u32));
+            }
+            Opcode::Load8 | Opcode::Load16 | Opcode::Load32 => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                self.load(a, b, opcode.width());
+            }
+            Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                self.store(a, b, opcode.width());
+            }
+            Opcode::NewArray => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                self.new_array(a, b);
+            }
+            Opcode::NewRecord => self.new_record(a, wide_bx(prefix, insn)),
+            Opcode::NewString => self.new_string(a, wide_bx(prefix, insn)),
+            Opcode::Get => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                let c = self.wide_rk(arg_c(prefix), arg_c(insn));
+                self.get(a, b, c);
+            }
+            Opcode::Set => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                let c = self.wide_rk(arg_c(prefix), arg_c(insn));
+                self.set(a, b, c);
+            }
+            Opcode::Len => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                self.len(a, b);
+            }
+            Opcode::CallNative => {
+                self.call_native(a, arg_b(insn), arg_c(prefix) << 8 | arg_c(insn));
+            }
+            Opcode::Yield => {
+                let val = self.wide_rk(arg_b(prefix), arg_b(insn));
+                if self.running.is_empty() {
+                    self.set_var(a, val);
+                    self.yield_value(val);
+                } else {
+                    self.suspend(a, val, false);
+                }
+            }
+            Opcode::NewCoroutine => self.new_coroutine(a, wide_sbx(prefix, insn)),
+            Opcode::Resume => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                let c = self.wide_rk(arg_c(prefix), arg_c(insn));
+                self.resume(a, b, c);
+            }
+            Opcode::Throw => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                self.throw(b);
+            }
+            Opcode::Add => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn));
+                let c = self.wide_rk(arg_c(prefix), arg_c(insn));
+                self.set_var(a, b + c);
+            }
+            Opcode::LessEq | Opcode::Multiply | Opcode::Subtract => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn)).as_int();
+                let c = self.wide_rk(arg_c(prefix), arg_c(insn)).as_int();
+                let val = match opcode {
+                    Opcode::LessEq => (b <= c) as u32,
+                    Opcode::Multiply => b.wrapping_mul(c),
+                    _ => b.wrapping_sub(c),
+                };
+                self.set_var(a, Value::int(val));
+            }
+            Opcode::Divide | Opcode::Remainder => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn)).as_int();
+                let c = self.wide_rk(arg_c(prefix), arg_c(insn)).as_int();
+                let val = match opcode {
+                    Opcode::Divide => b.checked_div(c),
+                    _ => b.checked_rem(c),
+                };
+                self.divide(a, val);
+            }
+            Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide => {
+                let b = self.wide_rk(arg_b(prefix), arg_b(insn)).as_float();
+                let c = self.wide_rk(arg_c(prefix), arg_c(insn)).as_float();
+                let val = match opcode {
+                    Opcode::FLess => Value::int((b < c) as u32),
+                    Opcode::FAdd => Value::float(b + c),
+                    Opcode::FMultiply => Value::float(b * c),
+                    Opcode::FSubtract => Value::float(b - c),
+                    _ => Value::float(b / c),
+                };
+                self.set_var(a, val);
+            }
+            Opcode::JumpIfNot => {
+                if !self.var(a).is_truthy() {
+                    self.jump(wide_sbx(prefix, insn));
+                }
+            }
+            Opcode::Jump => self.jump(wide_sbx(prefix, insn)),
+            Opcode::Return if !self.running.is_empty() => {
+                let val = self.var(a);
+                self.suspend(0, val, true);
+            }
+            Opcode::Return => {
+                self.result = self.var(a);
+                return true;
+            }
+            Opcode::Wide => unsafe { std::hint::unreachable_unchecked() },
+        }
+        false
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    program: Program,
+}
+
+impl Writer {
+    fn pc(&self) -> u32 {
+        self.program.code.len() as u32
+    }
+
+    fn write(&mut self, insn: u32) -> usize {
+        let i = self.program.code.len();
+        self.program.code.push(insn);
+        i
+    }
+
+    /// Patches the jump at `at`, which may start with a `Wide` prefix, to land on `target`.
+    /// Returns `false` and leaves the jump alone if a narrow jump cannot reach the target.
+    fn patch_jump(&mut self, at: usize, target: u32) -> bool {
+        let code = &mut self.program.code;
+        if opcode_bits(code[at]) == Opcode::Wide as u8 {
+            let (prefix, insn) = (code[at], code[at + 1]);
+            let opcode = unsafe { std::mem::transmute::<u8, Opcode>(opcode_bits(insn)) };
+            let bx = (target as i64 - (at as i64 + 2) + MAX_WIDE_SBX) as u64;
+            code[at] = abx(Opcode::Wide, arg_a(prefix) as u8, (bx >> 18) as u32);
+            code[at + 1] = abx(opcode, arg_a(insn) as u8, bx as u32 & MAX_BX);
+            return true;
+        }
+        let insn = code[at];
+        let opcode = unsafe { std::mem::transmute::<u8, Opcode>(opcode_bits(insn)) };
+        let offset = target as i64 - (at as i64 + 1);
+        if offset.abs() > MAX_SBX as i64 {
+            return false;
+        }
+        code[at] = asbx(opcode, arg_a(insn) as u8, offset as i32);
+        true
+    }
+
+    /// Returns the index of `x` in the constant table, adding it if it isn't there yet.
+    fn constant(&mut self, x: u32) -> u32 {
+        let constants = &mut self.program.constants;
+        match constants.iter().position(|&k| k == x) {
+            Some(i) => i as u32,
+            None => {
+                constants.push(x);
+                constants.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Returns the index of the two words of `x` in the constant table, adding them if they
+    /// aren't there yet. The words may also be parts of other constants.
+    fn float_constant(&mut self, x: f64) -> u32 {
+        let bits = x.to_bits();
+        let words = [bits as u32, (bits >> 32) as u32];
+        let constants = &mut self.program.constants;
+        match constants.windows(2).position(|pair| pair == words) {
+            Some(i) => i as u32,
+            None => {
+                constants.extend(words);
+                constants.len() as u32 - 2
+            }
+        }
+    }
+}
+
+const VAR_N: u8 = 0;
+const VAR_I: u8 = 1;
+const VAR_X: u8 = 2;
+
+const TEMP: u8 = 3;
+
+pub fn code() -> Program {
+    let mut w = Writer::default();
+    let one = w.constant(1);
+
+    w.write(abx(Opcode::LoadK, VAR_I, one));
+    w.write(abx(Opcode::LoadK, VAR_X, one));
+
+    let loop_start = w.pc();
+    w.write(abc(Opcode::LessEq, TEMP, VAR_I as u32, VAR_N as u32));
+    let loop_jump_hole = w.write(asbx(Opcode::JumpIfNot, TEMP, 0));
+    w.write(abc(Opcode::Multiply, VAR_X, VAR_X as u32, VAR_I as u32));
+    w.write(abc(Opcode::Add, VAR_I, VAR_I as u32, CONSTANT | one));
+    let jump = w.write(asbx(Opcode::Jump, 0, 0));
+    w.patch_jump(jump, loop_start);
+
+    let loop_end = w.pc();
+    w.patch_jump(loop_jump_hole, loop_end);
+    w.write(abc(Opcode::Return, VAR_X, 0, 0));
+
+    w.program.slots = 4;
+    w.program
+}
+
+pub fn run(program: &Program) -> u32 {
+    let mut variables = [Value::int(0); 256];
+    let mut frame = Frame {
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        code: &program.code,
+        armed: None,
+        constants: &program.constants,
+        handlers: &program.handlers,
+        pc: 0,
+        result: Value::int(0),
+        running: vec![],
+        profiler: Profiler::new(),
+    };
+    frame.variables[VAR_N as usize] = Value::int(10);
+    frame.eval().expect("unmetered runs don't stop");
+    frame.variables[VAR_X as usize].as_int()
+}
+
+#[cfg(feature = "profile")]
+pub fn profile(program: &Program) -> crate::profile::Profile {
+    let mut variables = [Value::int(0); 256];
+    let mut frame = Frame {
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        code: &program.code,
+        armed: None,
+        constants: &program.constants,
+        handlers: &program.handlers,
+        pc: 0,
+        result: Value::int(0),
+        running: vec![],
+        profiler: Profiler::new(),
+    };
+    frame.variables[VAR_N as usize] = Value::int(10);
+    frame.eval().expect("unmetered runs don't stop");
+    frame.profiler.finish("register (fixed)", |opcode| {
+        format!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) })
+    })
+}
+
+/// Runs a program produced by [`compile`] with the registers initialized from `variables` and
+/// the given linear memory, heap and host functions, and returns its result. Afterwards
+/// `variables` holds the final contents of the registers; those not used by the program's
+/// variables may have been overwritten with temporaries. Registers beyond the end of `variables`
+/// start out as zero. An exception that nothing catches ends the run with [`VmError::Uncaught`].
+pub fn execute(
+    program: &Program,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Result<Value, VmError> {
+    execute_with(program, variables, memory, heap, host, Profiler::new()).0
+}
+
+/// Like [`execute`], but runs the program under the given profiler and hands it back.
+pub fn execute_with(
+    program: &Program,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    profiler: Profiler,
+) -> (Result<Value, VmError>, Profiler) {
+    with_slots(program.slots, variables, |variables| {
+        let mut frame = Frame {
+            variables,
+            memory,
+            heap,
+            host,
+            meter: &Meter::unlimited(),
+            stopped: None,
+            code: &program.code,
+            armed: None,
+            constants: &program.constants,
+            handlers: &program.handlers,
+            pc: 0,
+            result: Value::int(0),
+            running: vec![],
+            profiler,
+        };
+        let result = fuel::unmetered(frame.eval()).map(|()| frame.result);
+        (result, frame.profiler)
+    })
+}
+
+/// Like [`execute`], but stops with an error when `fuel` runs out or is interrupted, which
+/// [`resume`] can carry on from.
+pub fn execute_metered(
+    program: &Program,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    execute_from(program, None, variables, memory, heap, host, fuel)
+}
+
+/// Carries on with a run of `program` that stopped with `suspended`, from the registers it
+/// left behind. `variables` receives the final contents of the registers, as with [`execute`].
+pub fn resume(
+    program: &Program,
+    suspended: Suspended,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    assert!(
+        (suspended.pc() as usize) < program.code.len(),
+        "resumed with different code"
+    );
+    execute_from(
+        program,
+        Some(suspended),
+        variables,
+        memory,
+        heap,
+        host,
+        fuel,
+    )
+}
+
+/// The checksum of the program's module. Runs that stop record it, for [`resume`] to check that
+/// it's given the same program.
+pub fn checksum(program: &Program) -> u32 {
+    program.to_module().checksum()
+}
+
+fn execute_from(
+    program: &Program,
+    suspended: Option<Suspended>,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    fuel::run(
+        module::Backend::RegisterFixed,
+        checksum(program),
+        program.slots,
+        variables,
+        fuel,
+        suspended,
+        |variables, meter, state| {
+            let armed = meter.armed(&program.code, HALT[0], starts);
+            let stepping = meter.stepping();
+            let mut frame = Frame {
+                variables,
+                memory,
+                heap,
+                host,
+                meter,
+                stopped: None,
+                code: if stepping { &program.code } else { &armed },
+                armed: stepping.then_some(&armed),
+                constants: &program.constants,
+                handlers: &program.handlers,
+                pc: state.pc,
+                result: state.result,
+                running: state.running,
+                profiler: Profiler::new(),
+            };
+            match frame.eval() {
+                Ok(()) => Ok(frame.result),
+                Err(stop) => Err((
+                    stop,
+                    State {
+                        pc: frame.pc,
+                        result: frame.result,
+                        running: frame.running,
+                        ..state
+                    },
+                )),
+            }
+        },
+    )
+}
+
+impl Opcode {
+    fn from_u8(x: u8) -> Option<Self> {
+        (x <= Opcode::Wide as u8).then(|| unsafe { std::mem::transmute::<u8, Opcode>(x) })
+    }
+
+    /// The width of a load or store.
+    fn width(self) -> Width {
+        match self {
+            Opcode::Load8 | Opcode::Store8 => Width::U8,
+            Opcode::Load16 | Opcode::Store16 => Width::U16,
+            Opcode::Load32 | Opcode::Store32 => Width::U32,
+            _ => unreachable!("{self:?} does not access memory"),
+        }
+    }
+}
+
+/// An instruction with the high bits from its `Wide` prefix, if any, folded into its fields.
+struct Decoded {
+    opcode: Opcode,
+    wide: bool,
+    /// The number of words the instruction occupies, including the prefix.
+    size: usize,
+    a: u16,
+    b: Rk,
+    c: Rk,
+    bx: u64,
+    /// The target of a jump, which may be out of bounds.
+    target: i64,
+}
+
+fn decode(code: &[u32], pc: usize) -> Result<Decoded, VerifyError> {
+    let error = |message| VerifyError { pc, message };
+    let opcode = |insn| Opcode::from_u8(opcode_bits(insn)).ok_or_else(|| error("invalid opcode"));
+    let (prefix, insn, size) = match opcode(code[pc])? {
+        Opcode::Wide => {
+            let insn = *code
+                .get(pc + 1)
+                .ok_or_else(|| error("truncated instruction"))?;
+            (code[pc], insn, 2)
+        }
+        _ => (0, code[pc], 1),
+    };
+    let opcode = opcode(insn)?;
+    if opcode == Opcode::Wide && size == 2 {
+        return Err(error("instruction cannot be widened"));
+    }
+    let sbx = if size == 2 {
+        wide_sbx(prefix, insn)
+    } else {
+        arg_sbx(insn) as i64
+    };
+    Ok(Decoded {
+        opcode,
+        wide: size == 2,
+        size,
+        a: wide_a(prefix, insn),
+        b: Rk::decode(arg_b(prefix), arg_b(insn)),
+        c: Rk::decode(arg_c(prefix), arg_c(insn)),
+        bx: wide_bx(prefix, insn),
+        target: (pc + size) as i64 + sbx,
+    })
+}
+
+/// The pcs that instructions start at, up to the first that doesn't decode.
+fn starts(code: &[u32]) -> Vec<u32> {
+    let mut starts = vec![];
+    let mut pc = 0;
+    while pc < code.len() {
+        let Ok(decoded) = decode(code, pc) else { break };
+        starts.push(pc as u32);
+        pc += decoded.size;
+    }
+    starts
+}
+
+/// The argument count and function index of a `CallNative`, which aren't RK operands.
+fn native_operands(insn: &Decoded) -> (u32, u32) {
+    let field = |operand| match operand {
+        Rk::Register(r) => r as u32,
+        Rk::Constant(k) => CONSTANT | k,
+    };
+    (field(insn.b), field(insn.c))
+}
+
+pub fn disassemble(program: &Program) -> Disassembly {
+    let rk = |operand: Rk| match operand {
+        Rk::Register(r) => format!("%{r}"),
+        Rk::Constant(k) => match program.constants.get(k as usize) {
+            Some(k) => k.to_string(),
+            None => format!("<constant {k}>"),
+        },
+    };
+
+    let mut disassembly = vec![];
+    let mut pc = 0;
+    while pc < program.code.len() {
+        let insn = match decode(&program.code, pc) {
+            Ok(insn) => insn,
+            Err(error) => {
+                disassembly.push((pc as u32, format!("<{}>", error.message)));
+                break;
+            }
+        };
+        let (opcode, a) = (insn.opcode, insn.a);
+        let text = match opcode {
+            Opcode::LoadK => match program.constants.get(insn.bx as usize) {
+                Some(k) => format!("%{a} = LoadK {k}"),
+                None => format!("%{a} = LoadK <constant {}>", insn.bx),
+            },
+            Opcode::LoadF => match program
+                .constants
+                .get(insn.bx as usize..insn.bx as usize + 2)
+            {
+                Some(&[lo, hi]) => {
+                    let f = f64::from_bits((hi as u64) << 32 | lo as u64);
+                    format!("%{a} = LoadF {f:?}")
+                }
+                _ => format!("%{a} = LoadF <constant {}>", insn.bx),
+            },
+            Opcode::Move | Opcode::IntToFloat | Opcode::FloatToInt => {
+                format!("%{a} = {opcode:?} {}", rk(insn.b))
+            }
+            Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Load32
+            | Opcode::NewArray
+            | Opcode::Len
+            | Opcode::Yield => {
+                format!("%{a} = {opcode:?} {}", rk(insn.b))
+            }
+            Opcode::NewRecord => format!("%{a} = NewRecord {}", insn.bx),
+            Opcode::NewString => {
+                match heap::read_string_constant(&program.constants, insn.bx as usize) {
+                    Some(s) => format!("%{a} = NewString {:?}", String::from_utf8_lossy(&s)),
+                    None => format!("%{a} = NewString <constant {}>", insn.bx),
+                }
+            }
+            Opcode::Get | Opcode::Resume => {
+                format!("%{a} = {opcode:?} {}, {}", rk(insn.b), rk(insn.c))
+            }
+            Opcode::Set => format!("Set %{a}, {}, {}", rk(insn.b), rk(insn.c)),
+            Opcode::Throw => format!("Throw {}", rk(insn.b)),
+            Opcode::CallNative => {
+                let (argc, function) = native_operands(&insn);
+                format!("%{a} = CallNative {function}, %{a}, {argc}")
+            }
+            Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => {
+                format!("{opcode:?} {}, %{a}", rk(insn.b))
+            }
+            Opcode::LessEq
+            | Opcode::Add
+            | Opcode::Multiply
+            | Opcode::Subtract
+            | Opcode::Divide
+            | Opcode::Remainder
+            | Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide => {
+                format!("%{a} = {opcode:?} {}, {}", rk(insn.b), rk(insn.c))
+            }
+            Opcode::JumpIfNot => format!("JumpIfNot {}, %{a}", insn.target),
+            Opcode::NewCoroutine => format!("%{a} = NewCoroutine {}", insn.target),
+            Opcode::Jump => format!("Jump {}", insn.target),
+            Opcode::Return => format!("Return %{a}"),
+            Opcode::Wide => "Wide".into(),
+        };
+        let text = if insn.wide {
+            format!("Wide {text}")
+        } else {
+            text
+        };
+        disassembly.push((pc as u32, text));
+        pc += insn.size;
+    }
+    disassembly
+}
+
+/// Checks that the program cannot read registers or constants or jump out of bounds. Program
+/// counters in errors are indices of instructions rather than bytes.
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    let code = &program.code;
+    if code.len() > u32::MAX as usize {
+        return Err(VerifyError {
+            pc: u32::MAX as usize,
+            message: "program has more instructions than a 32-bit pc can address",
+        });
+    }
+
+    let operand = |operand: Rk, pc| match operand {
+        Rk::Register(r) if r as usize >= program.slots => Err(VerifyError {
+            pc,
+            message: "register out of bounds",
+        }),
+        Rk::Constant(k) if k as usize >= program.constants.len() => Err(VerifyError {
+            pc,
+            message: "constant out of bounds",
+        }),
+        _ => Ok(()),
+    };
+    let mut starts = vec![false; code.len()];
+    let mut jumps = vec![];
+    let mut last = None;
+    let mut pc = 0;
+    while pc < code.len() {
+        let insn = decode(code, pc)?;
+        starts[pc] = true;
+        if !matches!(insn.opcode, Opcode::Jump | Opcode::Throw) {
+            operand(Rk::Register(insn.a), pc)?;
+        }
+        match insn.opcode {
+            Opcode::LoadK if insn.bx as usize >= program.constants.len() => {
+                return Err(VerifyError {
+                    pc,
+                    message: "constant out of bounds",
+                });
+            }
+            Opcode::LoadF if insn.bx as usize + 1 >= program.constants.len() => {
+                return Err(VerifyError {
+                    pc,
+                    message: "constant out of bounds",
+                });
+            }
+            Opcode::NewString
+                if heap::read_string_constant(&program.constants, insn.bx as usize).is_none() =>
+            {
+                return Err(VerifyError {
+                    pc,
+                    message: "string constant out of bounds",
+                });
+            }
+            Opcode::Move | Opcode::IntToFloat | Opcode::FloatToInt
+                if matches!(insn.b, Rk::Constant(_)) =>
+            {
+                return Err(VerifyError {
+                    pc,
+                    message: "Move and conversions can only read registers",
+                });
+            }
+            Opcode::Move
+            | Opcode::IntToFloat
+            | Opcode::FloatToInt
+            | Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Load32
+            | Opcode::Store8
+            | Opcode::Store16
+            | Opcode::Store32
+            | Opcode::NewArray
+            | Opcode::Len
+            | Opcode::Yield
+            | Opcode::Throw => operand(insn.b, pc)?,
+            Opcode::LessEq
+            | Opcode::Add
+            | Opcode::Multiply
+            | Opcode::Subtract
+            | Opcode::Divide
+            | Opcode::Remainder
+            | Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide
+            | Opcode::Get
+            | Opcode::Set
+            | Opcode::Resume => {
+                operand(insn.b, pc)?;
+                operand(insn.c, pc)?;
+            }
+            Opcode::CallNative => {
+                let (argc, _) = native_operands(&insn);
+                if insn.a as usize + argc as usize > program.slots {
+                    return Err(VerifyError {
+                        pc,
+                        message: "register out of bounds",
+                    });
+                }
+            }
+            Opcode::JumpIfNot | Opcode::Jump | Opcode::NewCoroutine => {
+                jumps.push((pc, insn.target))
+            }
+            _ => (),
+        }
+        last = Some(insn.opcode);
+        pc += insn.size;
+    }
+    if !matches!(last, Some(Opcode::Return | Opcode::Jump | Opcode::Throw)) {
+        return Err(VerifyError {
+            pc: code.len(),
+            message: "execution can run past the end of the program",
+        });
+    }
+    for handler in &program.handlers {
+        let pc = handler.target as usize;
+        if handler.start > handler.end || handler.end as usize > code.len() {
+            return Err(VerifyError {
+                pc,
+                message: "handler covers code out of bounds",
+            });
+        }
+        let bounds = [handler.start, handler.end].map(|pc| pc as usize);
+        if bounds.iter().any(|&pc| pc < code.len() && !starts[pc]) {
+            return Err(VerifyError {
+                pc,
+                message: "handler covers part of an instruction",
+            });
+        }
+        if handler.depth as usize >= program.slots {
+            return Err(VerifyError {
+                pc,
+                message: "register out of bounds",
+            });
+        }
+        jumps.push((pc, handler.target as i64));
+    }
+    for (pc, target) in jumps {
+        if !usize::try_from(target).is_ok_and(|target| starts.get(target) == Some(&true)) {
+            return Err(VerifyError {
+                pc,
+                message: "jump target is not the start of an instruction",
+            });
+        }
+    }
+    Ok(())
+}
+
+struct Compiler {
+    w: Writer,
+    /// The number of registers holding the program's variables. Registers above them are used
+    /// for temporaries, which are allocated like a stack.
+    variables: usize,
+    temps: usize,
+    /// The number of registers used so far.
+    slots: usize,
+    /// Whether all jumps are widened, which is needed once the program outgrows the reach of
+    /// `sBx`.
+    wide_jumps: bool,
+    /// Whether every narrow jump so far could reach its target.
+    jumps_fit: bool,
+    handlers: exception::Table<u32>,
+}
+
+impl Compiler {
+    fn alloc_temp(&mut self) -> Result<u16, CompileError> {
+        let temp = u16::try_from(self.temps).map_err(|_| CompileError::TooManyVariables)?;
+        self.temps += 1;
+        self.slots = self.slots.max(self.temps);
+        Ok(temp)
+    }
+
+    fn is_temp(&self, register: u16) -> bool {
+        register as usize >= self.variables
+    }
+
+    /// Writes an ABC instruction, with a `Wide` prefix if any of its registers need more than
+    /// eight bits.
+    fn write_abc(&mut self, opcode: Opcode, a: u16, b: Rk, c: Rk) {
+        let (b_hi, b) = b.encode();
+        let (c_hi, c) = c.encode();
+        if a > u8::MAX as u16 || b_hi != 0 || c_hi != 0 {
+            self.w.write(abc(Opcode::Wide, (a >> 8) as u8, b_hi, c_hi));
+        }
+        self.w.write(abc(opcode, a as u8, b, c));
+    }
+
+    /// Writes an ABx instruction, with a `Wide` prefix if its register or constant index is too
+    /// large for it.
+    fn write_abx(&mut self, opcode: Opcode, a: u16, bx: u32) {
+        if a > u8::MAX as u16 || bx > MAX_BX {
+            self.w.write(abx(Opcode::Wide, (a >> 8) as u8, bx >> 18));
+        }
+        self.w.write(abx(opcode, a as u8, bx & MAX_BX));
+    }
+
+    /// Writes a jump with a placeholder offset, and returns its location for
+    /// [`Writer::patch_jump`].
+    fn write_jump(&mut self, opcode: Opcode, a: u16) -> usize {
+        if self.wide_jumps || a > u8::MAX as u16 {
+            let prefix = self.w.write(abx(Opcode::Wide, (a >> 8) as u8, 0));
+            self.w.write(abx(opcode, a as u8, 0));
+            prefix
+        } else {
+            self.w.write(asbx(opcode, a as u8, 0))
+        }
+    }
+
+    fn patch_jump(&mut self, at: usize, target: u32) {
+        self.jumps_fit &= self.w.patch_jump(at, target);
+    }
+
+    fn write_move(&mut self, source: u16, target: u16) {
+        if source != target {
+            self.write_abc(Opcode::Move, target, Rk::Register(source), Rk::Register(0));
+        }
+    }
+
+    fn write_loop_start(&mut self, condition: &Instruction) -> Result<(u32, usize), CompileError> {
+        let loop_start = self.w.pc();
+        let mark = self.temps;
+        let condition = self.value(condition)?;
+        self.temps = mark;
+        let loop_jump_hole = self.write_jump(Opcode::JumpIfNot, condition);
+        Ok((loop_start, loop_jump_hole))
+    }
+
+    fn write_loop_end(&mut self, loop_start: u32, loop_jump_hole: usize) {
+        let jump = self.write_jump(Opcode::Jump, 0);
+        self.patch_jump(jump, loop_start);
+        let loop_end = self.w.pc();
+        self.patch_jump(loop_jump_hole, loop_end);
+    }
+
+    /// Compiles an instruction whose value is not needed.
+    fn effect(&mut self, insn: &Instruction) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Int(_)
+            | Instruction::Float(_)
+            | Instruction::Var(_)
+            | Instruction::NewRecord(_)
+            | Instruction::NewString(_) => (),
+            Instruction::Let { variable, value } => self.value_into(value, *variable)?,
+            Instruction::LessEq(a, b)
+            | Instruction::Add(a, b)
+            | Instruction::Multiply(a, b)
+            | Instruction::Subtract(a, b)
+            | Instruction::FLess(a, b)
+            | Instruction::FAdd(a, b)
+            | Instruction::FMultiply(a, b)
+            | Instruction::FSubtract(a, b)
+            | Instruction::FDivide(a, b) => {
+                self.effect(a)?;
+                self.effect(b)?;
+            }
+            Instruction::IntToFloat(a)
+            | Instruction::FloatToInt(a)
+            | Instruction::NewArray(a)
+            | Instruction::Len(a) => self.effect(a)?,
+            Instruction::StoreIndex {
+                width,
+                address,
+                value,
+            } => {
+                self.store(*width, address, value)?;
+            }
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => {
+                self.set(object, index, value)?;
+            }
+            Instruction::Coroutine(_) => (),
+            // Division and loads are kept for their side effect of panicking on a zero divisor
+            // or an out-of-bounds address or index, calls for whatever the host does, and
+            // yields and resumes for switching to another frame.
+            Instruction::Divide(..)
+            | Instruction::Remainder(..)
+            | Instruction::Index { .. }
+            | Instruction::Get(..)
+            | Instruction::CallNative { .. }
+            | Instruction::Yield(..)
+            | Instruction::Resume(..) => {
+                let mark = self.temps;
+                self.value(insn)?;
+                self.temps = mark;
+            }
+            Instruction::Sequence(s) => {
+                for insn in s {
+                    self.effect(insn)?;
+                }
+            }
+            Instruction::While { condition, body } => {
+                let (loop_start, loop_jump_hole) = self.write_loop_start(condition)?;
+                self.effect(body)?;
+                self.write_loop_end(loop_start, loop_jump_hole);
+            }
+            Instruction::Try {
+                body,
+                variable,
+                handler,
+            } => self.try_catch(body, *variable, handler, None)?,
+            Instruction::Throw(value) => self.throw(value)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles an instruction and returns the register holding its value, which may be the
+    /// register of a variable.
+    fn value(&mut self, insn: &Instruction) -> Result<u16, CompileError> {
+        match insn {
+            Instruction::Var(v) => Ok(*v),
+            Instruction::Let { variable, value } => {
+                self.value_into(value, *variable)?;
+                Ok(*variable)
+            }
+            _ => {
+                let temp = self.alloc_temp()?;
+                self.value_into(insn, temp)?;
+                Ok(temp)
+            }
+        }
+    }
+
+    /// Like [`Compiler::value`], but returns an RK operand, so that integers among the first
+    /// 256 constants don't need to be loaded into a register.
+    fn operand(&mut self, insn: &Instruction) -> Result<Rk, CompileError> {
+        if let Instruction::Int(i) = insn {
+            let k = self.w.constant(*i);
+            if k < CONSTANT {
+                return Ok(Rk::Constant(k));
+            }
+        }
+        Ok(Rk::Register(self.value(insn)?))
+    }
+
+    /// Compiles an instruction, storing its value in `target`. `target` is only written once
+    /// the value has been computed, so the instruction may still read its old value.
+    fn value_into(&mut self, insn: &Instruction, target: u16) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Int(i) => {
+                let k = self.w.constant(*i);
+                self.write_abx(Opcode::LoadK, target, k);
+            }
+            Instruction::Float(f) => {
+                let k = self.w.float_constant(*f);
+                self.write_abx(Opcode::LoadF, target, k);
+            }
+            Instruction::Var(v) => self.write_move(*v, target),
+            Instruction::Let { variable, value } => {
+                self.value_into(value, *variable)?;
+                self.write_move(*variable, target);
+            }
+            Instruction::LessEq(a, b) => self.binary(Opcode::LessEq, a, b, target)?,
+            Instruction::Add(a, b) => self.binary(Opcode::Add, a, b, target)?,
+            Instruction::Multiply(a, b) => self.binary(Opcode::Multiply, a, b, target)?,
+            Instruction::Subtract(a, b) => self.binary(Opcode::Subtract, a, b, target)?,
+            Instruction::Divide(a, b) => self.binary(Opcode::Divide, a, b, target)?,
+            Instruction::Remainder(a, b) => self.binary(Opcode::Remainder, a, b, target)?,
+            Instruction::FLess(a, b) => self.binary(Opcode::FLess, a, b, target)?,
+            Instruction::FAdd(a, b) => self.binary(Opcode::FAdd, a, b, target)?,
+            Instruction::FMultiply(a, b) => self.binary(Opcode::FMultiply, a, b, target)?,
+            Instruction::FSubtract(a, b) => self.binary(Opcode::FSubtract, a, b, target)?,
+            Instruction::FDivide(a, b) => self.binary(Opcode::FDivide, a, b, target)?,
+            Instruction::IntToFloat(a) => self.unary(Opcode::IntToFloat, a, target)?,
+            Instruction::FloatToInt(a) => self.unary(Opcode::FloatToInt, a, target)?,
+            Instruction::Index { width, address } => {
+                let opcode = match width {
+                    Width::U8 => Opcode::Load8,
+                    Width::U16 => Opcode::Load16,
+                    Width::U32 => Opcode::Load32,
+                };
+                let mark = self.temps;
+                let address = self.operand(address)?;
+                self.temps = mark;
+                self.write_abc(opcode, target, address, Rk::Register(0));
+            }
+            Instruction::StoreIndex {
+                width,
+                address,
+                value,
+            } => {
+                let value = self.store(*width, address, value)?;
+                self.write_move(value, target);
+            }
+            Instruction::NewArray(len) => {
+                let mark = self.temps;
+                let len = self.operand(len)?;
+                self.temps = mark;
+                self.write_abc(Opcode::NewArray, target, len, Rk::Register(0));
+            }
+            Instruction::NewRecord(fields) => {
+                self.write_abx(Opcode::NewRecord, target, *fields as u32);
+            }
+            Instruction::NewString(s) => {
+                let k = heap::add_string_constant(&mut self.w.program.constants, s);
+                let k = u32::try_from(k).map_err(|_| CompileError::TooManyConstants)?;
+                self.write_abx(Opcode::NewString, target, k);
+            }
+            Instruction::Get(object, index) => self.binary(Opcode::Get, object, index, target)?,
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => match self.set(object, index, value)? {
+                Rk::Register(value) => self.write_move(value, target),
+                Rk::Constant(k) => self.write_abx(Opcode::LoadK, target, k),
+            },
+            Instruction::Len(object) => {
+                let mark = self.temps;
+                let object = self.value(object)?;
+                self.temps = mark;
+                self.write_abc(Opcode::Len, target, Rk::Register(object), Rk::Register(0));
+            }
+            Instruction::CallNative {
+                function,
+                arguments,
+            } => {
+                let argc =
+                    u8::try_from(arguments.len()).map_err(|_| CompileError::TooManyArguments)?;
+                // The arguments are evaluated into fresh temporaries, so none of them can be
+                // overwritten by a later one, and the result replaces the first.
+                let mark = self.temps;
+                let first = self.alloc_temp()?;
+                for _ in 1..argc {
+                    self.alloc_temp()?;
+                }
+                for (i, argument) in arguments.iter().enumerate() {
+                    self.value_into(argument, first + i as u16)?;
+                }
+                self.temps = mark;
+                let (hi, lo) = (*function as u32 >> 8, *function as u32 & 0xff);
+                if first > u8::MAX as u16 || hi != 0 {
+                    self.w.write(abc(Opcode::Wide, (first >> 8) as u8, 0, hi));
+                }
+                self.w
+                    .write(abc(Opcode::CallNative, first as u8, argc as u32, lo));
+                self.write_move(first, target);
+            }
+            Instruction::Yield(value) => {
+                let mark = self.temps;
+                let value = self.operand(value)?;
+                self.temps = mark;
+                self.write_abc(Opcode::Yield, target, value, Rk::Register(0));
+            }
+            Instruction::Coroutine(body) => {
+                // The body runs in its own copy of the registers, so it may use any of them.
+                let hole = self.write_jump(Opcode::NewCoroutine, target);
+                let start = self.w.pc();
+                self.handlers.enter_coroutine();
+                let mark = self.temps;
+                let result = self.value(body)?;
+                self.temps = mark;
+                self.write_abc(Opcode::Return, result, Rk::Register(0), Rk::Register(0));
+                let end = self.w.pc();
+                self.handlers.leave_coroutine(start, end);
+                self.patch_jump(hole, end);
+            }
+            Instruction::Resume(coroutine, value) => {
+                self.binary(Opcode::Resume, coroutine, value, target)?
+            }
+            Instruction::Sequence(s) => match s.split_last() {
+                Some((last, init)) => {
+                    for insn in init {
+                        self.effect(insn)?;
+                    }
+                    self.value_into(last, target)?;
+                }
+                None => self.value_into(&Instruction::Int(0), target)?,
+            },
+            Instruction::While { .. } if !self.is_temp(target) => {
+                // The loop's result is updated on every iteration, so it must not be stored in
+                // a variable that the loop itself might read.
+                let mark = self.temps;
+                let temp = self.value(insn)?;
+                self.temps = mark;
+                self.write_move(temp, target);
+            }
+            Instruction::While { condition, body } => {
+                // The value of a loop is the value of its body in the last iteration.
+                self.value_into(&Instruction::Int(0), target)?;
+                let (loop_start, loop_jump_hole) = self.write_loop_start(condition)?;
+                self.value_into(body, target)?;
+                self.write_loop_end(loop_start, loop_jump_hole);
+            }
+            Instruction::Try {
+                body,
+                variable,
+                handler,
+            } => self.try_catch(body, *variable, handler, Some(target))?,
+            // Nothing runs after the throw, so `target` is left alone.
+            Instruction::Throw(value) => self.throw(value)?,
+        }
+        Ok(())
+    }
+
+    fn try_catch(
+        &mut self,
+        body: &Instruction,
+        variable: u16,
+        handler: &Instruction,
+        target: Option<u16>,
+    ) -> Result<(), CompileError> {
+        let mark = self.handlers.mark();
+        let start = self.w.pc();
+        match target {
+            Some(target) => self.value_into(body, target)?,
+            None => self.effect(body)?,
+        }
+        let end = self.w.pc();
+        let done = self.write_jump(Opcode::Jump, 0);
+
+        let handler_start = self.w.pc();
+        self.handlers
+            .add(mark, start, end, handler_start, variable as u32);
+        match target {
+            Some(target) => self.value_into(handler, target)?,
+            None => self.effect(handler)?,
+        }
+        let after = self.w.pc();
+        self.patch_jump(done, after);
+        Ok(())
+    }
+
+    fn throw(&mut self, value: &Instruction) -> Result<(), CompileError> {
+        let mark = self.temps;
+        let value = self.operand(value)?;
+        self.temps = mark;
+        self.write_abc(Opcode::Throw, 0, value, Rk::Register(0));
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        opcode: Opcode,
+        a: &Instruction,
+        b: &Instruction,
+        target: u16,
+    ) -> Result<(), CompileError> {
+        let mark = self.temps;
+        let mut ra = self.operand(a)?;
+        // `a` may have evaluated to a variable that `b` assigns to, in which case its value
+        // needs to be copied before it's overwritten.
+        if let Rk::Register(r) = ra {
+            if !self.is_temp(r) && b.assigns(r) {
+                let temp = self.alloc_temp()?;
+                self.write_move(r, temp);
+                ra = Rk::Register(temp);
+            }
+        }
+        let rb = self.operand(b)?;
+        self.temps = mark;
+        self.write_abc(opcode, target, ra, rb);
+        Ok(())
+    }
+
+    fn unary(&mut self, opcode: Opcode, a: &Instruction, target: u16) -> Result<(), CompileError> {
+        let mark = self.temps;
+        let ra = self.value(a)?;
+        self.temps = mark;
+        self.write_abc(opcode, target, Rk::Register(ra), Rk::Register(0));
+        Ok(())
+    }
+
+    /// Compiles a store, and returns the register holding the stored value. The register may be
+    /// a temporary that has already been freed, so it must be read straight away.
+    fn store(
+        &mut self,
+        width: Width,
+        address: &Instruction,
+        value: &Instruction,
+    ) -> Result<u16, CompileError> {
+        let opcode = match width {
+            Width::U8 => Opcode::Store8,
+            Width::U16 => Opcode::Store16,
+            Width::U32 => Opcode::Store32,
+        };
+        let mark = self.temps;
+        let mut address = self.operand(address)?;
+        if let Rk::Register(r) = address {
+            if !self.is_temp(r) && value.assigns(r) {
+                let temp = self.alloc_temp()?;
+                self.write_move(r, temp);
+                address = Rk::Register(temp);
+            }
+        }
+        let value = self.value(value)?;
+        self.temps = mark;
+        self.write_abc(opcode, value, address, Rk::Register(0));
+        Ok(value)
+    }
+
+    /// Compiles a write to an object, and returns the operand holding the value written, like
+    /// [`Compiler::store`].
+    fn set(
+        &mut self,
+        object: &Instruction,
+        index: &Instruction,
+        value: &Instruction,
+    ) -> Result<Rk, CompileError> {
+        let mark = self.temps;
+        let mut object_register = self.value(object)?;
+        if !self.is_temp(object_register)
+            && (index.assigns(object_register) || value.assigns(object_register))
+        {
+            let temp = self.alloc_temp()?;
+            self.write_move(object_register, temp);
+            object_register = temp;
+        }
+        let mut index = self.operand(index)?;
+        if let Rk::Register(r) = index {
+            if !self.is_temp(r) && value.assigns(r) {
+                let temp = self.alloc_temp()?;
+                self.write_move(r, temp);
+                index = Rk::Register(temp);
+            }
+        }
+        let value = self.operand(value)?;
+        self.temps = mark;
+        self.write_abc(Opcode::Set, object_register, index, value);
+        Ok(value)
+    }
+}
+
+/// Compiles a program that [`execute`] can run. Each variable of the program is mapped to the
+/// register of the same number.
+pub fn compile(program: &Instruction) -> Result<Program, CompileError> {
+    match compile_with(program, false)? {
+        Some(program) => Ok(program),
+        None => Ok(compile_with(program, true)?.expect("wide jumps reach any target")),
+    }
+}
+
+/// Compiles a program with narrow or wide jumps, returning `None` if narrow ones cannot reach
+/// all their targets.
+fn compile_with(program: &Instruction, wide_jumps: bool) -> Result<Option<Program>, CompileError> {
+    let variables = program.variable_count();
+    let mut c = Compiler {
+        w: Writer::default(),
+        variables,
+        temps: variables,
+        slots: variables,
+        wide_jumps,
+        jumps_fit: true,
+        handlers: exception::Table::default(),
+    };
+
+    let result = c.value(program)?;
+    c.write_abc(Opcode::Return, result, Rk::Register(0), Rk::Register(0));
+
+    if !c.jumps_fit {
+        return Ok(None);
+    }
+    if c.w.program.code.len() > u32::MAX as usize {
+        return Err(CompileError::CodeTooLarge);
+    }
+    c.w.program.slots = c.slots;
+    c.w.program.handlers = c.handlers.resolve(|pc| pc, 0);
+    Ok(Some(c.w.program))
+}
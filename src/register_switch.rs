@@ -1,26 +1,135 @@
+use std::collections::HashMap;
+
+use crate::{
+    bytecode::{
+        sign_extend, with_slots, Chunk, CompileError, Disassembly, Jumps, Label, Linked, Reach,
+        VerifyError,
+    },
+    coroutine::{self, Registers},
+    exception::{self, Handler, DIVISION_BY_ZERO},
+    fuel::{self, Fuel, Meter, Patched, State, Stop, Suspended, VmError},
+    heap::{self, Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    module::{self, Module},
+    profile::Profiler,
+    treewalk::Instruction,
+    value::Value,
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 enum Opcode {
     Int,
+    /// Loads its one-byte operand, for the many small integers in programs.
+    SmallInt,
+    /// Load the constant at a one- or two-byte index in the constant pool.
+    Const8,
+    Const16,
+    /// Loads its eight-byte operand.
+    Float,
+    Move,
 
     LessEq,
+    /// Adds integers or floats. It rewrites itself into `AddInt` or `AddFloat` for the type it
+    /// sees, and those rewrite themselves back when they see the other type.
     Add,
+    AddInt,
+    AddFloat,
     Multiply,
+    Subtract,
+    Divide,
+    Remainder,
+
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+    /// Convert between integers and floats, with a source and a target register like `Move`.
+    IntToFloat,
+    FloatToInt,
+
+    /// Load the integer in linear memory at the address in a source register into a target
+    /// register, like `Move`.
+    Load8,
+    Load16,
+    Load32,
+    /// Store the integer in the second register at the address in the first.
+    Store8,
+    Store16,
+    Store32,
+
+    /// Allocates an array with as many elements as the integer in a source register, and puts
+    /// it in a target register.
+    NewArray,
+    /// Allocate a record with as many fields as a two-byte immediate, or a string copied from
+    /// the constant at one, and put it in a target register.
+    NewRecord,
+    NewString,
+    /// Reads element, field or byte (second register) of an object (first register) into a
+    /// target register.
+    Get,
+    /// Writes the third register to element or field (second register) of an object (first
+    /// register).
+    Set,
+    Len,
+
+    /// Calls the host function at a two-byte immediate with as many arguments as a one-byte
+    /// immediate, in consecutive registers from the first register, and puts the result in the
+    /// second.
+    CallNative,
+    /// Copies a source register to a target register, and stops a metered run, yielding it.
+    /// Inside a [coroutine][crate::coroutine], it hands the source register to whoever resumed
+    /// it instead, and the target receives the value it's resumed with.
+    Yield,
+    /// Puts a new coroutine whose body starts at the next instruction into a register, and
+    /// jumps past the body, without costing fuel. Its operands are those of `JumpIfNot`, and it
+    /// has no `8` form.
+    NewCoroutine,
+    /// Resumes the coroutine in the first register with the second, and puts what it yields or
+    /// returns into a target register.
+    Resume,
+    /// Throws a register to the handler in the exception table.
+    Throw,
 
+    /// Jump by a signed offset from the end of the instruction, so code can be moved without
+    /// patching. The offset is one byte in the `8` forms, and two bytes otherwise.
+    JumpIfNot8,
+    Jump8,
     JumpIfNot,
     Jump,
+    /// Sets the program's result, or ends the running coroutine with it.
+    Return,
+    /// Prefix giving the following instruction two-byte register operands and a four-byte jump
+    /// offset. The `8` forms of jumps cannot be widened.
+    Wide,
     Halt,
 }
 
 struct Frame<'c> {
-    variables: [u32; 256],
-    bytecode: &'c [u8],
-    pc: u16,
+    variables: &'c mut [Value],
+    memory: &'c mut [u8],
+    heap: &'c mut Heap,
+    host: &'c Host,
+    meter: &'c Meter<'c>,
+    /// Why the meter stopped the run, and the opcode the `Halt` that stopped it replaced.
+    stopped: Option<(Stop, u8)>,
+    /// With a `Halt` written over each breakpoint.
+    bytecode: Patched<'c, u8>,
+    constants: &'c [u32],
+    handlers: &'c [Handler],
+    pc: u32,
+    result: Value,
+    /// The coroutines that are running, innermost last, which hold the frames that resumed them.
+    running: Vec<Value>,
+    profiler: Profiler,
 }
 
 impl<'c> Frame<'c> {
     fn read_u8(&mut self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         let x = *unsafe { self.bytecode.get_unchecked(self.pc as usize) };
         self.pc += 1;
         x
@@ -28,8 +137,9 @@ impl<'c> Frame<'c> {
 
     fn read_u16(&mut self) -> u16 {
         debug_assert!((self.pc as usize + 1) < self.bytecode.len());
+        self.profiler.read_bytecode(2);
         let x = unsafe {
-            *std::mem::transmute::<_, &u16>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u16>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 2;
         u16::from_le(x)
@@ -37,80 +147,776 @@ impl<'c> Frame<'c> {
 
     fn read_u32(&mut self) -> u32 {
         debug_assert!((self.pc as usize + 3) < self.bytecode.len());
+        self.profiler.read_bytecode(4);
         let x = unsafe {
-            *std::mem::transmute::<_, &u32>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u32>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 4;
         u32::from_le(x)
     }
 
-    fn var(&self, i: u8) -> u32 {
+    fn read_u64(&mut self) -> u64 {
+        debug_assert!((self.pc as usize + 7) < self.bytecode.len());
+        self.profiler.read_bytecode(8);
+        let x = unsafe {
+            *std::mem::transmute::<&u8, &u64>(self.bytecode.get_unchecked(self.pc as usize))
+        };
+        self.pc += 8;
+        u64::from_le(x)
+    }
+
+    /// Replaces the opcode of the instruction at `pc`, which must have the same operands.
+    fn quicken(&mut self, pc: u32, opcode: Opcode) {
+        debug_assert!((pc as usize) < self.bytecode.len());
+        unsafe {
+            *self.bytecode.get_unchecked_mut(pc as usize) = opcode as u8;
+        }
+    }
+
+    /// Jumps by `offset` from the end of the instruction. Jumping backward costs fuel; see
+    /// [`stack_switch`][crate::stack_switch] for how the meter stops the run.
+    #[inline(always)]
+    fn jump(&mut self, offset: i32) {
+        self.pc = self.pc.wrapping_add_signed(offset);
+        if offset < 0 {
+            if let Err(stop) = self.meter.tick() {
+                self.stop(stop);
+            }
+        }
+    }
+
+    /// Stops a metered run at a `Yield`, like a jump without fuel.
+    #[inline(always)]
+    fn yield_value(&mut self, value: Value) {
+        if let Err(stop) = self.meter.yield_value(value) {
+            self.stop(stop);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn stop(&mut self, stop: Stop) {
+        let opcode = std::mem::replace(&mut self.bytecode[self.pc as usize], Opcode::Halt as u8);
+        self.stopped = Some((stop, opcode));
+    }
+
+    fn constant(&self, i: u16) -> u32 {
+        debug_assert!((i as usize) < self.constants.len());
+        self.profiler.read_bytecode(4);
+        *unsafe { self.constants.get_unchecked(i as usize) }
+    }
+
+    fn var(&self, i: u16) -> Value {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.read_variable();
         unsafe { *self.variables.get_unchecked(i as usize) }
     }
 
-    fn set_var(&mut self, i: u8, val: u32) {
+    fn set_var(&mut self, i: u16, val: Value) {
         debug_assert!((i as usize) < self.variables.len());
+        self.profiler.write_variable();
         unsafe {
             *self.variables.get_unchecked_mut(i as usize) = val;
         }
     }
+
+    fn load(&mut self, source: u16, target: u16, width: Width) {
+        let address = self.var(source).as_int();
+        self.set_var(
+            target,
+            Value::int(memory::load(self.memory, address, width)),
+        );
+    }
+
+    fn store(&mut self, ra: u16, rb: u16, width: Width) {
+        let address = self.var(ra).as_int();
+        let value = self.var(rb).as_int();
+        memory::store(self.memory, address, width, value);
+    }
+
+    /// Allocates an object into `target`. All registers and the running coroutines are the
+    /// garbage collector's roots.
+    fn alloc(&mut self, target: u16, object: Object) {
+        let object = self.heap.alloc(object, &[self.variables, &self.running]);
+        self.set_var(target, object);
+    }
+
+    fn new_array(&mut self, source: u16, target: u16) {
+        let len = self.var(source).as_int();
+        self.alloc(target, Object::Array(vec![Value::int(0); len as usize]));
+    }
+
+    fn new_record(&mut self, target: u16, fields: u16) {
+        self.alloc(
+            target,
+            Object::Record(vec![Value::int(0); fields as usize].into()),
+        );
+    }
+
+    fn new_string(&mut self, target: u16, i: u16) {
+        let string = heap::read_string_constant(self.constants, i as usize)
+            .expect("verified string constant");
+        self.alloc(target, Object::String(string));
+    }
+
+    fn get(&mut self, ra: u16, rb: u16, target: u16) {
+        let object = self.var(ra);
+        let index = self.var(rb).as_int();
+        self.set_var(target, self.heap.get(object, index));
+    }
+
+    fn set(&mut self, ra: u16, rb: u16, rc: u16) {
+        let object = self.var(ra);
+        let index = self.var(rb).as_int();
+        let value = self.var(rc);
+        self.heap.set(object, index, value);
+    }
+
+    fn len(&mut self, source: u16, target: u16) {
+        let object = self.var(source);
+        self.set_var(target, Value::int(self.heap.len(object)));
+    }
+
+    /// Calls a host function. All registers, including the arguments, are the garbage
+    /// collector's roots.
+    fn call_native(&mut self, first: u16, target: u16, argc: u8, function: u16) {
+        for _ in 0..argc {
+            self.profiler.read_variable();
+        }
+        let args = &self.variables[first as usize..][..argc as usize];
+        let result = self.host.call(
+            function,
+            &mut Call::new(args, self.heap, &[self.variables, &self.running]),
+        );
+        self.set_var(target, result);
+    }
+
+    /// Puts a new coroutine whose body starts at the next instruction, with a copy of the
+    /// registers, into `target`, and jumps past the body.
+    fn new_coroutine(&mut self, offset: i32, target: u16) {
+        let registers = self.registers(0);
+        let roots = [&*self.variables, &self.running];
+        let coroutine = coroutine::new(self.heap, self.variables, registers, &roots);
+        self.set_var(target, coroutine);
+        self.pc = self.pc.wrapping_add_signed(offset);
+    }
+
+    #[inline(never)]
+    fn resume(&mut self, rc: u16, rv: u16, target: u16) {
+        let coroutine = self.var(rc);
+        let value = self.var(rv);
+        let mut registers = self.registers(target);
+        let yielded = coroutine::resume(self.heap, coroutine, self.variables, &mut registers);
+        self.running.push(coroutine);
+        self.pc = registers.pc;
+        if yielded {
+            self.set_var(registers.target, value);
+        }
+    }
+
+    /// Hands `source` back to whoever resumed the running coroutine, at a `Yield` whose target
+    /// is `target` or, if `returned`, at the end of its body.
+    #[inline(never)]
+    fn suspend(&mut self, source: u16, target: u16, returned: bool) {
+        let value = self.var(source);
+        let target = self.switch_back(target, returned);
+        self.set_var(target, value);
+    }
+
+    /// Switches from the running coroutine back to whoever resumed it, and returns the register
+    /// the resumer expects its value in.
+    fn switch_back(&mut self, target: u16, returned: bool) -> u16 {
+        let coroutine = self.running.pop().expect("a coroutine is running");
+        let mut registers = self.registers(target);
+        coroutine::suspend(
+            self.heap,
+            coroutine,
+            self.variables,
+            &mut registers,
+            returned,
+        );
+        self.pc = registers.pc;
+        registers.target
+    }
+
+    /// Throws a value from the instruction just executed to its handler, which receives it in
+    /// the handler's register. A coroutine without a handler for it dies, and the `Resume`
+    /// running it throws the value again. Nothing else catching it stops the run.
+    #[cold]
+    #[inline(never)]
+    fn throw(&mut self, value: Value) {
+        loop {
+            if let Some(handler) = exception::find(self.handlers, self.pc - 1) {
+                self.pc = handler.target;
+                self.set_var(handler.depth as u16, value);
+                return;
+            }
+            if self.running.is_empty() {
+                // The run is over, so the `Halt` that ends it can go anywhere.
+                self.pc = 0;
+                self.stop(Stop::Uncaught(value));
+                return;
+            }
+            self.switch_back(0, true);
+        }
+    }
+
+    /// Puts the quotient or remainder of a division in `target`, or throws if the divisor was
+    /// zero.
+    fn divide(&mut self, result: Option<u32>, target: u16) {
+        match result {
+            Some(result) => self.set_var(target, Value::int(result)),
+            None => self.throw(DIVISION_BY_ZERO),
+        }
+    }
+
+    /// The registers to carry on from the next instruction with, with the value switched back
+    /// with going to `target`.
+    fn registers(&self, target: u16) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.variables.len() as u32,
+            target,
+        }
+    }
 }
 
 impl<'c> Frame<'c> {
+    /// Runs until `Halt`, or until the meter stops it at a backward jump or a breakpoint.
     #[inline(never)]
-    fn eval(&mut self) {
-        loop {
-            let opcode = unsafe { std::mem::transmute::<_, Opcode>(self.read_u8()) };
-            match opcode {
-                Opcode::Int => {
-                    let target = self.read_u8();
-                    let i = self.read_u32();
-                    self.set_var(target, i);
-                    self.dump();
-                }
-                Opcode::LessEq => {
-                    let ra = self.read_u8();
-                    let rb = self.read_u8();
-                    let a = self.var(ra);
-                    let b = self.var(rb);
-                    let target = self.read_u8();
-                    self.set_var(target, (a <= b) as u32);
-                    self.dump();
-                }
-                Opcode::Add => {
-                    let ra = self.read_u8();
-                    let rb = self.read_u8();
-                    let a = self.var(ra);
-                    let b = self.var(rb);
-                    let target = self.read_u8();
+    fn eval(&mut self) -> Result<(), Stop> {
+        // Resumed at a breakpoint, the run carries on with the instruction under it, and writes
+        // the breakpoint back straight after.
+        let mut running = true;
+        if self.bytecode.stepping() {
+            running = self.step();
+            if self.stopped.is_none() {
+                self.bytecode.rearm(Opcode::Halt as u8);
+            }
+        }
+        if running {
+            while self.step() {}
+        }
+        match self.stopped.take() {
+            Some((stop, opcode)) => {
+                self.pc -= 1;
+                self.bytecode[self.pc as usize] = opcode;
+                Err(stop)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs an instruction, and returns whether the run carries on.
+    #[inline(always)]
+    fn step(&mut self) -> bool {
+        let pc = self.pc;
+        let opcode = unsafe { std::mem::transmute::<u8, Opcode>(self.read_u8()) };
+        self.profiler.enter(pc, opcode as u8);
+        match opcode {
+            Opcode::Int => {
+                let target = self.read_u8() as u16;
+                let i = self.read_u32();
+                self.set_var(target, Value::int(i));
+                self.dump();
+            }
+            Opcode::SmallInt => {
+                let target = self.read_u8() as u16;
+                let i = self.read_u8();
+                self.set_var(target, Value::int(i as u32));
+            }
+            Opcode::Const8 => {
+                let target = self.read_u8() as u16;
+                let i = self.read_u8();
+                let k = self.constant(i as u16);
+                self.set_var(target, Value::int(k));
+            }
+            Opcode::Const16 => {
+                let target = self.read_u8() as u16;
+                let i = self.read_u16();
+                let k = self.constant(i);
+                self.set_var(target, Value::int(k));
+            }
+            Opcode::Float => {
+                let target = self.read_u8() as u16;
+                let f = f64::from_bits(self.read_u64());
+                self.set_var(target, Value::float(f));
+            }
+            Opcode::Move => {
+                let source = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                let val = self.var(source);
+                self.set_var(target, val);
+            }
+            Opcode::LessEq => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_int();
+                let b = self.var(rb).as_int();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::int((a <= b) as u32));
+                self.dump();
+            }
+            Opcode::Add => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra);
+                let b = self.var(rb);
+                let target = self.read_u8() as u16;
+                let quick = if a.is_int() {
+                    Opcode::AddInt
+                } else {
+                    Opcode::AddFloat
+                };
+                self.quicken(pc, quick);
+                self.set_var(target, a + b);
+                self.dump();
+            }
+            Opcode::AddInt => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra);
+                let b = self.var(rb);
+                let target = self.read_u8() as u16;
+                if a.is_int() {
+                    self.set_var(target, Value::int(a.as_int().wrapping_add(b.as_int())));
+                } else {
+                    self.quicken(pc, Opcode::Add);
                     self.set_var(target, a + b);
-                    self.dump();
-                }
-                Opcode::Multiply => {
-                    let ra = self.read_u8();
-                    let rb = self.read_u8();
-                    let a = self.var(ra);
-                    let b = self.var(rb);
-                    let target = self.read_u8();
-                    self.set_var(target, a * b);
-                    self.dump();
-                }
-                Opcode::JumpIfNot => {
-                    let offset = self.read_u16();
-                    let source = self.read_u8();
-                    let condition = self.var(source);
-                    if condition == 0 {
-                        self.pc = offset;
-                    }
                 }
-                Opcode::Jump => {
-                    let offset = self.read_u16();
-                    self.pc = offset;
+                self.dump();
+            }
+            Opcode::AddFloat => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra);
+                let b = self.var(rb);
+                let target = self.read_u8() as u16;
+                if a.is_float() {
+                    self.set_var(target, Value::float(a.as_float() + b.as_float()));
+                } else {
+                    self.quicken(pc, Opcode::Add);
+                    self.set_var(target, a + b);
+                }
+                self.dump();
+            }
+            Opcode::Multiply => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_int();
+                let b = self.var(rb).as_int();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::int(a.wrapping_mul(b)));
+                self.dump();
+            }
+            Opcode::Subtract => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_int();
+                let b = self.var(rb).as_int();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::int(a.wrapping_sub(b)));
+                self.dump();
+            }
+            Opcode::Divide => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_int();
+                let b = self.var(rb).as_int();
+                let target = self.read_u8() as u16;
+                self.divide(a.checked_div(b), target);
+                self.dump();
+            }
+            Opcode::Remainder => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_int();
+                let b = self.var(rb).as_int();
+                let target = self.read_u8() as u16;
+                self.divide(a.checked_rem(b), target);
+                self.dump();
+            }
+            Opcode::FLess => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_float();
+                let b = self.var(rb).as_float();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::int((a < b) as u32));
+                self.dump();
+            }
+            Opcode::FAdd => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_float();
+                let b = self.var(rb).as_float();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::float(a + b));
+                self.dump();
+            }
+            Opcode::FMultiply => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_float();
+                let b = self.var(rb).as_float();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::float(a * b));
+                self.dump();
+            }
+            Opcode::FSubtract => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_float();
+                let b = self.var(rb).as_float();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::float(a - b));
+                self.dump();
+            }
+            Opcode::FDivide => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let a = self.var(ra).as_float();
+                let b = self.var(rb).as_float();
+                let target = self.read_u8() as u16;
+                self.set_var(target, Value::float(a / b));
+                self.dump();
+            }
+            Opcode::IntToFloat => {
+                let source = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                let a = self.var(source).as_int();
+                self.set_var(target, Value::float(a as f64));
+            }
+            Opcode::FloatToInt => {
+                let source = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                let a = self.var(source).as_float();
+                self.set_var(target, Value::int(a as u32));
+            }
+            Opcode::Load8 | Opcode::Load16 | Opcode::Load32 => {
+                let source = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                self.load(source, target, opcode.width());
+            }
+            Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                self.store(ra, rb, opcode.width());
+            }
+            Opcode::NewArray => {
+                let source = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                self.new_array(source, target);
+            }
+            Opcode::NewRecord => {
+                let target = self.read_u8() as u16;
+                let fields = self.read_u16();
+                self.new_record(target, fields);
+            }
+            Opcode::NewString => {
+                let target = self.read_u8() as u16;
+                let i = self.read_u16();
+                self.new_string(target, i);
+            }
+            Opcode::Get => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                self.get(ra, rb, target);
+            }
+            Opcode::Set => {
+                let ra = self.read_u8() as u16;
+                let rb = self.read_u8() as u16;
+                let rc = self.read_u8() as u16;
+                self.set(ra, rb, rc);
+            }
+            Opcode::Len => {
+                let source = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                self.len(source, target);
+            }
+            Opcode::CallNative => {
+                let first = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                let argc = self.read_u8();
+                let function = self.read_u16();
+                self.call_native(first, target, argc, function);
+            }
+            Opcode::Yield => {
+                let source = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                if self.running.is_empty() {
+                    let val = self.var(source);
+                    self.set_var(target, val);
+                    self.yield_value(val);
+                } else {
+                    self.suspend(source, target, false);
+                }
+            }
+            Opcode::NewCoroutine => {
+                let offset = self.read_u16() as i16;
+                let target = self.read_u8() as u16;
+                self.new_coroutine(offset as i32, target);
+            }
+            Opcode::Resume => {
+                let rc = self.read_u8() as u16;
+                let rv = self.read_u8() as u16;
+                let target = self.read_u8() as u16;
+                self.resume(rc, rv, target);
+            }
+            Opcode::Throw => {
+                let source = self.read_u8() as u16;
+                self.throw(self.var(source));
+            }
+            Opcode::JumpIfNot8 => {
+                let offset = self.read_u8() as i8;
+                let source = self.read_u8() as u16;
+                let condition = self.var(source);
+                if !condition.is_truthy() {
+                    self.jump(offset as i32);
+                }
+            }
+            Opcode::Jump8 => {
+                let offset = self.read_u8() as i8;
+                self.jump(offset as i32);
+            }
+            Opcode::JumpIfNot => {
+                let offset = self.read_u16() as i16;
+                let source = self.read_u8() as u16;
+                let condition = self.var(source);
+                if !condition.is_truthy() {
+                    self.jump(offset as i32);
+                }
+            }
+            Opcode::Jump => {
+                let offset = self.read_u16() as i16;
+                self.jump(offset as i32);
+            }
+            Opcode::Return => {
+                let source = self.read_u8() as u16;
+                if self.running.is_empty() {
+                    self.result = self.var(source);
+                } else {
+                    self.suspend(source, 0, true);
+                }
+            }
+            Opcode::Wide => self.wide(),
+            Opcode::Halt if self.stopped.is_none() && self.bytecode.is_breakpoint(pc) => {
+                self.stopped = Some((Stop::Breakpoint, Opcode::Halt as u8));
+                self.profiler.leave();
+                return false;
+            }
+            Opcode::Halt => {
+                self.profiler.leave();
+                return false;
+            }
+        }
+        self.profiler.leave();
+        self.dump();
+        true
+    }
+
+    /// Executes the instruction following a `Wide` prefix.
+    #[cold]
+    fn wide(&mut self) {
+        let opcode = unsafe { std::mem::transmute::<u8, Opcode>(self.read_u8()) };
+        match opcode {
+            Opcode::Int => {
+                let target = self.read_u16();
+                let i = self.read_u32();
+                self.set_var(target, Value::int(i));
+            }
+            Opcode::SmallInt => {
+                let target = self.read_u16();
+                let i = self.read_u8();
+                self.set_var(target, Value::int(i as u32));
+            }
+            Opcode::Const8 => {
+                let target = self.read_u16();
+                let i = self.read_u8();
+                let k = self.constant(i as u16);
+                self.set_var(target, Value::int(k));
+            }
+            Opcode::Const16 => {
+                let target = self.read_u16();
+                let i = self.read_u16();
+                let k = self.constant(i);
+                self.set_var(target, Value::int(k));
+            }
+            Opcode::Float => {
+                let target = self.read_u16();
+                let f = f64::from_bits(self.read_u64());
+                self.set_var(target, Value::float(f));
+            }
+            Opcode::Move => {
+                let source = self.read_u16();
+                let target = self.read_u16();
+                let val = self.var(source);
+                self.set_var(target, val);
+            }
+            // Wide instructions are rare enough not to be worth quickening.
+            Opcode::Add | Opcode::AddInt | Opcode::AddFloat => {
+                let ra = self.read_u16();
+                let rb = self.read_u16();
+                let a = self.var(ra);
+                let b = self.var(rb);
+                let target = self.read_u16();
+                self.set_var(target, a + b);
+            }
+            Opcode::LessEq | Opcode::Multiply | Opcode::Subtract => {
+                let ra = self.read_u16();
+                let rb = self.read_u16();
+                let a = self.var(ra).as_int();
+                let b = self.var(rb).as_int();
+                let target = self.read_u16();
+                let val = match opcode {
+                    Opcode::LessEq => (a <= b) as u32,
+                    Opcode::Multiply => a.wrapping_mul(b),
+                    _ => a.wrapping_sub(b),
+                };
+                self.set_var(target, Value::int(val));
+            }
+            Opcode::Divide | Opcode::Remainder => {
+                let ra = self.read_u16();
+                let rb = self.read_u16();
+                let a = self.var(ra).as_int();
+                let b = self.var(rb).as_int();
+                let target = self.read_u16();
+                let val = match opcode {
+                    Opcode::Divide => a.checked_div(b),
+                    _ => a.checked_rem(b),
+                };
+                self.divide(val, target);
+            }
+            Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide => {
+                let ra = self.read_u16();
+                let rb = self.read_u16();
+                let a = self.var(ra).as_float();
+                let b = self.var(rb).as_float();
+                let target = self.read_u16();
+                let val = match opcode {
+                    Opcode::FLess => Value::int((a < b) as u32),
+                    Opcode::FAdd => Value::float(a + b),
+                    Opcode::FMultiply => Value::float(a * b),
+                    Opcode::FSubtract => Value::float(a - b),
+                    _ => Value::float(a / b),
+                };
+                self.set_var(target, val);
+            }
+            Opcode::IntToFloat => {
+                let source = self.read_u16();
+                let target = self.read_u16();
+                let a = self.var(source).as_int();
+                self.set_var(target, Value::float(a as f64));
+            }
+            Opcode::FloatToInt => {
+                let source = self.read_u16();
+                let target = self.read_u16();
+                let a = self.var(source).as_float();
+                self.set_var(target, Value::int(a as u32));
+            }
+            Opcode::Load8 | Opcode::Load16 | Opcode::Load32 => {
+                let source = self.read_u16();
+                let target = self.read_u16();
+                self.load(source, target, opcode.width());
+            }
+            Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => {
+                let ra = self.read_u16();
+                let rb = self.read_u16();
+                self.store(ra, rb, opcode.width());
+            }
+            Opcode::NewArray => {
+                let source = self.read_u16();
+                let target = self.read_u16();
+                self.new_array(source, target);
+            }
+            Opcode::NewRecord => {
+                let target = self.read_u16();
+                let fields = self.read_u16();
+                self.new_record(target, fields);
+            }
+            Opcode::NewString => {
+                let target = self.read_u16();
+                let i = self.read_u16();
+                self.new_string(target, i);
+            }
+            Opcode::Get => {
+                let ra = self.read_u16();
+                let rb = self.read_u16();
+                let target = self.read_u16();
+                self.get(ra, rb, target);
+            }
+            Opcode::Set => {
+                let ra = self.read_u16();
+                let rb = self.read_u16();
+                let rc = self.read_u16();
+                self.set(ra, rb, rc);
+            }
+            Opcode::Len => {
+                let source = self.read_u16();
+                let target = self.read_u16();
+                self.len(source, target);
+            }
+            Opcode::CallNative => {
+                let first = self.read_u16();
+                let target = self.read_u16();
+                let argc = self.read_u8();
+                let function = self.read_u16();
+                self.call_native(first, target, argc, function);
+            }
+            Opcode::Yield => {
+                let source = self.read_u16();
+                let target = self.read_u16();
+                if self.running.is_empty() {
+                    let val = self.var(source);
+                    self.set_var(target, val);
+                    self.yield_value(val);
+                } else {
+                    self.suspend(source, target, false);
+                }
+            }
+            Opcode::NewCoroutine => {
+                let offset = self.read_u32() as i32;
+                let target = self.read_u16();
+                self.new_coroutine(offset, target);
+            }
+            Opcode::Resume => {
+                let rc = self.read_u16();
+                let rv = self.read_u16();
+                let target = self.read_u16();
+                self.resume(rc, rv, target);
+            }
+            Opcode::Throw => {
+                let source = self.read_u16();
+                self.throw(self.var(source));
+            }
+            Opcode::JumpIfNot => {
+                let offset = self.read_u32() as i32;
+                let source = self.read_u16();
+                let condition = self.var(source);
+                if !condition.is_truthy() {
+                    self.jump(offset);
                 }
-                Opcode::Halt => break,
             }
-            self.dump();
+            Opcode::Jump => {
+                let offset = self.read_u32() as i32;
+                self.jump(offset);
+            }
+            Opcode::Return => {
+                let source = self.read_u16();
+                if self.running.is_empty() {
+                    self.result = self.var(source);
+                } else {
+                    self.suspend(source, 0, true);
+                }
+            }
+            Opcode::JumpIfNot8 | Opcode::Jump8 | Opcode::Wide | Opcode::Halt => unsafe {
+                std::hint::unreachable_unchecked()
+            },
         }
     }
 
@@ -122,6 +928,11 @@ impl<'c> Frame<'c> {
 #[derive(Default)]
 struct Writer {
     bytecode: Vec<u8>,
+    constants: Vec<u32>,
+    constant_indices: HashMap<u32, u16>,
+    /// Jumps written by the compiler and their conditions, which are only inserted into
+    /// `bytecode` by `link`.
+    jumps: Jumps<(Opcode, Option<u16>)>,
 }
 
 impl Writer {
@@ -131,8 +942,8 @@ impl Writer {
         i
     }
 
-    fn pc(&self) -> u16 {
-        self.bytecode.len() as u16
+    fn pc(&self) -> u32 {
+        self.bytecode.len() as u32
     }
 
     fn write_u16(&mut self, x: u16) -> usize {
@@ -153,9 +964,91 @@ impl Writer {
         i
     }
 
+    fn write_u64(&mut self, x: u64) -> usize {
+        let i = self.bytecode.len();
+        self.bytecode.extend_from_slice(&x.to_le_bytes());
+        i
+    }
+
     fn write_opcode(&mut self, opcode: Opcode) {
         self.write_u8(opcode as u8);
     }
+
+    /// Returns the index of `x` in the constant pool, adding it if it isn't there yet, or `None`
+    /// if the pool is full.
+    fn constant(&mut self, x: u32) -> Option<u16> {
+        if let Some(&i) = self.constant_indices.get(&x) {
+            return Some(i);
+        }
+        let i = u16::try_from(self.constants.len()).ok()?;
+        self.constants.push(x);
+        self.constant_indices.insert(x, i);
+        Some(i)
+    }
+
+    fn label(&mut self) -> Label {
+        self.jumps.label()
+    }
+
+    fn place(&mut self, label: Label) {
+        self.jumps.place(label, self.bytecode.len());
+    }
+
+    /// Writes a `JumpIfNot` on `condition` or a `Jump` to `target`, in whichever form reaches it
+    /// once the code is linked. Conditions in registers above 255 need the `Wide` form.
+    fn write_jump(&mut self, opcode: Opcode, target: Label, condition: Option<u16>) {
+        let at = self.bytecode.len();
+        let (reach, sizes) = match condition {
+            Some(register) if register > u8::MAX as u16 => (Reach::Wide, [3, 4, 8]),
+            Some(_) => (Reach::Short, [3, 4, 8]),
+            None => (Reach::Short, [2, 3, 6]),
+        };
+        self.jumps
+            .add(at, target, reach, sizes, (opcode, condition));
+    }
+
+    /// Writes a `NewCoroutine` into `target` whose body ends at `end`. It has no short form.
+    fn write_new_coroutine(&mut self, end: Label, target: u16) {
+        let at = self.bytecode.len();
+        let reach = if target > u8::MAX as u16 {
+            Reach::Wide
+        } else {
+            Reach::Long
+        };
+        self.jumps.add(
+            at,
+            end,
+            reach,
+            [3, 4, 8],
+            (Opcode::NewCoroutine, Some(target)),
+        );
+    }
+
+    /// Returns the bytecode with the jumps inserted.
+    fn link(&mut self) -> Result<Linked, CompileError> {
+        let jumps = std::mem::take(&mut self.jumps);
+        jumps.link(
+            &self.bytecode,
+            |code, &(opcode, condition), reach, offset| {
+                match reach {
+                    Reach::Short => code.extend([opcode.short_jump() as u8, offset as i8 as u8]),
+                    Reach::Long => {
+                        code.push(opcode as u8);
+                        code.extend((offset as i16).to_le_bytes());
+                    }
+                    Reach::Wide => {
+                        code.extend([Opcode::Wide as u8, opcode as u8]);
+                        code.extend(offset.to_le_bytes());
+                    }
+                }
+                match condition {
+                    Some(register) if reach == Reach::Wide => code.extend(register.to_le_bytes()),
+                    Some(register) => code.push(register as u8),
+                    None => (),
+                }
+            },
+        )
+    }
 }
 
 const VAR_N: u8 = 0;
@@ -184,6 +1077,7 @@ pub fn code() -> Vec<u8> {
     w.write_opcode(Opcode::JumpIfNot);
     let loop_jump_hole = w.write_u16(0);
     w.write_u8(TEMP);
+    let loop_jump_base = w.pc();
 
     w.write_opcode(Opcode::Multiply);
     w.write_u8(VAR_X);
@@ -200,22 +1094,1000 @@ pub fn code() -> Vec<u8> {
     w.write_u8(VAR_I);
 
     w.write_opcode(Opcode::Jump);
-    w.write_u16(loop_start);
+    w.write_u16((loop_start as i32 - (w.pc() as i32 + 2)) as u16);
 
     let loop_end = w.pc();
-    w.patch_u16(loop_jump_hole, loop_end);
+    w.patch_u16(loop_jump_hole, (loop_end - loop_jump_base) as u16);
     w.write_opcode(Opcode::Halt);
 
     w.bytecode
 }
 
-pub fn run(code: &[u8]) -> u32 {
+pub fn run(code: &mut [u8]) -> u32 {
+    let mut variables = [Value::int(0); 256];
+    let mut frame = Frame {
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        bytecode: Patched::clean(code),
+        constants: &[],
+        handlers: &[],
+        pc: 0,
+        result: Value::int(0),
+        running: vec![],
+        profiler: Profiler::new(),
+    };
+    frame.variables[VAR_N as usize] = Value::int(10);
+    frame.eval().expect("unmetered runs don't stop");
+    frame.variables[VAR_X as usize].as_int()
+}
+
+#[cfg(feature = "profile")]
+pub fn profile(code: &mut [u8]) -> crate::profile::Profile {
+    let mut variables = [Value::int(0); 256];
     let mut frame = Frame {
-        variables: [0; 256],
-        bytecode: code,
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        bytecode: Patched::clean(code),
+        constants: &[],
+        handlers: &[],
         pc: 0,
+        result: Value::int(0),
+        running: vec![],
+        profiler: Profiler::new(),
     };
-    frame.variables[VAR_N as usize] = 10;
-    frame.eval();
-    frame.variables[VAR_X as usize]
+    frame.variables[VAR_N as usize] = Value::int(10);
+    frame.eval().expect("unmetered runs don't stop");
+    frame.profiler.finish("register (switch)", |opcode| {
+        format!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) })
+    })
+}
+
+/// Runs a program produced by [`compile`] with the registers initialized from `variables` and
+/// the given linear memory and heap, and returns its result. Afterwards `variables` holds the final
+/// contents of the registers; those not used by the program's variables may have been
+/// overwritten with temporaries. Registers beyond the end of `variables` start out as zero. An
+/// exception that nothing catches ends the run with [`VmError::Uncaught`].
+///
+/// Instructions are quickened in `code` as they run, so later runs start out with them
+/// specialised for the types seen so far.
+pub fn execute(
+    code: &mut Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Result<Value, VmError> {
+    execute_with(code, variables, memory, heap, host, Profiler::new()).0
+}
+
+/// Like [`execute`], but runs the program under the given profiler and hands it back.
+pub fn execute_with(
+    code: &mut Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    profiler: Profiler,
+) -> (Result<Value, VmError>, Profiler) {
+    with_slots(code.slots, variables, |variables| {
+        let mut frame = Frame {
+            variables,
+            memory,
+            heap,
+            host,
+            meter: &Meter::unlimited(),
+            stopped: None,
+            bytecode: Patched::clean(&mut code.code),
+            constants: &code.constants,
+            handlers: &code.handlers,
+            pc: 0,
+            result: Value::int(0),
+            running: vec![],
+            profiler,
+        };
+        let result = fuel::unmetered(frame.eval()).map(|()| frame.result);
+        (result, frame.profiler)
+    })
+}
+
+/// Like [`execute`], but stops with an error when `fuel` runs out or is interrupted, which
+/// [`resume`] can carry on from.
+pub fn execute_metered(
+    code: &mut Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    execute_from(code, None, variables, memory, heap, host, fuel)
+}
+
+/// Carries on with a run of `code` that stopped with `suspended`, from the registers it left
+/// behind. `variables` receives the final contents of the registers, as with [`execute`].
+pub fn resume(
+    code: &mut Chunk,
+    suspended: Suspended,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    assert!(
+        (suspended.pc() as usize) < code.code.len(),
+        "resumed with different code"
+    );
+    execute_from(code, Some(suspended), variables, memory, heap, host, fuel)
+}
+
+/// The checksum of `chunk` as a module, with quickened instructions in their generic form so that
+/// running the code doesn't change it. Runs that stop record it, for [`resume`] to check that it's
+/// given the same code.
+pub fn checksum(chunk: &Chunk) -> u32 {
+    let mut chunk = chunk.clone();
+    let mut pc = 0;
+    while let Ok((opcode, _, wide, size)) = decode(&chunk.code, pc) {
+        if matches!(opcode, Opcode::AddInt | Opcode::AddFloat) {
+            chunk.code[pc + wide as usize] = Opcode::Add as u8;
+        }
+        pc += size;
+    }
+    Module::from_chunk(module::Backend::Register, chunk).checksum()
+}
+
+fn execute_from(
+    code: &mut Chunk,
+    suspended: Option<Suspended>,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    fuel::run(
+        module::Backend::Register,
+        checksum(code),
+        code.slots,
+        variables,
+        fuel,
+        suspended,
+        |variables, meter, state| {
+            let mut frame = Frame {
+                variables,
+                memory,
+                heap,
+                host,
+                meter,
+                stopped: None,
+                bytecode: meter.patch(&mut code.code, Opcode::Halt as u8, starts),
+                constants: &code.constants,
+                handlers: &code.handlers,
+                pc: state.pc,
+                result: state.result,
+                running: state.running,
+                profiler: Profiler::new(),
+            };
+            match frame.eval() {
+                Ok(()) => Ok(frame.result),
+                Err(stop) => Err((
+                    stop,
+                    State {
+                        pc: frame.pc,
+                        result: frame.result,
+                        running: frame.running,
+                        ..state
+                    },
+                )),
+            }
+        },
+    )
+}
+
+/// The kinds of operands an instruction can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    /// A register, one byte wide or two after `Wide`.
+    Register,
+    /// A jump offset, two bytes wide or four after `Wide`.
+    Target,
+    /// A one-byte jump offset.
+    ShortTarget,
+    /// An immediate of the given size, which `Wide` doesn't change.
+    Immediate(usize),
+}
+
+impl Operand {
+    fn size(self, wide: bool) -> usize {
+        match (self, wide) {
+            (Operand::Register, false) => 1,
+            (Operand::Register, true) | (Operand::Target, false) => 2,
+            (Operand::Target, true) => 4,
+            (Operand::ShortTarget, _) => 1,
+            (Operand::Immediate(size), _) => size,
+        }
+    }
+}
+
+impl Opcode {
+    fn from_u8(x: u8) -> Option<Self> {
+        (x <= Opcode::Halt as u8).then(|| unsafe { std::mem::transmute::<u8, Opcode>(x) })
+    }
+
+    /// The instruction's operands, in the order they're encoded.
+    fn operands(self) -> &'static [Operand] {
+        use Operand::*;
+        match self {
+            Opcode::Int => &[Register, Immediate(4)],
+            Opcode::SmallInt | Opcode::Const8 => &[Register, Immediate(1)],
+            Opcode::Const16 | Opcode::NewRecord | Opcode::NewString => &[Register, Immediate(2)],
+            Opcode::Float => &[Register, Immediate(8)],
+            Opcode::CallNative => &[Register, Register, Immediate(1), Immediate(2)],
+            Opcode::Move
+            | Opcode::IntToFloat
+            | Opcode::FloatToInt
+            | Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Load32
+            | Opcode::Store8
+            | Opcode::Store16
+            | Opcode::Store32
+            | Opcode::NewArray
+            | Opcode::Len
+            | Opcode::Yield => &[Register, Register],
+            Opcode::LessEq
+            | Opcode::Add
+            | Opcode::AddInt
+            | Opcode::AddFloat
+            | Opcode::Multiply
+            | Opcode::Subtract
+            | Opcode::Divide
+            | Opcode::Remainder
+            | Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide
+            | Opcode::Get
+            | Opcode::Set
+            | Opcode::Resume => &[Register, Register, Register],
+            Opcode::JumpIfNot8 => &[ShortTarget, Register],
+            Opcode::Jump8 => &[ShortTarget],
+            Opcode::JumpIfNot | Opcode::NewCoroutine => &[Target, Register],
+            Opcode::Jump => &[Target],
+            Opcode::Return | Opcode::Throw => &[Register],
+            Opcode::Wide | Opcode::Halt => &[],
+        }
+    }
+
+    /// The width of a load or store.
+    fn width(self) -> Width {
+        match self {
+            Opcode::Load8 | Opcode::Store8 => Width::U8,
+            Opcode::Load16 | Opcode::Store16 => Width::U16,
+            Opcode::Load32 | Opcode::Store32 => Width::U32,
+            _ => unreachable!("{self:?} does not access memory"),
+        }
+    }
+
+    /// Whether the first operand is a jump offset, which `NewCoroutine`'s is too.
+    fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JumpIfNot8
+                | Opcode::Jump8
+                | Opcode::JumpIfNot
+                | Opcode::Jump
+                | Opcode::NewCoroutine
+        )
+    }
+
+    /// The form of a `JumpIfNot` or `Jump` with a one-byte offset.
+    fn short_jump(self) -> Opcode {
+        match self {
+            Opcode::JumpIfNot => Opcode::JumpIfNot8,
+            Opcode::Jump => Opcode::Jump8,
+            _ => unreachable!("{self:?} is not a jump with a short form"),
+        }
+    }
+}
+
+/// Decodes the instruction at `pc`, returning its opcode, operands, whether it has a `Wide`
+/// prefix, and its size. The offsets of jumps are resolved to their targets.
+fn decode(code: &[u8], pc: usize) -> Result<(Opcode, Vec<u64>, bool, usize), VerifyError> {
+    let error = |message| VerifyError { pc, message };
+    let opcode = |pc: usize| {
+        let opcode = code.get(pc).ok_or_else(|| error("truncated instruction"))?;
+        Opcode::from_u8(*opcode).ok_or_else(|| error("invalid opcode"))
+    };
+    let (opcode, wide) = match opcode(pc)? {
+        Opcode::Wide => match opcode(pc + 1)? {
+            Opcode::JumpIfNot8 | Opcode::Jump8 | Opcode::Wide | Opcode::Halt => {
+                return Err(error("instruction cannot be widened"))
+            }
+            opcode => (opcode, true),
+        },
+        opcode => (opcode, false),
+    };
+    let mut operands = vec![];
+    let mut at = pc + 1 + wide as usize;
+    for operand in opcode.operands() {
+        let size = operand.size(wide);
+        let bytes = code
+            .get(at..at + size)
+            .ok_or_else(|| error("truncated instruction"))?;
+        operands.push(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |acc, &byte| acc << 8 | byte as u64),
+        );
+        at += size;
+    }
+    if opcode.is_jump() {
+        let size = opcode.operands()[0].size(wide);
+        let target = at as i64 + sign_extend(operands[0] as u32, size) as i64;
+        operands[0] = u32::try_from(target).map_err(|_| error("jump target out of bounds"))? as u64;
+    }
+    Ok((opcode, operands, wide, at - pc))
+}
+
+/// The pcs that instructions start at, up to the first that doesn't decode.
+pub(crate) fn starts(code: &[u8]) -> Vec<u32> {
+    let mut starts = vec![];
+    let mut pc = 0;
+    while let Ok((_, _, _, size)) = decode(code, pc) {
+        starts.push(pc as u32);
+        pc += size;
+    }
+    starts
+}
+
+pub fn disassemble(chunk: &Chunk) -> Disassembly {
+    let code = &chunk.code;
+    let mut disassembly = vec![];
+    let mut pc = 0;
+    while pc < code.len() {
+        match decode(code, pc) {
+            Ok((opcode, o, wide, size)) => {
+                let text = match opcode {
+                    Opcode::Int | Opcode::SmallInt => format!("%{} = {opcode:?} {}", o[0], o[1]),
+                    Opcode::Const8 | Opcode::Const16 => match chunk.constants.get(o[1] as usize) {
+                        Some(k) => format!("%{} = {opcode:?} {} ({k})", o[0], o[1]),
+                        None => format!("%{} = {opcode:?} {}", o[0], o[1]),
+                    },
+                    Opcode::Float => format!("%{} = Float {:?}", o[0], f64::from_bits(o[1])),
+                    Opcode::Move
+                    | Opcode::IntToFloat
+                    | Opcode::FloatToInt
+                    | Opcode::Load8
+                    | Opcode::Load16
+                    | Opcode::Load32
+                    | Opcode::NewArray
+                    | Opcode::Len
+                    | Opcode::Yield => format!("%{} = {opcode:?} %{}", o[1], o[0]),
+                    Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => {
+                        format!("{opcode:?} %{}, %{}", o[0], o[1])
+                    }
+                    Opcode::NewRecord => format!("%{} = NewRecord {}", o[0], o[1]),
+                    Opcode::NewString => {
+                        match heap::read_string_constant(&chunk.constants, o[1] as usize) {
+                            Some(s) => format!(
+                                "%{} = NewString {} ({:?})",
+                                o[0],
+                                o[1],
+                                String::from_utf8_lossy(&s)
+                            ),
+                            None => format!("%{} = NewString {}", o[0], o[1]),
+                        }
+                    }
+                    Opcode::Set => format!("Set %{}, %{}, %{}", o[0], o[1], o[2]),
+                    Opcode::CallNative => {
+                        format!("%{} = CallNative {}, %{}, {}", o[1], o[3], o[0], o[2])
+                    }
+                    Opcode::LessEq
+                    | Opcode::Add
+                    | Opcode::AddInt
+                    | Opcode::AddFloat
+                    | Opcode::Multiply
+                    | Opcode::Subtract
+                    | Opcode::Divide
+                    | Opcode::Remainder
+                    | Opcode::FLess
+                    | Opcode::FAdd
+                    | Opcode::FMultiply
+                    | Opcode::FSubtract
+                    | Opcode::FDivide
+                    | Opcode::Get
+                    | Opcode::Resume => {
+                        format!("%{} = {:?} %{}, %{}", o[2], opcode, o[0], o[1])
+                    }
+                    Opcode::JumpIfNot8 | Opcode::JumpIfNot => {
+                        format!("{opcode:?} {}, %{}", o[0], o[1])
+                    }
+                    Opcode::NewCoroutine => format!("%{} = NewCoroutine {}", o[1], o[0]),
+                    Opcode::Jump8 | Opcode::Jump => format!("{opcode:?} {}", o[0]),
+                    Opcode::Return | Opcode::Throw => format!("{opcode:?} %{}", o[0]),
+                    Opcode::Wide | Opcode::Halt => format!("{opcode:?}"),
+                };
+                let text = if wide { format!("Wide {text}") } else { text };
+                disassembly.push((pc as u32, text));
+                pc += size;
+            }
+            Err(error) => {
+                disassembly.push((pc as u32, format!("<{}>", error.message)));
+                break;
+            }
+        }
+    }
+    disassembly
+}
+
+/// Checks that the bytecode cannot read or jump out of bounds.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    let code = &chunk.code;
+    if code.len() > u32::MAX as usize {
+        return Err(VerifyError {
+            pc: u32::MAX as usize,
+            message: "bytecode is larger than 4 GiB",
+        });
+    }
+
+    let mut starts = vec![false; code.len() + 1];
+    let mut jumps = vec![];
+    let mut last = None;
+    let mut pc = 0;
+    while pc < code.len() {
+        let (opcode, operands, _, size) = decode(code, pc)?;
+        let mut registers = opcode.operands().iter().zip(&operands);
+        if registers.any(|(&kind, &o)| kind == Operand::Register && o as usize >= chunk.slots) {
+            return Err(VerifyError {
+                pc,
+                message: "register out of bounds",
+            });
+        }
+        if opcode == Opcode::CallNative && (operands[0] + operands[2]) as usize > chunk.slots {
+            return Err(VerifyError {
+                pc,
+                message: "register out of bounds",
+            });
+        }
+        if let Opcode::Const8 | Opcode::Const16 = opcode {
+            if operands[1] as usize >= chunk.constants.len() {
+                return Err(VerifyError {
+                    pc,
+                    message: "constant out of bounds",
+                });
+            }
+        }
+        if opcode == Opcode::NewString
+            && heap::read_string_constant(&chunk.constants, operands[1] as usize).is_none()
+        {
+            return Err(VerifyError {
+                pc,
+                message: "string constant out of bounds",
+            });
+        }
+        starts[pc] = true;
+        if opcode.is_jump() {
+            jumps.push((pc, operands[0] as usize));
+        }
+        last = Some(opcode);
+        pc += size;
+    }
+    if !matches!(
+        last,
+        Some(Opcode::Halt | Opcode::Jump8 | Opcode::Jump | Opcode::Throw)
+    ) {
+        return Err(VerifyError {
+            pc,
+            message: "execution can run past the end of the bytecode",
+        });
+    }
+    // Handlers may cover code up to the end.
+    starts[code.len()] = true;
+    for handler in &chunk.handlers {
+        let pc = handler.target as usize;
+        if handler.start > handler.end || handler.end as usize > code.len() {
+            return Err(VerifyError {
+                pc,
+                message: "handler covers code out of bounds",
+            });
+        }
+        if !starts[handler.start as usize] || !starts[handler.end as usize] {
+            return Err(VerifyError {
+                pc,
+                message: "handler covers part of an instruction",
+            });
+        }
+        if handler.depth as usize >= chunk.slots {
+            return Err(VerifyError {
+                pc,
+                message: "register out of bounds",
+            });
+        }
+        jumps.push((pc, pc));
+    }
+    for (pc, target) in jumps {
+        if target >= code.len() || !starts[target] {
+            return Err(VerifyError {
+                pc,
+                message: "jump target is not the start of an instruction",
+            });
+        }
+    }
+    Ok(())
+}
+
+struct Compiler {
+    w: Writer,
+    /// The number of registers holding the program's variables. Registers above them are used
+    /// for temporaries, which are allocated like a stack.
+    variables: usize,
+    temps: usize,
+    /// The number of registers used so far.
+    slots: usize,
+    handlers: exception::Table<Label>,
+}
+
+impl Compiler {
+    fn alloc_temp(&mut self) -> Result<u16, CompileError> {
+        let temp = u16::try_from(self.temps).map_err(|_| CompileError::TooManyVariables)?;
+        self.temps += 1;
+        self.slots = self.slots.max(self.temps);
+        Ok(temp)
+    }
+
+    fn is_temp(&self, register: u16) -> bool {
+        register as usize >= self.variables
+    }
+
+    /// Writes an opcode followed by its register operands, with a `Wide` prefix if any of them
+    /// needs two bytes. Immediates are written by the caller afterwards.
+    fn write_registers(&mut self, opcode: Opcode, registers: &[u16]) {
+        if registers.iter().all(|&r| r <= u8::MAX as u16) {
+            self.w.write_opcode(opcode);
+            for &register in registers {
+                self.w.write_u8(register as u8);
+            }
+        } else {
+            self.w.write_opcode(Opcode::Wide);
+            self.w.write_opcode(opcode);
+            for &register in registers {
+                self.w.write_u16(register);
+            }
+        }
+    }
+
+    fn write_move(&mut self, source: u16, target: u16) {
+        if source != target {
+            self.write_registers(Opcode::Move, &[source, target]);
+        }
+    }
+
+    /// Loads an integer into `target`, using the shortest encoding available for it.
+    fn write_int(&mut self, target: u16, i: u32) {
+        if let Ok(small) = u8::try_from(i) {
+            self.write_registers(Opcode::SmallInt, &[target]);
+            self.w.write_u8(small);
+        } else {
+            match self.w.constant(i) {
+                Some(k) if k <= u8::MAX as u16 => {
+                    self.write_registers(Opcode::Const8, &[target]);
+                    self.w.write_u8(k as u8);
+                }
+                Some(k) => {
+                    self.write_registers(Opcode::Const16, &[target]);
+                    self.w.write_u16(k);
+                }
+                None => {
+                    self.write_registers(Opcode::Int, &[target]);
+                    self.w.write_u32(i);
+                }
+            }
+        }
+    }
+
+    /// Compiles an instruction whose value is not needed.
+    fn effect(&mut self, insn: &Instruction) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Int(_)
+            | Instruction::Float(_)
+            | Instruction::Var(_)
+            | Instruction::NewRecord(_)
+            | Instruction::NewString(_) => (),
+            Instruction::Let { variable, value } => self.value_into(value, *variable)?,
+            Instruction::LessEq(a, b)
+            | Instruction::Add(a, b)
+            | Instruction::Multiply(a, b)
+            | Instruction::Subtract(a, b)
+            | Instruction::FLess(a, b)
+            | Instruction::FAdd(a, b)
+            | Instruction::FMultiply(a, b)
+            | Instruction::FSubtract(a, b)
+            | Instruction::FDivide(a, b) => {
+                self.effect(a)?;
+                self.effect(b)?;
+            }
+            Instruction::IntToFloat(a)
+            | Instruction::FloatToInt(a)
+            | Instruction::NewArray(a)
+            | Instruction::Len(a) => self.effect(a)?,
+            Instruction::StoreIndex {
+                width,
+                address,
+                value,
+            } => {
+                self.store(*width, address, value)?;
+            }
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => {
+                self.set(object, index, value)?;
+            }
+            Instruction::Coroutine(_) => (),
+            // Division and loads are kept for their side effect of throwing on a zero divisor or
+            // panicking on an out-of-bounds address or index, calls for whatever the host does,
+            // and yields and resumes for switching to another frame.
+            Instruction::Divide(..)
+            | Instruction::Remainder(..)
+            | Instruction::Index { .. }
+            | Instruction::Get(..)
+            | Instruction::CallNative { .. }
+            | Instruction::Yield(_)
+            | Instruction::Resume(..) => {
+                let mark = self.temps;
+                self.value(insn)?;
+                self.temps = mark;
+            }
+            Instruction::Sequence(s) => {
+                for insn in s {
+                    self.effect(insn)?;
+                }
+            }
+            Instruction::While { condition, body } => {
+                let loop_start = self.w.label();
+                let loop_end = self.w.label();
+                self.w.place(loop_start);
+                let mark = self.temps;
+                let condition = self.value(condition)?;
+                self.temps = mark;
+                self.w
+                    .write_jump(Opcode::JumpIfNot, loop_end, Some(condition));
+                self.effect(body)?;
+                self.w.write_jump(Opcode::Jump, loop_start, None);
+                self.w.place(loop_end);
+            }
+            Instruction::Try {
+                body,
+                variable,
+                handler,
+            } => self.try_catch(body, *variable, handler, None)?,
+            Instruction::Throw(value) => self.throw(value)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles an instruction and returns the register holding its value, which may be the
+    /// register of a variable.
+    fn value(&mut self, insn: &Instruction) -> Result<u16, CompileError> {
+        match insn {
+            Instruction::Var(v) => Ok(*v),
+            Instruction::Let { variable, value } => {
+                self.value_into(value, *variable)?;
+                Ok(*variable)
+            }
+            _ => {
+                let temp = self.alloc_temp()?;
+                self.value_into(insn, temp)?;
+                Ok(temp)
+            }
+        }
+    }
+
+    /// Compiles an instruction, storing its value in `target`. `target` is only written once
+    /// the value has been computed, so the instruction may still read its old value.
+    fn value_into(&mut self, insn: &Instruction, target: u16) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Int(i) => self.write_int(target, *i),
+            Instruction::Float(f) => {
+                self.write_registers(Opcode::Float, &[target]);
+                self.w.write_u64(f.to_bits());
+            }
+            Instruction::Var(v) => self.write_move(*v, target),
+            Instruction::Let { variable, value } => {
+                self.value_into(value, *variable)?;
+                self.write_move(*variable, target);
+            }
+            Instruction::LessEq(a, b) => self.binary(Opcode::LessEq, a, b, target)?,
+            Instruction::Add(a, b) => self.binary(Opcode::Add, a, b, target)?,
+            Instruction::Multiply(a, b) => self.binary(Opcode::Multiply, a, b, target)?,
+            Instruction::Subtract(a, b) => self.binary(Opcode::Subtract, a, b, target)?,
+            Instruction::Divide(a, b) => self.binary(Opcode::Divide, a, b, target)?,
+            Instruction::Remainder(a, b) => self.binary(Opcode::Remainder, a, b, target)?,
+            Instruction::FLess(a, b) => self.binary(Opcode::FLess, a, b, target)?,
+            Instruction::FAdd(a, b) => self.binary(Opcode::FAdd, a, b, target)?,
+            Instruction::FMultiply(a, b) => self.binary(Opcode::FMultiply, a, b, target)?,
+            Instruction::FSubtract(a, b) => self.binary(Opcode::FSubtract, a, b, target)?,
+            Instruction::FDivide(a, b) => self.binary(Opcode::FDivide, a, b, target)?,
+            Instruction::IntToFloat(a) => self.unary(Opcode::IntToFloat, a, target)?,
+            Instruction::FloatToInt(a) => self.unary(Opcode::FloatToInt, a, target)?,
+            Instruction::Index { width, address } => {
+                let opcode = match width {
+                    Width::U8 => Opcode::Load8,
+                    Width::U16 => Opcode::Load16,
+                    Width::U32 => Opcode::Load32,
+                };
+                self.unary(opcode, address, target)?;
+            }
+            Instruction::StoreIndex {
+                width,
+                address,
+                value,
+            } => {
+                let value = self.store(*width, address, value)?;
+                self.write_move(value, target);
+            }
+            Instruction::NewArray(len) => self.unary(Opcode::NewArray, len, target)?,
+            Instruction::NewRecord(fields) => {
+                self.write_registers(Opcode::NewRecord, &[target]);
+                self.w.write_u16(*fields);
+            }
+            Instruction::NewString(s) => {
+                let k = u16::try_from(heap::add_string_constant(&mut self.w.constants, s))
+                    .map_err(|_| CompileError::TooManyConstants)?;
+                self.write_registers(Opcode::NewString, &[target]);
+                self.w.write_u16(k);
+            }
+            Instruction::Get(object, index) => self.binary(Opcode::Get, object, index, target)?,
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => {
+                let value = self.set(object, index, value)?;
+                self.write_move(value, target);
+            }
+            Instruction::Len(object) => self.unary(Opcode::Len, object, target)?,
+            Instruction::CallNative {
+                function,
+                arguments,
+            } => {
+                let argc =
+                    u8::try_from(arguments.len()).map_err(|_| CompileError::TooManyArguments)?;
+                // The arguments are evaluated into fresh temporaries, so none of them can be
+                // overwritten by a later one. Without arguments any register will do.
+                let mark = self.temps;
+                let first = if argc == 0 { target } else { self.temps as u16 };
+                for _ in 0..argc {
+                    self.alloc_temp()?;
+                }
+                for (i, argument) in arguments.iter().enumerate() {
+                    self.value_into(argument, first + i as u16)?;
+                }
+                self.temps = mark;
+                self.write_registers(Opcode::CallNative, &[first, target]);
+                self.w.write_u8(argc);
+                self.w.write_u16(*function);
+            }
+            Instruction::Yield(value) => self.unary(Opcode::Yield, value, target)?,
+            Instruction::Coroutine(body) => {
+                // The body runs in its own copy of the registers, so it may use any of them.
+                let start = self.w.label();
+                let end = self.w.label();
+                self.w.write_new_coroutine(end, target);
+                self.w.place(start);
+                self.handlers.enter_coroutine();
+                let mark = self.temps;
+                let result = self.value(body)?;
+                self.temps = mark;
+                self.write_registers(Opcode::Return, &[result]);
+                self.handlers.leave_coroutine(start, end);
+                self.w.place(end);
+            }
+            Instruction::Resume(coroutine, value) => {
+                self.binary(Opcode::Resume, coroutine, value, target)?
+            }
+            Instruction::Sequence(s) => match s.split_last() {
+                Some((last, init)) => {
+                    for insn in init {
+                        self.effect(insn)?;
+                    }
+                    self.value_into(last, target)?;
+                }
+                None => self.value_into(&Instruction::Int(0), target)?,
+            },
+            Instruction::While { .. } if !self.is_temp(target) => {
+                // The loop's result is updated on every iteration, so it must not be stored in
+                // a variable that the loop itself might read.
+                let mark = self.temps;
+                let temp = self.value(insn)?;
+                self.temps = mark;
+                self.write_move(temp, target);
+            }
+            Instruction::While { condition, body } => {
+                // The value of a loop is the value of its body in the last iteration.
+                self.value_into(&Instruction::Int(0), target)?;
+                let loop_start = self.w.label();
+                let loop_end = self.w.label();
+                self.w.place(loop_start);
+                let mark = self.temps;
+                let condition = self.value(condition)?;
+                self.temps = mark;
+                self.w
+                    .write_jump(Opcode::JumpIfNot, loop_end, Some(condition));
+                self.value_into(body, target)?;
+                self.w.write_jump(Opcode::Jump, loop_start, None);
+                self.w.place(loop_end);
+            }
+            Instruction::Try {
+                body,
+                variable,
+                handler,
+            } => self.try_catch(body, *variable, handler, Some(target))?,
+            // Nothing runs after the throw, so `target` is left alone.
+            Instruction::Throw(value) => self.throw(value)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles a `Try`, storing its value in `target` if there is one. The body only writes
+    /// `target` once it's done, so a throw leaves it alone. The handler receives the thrown
+    /// value in the variable's register.
+    fn try_catch(
+        &mut self,
+        body: &Instruction,
+        variable: u16,
+        handler: &Instruction,
+        target: Option<u16>,
+    ) -> Result<(), CompileError> {
+        let [start, end, handler_start, done] = [(); 4].map(|()| self.w.label());
+        let mark = self.handlers.mark();
+        self.w.place(start);
+        match target {
+            Some(target) => self.value_into(body, target)?,
+            None => self.effect(body)?,
+        }
+        self.w.place(end);
+        self.w.write_jump(Opcode::Jump, done, None);
+
+        self.w.place(handler_start);
+        self.handlers
+            .add(mark, start, end, handler_start, variable as u32);
+        match target {
+            Some(target) => self.value_into(handler, target)?,
+            None => self.effect(handler)?,
+        }
+        self.w.place(done);
+        Ok(())
+    }
+
+    fn throw(&mut self, value: &Instruction) -> Result<(), CompileError> {
+        let mark = self.temps;
+        let source = self.value(value)?;
+        self.temps = mark;
+        self.write_registers(Opcode::Throw, &[source]);
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        opcode: Opcode,
+        a: &Instruction,
+        b: &Instruction,
+        target: u16,
+    ) -> Result<(), CompileError> {
+        let mark = self.temps;
+        let mut ra = self.value(a)?;
+        // `a` may have evaluated to a variable that `b` assigns to, in which case its value
+        // needs to be copied before it's overwritten.
+        if !self.is_temp(ra) && b.assigns(ra) {
+            let temp = self.alloc_temp()?;
+            self.write_move(ra, temp);
+            ra = temp;
+        }
+        let rb = self.value(b)?;
+        self.temps = mark;
+        self.write_registers(opcode, &[ra, rb, target]);
+        Ok(())
+    }
+
+    fn unary(&mut self, opcode: Opcode, a: &Instruction, target: u16) -> Result<(), CompileError> {
+        let mark = self.temps;
+        let ra = self.value(a)?;
+        self.temps = mark;
+        self.write_registers(opcode, &[ra, target]);
+        Ok(())
+    }
+
+    /// Compiles a store, and returns the register holding the stored value. The register may be
+    /// a temporary that has already been freed, so it must be read straight away.
+    fn store(
+        &mut self,
+        width: Width,
+        address: &Instruction,
+        value: &Instruction,
+    ) -> Result<u16, CompileError> {
+        let opcode = match width {
+            Width::U8 => Opcode::Store8,
+            Width::U16 => Opcode::Store16,
+            Width::U32 => Opcode::Store32,
+        };
+        let mark = self.temps;
+        let mut ra = self.value(address)?;
+        if !self.is_temp(ra) && value.assigns(ra) {
+            let temp = self.alloc_temp()?;
+            self.write_move(ra, temp);
+            ra = temp;
+        }
+        let rb = self.value(value)?;
+        self.temps = mark;
+        self.write_registers(opcode, &[ra, rb]);
+        Ok(rb)
+    }
+
+    /// Compiles a write to an object, and returns the register holding the value written, like
+    /// [`Compiler::store`].
+    fn set(
+        &mut self,
+        object: &Instruction,
+        index: &Instruction,
+        value: &Instruction,
+    ) -> Result<u16, CompileError> {
+        let mark = self.temps;
+        let mut ra = self.value(object)?;
+        if !self.is_temp(ra) && (index.assigns(ra) || value.assigns(ra)) {
+            let temp = self.alloc_temp()?;
+            self.write_move(ra, temp);
+            ra = temp;
+        }
+        let mut rb = self.value(index)?;
+        if !self.is_temp(rb) && value.assigns(rb) {
+            let temp = self.alloc_temp()?;
+            self.write_move(rb, temp);
+            rb = temp;
+        }
+        let rc = self.value(value)?;
+        self.temps = mark;
+        self.write_registers(Opcode::Set, &[ra, rb, rc]);
+        Ok(rc)
+    }
+}
+
+/// Compiles a program to bytecode that [`execute`] can run. Each variable of the program is
+/// mapped to the register of the same number.
+pub fn compile(program: &Instruction) -> Result<Chunk, CompileError> {
+    let variables = program.variable_count();
+    let mut c = Compiler {
+        w: Writer::default(),
+        variables,
+        temps: variables,
+        slots: variables,
+        handlers: exception::Table::default(),
+    };
+
+    let result = c.value(program)?;
+    c.write_registers(Opcode::Return, &[result]);
+    c.w.write_opcode(Opcode::Halt);
+
+    let linked = c.w.link()?;
+    if linked.code.len() > u32::MAX as usize {
+        return Err(CompileError::CodeTooLarge);
+    }
+    Ok(Chunk {
+        handlers: c.handlers.resolve(|label| linked.position(label), 0),
+        code: linked.code,
+        constants: c.w.constants,
+        slots: c.slots,
+    })
 }
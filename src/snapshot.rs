@@ -0,0 +1,263 @@
+//! Snapshots of suspended runs, so that a run stopped by its [fuel][crate::fuel] or a yield can
+//! be saved and resumed later, even in another process. A snapshot holds the frame of the run:
+//! where it stopped, its variables and temporaries, and its stack pointer or result so far. The
+//! linear memory and the heap belong to the embedder, so a snapshot cannot hold references to
+//! objects. It records the checksum of the code it was taken of, which resuming checks, so it
+//! can only carry on with the same code, compiled by the same version.
+//!
+//! All integers are little endian. A snapshot is laid out as follows:
+//!
+//! ```text
+//! magic     [u8; 4]   "DSPS"
+//! version   u16       must be equal to module::VERSION
+//! backend   u8        the encoding of the code, see module::Backend
+//! stopped   u8        0 after a yield, 1 at a backward jump it hasn't paid for, 2 at a breakpoint
+//! code      u32       the checksum of the code, as the backend's `checksum` computes it
+//! pc        u32
+//! sp        u32       the stack pointer of the stack backends
+//! result    value     the result so far of the register backends
+//! frame     u32 count, followed by that many values
+//! checksum  u32       FNV-1a of everything above
+//! ```
+//!
+//! A value is a tag byte, followed by a payload depending on the tag: 0 for nil, with no payload,
+//! 1 for a boolean, with a byte, 2 for an integer, with a `u32`, and 3 for a float, with the
+//! `u64` of its bits.
+
+use std::{
+    error, fmt,
+    io::{self, Read, Write},
+};
+
+use crate::{
+    fuel::{State, StoppedAt, Suspended},
+    module::{self, fnv1a, Backend, Reader, Truncated},
+    value::{Unpacked, Value},
+};
+
+pub const MAGIC: [u8; 4] = *b"DSPS";
+
+impl Suspended {
+    /// Fails if the frame holds a reference to an object on the heap, or the run stopped in a
+    /// coroutine, which is one.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SnapshotError> {
+        if !self.state.running.is_empty() {
+            return Err(SnapshotError::HeapReference);
+        }
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&module::VERSION.to_le_bytes());
+        bytes.push(self.backend as u8);
+        bytes.push(self.at as u8);
+        bytes.extend_from_slice(&self.code.to_le_bytes());
+        bytes.extend_from_slice(&self.state.pc.to_le_bytes());
+        bytes.extend_from_slice(&(self.state.sp as u32).to_le_bytes());
+        write_value(&mut bytes, self.state.result)?;
+
+        bytes.extend_from_slice(&(self.frame.len() as u32).to_le_bytes());
+        for &value in &self.frame {
+            write_value(&mut bytes, value)?;
+        }
+
+        let checksum = fnv1a(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut r = Reader { bytes, pos: 0 };
+
+        if r.take(4).map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != module::VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let Some(body_len) = bytes.len().checked_sub(4) else {
+            return Err(SnapshotError::Truncated);
+        };
+        let (body, checksum) = bytes.split_at(body_len);
+        let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+        let actual = fnv1a(body);
+        if expected != actual {
+            return Err(SnapshotError::ChecksumMismatch { expected, actual });
+        }
+        r.bytes = body;
+
+        let backend = r.u8()?;
+        let backend = match Backend::from_u8(backend) {
+            Some(Backend::CompactTreewalk) => {
+                return Err(SnapshotError::Malformed(
+                    "compact treewalk runs cannot be suspended",
+                ))
+            }
+            Some(backend) => backend,
+            None => return Err(SnapshotError::UnknownBackend(backend)),
+        };
+        let at = match r.u8()? {
+            0 => StoppedAt::Yield,
+            1 => StoppedAt::Jump,
+            2 => StoppedAt::Breakpoint,
+            _ => return Err(SnapshotError::Malformed("invalid stopping point")),
+        };
+        let code = r.u32()?;
+        let pc = r.u32()?;
+        let sp = r.u32()? as usize;
+        let result = read_value(&mut r)?;
+
+        let count = r.u32()?;
+        if count > 1 << 16 {
+            return Err(SnapshotError::Malformed(
+                "more slots than operands can address",
+            ));
+        }
+        let frame = (0..count)
+            .map(|_| read_value(&mut r))
+            .collect::<Result<Vec<_>, _>>()?;
+        if sp > frame.len() {
+            return Err(SnapshotError::Malformed(
+                "stack pointer outside of the frame",
+            ));
+        }
+
+        if r.pos != body.len() {
+            return Err(SnapshotError::Malformed("trailing data after frame"));
+        }
+
+        Ok(Self {
+            backend,
+            code,
+            at,
+            state: State {
+                pc,
+                sp,
+                result,
+                running: vec![],
+            },
+            frame,
+        })
+    }
+
+    /// Fails unless the snapshot was taken of code with the given checksum, as the backend's
+    /// `checksum` computes it.
+    pub fn check_code(&self, checksum: u32) -> Result<(), SnapshotError> {
+        if self.code != checksum {
+            return Err(SnapshotError::DifferentCode {
+                expected: checksum,
+                actual: self.code,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, mut writer: impl Write) -> Result<(), SnapshotError> {
+        Ok(writer.write_all(&self.to_bytes()?)?)
+    }
+
+    pub fn load(mut reader: impl Read) -> Result<Self, SnapshotError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+fn write_value(bytes: &mut Vec<u8>, value: Value) -> Result<(), SnapshotError> {
+    match value.unpack() {
+        Unpacked::Nil => bytes.push(0),
+        Unpacked::Bool(b) => bytes.extend_from_slice(&[1, b as u8]),
+        Unpacked::Int(i) => {
+            bytes.push(2);
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        Unpacked::Float(f) => {
+            bytes.push(3);
+            bytes.extend_from_slice(&f.to_bits().to_le_bytes());
+        }
+        Unpacked::Ref(_) => return Err(SnapshotError::HeapReference),
+    }
+    Ok(())
+}
+
+fn read_value(r: &mut Reader) -> Result<Value, SnapshotError> {
+    Ok(match r.u8()? {
+        0 => Value::NIL,
+        1 => match r.u8()? {
+            0 => Value::bool(false),
+            1 => Value::bool(true),
+            _ => return Err(SnapshotError::Malformed("invalid boolean")),
+        },
+        2 => Value::int(r.u32()?),
+        3 => Value::float(f64::from_bits(r.u64()?)),
+        _ => return Err(SnapshotError::Malformed("invalid value tag")),
+    })
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The frame refers to an object on the heap, which the snapshot cannot hold.
+    HeapReference,
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownBackend(u8),
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The snapshot was taken of code with a different checksum.
+    DifferentCode {
+        expected: u32,
+        actual: u32,
+    },
+    Truncated,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{error}"),
+            Self::HeapReference => write!(f, "the run holds a reference to a heap object"),
+            Self::BadMagic => write!(f, "not a snapshot file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "snapshot has format version {version}, but only version {} is supported",
+                module::VERSION
+            ),
+            Self::UnknownBackend(backend) => write!(f, "unknown backend {backend}"),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch (expected {expected:08x}, got {actual:08x})"
+            ),
+            Self::DifferentCode { expected, actual } => write!(
+                f,
+                "snapshot was taken of different code (checksum {actual:08x}, not {expected:08x})"
+            ),
+            Self::Truncated => write!(f, "snapshot is truncated"),
+            Self::Malformed(reason) => write!(f, "malformed snapshot: {reason}"),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<Truncated> for SnapshotError {
+    fn from(_: Truncated) -> Self {
+        Self::Truncated
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
@@ -1,30 +1,107 @@
+use crate::{
+    bytecode::{with_slots, Chunk},
+    coroutine::{self, Registers},
+    exception::{self, Handler, DIVISION_BY_ZERO},
+    fuel::{self, Fuel, Meter, State, Stop, Suspended, VmError},
+    heap::{self, Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    module,
+    profile::Profiler,
+    stack_switch::starts,
+    value::Value,
+};
+
+/// `stack (dtable)` shares its encoding with `stack (switch)`, so it reuses its tooling.
+pub use crate::stack_switch::{checksum, compile, disassemble, verify};
+
+// Must be kept in sync with `stack_switch::Opcode`, whose compiler emits the opcodes that
+// `code` doesn't use.
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 enum Opcode {
     Int,
+    SmallInt,
+    Const8,
+    Const16,
+    Float,
 
     Let,
     Var,
+    Tee,
+    Reserve,
 
     LessEq,
     Add,
+    AddInt,
+    AddFloat,
     Multiply,
+    Subtract,
+    Divide,
+    Remainder,
+
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+    IntToFloat,
+    FloatToInt,
+
+    Load8,
+    Load16,
+    Load32,
+    Store8,
+    Store16,
+    Store32,
+
+    NewArray,
+    NewRecord,
+    NewString,
+    Get,
+    Set,
+    Len,
+
+    CallNative,
+    Yield,
+    NewCoroutine,
+    Resume,
+    Throw,
 
+    JumpIfNot8,
+    Jump8,
     JumpIfNot,
     Jump,
+    Wide,
     Halt,
 }
 
 struct Frame<'c> {
-    stack: [u32; 256],
+    stack: &'c mut [Value],
     sp: usize,
+    memory: &'c mut [u8],
+    heap: &'c mut Heap,
+    host: &'c Host,
+    meter: &'c Meter<'c>,
+    /// Where the meter stopped the run, and why.
+    stopped: Option<(Stop, u32)>,
     bytecode: &'c [u8],
-    pc: u16,
+    /// The code with a `Halt` over every breakpoint, which a run resumed at a breakpoint
+    /// switches to once it has run the instruction under it.
+    armed: Option<&'c [u8]>,
+    constants: &'c [u32],
+    handlers: &'c [Handler],
+    pc: u32,
+    /// The coroutines that are running, innermost last, which hold the frames that resumed them.
+    running: Vec<Value>,
+    profiler: Profiler,
 }
 
 impl<'c> Frame<'c> {
     fn read_u8(&mut self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         let x = *unsafe { self.bytecode.get_unchecked(self.pc as usize) };
         self.pc += 1;
         x
@@ -32,13 +109,15 @@ impl<'c> Frame<'c> {
 
     fn peek_u8(&self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         *unsafe { self.bytecode.get_unchecked(self.pc as usize) }
     }
 
     fn read_u16(&mut self) -> u16 {
         debug_assert!((self.pc as usize + 1) < self.bytecode.len());
+        self.profiler.read_bytecode(2);
         let x = unsafe {
-            *std::mem::transmute::<_, &u16>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u16>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 2;
         u16::from_le(x)
@@ -46,64 +125,302 @@ impl<'c> Frame<'c> {
 
     fn read_u32(&mut self) -> u32 {
         debug_assert!((self.pc as usize + 3) < self.bytecode.len());
+        self.profiler.read_bytecode(4);
         let x = unsafe {
-            *std::mem::transmute::<_, &u32>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u32>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 4;
         u32::from_le(x)
     }
 
-    fn var(&self, i: u8) -> u32 {
+    fn read_u64(&mut self) -> u64 {
+        debug_assert!((self.pc as usize + 7) < self.bytecode.len());
+        self.profiler.read_bytecode(8);
+        let x = unsafe {
+            *std::mem::transmute::<&u8, &u64>(self.bytecode.get_unchecked(self.pc as usize))
+        };
+        self.pc += 8;
+        u64::from_le(x)
+    }
+
+    fn constant(&self, i: u16) -> u32 {
+        debug_assert!((i as usize) < self.constants.len());
+        self.profiler.read_bytecode(4);
+        *unsafe { self.constants.get_unchecked(i as usize) }
+    }
+
+    fn var(&self, i: u16) -> Value {
         debug_assert!((i as usize) < self.stack.len());
+        self.profiler.read_variable();
         unsafe { *self.stack.get_unchecked(i as usize) }
     }
 
-    fn set_var(&mut self, i: u8, val: u32) {
+    fn set_var(&mut self, i: u16, val: Value) {
         debug_assert!((i as usize) < self.stack.len());
+        self.profiler.write_variable();
         unsafe {
             *self.stack.get_unchecked_mut(i as usize) = val;
         }
     }
 
-    fn push(&mut self, x: u32) {
+    fn push(&mut self, x: Value) {
+        self.profiler.write_stack();
         unsafe {
             *self.stack.get_unchecked_mut(self.sp) = x;
         }
         self.sp += 1;
     }
 
-    fn pop(&mut self) -> u32 {
+    fn pop(&mut self) -> Value {
+        self.profiler.read_stack();
         let x = *unsafe { self.stack.get_unchecked(self.sp - 1) };
         self.sp -= 1;
         x
     }
+
+    fn load(&mut self, width: Width) {
+        let address = self.pop().as_int();
+        self.push(Value::int(memory::load(self.memory, address, width)));
+        self.dump();
+    }
+
+    fn store(&mut self, width: Width) {
+        let value = self.pop().as_int();
+        let address = self.pop().as_int();
+        memory::store(self.memory, address, width, value);
+        self.dump();
+    }
+
+    /// Allocates an object and pushes it. The stack, including the variables at its bottom, and
+    /// the running coroutines are the garbage collector's roots.
+    fn alloc(&mut self, object: Object) {
+        let object = self
+            .heap
+            .alloc(object, &[&self.stack[..self.sp], &self.running]);
+        self.push(object);
+        self.dump();
+    }
+
+    /// Calls a host function with the arguments on top of the stack, which stay roots until it
+    /// returns, and replaces them with its result.
+    fn call_native(&mut self, function: u16, argc: usize) {
+        for _ in 0..argc {
+            self.profiler.read_stack();
+        }
+        let base = self.sp - argc;
+        let (roots, args) = self.stack[..self.sp].split_at(base);
+        let result = self.host.call(
+            function,
+            &mut Call::new(args, self.heap, &[roots, &self.running]),
+        );
+        self.sp = base;
+        self.push(result);
+        self.dump();
+    }
+
+    /// Pushes a new coroutine whose body starts at the next instruction, with a copy of the
+    /// frame, and jumps past the body.
+    fn new_coroutine(&mut self, offset: i32) {
+        let registers = self.registers();
+        let roots = [&self.stack[..self.sp], &self.running];
+        let coroutine = coroutine::new(self.heap, self.stack, registers, &roots);
+        self.push(coroutine);
+        self.pc = self.pc.wrapping_add_signed(offset);
+    }
+
+    fn resume(&mut self) {
+        let value = self.pop();
+        let coroutine = self.pop();
+        let mut registers = self.registers();
+        let yielded = coroutine::resume(self.heap, coroutine, self.stack, &mut registers);
+        self.running.push(coroutine);
+        self.set_registers(registers);
+        if yielded {
+            self.push(value);
+        }
+    }
+
+    /// Hands the value on top of the stack back to whoever resumed the running coroutine, at a
+    /// yield or once its body has `returned`.
+    fn suspend(&mut self, returned: bool) {
+        let value = self.pop();
+        let coroutine = self.running.pop().expect("a coroutine is running");
+        let mut registers = self.registers();
+        coroutine::suspend(self.heap, coroutine, self.stack, &mut registers, returned);
+        self.set_registers(registers);
+        self.push(value);
+    }
+
+    fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp as u32,
+            target: 0,
+        }
+    }
+
+    fn set_registers(&mut self, registers: Registers) {
+        self.pc = registers.pc;
+        self.sp = registers.sp as usize;
+    }
+
+    /// Throws a value from the instruction just executed to its handler, cutting the stack back
+    /// to the handler's depth and pushing the value. A coroutine without a handler for it dies,
+    /// and the `Resume` running it throws the value again. Nothing else catching it stops the
+    /// run.
+    #[cold]
+    #[inline(never)]
+    fn throw(&mut self, value: Value) {
+        loop {
+            if let Some(handler) = exception::find(self.handlers, self.pc - 1) {
+                self.sp = handler.depth as usize;
+                self.pc = handler.target;
+                self.push(value);
+                return;
+            }
+            if self.running.is_empty() {
+                self.stop(Stop::Uncaught(value));
+                return;
+            }
+            self.push(value);
+            self.suspend(true);
+            self.pop();
+        }
+    }
 }
 
-static DISPATCH_TABLE: [fn(&mut Frame); 8] = [
+static HALT: [u8; 1] = [Opcode::Halt as u8];
+
+static DISPATCH_TABLE: [fn(&mut Frame); 46] = [
     exec_int,
+    exec_small_int,
+    exec_const8,
+    exec_const16,
+    exec_float,
     exec_let,
     exec_var,
+    exec_tee,
+    exec_reserve,
     exec_less_eq,
     exec_add,
+    // Code isn't quickened here, but it may have been by `stack (switch)`.
+    exec_add,
+    exec_add,
     exec_multiply,
+    exec_subtract,
+    exec_divide,
+    exec_remainder,
+    exec_f_less,
+    exec_f_add,
+    exec_f_multiply,
+    exec_f_subtract,
+    exec_f_divide,
+    exec_int_to_float,
+    exec_float_to_int,
+    exec_load8,
+    exec_load16,
+    exec_load32,
+    exec_store8,
+    exec_store16,
+    exec_store32,
+    exec_new_array,
+    exec_new_record,
+    exec_new_string,
+    exec_get,
+    exec_set,
+    exec_len,
+    exec_call_native,
+    exec_yield,
+    exec_new_coroutine,
+    exec_resume,
+    exec_throw,
+    exec_jump_if_not8,
+    exec_jump8,
     exec_jump_if_not,
     exec_jump,
+    exec_wide,
 ];
 
 impl<'c> Frame<'c> {
     fn step(&mut self) {
         // print!("{:4} | ", self.pc);
+        let pc = self.pc;
         let opcode = self.read_u8();
-        // println!("{:?}", unsafe { std::mem::transmute::<_, Opcode>(opcode) });
+        // println!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) });
+        self.profiler.enter(pc, opcode);
         unsafe { DISPATCH_TABLE.get_unchecked(opcode as usize)(self) };
+        self.profiler.leave();
+    }
+
+    /// Runs until `Halt`, or until the meter stops it at a backward jump or a breakpoint.
+    fn eval(&mut self) -> Result<(), Stop> {
+        // Resumed at a breakpoint, the run carries on with the instruction under it in the code
+        // without breakpoints, and switches to the code with them straight after.
+        if let Some(armed) = self.armed.take() {
+            match self.peek_u8() {
+                opcode if opcode != Opcode::Halt as u8 => self.step(),
+                _ if self.running.is_empty() => return Ok(()),
+                _ => {
+                    self.pc += 1;
+                    self.suspend(true);
+                }
+            }
+            if self.stopped.is_none() {
+                self.bytecode = armed;
+            }
+        }
+        loop {
+            while self.peek_u8() != Opcode::Halt as u8 {
+                self.step();
+            }
+            if self.stopped.is_none() && self.meter.is_breakpoint(self.pc) {
+                self.stopped = Some((Stop::Breakpoint, self.pc));
+            }
+            if self.stopped.is_some() || self.running.is_empty() {
+                break;
+            }
+            // The end of a coroutine's body.
+            self.pc += 1;
+            self.suspend(true);
+        }
+        match self.stopped.take() {
+            Some((stop, pc)) => {
+                self.pc = pc;
+                Err(stop)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Jumps by `offset` from the end of the instruction. Jumping backward costs fuel, and when
+    /// the meter stops the run, the frame switches to code that halts straight away, so that
+    /// the dispatch loop needn't check for it.
+    #[inline(always)]
+    fn jump(&mut self, offset: i32) {
+        self.pc = self.pc.wrapping_add_signed(offset);
+        if offset < 0 {
+            if let Err(stop) = self.meter.tick() {
+                self.stop(stop);
+            }
+        }
     }
 
-    fn eval(&mut self) {
-        while self.peek_u8() != Opcode::Halt as u8 {
-            self.step();
+    /// Stops a metered run at a `Yield`, like a jump without fuel.
+    #[inline(always)]
+    fn yield_value(&mut self, value: Value) {
+        if let Err(stop) = self.meter.yield_value(value) {
+            self.stop(stop);
         }
     }
 
+    #[cold]
+    #[inline(never)]
+    fn stop(&mut self, stop: Stop) {
+        self.stopped = Some((stop, self.pc));
+        self.bytecode = &HALT;
+        self.pc = 0;
+    }
+
     fn dump(&self) {
         // println!("{:?}", &self.stack[0..self.sp]);
     }
@@ -111,28 +428,67 @@ impl<'c> Frame<'c> {
 
 fn exec_int(frame: &mut Frame) {
     let i = frame.read_u32();
-    frame.push(i);
+    frame.push(Value::int(i));
+    frame.dump();
+}
+
+fn exec_small_int(frame: &mut Frame) {
+    let i = frame.read_u8();
+    frame.push(Value::int(i as u32));
+    frame.dump();
+}
+
+fn exec_const8(frame: &mut Frame) {
+    let i = frame.read_u8();
+    let k = frame.constant(i as u16);
+    frame.push(Value::int(k));
+    frame.dump();
+}
+
+fn exec_const16(frame: &mut Frame) {
+    let i = frame.read_u16();
+    let k = frame.constant(i);
+    frame.push(Value::int(k));
+    frame.dump();
+}
+
+fn exec_float(frame: &mut Frame) {
+    let f = f64::from_bits(frame.read_u64());
+    frame.push(Value::float(f));
     frame.dump();
 }
 
 fn exec_let(frame: &mut Frame) {
     let i = frame.read_u8();
     let val = frame.pop();
-    frame.set_var(i, val);
+    frame.set_var(i as u16, val);
     frame.dump();
 }
 
 fn exec_var(frame: &mut Frame) {
     let i = frame.read_u8();
-    let val = frame.var(i);
+    let val = frame.var(i as u16);
     frame.push(val);
     frame.dump();
 }
 
+fn exec_tee(frame: &mut Frame) {
+    let i = frame.read_u8();
+    let val = frame.pop();
+    frame.push(val);
+    frame.set_var(i as u16, val);
+    frame.dump();
+}
+
+fn exec_reserve(frame: &mut Frame) {
+    let n = frame.read_u8();
+    frame.sp = n as usize;
+}
+
 fn exec_less_eq(frame: &mut Frame) {
-    let b = frame.pop();
-    let a = frame.pop();
-    frame.push((a <= b) as u32);
+    let b = frame.pop().as_int();
+    let a = frame.pop().as_int();
+    frame.push(Value::int((a <= b) as u32));
     frame.dump();
 }
 
@@ -144,23 +500,246 @@ fn exec_add(frame: &mut Frame) {
 }
 
 fn exec_multiply(frame: &mut Frame) {
-    let b = frame.pop();
-    let a = frame.pop();
-    frame.push(a * b);
+    let b = frame.pop().as_int();
+    let a = frame.pop().as_int();
+    frame.push(Value::int(a.wrapping_mul(b)));
+    frame.dump();
+}
+
+fn exec_subtract(frame: &mut Frame) {
+    let b = frame.pop().as_int();
+    let a = frame.pop().as_int();
+    frame.push(Value::int(a.wrapping_sub(b)));
+    frame.dump();
+}
+
+fn exec_divide(frame: &mut Frame) {
+    let b = frame.pop().as_int();
+    let a = frame.pop().as_int();
+    match a.checked_div(b) {
+        Some(quotient) => frame.push(Value::int(quotient)),
+        None => frame.throw(DIVISION_BY_ZERO),
+    }
     frame.dump();
 }
 
+fn exec_remainder(frame: &mut Frame) {
+    let b = frame.pop().as_int();
+    let a = frame.pop().as_int();
+    match a.checked_rem(b) {
+        Some(remainder) => frame.push(Value::int(remainder)),
+        None => frame.throw(DIVISION_BY_ZERO),
+    }
+    frame.dump();
+}
+
+fn exec_f_less(frame: &mut Frame) {
+    let b = frame.pop().as_float();
+    let a = frame.pop().as_float();
+    frame.push(Value::int((a < b) as u32));
+    frame.dump();
+}
+
+fn exec_f_add(frame: &mut Frame) {
+    let b = frame.pop().as_float();
+    let a = frame.pop().as_float();
+    frame.push(Value::float(a + b));
+    frame.dump();
+}
+
+fn exec_f_multiply(frame: &mut Frame) {
+    let b = frame.pop().as_float();
+    let a = frame.pop().as_float();
+    frame.push(Value::float(a * b));
+    frame.dump();
+}
+
+fn exec_f_subtract(frame: &mut Frame) {
+    let b = frame.pop().as_float();
+    let a = frame.pop().as_float();
+    frame.push(Value::float(a - b));
+    frame.dump();
+}
+
+fn exec_f_divide(frame: &mut Frame) {
+    let b = frame.pop().as_float();
+    let a = frame.pop().as_float();
+    frame.push(Value::float(a / b));
+    frame.dump();
+}
+
+fn exec_int_to_float(frame: &mut Frame) {
+    let a = frame.pop().as_int();
+    frame.push(Value::float(a as f64));
+    frame.dump();
+}
+
+fn exec_float_to_int(frame: &mut Frame) {
+    let a = frame.pop().as_float();
+    frame.push(Value::int(a as u32));
+    frame.dump();
+}
+
+fn exec_load8(frame: &mut Frame) {
+    frame.load(Width::U8);
+}
+
+fn exec_load16(frame: &mut Frame) {
+    frame.load(Width::U16);
+}
+
+fn exec_load32(frame: &mut Frame) {
+    frame.load(Width::U32);
+}
+
+fn exec_store8(frame: &mut Frame) {
+    frame.store(Width::U8);
+}
+
+fn exec_store16(frame: &mut Frame) {
+    frame.store(Width::U16);
+}
+
+fn exec_store32(frame: &mut Frame) {
+    frame.store(Width::U32);
+}
+
+fn exec_new_array(frame: &mut Frame) {
+    let len = frame.pop().as_int();
+    frame.alloc(Object::Array(vec![Value::int(0); len as usize]));
+}
+
+fn exec_new_record(frame: &mut Frame) {
+    let fields = frame.read_u16();
+    frame.alloc(Object::Record(vec![Value::int(0); fields as usize].into()));
+}
+
+fn exec_new_string(frame: &mut Frame) {
+    let i = frame.read_u16();
+    let string =
+        heap::read_string_constant(frame.constants, i as usize).expect("verified string constant");
+    frame.alloc(Object::String(string));
+}
+
+fn exec_get(frame: &mut Frame) {
+    let index = frame.pop().as_int();
+    let object = frame.pop();
+    frame.push(frame.heap.get(object, index));
+    frame.dump();
+}
+
+fn exec_set(frame: &mut Frame) {
+    let value = frame.pop();
+    let index = frame.pop().as_int();
+    let object = frame.pop();
+    frame.heap.set(object, index, value);
+    frame.dump();
+}
+
+fn exec_len(frame: &mut Frame) {
+    let object = frame.pop();
+    frame.push(Value::int(frame.heap.len(object)));
+    frame.dump();
+}
+
+fn exec_call_native(frame: &mut Frame) {
+    let function = frame.read_u16();
+    let argc = frame.read_u8();
+    frame.call_native(function, argc as usize);
+}
+
+fn exec_yield(frame: &mut Frame) {
+    if !frame.running.is_empty() {
+        return frame.suspend(false);
+    }
+    let value = frame.pop();
+    frame.push(value);
+    frame.yield_value(value);
+}
+
+fn exec_new_coroutine(frame: &mut Frame) {
+    let offset = frame.read_u16() as i16;
+    frame.new_coroutine(offset as i32);
+}
+
+fn exec_resume(frame: &mut Frame) {
+    frame.resume();
+}
+
+fn exec_throw(frame: &mut Frame) {
+    let value = frame.pop();
+    frame.throw(value);
+}
+
+fn exec_jump_if_not8(frame: &mut Frame) {
+    let offset = frame.read_u8() as i8;
+    let condition = frame.pop();
+    if !condition.is_truthy() {
+        frame.jump(offset as i32);
+    }
+}
+
+fn exec_jump8(frame: &mut Frame) {
+    let offset = frame.read_u8() as i8;
+    frame.jump(offset as i32);
+}
+
 fn exec_jump_if_not(frame: &mut Frame) {
-    let offset = frame.read_u16();
+    let offset = frame.read_u16() as i16;
     let condition = frame.pop();
-    if condition == 0 {
-        frame.pc = offset;
+    if !condition.is_truthy() {
+        frame.jump(offset as i32);
     }
 }
 
 fn exec_jump(frame: &mut Frame) {
-    let offset = frame.read_u16();
-    frame.pc = offset;
+    let offset = frame.read_u16() as i16;
+    frame.jump(offset as i32);
+}
+
+#[cold]
+fn exec_wide(frame: &mut Frame) {
+    match unsafe { std::mem::transmute::<u8, Opcode>(frame.read_u8()) } {
+        Opcode::Let => {
+            let i = frame.read_u16();
+            let val = frame.pop();
+            frame.set_var(i, val);
+            frame.dump();
+        }
+        Opcode::Var => {
+            let i = frame.read_u16();
+            let val = frame.var(i);
+            frame.push(val);
+            frame.dump();
+        }
+        Opcode::Tee => {
+            let i = frame.read_u16();
+            let val = frame.pop();
+            frame.push(val);
+            frame.set_var(i, val);
+            frame.dump();
+        }
+        Opcode::Reserve => {
+            let n = frame.read_u16();
+            frame.sp = n as usize;
+        }
+        Opcode::JumpIfNot => {
+            let offset = frame.read_u32() as i32;
+            let condition = frame.pop();
+            if !condition.is_truthy() {
+                frame.jump(offset);
+            }
+        }
+        Opcode::Jump => {
+            let offset = frame.read_u32() as i32;
+            frame.jump(offset);
+        }
+        Opcode::NewCoroutine => {
+            let offset = frame.read_u32() as i32;
+            frame.new_coroutine(offset);
+        }
+        _ => unsafe { std::hint::unreachable_unchecked() },
+    }
 }
 
 #[derive(Default)]
@@ -175,8 +754,8 @@ impl Writer {
         i
     }
 
-    fn pc(&self) -> u16 {
-        self.bytecode.len() as u16
+    fn pc(&self) -> u32 {
+        self.bytecode.len() as u32
     }
 
     fn write_u16(&mut self, x: u16) -> usize {
@@ -232,6 +811,7 @@ pub fn code() -> Vec<u8> {
 
     w.write_opcode(Opcode::JumpIfNot);
     let loop_jump_hole = w.write_u16(0);
+    let loop_jump_base = w.pc();
 
     // x = x * i
     {
@@ -262,26 +842,184 @@ pub fn code() -> Vec<u8> {
     }
 
     w.write_opcode(Opcode::Jump);
-    w.write_u16(loop_start);
+    w.write_u16((loop_start as i32 - (w.pc() as i32 + 2)) as u16);
 
     let loop_end = w.pc();
     w.write_opcode(Opcode::Var);
     w.write_u8(VAR_X);
 
-    w.patch_u16(loop_jump_hole, loop_end);
+    w.patch_u16(loop_jump_hole, (loop_end - loop_jump_base) as u16);
     w.write_opcode(Opcode::Halt);
 
     w.bytecode
 }
 
 pub fn run(code: &[u8]) -> u32 {
+    let mut stack = [Value::int(0); 256];
+    let mut frame = Frame {
+        stack: &mut stack,
+        sp: 0,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        bytecode: code,
+        armed: None,
+        constants: &[],
+        handlers: &[],
+        pc: 0,
+        running: vec![],
+        profiler: Profiler::new(),
+    };
+    frame.push(Value::int(10));
+    frame.eval().expect("unmetered runs don't stop");
+    frame.pop().as_int()
+}
+
+#[cfg(feature = "profile")]
+pub fn profile(code: &[u8]) -> crate::profile::Profile {
+    let mut stack = [Value::int(0); 256];
     let mut frame = Frame {
-        stack: [0; 256],
+        stack: &mut stack,
         sp: 0,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
         bytecode: code,
+        armed: None,
+        constants: &[],
+        handlers: &[],
         pc: 0,
+        running: vec![],
+        profiler: Profiler::new(),
     };
-    frame.push(10);
-    frame.eval();
-    frame.pop()
+    frame.push(Value::int(10));
+    frame.eval().expect("unmetered runs don't stop");
+    frame.profiler.finish("stack (dtable)", |opcode| {
+        format!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) })
+    })
+}
+
+/// See [`stack_switch::execute`][crate::stack_switch::execute].
+pub fn execute(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Result<Value, VmError> {
+    execute_with(code, variables, memory, heap, host, Profiler::new()).0
+}
+
+pub fn execute_with(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    profiler: Profiler,
+) -> (Result<Value, VmError>, Profiler) {
+    with_slots(code.slots, variables, |stack| {
+        let mut frame = Frame {
+            stack,
+            sp: 0,
+            memory,
+            heap,
+            host,
+            meter: &Meter::unlimited(),
+            stopped: None,
+            bytecode: &code.code,
+            armed: None,
+            constants: &code.constants,
+            handlers: &code.handlers,
+            pc: 0,
+            running: vec![],
+            profiler,
+        };
+        let result = fuel::unmetered(frame.eval()).map(|()| frame.pop());
+        (result, frame.profiler)
+    })
+}
+
+/// See [`stack_switch::execute_metered`][crate::stack_switch::execute_metered].
+pub fn execute_metered(
+    code: &Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    execute_from(code, None, variables, memory, heap, host, fuel)
+}
+
+/// See [`stack_switch::resume`][crate::stack_switch::resume].
+pub fn resume(
+    code: &Chunk,
+    suspended: Suspended,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    assert!(
+        (suspended.pc() as usize) < code.code.len(),
+        "resumed with different code"
+    );
+    execute_from(code, Some(suspended), variables, memory, heap, host, fuel)
+}
+
+fn execute_from(
+    code: &Chunk,
+    suspended: Option<Suspended>,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    fuel::run(
+        module::Backend::Stack,
+        checksum(code),
+        code.slots,
+        variables,
+        fuel,
+        suspended,
+        |stack, meter, state| {
+            let armed = meter.armed(&code.code, Opcode::Halt as u8, starts);
+            let stepping = meter.stepping();
+            let mut frame = Frame {
+                stack,
+                sp: state.sp,
+                memory,
+                heap,
+                host,
+                meter,
+                stopped: None,
+                bytecode: if stepping { &code.code } else { &armed },
+                armed: stepping.then_some(&armed),
+                constants: &code.constants,
+                handlers: &code.handlers,
+                pc: state.pc,
+                running: state.running,
+                profiler: Profiler::new(),
+            };
+            match frame.eval() {
+                Ok(()) => Ok(frame.pop()),
+                Err(stop) => Err((
+                    stop,
+                    State {
+                        pc: frame.pc,
+                        sp: frame.sp,
+                        running: frame.running,
+                        ..state
+                    },
+                )),
+            }
+        },
+    )
 }
@@ -1,30 +1,133 @@
+use std::collections::HashMap;
+
+use crate::{
+    bytecode::{
+        sign_extend, with_slots, Chunk, CompileError, Disassembly, Jumps, Label, Linked, Reach,
+        VerifyError,
+    },
+    coroutine::{self, Registers},
+    exception::{self, Handler, DIVISION_BY_ZERO},
+    fuel::{self, Fuel, Meter, Patched, State, Stop, Suspended, VmError},
+    heap::{self, Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    module::{self, Module},
+    profile::Profiler,
+    treewalk::Instruction,
+    value::Value,
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 enum Opcode {
     Int,
+    /// Pushes its one-byte operand, for the many small integers in programs.
+    SmallInt,
+    /// Push the constant at a one- or two-byte index in the constant pool.
+    Const8,
+    Const16,
+    /// Pushes its eight-byte operand.
+    Float,
 
     Let,
     Var,
+    /// Stores the value on top of the stack in a variable without popping it, like a `Let`
+    /// followed by a `Var` of the same variable. Only [`optimize`] emits it.
+    Tee,
+    Reserve,
 
     LessEq,
+    /// Adds integers or floats. It rewrites itself into `AddInt` or `AddFloat` for the type it
+    /// sees, and those rewrite themselves back when they see the other type.
     Add,
+    AddInt,
+    AddFloat,
     Multiply,
+    Subtract,
+    Divide,
+    Remainder,
+
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+    IntToFloat,
+    FloatToInt,
+
+    /// Pop an address and push the integer in linear memory at it.
+    Load8,
+    Load16,
+    Load32,
+    /// Pop a value and an address below it, and store the integer at the address.
+    Store8,
+    Store16,
+    Store32,
+
+    /// Pops a length and pushes a new array of that many elements.
+    NewArray,
+    /// Pushes a new record, with as many fields as its two-byte operand.
+    NewRecord,
+    /// Pushes a new string, copied from the constant at its two-byte operand.
+    NewString,
+    /// Pops an index and an object below it, and pushes its element, field or byte.
+    Get,
+    /// Pops a value, an index and an object, and writes the value to the element or field.
+    Set,
+    Len,
+
+    /// Pops as many arguments as its one-byte operand, calls the host function at the two-byte
+    /// operand before it, and pushes the result.
+    CallNative,
+    /// Stops a metered run, yielding the value on top of the stack, which it leaves there. In a
+    /// coroutine, it pops the value and hands it to whoever resumed the coroutine instead, and
+    /// pushes the value it's resumed with.
+    Yield,
+    /// Pushes a new coroutine, whose body follows, and jumps past the body by a two-byte offset,
+    /// or a four-byte one after `Wide`.
+    NewCoroutine,
+    /// Pops a value and a coroutine below it, and resumes the coroutine with the value.
+    Resume,
+    /// Pops a value and throws it to the handler in the exception table.
+    Throw,
 
+    /// Jump by a signed offset from the end of the instruction, so code can be moved without
+    /// patching. The offset is one byte in the `8` forms, and two bytes otherwise.
+    JumpIfNot8,
+    Jump8,
     JumpIfNot,
     Jump,
+    /// Prefix giving the following `Let`, `Var`, `Tee` or `Reserve` a two-byte operand, or the
+    /// following `JumpIfNot`, `Jump` or `NewCoroutine` a four-byte offset.
+    Wide,
+    /// Ends the run, or the body of the running coroutine, popping its value and handing it to
+    /// whoever resumed it.
     Halt,
 }
 
 struct Frame<'c> {
-    stack: [u32; 256],
+    stack: &'c mut [Value],
     sp: usize,
-    bytecode: &'c [u8],
-    pc: u16,
+    memory: &'c mut [u8],
+    heap: &'c mut Heap,
+    host: &'c Host,
+    meter: &'c Meter<'c>,
+    /// Why the meter stopped the run, and the opcode the `Halt` that stopped it replaced.
+    stopped: Option<(Stop, u8)>,
+    /// With a `Halt` written over each breakpoint.
+    bytecode: Patched<'c, u8>,
+    constants: &'c [u32],
+    handlers: &'c [Handler],
+    pc: u32,
+    /// The coroutines that are running, innermost last, which hold the frames that resumed them.
+    running: Vec<Value>,
+    profiler: Profiler,
 }
 
 impl<'c> Frame<'c> {
     fn read_u8(&mut self) -> u8 {
         debug_assert!((self.pc as usize) < self.bytecode.len());
+        self.profiler.read_bytecode(1);
         let x = *unsafe { self.bytecode.get_unchecked(self.pc as usize) };
         self.pc += 1;
         x
@@ -32,8 +135,9 @@ impl<'c> Frame<'c> {
 
     fn read_u16(&mut self) -> u16 {
         debug_assert!((self.pc as usize + 1) < self.bytecode.len());
+        self.profiler.read_bytecode(2);
         let x = unsafe {
-            *std::mem::transmute::<_, &u16>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u16>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 2;
         u16::from_le(x)
@@ -41,33 +145,208 @@ impl<'c> Frame<'c> {
 
     fn read_u32(&mut self) -> u32 {
         debug_assert!((self.pc as usize + 3) < self.bytecode.len());
+        self.profiler.read_bytecode(4);
         let x = unsafe {
-            *std::mem::transmute::<_, &u32>(self.bytecode.get_unchecked(self.pc as usize))
+            *std::mem::transmute::<&u8, &u32>(self.bytecode.get_unchecked(self.pc as usize))
         };
         self.pc += 4;
         u32::from_le(x)
     }
 
-    fn var(&self, i: u8) -> u32 {
+    fn read_u64(&mut self) -> u64 {
+        debug_assert!((self.pc as usize + 7) < self.bytecode.len());
+        self.profiler.read_bytecode(8);
+        let x = unsafe {
+            *std::mem::transmute::<&u8, &u64>(self.bytecode.get_unchecked(self.pc as usize))
+        };
+        self.pc += 8;
+        u64::from_le(x)
+    }
+
+    /// Replaces the opcode of the instruction at `pc`, which must have the same operands.
+    fn quicken(&mut self, pc: u32, opcode: Opcode) {
+        debug_assert!((pc as usize) < self.bytecode.len());
+        unsafe {
+            *self.bytecode.get_unchecked_mut(pc as usize) = opcode as u8;
+        }
+    }
+
+    fn load(&mut self, width: Width) {
+        let address = self.pop().as_int();
+        self.push(Value::int(memory::load(self.memory, address, width)));
+    }
+
+    fn store(&mut self, width: Width) {
+        let value = self.pop().as_int();
+        let address = self.pop().as_int();
+        memory::store(self.memory, address, width, value);
+    }
+
+    /// Allocates an object and pushes it. The stack, including the variables at its bottom, and
+    /// the running coroutines are the garbage collector's roots.
+    fn alloc(&mut self, object: Object) {
+        let object = self
+            .heap
+            .alloc(object, &[&self.stack[..self.sp], &self.running]);
+        self.push(object);
+    }
+
+    /// Calls a host function with the arguments on top of the stack, which stay roots until it
+    /// returns, and replaces them with its result.
+    fn call_native(&mut self, function: u16, argc: usize) {
+        for _ in 0..argc {
+            self.profiler.read_stack();
+        }
+        let base = self.sp - argc;
+        let (roots, args) = self.stack[..self.sp].split_at(base);
+        let result = self.host.call(
+            function,
+            &mut Call::new(args, self.heap, &[roots, &self.running]),
+        );
+        self.sp = base;
+        self.push(result);
+    }
+
+    /// Jumps by `offset` from the end of the instruction. Jumping backward costs fuel, and when
+    /// the meter stops the run, the instruction jumped to is replaced by a `Halt` until `eval`
+    /// returns, so that the dispatch loop needn't check for it.
+    #[inline(always)]
+    fn jump(&mut self, offset: i32) {
+        self.pc = self.pc.wrapping_add_signed(offset);
+        if offset < 0 {
+            if let Err(stop) = self.meter.tick() {
+                self.stop(stop);
+            }
+        }
+    }
+
+    /// Stops a metered run at a `Yield`, like a jump without fuel.
+    #[inline(always)]
+    fn yield_value(&mut self, value: Value) {
+        if let Err(stop) = self.meter.yield_value(value) {
+            self.stop(stop);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn stop(&mut self, stop: Stop) {
+        let opcode = std::mem::replace(&mut self.bytecode[self.pc as usize], Opcode::Halt as u8);
+        self.stopped = Some((stop, opcode));
+    }
+
+    /// Pushes a new coroutine whose body starts at the next instruction, with a copy of the
+    /// frame, and jumps past the body.
+    fn new_coroutine(&mut self, offset: i32) {
+        let registers = Registers {
+            pc: self.pc,
+            sp: self.sp as u32,
+            target: 0,
+        };
+        let roots = [&self.stack[..self.sp], &self.running];
+        let coroutine = coroutine::new(self.heap, self.stack, registers, &roots);
+        self.push(coroutine);
+        self.pc = self.pc.wrapping_add_signed(offset);
+    }
+
+    #[inline(never)]
+    fn resume(&mut self) {
+        let value = self.pop();
+        let coroutine = self.pop();
+        let mut registers = self.registers();
+        let yielded = coroutine::resume(self.heap, coroutine, self.stack, &mut registers);
+        self.running.push(coroutine);
+        self.set_registers(registers);
+        if yielded {
+            self.push(value);
+        }
+    }
+
+    /// Hands the value on top of the stack back to whoever resumed the running coroutine, at a
+    /// yield or once its body has `returned`.
+    #[inline(never)]
+    fn suspend(&mut self, returned: bool) {
+        let value = self.pop();
+        let coroutine = self.running.pop().expect("a coroutine is running");
+        let mut registers = self.registers();
+        coroutine::suspend(self.heap, coroutine, self.stack, &mut registers, returned);
+        self.set_registers(registers);
+        self.push(value);
+    }
+
+    fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp as u32,
+            target: 0,
+        }
+    }
+
+    /// Throws a value from the instruction just executed to its handler, cutting the stack back
+    /// to the handler's depth and pushing the value. A coroutine without a handler for it dies,
+    /// and the `Resume` running it throws the value again. Nothing else catching it stops the
+    /// run.
+    #[cold]
+    #[inline(never)]
+    fn throw(&mut self, value: Value) {
+        loop {
+            if let Some(handler) = exception::find(self.handlers, self.pc - 1) {
+                self.sp = handler.depth as usize;
+                self.pc = handler.target;
+                self.push(value);
+                return;
+            }
+            if self.running.is_empty() {
+                // The run is over, so the `Halt` that ends it can go anywhere.
+                self.pc = 0;
+                self.stop(Stop::Uncaught(value));
+                return;
+            }
+            self.push(value);
+            self.suspend(true);
+            self.pop();
+        }
+    }
+
+    fn set_registers(&mut self, registers: Registers) {
+        self.pc = registers.pc;
+        self.sp = registers.sp as usize;
+    }
+
+    fn constant(&self, i: u16) -> u32 {
+        debug_assert!((i as usize) < self.constants.len());
+        self.profiler.read_bytecode(4);
+        *unsafe { self.constants.get_unchecked(i as usize) }
+    }
+
+    fn string(&self, i: u16) -> Box<[u8]> {
+        heap::read_string_constant(self.constants, i as usize).expect("verified string constant")
+    }
+
+    fn var(&self, i: u16) -> Value {
         debug_assert!((i as usize) < self.stack.len());
+        self.profiler.read_variable();
         unsafe { *self.stack.get_unchecked(i as usize) }
     }
 
-    fn set_var(&mut self, i: u8, val: u32) {
+    fn set_var(&mut self, i: u16, val: Value) {
         debug_assert!((i as usize) < self.stack.len());
+        self.profiler.write_variable();
         unsafe {
             *self.stack.get_unchecked_mut(i as usize) = val;
         }
     }
 
-    fn push(&mut self, x: u32) {
+    fn push(&mut self, x: Value) {
+        self.profiler.write_stack();
         unsafe {
             *self.stack.get_unchecked_mut(self.sp) = x;
         }
         self.sp += 1;
     }
 
-    fn pop(&mut self) -> u32 {
+    fn pop(&mut self) -> Value {
+        self.profiler.read_stack();
         let x = *unsafe { self.stack.get_unchecked(self.sp - 1) };
         self.sp -= 1;
         x
@@ -76,53 +355,309 @@ impl<'c> Frame<'c> {
 
 impl<'c> Frame<'c> {
     #[inline(never)]
-    fn eval(&mut self) {
-        loop {
-            let opcode = unsafe { std::mem::transmute::<_, Opcode>(self.read_u8()) };
-            match opcode {
-                Opcode::Int => {
-                    let i = self.read_u32();
-                    self.push(i);
-                }
-                Opcode::Let => {
-                    let i = self.read_u8();
-                    let val = self.pop();
-                    self.set_var(i, val);
-                }
-                Opcode::Var => {
-                    let i = self.read_u8();
-                    let val = self.var(i);
-                    self.push(val);
-                }
-                Opcode::LessEq => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push((a <= b) as u32);
-                }
-                Opcode::Add => {
-                    let b = self.pop();
-                    let a = self.pop();
+    /// Runs until `Halt`, or until the meter stops it at a backward jump or a breakpoint.
+    fn eval(&mut self) -> Result<(), Stop> {
+        // Resumed at a breakpoint, the run carries on with the instruction under it, and writes
+        // the breakpoint back straight after.
+        let mut running = true;
+        if self.bytecode.stepping() {
+            running = self.step();
+            if self.stopped.is_none() {
+                self.bytecode.rearm(Opcode::Halt as u8);
+            }
+        }
+        if running {
+            while self.step() {}
+        }
+        match self.stopped.take() {
+            Some((stop, opcode)) => {
+                self.pc -= 1;
+                self.bytecode[self.pc as usize] = opcode;
+                Err(stop)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs an instruction, and returns whether the run carries on.
+    #[inline(always)]
+    fn step(&mut self) -> bool {
+        let pc = self.pc;
+        let opcode = unsafe { std::mem::transmute::<u8, Opcode>(self.read_u8()) };
+        self.profiler.enter(pc, opcode as u8);
+        match opcode {
+            Opcode::Int => {
+                let i = self.read_u32();
+                self.push(Value::int(i));
+            }
+            Opcode::SmallInt => {
+                let i = self.read_u8();
+                self.push(Value::int(i as u32));
+            }
+            Opcode::Const8 => {
+                let i = self.read_u8();
+                let k = self.constant(i as u16);
+                self.push(Value::int(k));
+            }
+            Opcode::Const16 => {
+                let i = self.read_u16();
+                let k = self.constant(i);
+                self.push(Value::int(k));
+            }
+            Opcode::Float => {
+                let f = f64::from_bits(self.read_u64());
+                self.push(Value::float(f));
+            }
+            Opcode::Let => {
+                let i = self.read_u8();
+                let val = self.pop();
+                self.set_var(i as u16, val);
+            }
+            Opcode::Var => {
+                let i = self.read_u8();
+                let val = self.var(i as u16);
+                self.push(val);
+            }
+            Opcode::Tee => {
+                let i = self.read_u8();
+                let val = self.pop();
+                self.push(val);
+                self.set_var(i as u16, val);
+            }
+            Opcode::Reserve => {
+                let n = self.read_u8();
+                self.sp = n as usize;
+            }
+            Opcode::LessEq => {
+                let b = self.pop().as_int();
+                let a = self.pop().as_int();
+                self.push(Value::int((a <= b) as u32));
+            }
+            Opcode::Add => {
+                let b = self.pop();
+                let a = self.pop();
+                let quick = if a.is_int() {
+                    Opcode::AddInt
+                } else {
+                    Opcode::AddFloat
+                };
+                self.quicken(pc, quick);
+                self.push(a + b);
+            }
+            Opcode::AddInt => {
+                let b = self.pop();
+                let a = self.pop();
+                if a.is_int() {
+                    self.push(Value::int(a.as_int().wrapping_add(b.as_int())));
+                } else {
+                    self.quicken(pc, Opcode::Add);
                     self.push(a + b);
                 }
-                Opcode::Multiply => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a * b);
+            }
+            Opcode::AddFloat => {
+                let b = self.pop();
+                let a = self.pop();
+                if a.is_float() {
+                    self.push(Value::float(a.as_float() + b.as_float()));
+                } else {
+                    self.quicken(pc, Opcode::Add);
+                    self.push(a + b);
                 }
-                Opcode::JumpIfNot => {
-                    let offset = self.read_u16();
-                    let condition = self.pop();
-                    if condition == 0 {
-                        self.pc = offset;
-                    }
+            }
+            Opcode::Multiply => {
+                let b = self.pop().as_int();
+                let a = self.pop().as_int();
+                self.push(Value::int(a.wrapping_mul(b)));
+            }
+            Opcode::Subtract => {
+                let b = self.pop().as_int();
+                let a = self.pop().as_int();
+                self.push(Value::int(a.wrapping_sub(b)));
+            }
+            Opcode::Divide => {
+                let b = self.pop().as_int();
+                let a = self.pop().as_int();
+                match a.checked_div(b) {
+                    Some(quotient) => self.push(Value::int(quotient)),
+                    None => self.throw(DIVISION_BY_ZERO),
                 }
-                Opcode::Jump => {
-                    let offset = self.read_u16();
-                    self.pc = offset;
+            }
+            Opcode::Remainder => {
+                let b = self.pop().as_int();
+                let a = self.pop().as_int();
+                match a.checked_rem(b) {
+                    Some(remainder) => self.push(Value::int(remainder)),
+                    None => self.throw(DIVISION_BY_ZERO),
                 }
-                Opcode::Halt => break,
             }
-            self.dump();
+            Opcode::FLess => {
+                let b = self.pop().as_float();
+                let a = self.pop().as_float();
+                self.push(Value::int((a < b) as u32));
+            }
+            Opcode::FAdd => {
+                let b = self.pop().as_float();
+                let a = self.pop().as_float();
+                self.push(Value::float(a + b));
+            }
+            Opcode::FMultiply => {
+                let b = self.pop().as_float();
+                let a = self.pop().as_float();
+                self.push(Value::float(a * b));
+            }
+            Opcode::FSubtract => {
+                let b = self.pop().as_float();
+                let a = self.pop().as_float();
+                self.push(Value::float(a - b));
+            }
+            Opcode::FDivide => {
+                let b = self.pop().as_float();
+                let a = self.pop().as_float();
+                self.push(Value::float(a / b));
+            }
+            Opcode::IntToFloat => {
+                let a = self.pop().as_int();
+                self.push(Value::float(a as f64));
+            }
+            Opcode::FloatToInt => {
+                let a = self.pop().as_float();
+                self.push(Value::int(a as u32));
+            }
+            Opcode::Load8 => self.load(Width::U8),
+            Opcode::Load16 => self.load(Width::U16),
+            Opcode::Load32 => self.load(Width::U32),
+            Opcode::Store8 => self.store(Width::U8),
+            Opcode::Store16 => self.store(Width::U16),
+            Opcode::Store32 => self.store(Width::U32),
+            Opcode::NewArray => {
+                let len = self.pop().as_int();
+                self.alloc(Object::Array(vec![Value::int(0); len as usize]));
+            }
+            Opcode::NewRecord => {
+                let fields = self.read_u16();
+                self.alloc(Object::Record(vec![Value::int(0); fields as usize].into()));
+            }
+            Opcode::NewString => {
+                let i = self.read_u16();
+                let string = self.string(i);
+                self.alloc(Object::String(string));
+            }
+            Opcode::Get => {
+                let index = self.pop().as_int();
+                let object = self.pop();
+                self.push(self.heap.get(object, index));
+            }
+            Opcode::Set => {
+                let value = self.pop();
+                let index = self.pop().as_int();
+                let object = self.pop();
+                self.heap.set(object, index, value);
+            }
+            Opcode::Len => {
+                let object = self.pop();
+                self.push(Value::int(self.heap.len(object)));
+            }
+            Opcode::CallNative => {
+                let function = self.read_u16();
+                let argc = self.read_u8();
+                self.call_native(function, argc as usize);
+            }
+            Opcode::Yield if self.running.is_empty() => {
+                let value = self.pop();
+                self.push(value);
+                self.yield_value(value);
+            }
+            Opcode::Yield => self.suspend(false),
+            Opcode::NewCoroutine => {
+                let offset = self.read_u16() as i16;
+                self.new_coroutine(offset as i32);
+            }
+            Opcode::Resume => self.resume(),
+            Opcode::Throw => {
+                let value = self.pop();
+                self.throw(value);
+            }
+            Opcode::JumpIfNot8 => {
+                let offset = self.read_u8() as i8;
+                let condition = self.pop();
+                if !condition.is_truthy() {
+                    self.jump(offset as i32);
+                }
+            }
+            Opcode::Jump8 => {
+                let offset = self.read_u8() as i8;
+                self.jump(offset as i32);
+            }
+            Opcode::JumpIfNot => {
+                let offset = self.read_u16() as i16;
+                let condition = self.pop();
+                if !condition.is_truthy() {
+                    self.jump(offset as i32);
+                }
+            }
+            Opcode::Jump => {
+                let offset = self.read_u16() as i16;
+                self.jump(offset as i32);
+            }
+            Opcode::Wide => self.wide(),
+            Opcode::Halt if self.stopped.is_none() && self.bytecode.is_breakpoint(pc) => {
+                self.stopped = Some((Stop::Breakpoint, Opcode::Halt as u8));
+                self.profiler.leave();
+                return false;
+            }
+            Opcode::Halt if self.stopped.is_none() && !self.running.is_empty() => {
+                self.suspend(true);
+            }
+            Opcode::Halt => {
+                self.profiler.leave();
+                return false;
+            }
+        }
+        self.profiler.leave();
+        self.dump();
+        true
+    }
+
+    /// Executes the instruction following a `Wide` prefix.
+    #[cold]
+    fn wide(&mut self) {
+        match unsafe { std::mem::transmute::<u8, Opcode>(self.read_u8()) } {
+            Opcode::Let => {
+                let i = self.read_u16();
+                let val = self.pop();
+                self.set_var(i, val);
+            }
+            Opcode::Var => {
+                let i = self.read_u16();
+                let val = self.var(i);
+                self.push(val);
+            }
+            Opcode::Tee => {
+                let i = self.read_u16();
+                let val = self.pop();
+                self.push(val);
+                self.set_var(i, val);
+            }
+            Opcode::Reserve => {
+                let n = self.read_u16();
+                self.sp = n as usize;
+            }
+            Opcode::JumpIfNot => {
+                let offset = self.read_u32() as i32;
+                let condition = self.pop();
+                if !condition.is_truthy() {
+                    self.jump(offset);
+                }
+            }
+            Opcode::Jump => {
+                let offset = self.read_u32() as i32;
+                self.jump(offset);
+            }
+            Opcode::NewCoroutine => {
+                let offset = self.read_u32() as i32;
+                self.new_coroutine(offset);
+            }
+            _ => unsafe { std::hint::unreachable_unchecked() },
         }
     }
 
@@ -134,6 +669,10 @@ impl<'c> Frame<'c> {
 #[derive(Default)]
 struct Writer {
     bytecode: Vec<u8>,
+    constants: Vec<u32>,
+    constant_indices: HashMap<u32, u16>,
+    /// Jumps written by the compiler, which are only inserted into `bytecode` by `link`.
+    jumps: Jumps<Opcode>,
 }
 
 impl Writer {
@@ -143,8 +682,8 @@ impl Writer {
         i
     }
 
-    fn pc(&self) -> u16 {
-        self.bytecode.len() as u16
+    fn pc(&self) -> u32 {
+        self.bytecode.len() as u32
     }
 
     fn write_u16(&mut self, x: u16) -> usize {
@@ -165,9 +704,65 @@ impl Writer {
         i
     }
 
+    fn write_u64(&mut self, x: u64) -> usize {
+        let i = self.bytecode.len();
+        self.bytecode.extend_from_slice(&x.to_le_bytes());
+        i
+    }
+
     fn write_opcode(&mut self, opcode: Opcode) {
         self.write_u8(opcode as u8);
     }
+
+    /// Returns the index of `x` in the constant pool, adding it if it isn't there yet, or `None`
+    /// if the pool is full.
+    fn constant(&mut self, x: u32) -> Option<u16> {
+        if let Some(&i) = self.constant_indices.get(&x) {
+            return Some(i);
+        }
+        let i = u16::try_from(self.constants.len()).ok()?;
+        self.constants.push(x);
+        self.constant_indices.insert(x, i);
+        Some(i)
+    }
+
+    fn label(&mut self) -> Label {
+        self.jumps.label()
+    }
+
+    fn place(&mut self, label: Label) {
+        self.jumps.place(label, self.bytecode.len());
+    }
+
+    /// Writes a `JumpIfNot` or `Jump` to `target`, in whichever form reaches it once the code
+    /// is linked.
+    fn write_jump(&mut self, opcode: Opcode, target: Label) {
+        let at = self.bytecode.len();
+        self.jumps.add(at, target, Reach::Short, [2, 3, 6], opcode);
+    }
+
+    /// Writes a `NewCoroutine` whose body ends at `end`. It has no short form.
+    fn write_new_coroutine(&mut self, end: Label) {
+        let at = self.bytecode.len();
+        self.jumps
+            .add(at, end, Reach::Long, [2, 3, 6], Opcode::NewCoroutine);
+    }
+
+    /// Returns the bytecode with the jumps inserted.
+    fn link(&mut self) -> Result<Linked, CompileError> {
+        let jumps = std::mem::take(&mut self.jumps);
+        jumps.link(&self.bytecode, |code, &opcode, reach, offset| match reach {
+            Reach::Short => code.extend([opcode.short_jump() as u8, offset as i8 as u8]),
+            Reach::Long => {
+                code.push(opcode as u8);
+                code.extend((offset as i16).to_le_bytes());
+            }
+            Reach::Wide => {
+                code.extend([Opcode::Wide as u8, opcode as u8]);
+                code.extend(offset.to_le_bytes());
+            }
+        })
+    }
 }
 
 const VAR_N: u8 = 0;
@@ -200,6 +795,7 @@ pub fn code() -> Vec<u8> {
 
     w.write_opcode(Opcode::JumpIfNot);
     let loop_jump_hole = w.write_u16(0);
+    let loop_jump_base = w.pc();
 
     // x = x * i
     {
@@ -230,26 +826,1218 @@ pub fn code() -> Vec<u8> {
     }
 
     w.write_opcode(Opcode::Jump);
-    w.write_u16(loop_start);
+    w.write_u16((loop_start as i32 - (w.pc() as i32 + 2)) as u16);
 
     let loop_end = w.pc();
     w.write_opcode(Opcode::Var);
     w.write_u8(VAR_X);
 
-    w.patch_u16(loop_jump_hole, loop_end);
+    w.patch_u16(loop_jump_hole, (loop_end - loop_jump_base) as u16);
     w.write_opcode(Opcode::Halt);
 
     w.bytecode
 }
 
-pub fn run(code: &[u8]) -> u32 {
+pub fn run(code: &mut [u8]) -> u32 {
+    let mut stack = [Value::int(0); 256];
     let mut frame = Frame {
-        stack: [0; 256],
+        stack: &mut stack,
         sp: 0,
-        bytecode: code,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        bytecode: Patched::clean(code),
+        constants: &[],
+        handlers: &[],
         pc: 0,
+        running: vec![],
+        profiler: Profiler::new(),
     };
-    frame.push(10);
-    frame.eval();
-    frame.pop()
+    frame.push(Value::int(10));
+    frame.eval().expect("unmetered runs don't stop");
+    frame.pop().as_int()
+}
+
+#[cfg(feature = "profile")]
+pub fn profile(code: &mut [u8]) -> crate::profile::Profile {
+    let mut stack = [Value::int(0); 256];
+    let mut frame = Frame {
+        stack: &mut stack,
+        sp: 0,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        meter: &Meter::unlimited(),
+        stopped: None,
+        bytecode: Patched::clean(code),
+        constants: &[],
+        handlers: &[],
+        pc: 0,
+        running: vec![],
+        profiler: Profiler::new(),
+    };
+    frame.push(Value::int(10));
+    frame.eval().expect("unmetered runs don't stop");
+    frame.profiler.finish("stack (switch)", |opcode| {
+        format!("{:?}", unsafe { std::mem::transmute::<u8, Opcode>(opcode) })
+    })
+}
+
+/// Runs a program produced by [`compile`], with the stack's variable slots initialized from
+/// `variables` and the given linear memory, heap and host functions, and returns its result.
+/// Afterwards `variables` holds the final contents of the slots; those not used by the program
+/// may have been overwritten with temporaries. Slots beyond the end of `variables` start out as
+/// zero. An exception that nothing catches ends the run with [`VmError::Uncaught`].
+///
+/// Instructions are quickened in `code` as they run, so later runs start out with them
+/// specialised for the types seen so far.
+pub fn execute(
+    code: &mut Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Result<Value, VmError> {
+    execute_with(code, variables, memory, heap, host, Profiler::new()).0
+}
+
+/// Like [`execute`], but runs the program under the given profiler and hands it back.
+pub fn execute_with(
+    code: &mut Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    profiler: Profiler,
+) -> (Result<Value, VmError>, Profiler) {
+    with_slots(code.slots, variables, |stack| {
+        let mut frame = Frame {
+            stack,
+            sp: 0,
+            memory,
+            heap,
+            host,
+            meter: &Meter::unlimited(),
+            stopped: None,
+            bytecode: Patched::clean(&mut code.code),
+            constants: &code.constants,
+            handlers: &code.handlers,
+            pc: 0,
+            running: vec![],
+            profiler,
+        };
+        let result = fuel::unmetered(frame.eval()).map(|()| frame.pop());
+        (result, frame.profiler)
+    })
+}
+
+/// Like [`execute`], but stops with an error when `fuel` runs out or is interrupted, which
+/// [`resume`] can carry on from.
+pub fn execute_metered(
+    code: &mut Chunk,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    execute_from(code, None, variables, memory, heap, host, fuel)
+}
+
+/// Carries on with a run of `code` that stopped with `suspended`, from the frame it left
+/// behind. `variables` receives the final contents of the slots, as with [`execute`].
+pub fn resume(
+    code: &mut Chunk,
+    suspended: Suspended,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    assert!(
+        (suspended.pc() as usize) < code.code.len(),
+        "resumed with different code"
+    );
+    execute_from(code, Some(suspended), variables, memory, heap, host, fuel)
+}
+
+/// The checksum of `chunk` as a module, with quickened instructions in their generic form so that
+/// running the code doesn't change it. Runs that stop record it, for [`resume`] to check that it's
+/// given the same code.
+pub fn checksum(chunk: &Chunk) -> u32 {
+    let mut chunk = chunk.clone();
+    let mut pc = 0;
+    while let Ok(decoded) = decode(&chunk.code, pc) {
+        if matches!(decoded.opcode, Opcode::AddInt | Opcode::AddFloat) {
+            chunk.code[pc + decoded.wide as usize] = Opcode::Add as u8;
+        }
+        pc += decoded.size;
+    }
+    Module::from_chunk(module::Backend::Stack, chunk).checksum()
+}
+
+fn execute_from(
+    code: &mut Chunk,
+    suspended: Option<Suspended>,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+    fuel: &mut Fuel,
+) -> Result<Value, VmError> {
+    fuel::run(
+        module::Backend::Stack,
+        checksum(code),
+        code.slots,
+        variables,
+        fuel,
+        suspended,
+        |stack, meter, state| {
+            let mut frame = Frame {
+                stack,
+                sp: state.sp,
+                memory,
+                heap,
+                host,
+                meter,
+                stopped: None,
+                bytecode: meter.patch(&mut code.code, Opcode::Halt as u8, starts),
+                constants: &code.constants,
+                handlers: &code.handlers,
+                pc: state.pc,
+                running: state.running,
+                profiler: Profiler::new(),
+            };
+            match frame.eval() {
+                Ok(()) => Ok(frame.pop()),
+                Err(stop) => Err((
+                    stop,
+                    State {
+                        pc: frame.pc,
+                        sp: frame.sp,
+                        running: frame.running,
+                        ..state
+                    },
+                )),
+            }
+        },
+    )
+}
+
+impl Opcode {
+    fn from_u8(x: u8) -> Option<Self> {
+        (x <= Opcode::Halt as u8).then(|| unsafe { std::mem::transmute::<u8, Opcode>(x) })
+    }
+
+    fn operand_size(self) -> usize {
+        match self {
+            Opcode::Float => 8,
+            Opcode::Int => 4,
+            Opcode::SmallInt
+            | Opcode::Const8
+            | Opcode::Let
+            | Opcode::Var
+            | Opcode::Tee
+            | Opcode::Reserve
+            | Opcode::JumpIfNot8
+            | Opcode::Jump8 => 1,
+            Opcode::Const16
+            | Opcode::NewRecord
+            | Opcode::NewString
+            | Opcode::JumpIfNot
+            | Opcode::Jump
+            | Opcode::NewCoroutine => 2,
+            Opcode::CallNative => 3,
+            Opcode::LessEq
+            | Opcode::Add
+            | Opcode::AddInt
+            | Opcode::AddFloat
+            | Opcode::Multiply
+            | Opcode::Subtract
+            | Opcode::Divide
+            | Opcode::Remainder
+            | Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide
+            | Opcode::IntToFloat
+            | Opcode::FloatToInt
+            | Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Load32
+            | Opcode::Store8
+            | Opcode::Store16
+            | Opcode::Store32
+            | Opcode::NewArray
+            | Opcode::Get
+            | Opcode::Set
+            | Opcode::Len
+            | Opcode::Yield
+            | Opcode::Resume
+            | Opcode::Throw
+            | Opcode::Wide
+            | Opcode::Halt => 0,
+        }
+    }
+
+    /// The size of the operand after a `Wide` prefix, or `None` if the instruction cannot be
+    /// widened.
+    fn wide_operand_size(self) -> Option<usize> {
+        match self {
+            Opcode::Let | Opcode::Var | Opcode::Tee | Opcode::Reserve => Some(2),
+            Opcode::JumpIfNot | Opcode::Jump | Opcode::NewCoroutine => Some(4),
+            _ => None,
+        }
+    }
+
+    /// Whether the operand is an offset, which is decoded as the target it reaches.
+    fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JumpIfNot8
+                | Opcode::Jump8
+                | Opcode::JumpIfNot
+                | Opcode::Jump
+                | Opcode::NewCoroutine
+        )
+    }
+
+    /// The form of a `JumpIfNot` or `Jump` with a one-byte offset.
+    fn short_jump(self) -> Opcode {
+        match self {
+            Opcode::JumpIfNot => Opcode::JumpIfNot8,
+            Opcode::Jump => Opcode::Jump8,
+            _ => unreachable!("{self:?} is not a jump with a short form"),
+        }
+    }
+}
+
+/// A decoded instruction.
+#[derive(Clone, Copy)]
+struct Decoded {
+    opcode: Opcode,
+    /// The instruction's operand, or its target if it's a jump.
+    operand: u64,
+    wide: bool,
+    /// The size of the instruction in bytes, including any `Wide` prefix.
+    size: usize,
+}
+
+/// Every instruction in this encoding has at most a single operand, except for `CallNative`,
+/// whose two are read as one, with the argument count in the high byte.
+fn decode(code: &[u8], pc: usize) -> Result<Decoded, VerifyError> {
+    let error = |message| VerifyError { pc, message };
+    let opcode = |pc: usize| {
+        let opcode = code.get(pc).ok_or_else(|| error("truncated instruction"))?;
+        Opcode::from_u8(*opcode).ok_or_else(|| error("invalid opcode"))
+    };
+    let (opcode, start, size, wide) = match opcode(pc)? {
+        Opcode::Wide => {
+            let opcode = opcode(pc + 1)?;
+            let size = opcode
+                .wide_operand_size()
+                .ok_or_else(|| error("instruction cannot be widened"))?;
+            (opcode, pc + 2, size, true)
+        }
+        opcode => (opcode, pc + 1, opcode.operand_size(), false),
+    };
+    let operand = code
+        .get(start..start + size)
+        .ok_or_else(|| error("truncated instruction"))?;
+    let mut operand = operand
+        .iter()
+        .rev()
+        .fold(0, |acc, &byte| acc << 8 | byte as u64);
+    if opcode.is_jump() {
+        let target = (start + size) as i64 + sign_extend(operand as u32, size) as i64;
+        operand = u32::try_from(target).map_err(|_| error("jump target out of bounds"))? as u64;
+    }
+    Ok(Decoded {
+        opcode,
+        operand,
+        wide,
+        size: start + size - pc,
+    })
+}
+
+/// The pcs that instructions start at, up to the first that doesn't decode.
+pub(crate) fn starts(code: &[u8]) -> Vec<u32> {
+    let mut starts = vec![];
+    let mut pc = 0;
+    while let Ok(decoded) = decode(code, pc) {
+        starts.push(pc as u32);
+        pc += decoded.size;
+    }
+    starts
+}
+
+pub fn disassemble(chunk: &Chunk) -> Disassembly {
+    let code = &chunk.code;
+    let mut disassembly = vec![];
+    let mut pc = 0;
+    while pc < code.len() {
+        match decode(code, pc) {
+            Ok(Decoded {
+                opcode,
+                operand,
+                wide,
+                size,
+            }) => {
+                let wide = if wide { "Wide " } else { "" };
+                let text = match (opcode, chunk.constants.get(operand as usize)) {
+                    (Opcode::Const8 | Opcode::Const16, Some(k)) => {
+                        format!("{opcode:?} {operand} ({k})")
+                    }
+                    (Opcode::Float, _) => format!("Float {:?}", f64::from_bits(operand)),
+                    (Opcode::CallNative, _) => {
+                        format!("CallNative {}, {}", operand & 0xffff, operand >> 16)
+                    }
+                    (Opcode::NewString, _) => {
+                        match heap::read_string_constant(&chunk.constants, operand as usize) {
+                            Some(s) => {
+                                format!("NewString {operand} ({:?})", String::from_utf8_lossy(&s))
+                            }
+                            None => format!("NewString {operand}"),
+                        }
+                    }
+                    _ if size > 1 => format!("{wide}{opcode:?} {operand}"),
+                    _ => format!("{opcode:?}"),
+                };
+                disassembly.push((pc as u32, text));
+                pc += size;
+            }
+            Err(error) => {
+                disassembly.push((pc as u32, format!("<{}>", error.message)));
+                break;
+            }
+        }
+    }
+    disassembly
+}
+
+/// Checks that the bytecode cannot read or jump out of bounds, and that the stack never
+/// underflows or overflows.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    let code = &chunk.code;
+    if code.len() > u32::MAX as usize {
+        return Err(VerifyError {
+            pc: u32::MAX as usize,
+            message: "bytecode is larger than 4 GiB",
+        });
+    }
+
+    let mut instructions = vec![];
+    let mut pc = 0;
+    while pc < code.len() {
+        let Decoded {
+            opcode,
+            operand,
+            size,
+            ..
+        } = decode(code, pc)?;
+        let out_of_bounds = match opcode {
+            Opcode::Let | Opcode::Var | Opcode::Tee => operand as usize >= chunk.slots,
+            Opcode::Reserve => operand as usize > chunk.slots,
+            _ => false,
+        };
+        if out_of_bounds {
+            return Err(VerifyError {
+                pc,
+                message: "variable out of bounds",
+            });
+        }
+        if let Opcode::Const8 | Opcode::Const16 = opcode {
+            if operand as usize >= chunk.constants.len() {
+                return Err(VerifyError {
+                    pc,
+                    message: "constant out of bounds",
+                });
+            }
+        }
+        if opcode == Opcode::NewString
+            && heap::read_string_constant(&chunk.constants, operand as usize).is_none()
+        {
+            return Err(VerifyError {
+                pc,
+                message: "string constant out of bounds",
+            });
+        }
+        instructions.push((pc, opcode, operand));
+        pc += size;
+    }
+    match instructions.last() {
+        Some((_, Opcode::Halt | Opcode::Jump8 | Opcode::Jump | Opcode::Throw, _)) => (),
+        _ => {
+            return Err(VerifyError {
+                pc,
+                message: "execution can run past the end of the bytecode",
+            })
+        }
+    }
+
+    // Stack depth at the start of each instruction, propagated along control flow.
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    let mut worklist = vec![(0, 0)];
+    let index_of = |pc: usize| {
+        instructions
+            .binary_search_by_key(&pc, |&(pc, _, _)| pc)
+            .map_err(|_| VerifyError {
+                pc,
+                message: "jump into the middle of an instruction",
+            })
+    };
+    // Handlers start with the thrown value on top of the stack as it was cut back.
+    for handler in &chunk.handlers {
+        let error = |message| VerifyError {
+            pc: handler.target as usize,
+            message,
+        };
+        if handler.start > handler.end || handler.end as usize > code.len() {
+            return Err(error("handler covers code out of bounds"));
+        }
+        let bounds = [handler.start, handler.end].map(|pc| pc as usize);
+        if bounds
+            .iter()
+            .any(|&pc| pc < code.len() && index_of(pc).is_err())
+        {
+            return Err(error("handler covers part of an instruction"));
+        }
+        if handler.depth as usize >= chunk.slots {
+            return Err(error("stack overflow"));
+        }
+        worklist.push((
+            index_of(handler.target as usize)?,
+            handler.depth as usize + 1,
+        ));
+    }
+    while let Some((index, depth)) = worklist.pop() {
+        let (pc, opcode, operand) = instructions[index];
+        match depths[pc] {
+            Some(known) if known == depth => continue,
+            Some(_) => {
+                return Err(VerifyError {
+                    pc,
+                    message: "stack depth differs between paths",
+                })
+            }
+            None => depths[pc] = Some(depth),
+        }
+
+        let error = |message| VerifyError { pc, message };
+        let (pops, pushes) = match opcode {
+            Opcode::Int
+            | Opcode::SmallInt
+            | Opcode::Const8
+            | Opcode::Const16
+            | Opcode::Float
+            | Opcode::Var
+            | Opcode::NewRecord
+            | Opcode::NewString
+            | Opcode::NewCoroutine => (0, 1),
+            Opcode::Let | Opcode::JumpIfNot8 | Opcode::JumpIfNot => (1, 0),
+            Opcode::LessEq
+            | Opcode::Add
+            | Opcode::AddInt
+            | Opcode::AddFloat
+            | Opcode::Multiply
+            | Opcode::Subtract
+            | Opcode::Divide
+            | Opcode::Remainder
+            | Opcode::FLess
+            | Opcode::FAdd
+            | Opcode::FMultiply
+            | Opcode::FSubtract
+            | Opcode::FDivide
+            | Opcode::Get
+            | Opcode::Resume => (2, 1),
+            Opcode::IntToFloat
+            | Opcode::FloatToInt
+            | Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Load32
+            | Opcode::NewArray
+            | Opcode::Len
+            | Opcode::Yield
+            | Opcode::Tee => (1, 1),
+            Opcode::Throw => (1, 0),
+            Opcode::Store8 | Opcode::Store16 | Opcode::Store32 => (2, 0),
+            Opcode::Set => (3, 0),
+            Opcode::CallNative => ((operand >> 16) as usize, 1),
+            Opcode::Jump8 | Opcode::Jump | Opcode::Reserve => (0, 0),
+            Opcode::Halt => (1, 1),
+            Opcode::Wide => unreachable!("prefixes are decoded with their instructions"),
+        };
+        let depth = depth
+            .checked_sub(pops)
+            .ok_or_else(|| error("stack underflow"))?
+            + pushes;
+        let depth = if opcode == Opcode::Reserve {
+            operand as usize
+        } else {
+            depth
+        };
+        if depth > chunk.slots {
+            return Err(error("stack overflow"));
+        }
+
+        match opcode {
+            Opcode::Halt | Opcode::Throw => (),
+            Opcode::Jump8 | Opcode::Jump => worklist.push((index_of(operand as usize)?, depth)),
+            // The body starts out with the stack as it was.
+            Opcode::NewCoroutine => {
+                worklist.push((index_of(operand as usize)?, depth));
+                worklist.push((index + 1, depth - 1));
+            }
+            Opcode::JumpIfNot8 | Opcode::JumpIfNot => {
+                worklist.push((index_of(operand as usize)?, depth));
+                worklist.push((index + 1, depth));
+            }
+            _ => worklist.push((index + 1, depth)),
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites bytecode into equivalent bytecode that does less work:
+///
+/// - a `Let` of a variable followed by a `Var` of it becomes a `Tee`,
+/// - a `Var` of a variable followed by a `Let` of it is removed,
+/// - jumps to unconditional jumps go straight to where those lead,
+/// - jumps to the next instruction and unreachable code are removed.
+///
+/// Instructions that jumps or handlers start at are never merged with the one before them.
+/// Jumps are given the shortest encodings that reach their new targets, and the exception table
+/// moves with the code it covers. Fails if the chunk doesn't pass [`verify`].
+pub fn optimize(chunk: &Chunk) -> Result<Chunk, VerifyError> {
+    verify(chunk)?;
+    let mut pcs = vec![];
+    let mut instructions = vec![];
+    let mut pc = 0;
+    while pc < chunk.code.len() {
+        let insn = decode(&chunk.code, pc)?;
+        pcs.push(pc);
+        instructions.push(insn);
+        pc += insn.size;
+    }
+    // Positions are replaced by the index of the instruction there, or the number of
+    // instructions at the end of the code.
+    let index_of = |pc: u32| pcs.partition_point(|&start| start < pc as usize);
+    for insn in &mut instructions {
+        if insn.opcode.is_jump() {
+            insn.operand = index_of(insn.operand as u32) as u64;
+        }
+        insn.opcode = match insn.opcode {
+            Opcode::JumpIfNot8 => Opcode::JumpIfNot,
+            Opcode::Jump8 => Opcode::Jump,
+            opcode => opcode,
+        };
+    }
+
+    let n = instructions.len();
+    let mut alive = vec![true; n];
+    // Removed instructions stand for the next one that's left, which a jump to them reaches.
+    let next_alive = |alive: &[bool], i: usize| (i..n).find(|&i| alive[i]).unwrap_or(n);
+    loop {
+        let mut changed = false;
+
+        for i in 0..n {
+            let insn = instructions[i];
+            if !alive[i] || !matches!(insn.opcode, Opcode::JumpIfNot | Opcode::Jump) {
+                continue;
+            }
+            // Chains are followed at most `n` steps, which is as far as a loop of jumps gets.
+            let mut target = next_alive(&alive, insn.operand as usize);
+            for _ in 0..n {
+                match instructions.get(target) {
+                    Some(next) if next.opcode == Opcode::Jump => {
+                        target = next_alive(&alive, next.operand as usize);
+                    }
+                    _ => break,
+                }
+            }
+            if target != insn.operand as usize {
+                instructions[i].operand = target as u64;
+                changed = true;
+            }
+        }
+
+        let mut reachable = vec![false; n];
+        let mut worklist = vec![next_alive(&alive, 0)];
+        for handler in &chunk.handlers {
+            worklist.push(next_alive(&alive, index_of(handler.target)));
+        }
+        while let Some(i) = worklist.pop() {
+            if i >= n || reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+            let Decoded {
+                opcode, operand, ..
+            } = instructions[i];
+            let target = next_alive(&alive, operand as usize);
+            let next = next_alive(&alive, i + 1);
+            match opcode {
+                Opcode::Halt | Opcode::Throw => (),
+                Opcode::Jump => worklist.push(target),
+                Opcode::JumpIfNot | Opcode::NewCoroutine => worklist.extend([target, next]),
+                _ => worklist.push(next),
+            }
+        }
+        for i in 0..n {
+            if alive[i] && !reachable[i] {
+                alive[i] = false;
+                changed = true;
+            }
+        }
+
+        for i in 0..n {
+            let insn = instructions[i];
+            if alive[i]
+                && insn.opcode == Opcode::Jump
+                && next_alive(&alive, insn.operand as usize) == next_alive(&alive, i + 1)
+            {
+                alive[i] = false;
+                changed = true;
+            }
+        }
+
+        let mut targets = vec![false; n + 1];
+        for (i, insn) in instructions.iter().enumerate() {
+            if alive[i] && insn.opcode.is_jump() {
+                targets[next_alive(&alive, insn.operand as usize)] = true;
+            }
+        }
+        for handler in &chunk.handlers {
+            targets[next_alive(&alive, index_of(handler.target))] = true;
+        }
+        for i in 0..n {
+            if !alive[i] {
+                continue;
+            }
+            let j = next_alive(&alive, i + 1);
+            let (Some(&first), Some(&second)) = (instructions.get(i), instructions.get(j)) else {
+                continue;
+            };
+            if targets[j] || first.operand != second.operand || first.wide != second.wide {
+                continue;
+            }
+            match (first.opcode, second.opcode) {
+                (Opcode::Let, Opcode::Var) => {
+                    instructions[i].opcode = Opcode::Tee;
+                    alive[j] = false;
+                }
+                (Opcode::Var, Opcode::Let) => {
+                    alive[i] = false;
+                    alive[j] = false;
+                }
+                _ => continue,
+            }
+            changed = true;
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut w = Writer::default();
+    let labels: Vec<Label> = (0..=n).map(|_| w.label()).collect();
+    for (i, insn) in instructions.iter().enumerate() {
+        w.place(labels[i]);
+        if !alive[i] {
+            continue;
+        }
+        match insn.opcode {
+            Opcode::JumpIfNot | Opcode::Jump => {
+                w.write_jump(insn.opcode, labels[insn.operand as usize]);
+            }
+            Opcode::NewCoroutine => w.write_new_coroutine(labels[insn.operand as usize]),
+            opcode if insn.wide => {
+                w.write_opcode(Opcode::Wide);
+                w.write_opcode(opcode);
+                w.write_u16(insn.operand as u16);
+            }
+            opcode => {
+                w.write_opcode(opcode);
+                let size = opcode.operand_size();
+                w.bytecode
+                    .extend_from_slice(&insn.operand.to_le_bytes()[..size]);
+            }
+        }
+    }
+    w.place(labels[n]);
+    let linked = w.link().map_err(|_| VerifyError {
+        pc: chunk.code.len(),
+        message: "bytecode is too large to optimize",
+    })?;
+
+    let position = |pc: u32| linked.position(labels[index_of(pc)]);
+    let handlers = chunk
+        .handlers
+        .iter()
+        .map(|handler| Handler {
+            start: position(handler.start),
+            end: position(handler.end),
+            target: position(handler.target),
+            depth: handler.depth,
+        })
+        .filter(|handler| handler.start < handler.end)
+        .collect();
+    Ok(Chunk {
+        code: linked.code,
+        constants: chunk.constants.clone(),
+        slots: chunk.slots,
+        handlers,
+    })
+}
+
+struct Compiler {
+    w: Writer,
+    /// The next free variable slot for temporaries.
+    temps: usize,
+    max_temps: usize,
+    depth: usize,
+    max_depth: usize,
+    handlers: exception::Table<Label>,
+}
+
+impl Compiler {
+    fn emit(&mut self, opcode: Opcode, pops: usize, pushes: usize) {
+        self.w.write_opcode(opcode);
+        self.depth = self.depth - pops + pushes;
+        self.max_depth = self.max_depth.max(self.depth);
+    }
+
+    fn alloc_temp(&mut self) -> Result<u16, CompileError> {
+        let temp = u16::try_from(self.temps).map_err(|_| CompileError::TooManyVariables)?;
+        self.temps += 1;
+        self.max_temps = self.max_temps.max(self.temps);
+        Ok(temp)
+    }
+
+    fn free_temp(&mut self) {
+        self.temps -= 1;
+    }
+
+    /// Emits a `Let` or `Var` of the given variable, widening it if needed.
+    fn variable(&mut self, opcode: Opcode, variable: u16) {
+        let (pops, pushes) = if opcode == Opcode::Let {
+            (1, 0)
+        } else {
+            (0, 1)
+        };
+        match u8::try_from(variable) {
+            Ok(variable) => {
+                self.emit(opcode, pops, pushes);
+                self.w.write_u8(variable);
+            }
+            Err(_) => {
+                self.w.write_opcode(Opcode::Wide);
+                self.emit(opcode, pops, pushes);
+                self.w.write_u16(variable);
+            }
+        }
+    }
+
+    fn jump(&mut self, opcode: Opcode, target: Label) {
+        if opcode == Opcode::JumpIfNot {
+            self.depth -= 1;
+        }
+        self.w.write_jump(opcode, target);
+    }
+
+    /// Pushes an integer, using the shortest encoding available for it.
+    fn int(&mut self, i: u32) {
+        if let Ok(small) = u8::try_from(i) {
+            self.emit(Opcode::SmallInt, 0, 1);
+            self.w.write_u8(small);
+        } else {
+            match self.w.constant(i) {
+                Some(k) if k <= u8::MAX as u16 => {
+                    self.emit(Opcode::Const8, 0, 1);
+                    self.w.write_u8(k as u8);
+                }
+                Some(k) => {
+                    self.emit(Opcode::Const16, 0, 1);
+                    self.w.write_u16(k);
+                }
+                None => {
+                    self.emit(Opcode::Int, 0, 1);
+                    self.w.write_u32(i);
+                }
+            }
+        }
+    }
+
+    /// Compiles an instruction whose value is not needed.
+    fn effect(&mut self, insn: &Instruction) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Int(_)
+            | Instruction::Float(_)
+            | Instruction::Var(_)
+            | Instruction::NewRecord(_)
+            | Instruction::NewString(_)
+            | Instruction::Coroutine(_) => (),
+            Instruction::Let { variable, value } => {
+                self.value(value)?;
+                self.variable(Opcode::Let, *variable);
+            }
+            Instruction::LessEq(a, b)
+            | Instruction::Add(a, b)
+            | Instruction::Multiply(a, b)
+            | Instruction::Subtract(a, b)
+            | Instruction::FLess(a, b)
+            | Instruction::FAdd(a, b)
+            | Instruction::FMultiply(a, b)
+            | Instruction::FSubtract(a, b)
+            | Instruction::FDivide(a, b) => {
+                self.effect(a)?;
+                self.effect(b)?;
+            }
+            Instruction::IntToFloat(a)
+            | Instruction::FloatToInt(a)
+            | Instruction::NewArray(a)
+            | Instruction::Len(a) => self.effect(a)?,
+            Instruction::StoreIndex {
+                width,
+                address,
+                value,
+            } => {
+                self.value(address)?;
+                self.value(value)?;
+                self.emit(store_opcode(*width), 2, 0);
+            }
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => {
+                self.value(object)?;
+                self.value(index)?;
+                self.value(value)?;
+                self.emit(Opcode::Set, 3, 0);
+            }
+            // Division and loads are kept for their side effect of throwing on a zero divisor or
+            // panicking on an out-of-bounds address or index, calls for whatever the host does,
+            // and yields and resumes for switching to another frame.
+            Instruction::Divide(..)
+            | Instruction::Remainder(..)
+            | Instruction::Index { .. }
+            | Instruction::Get(..)
+            | Instruction::CallNative { .. }
+            | Instruction::Yield(_)
+            | Instruction::Resume(..) => {
+                self.value(insn)?;
+                let temp = self.alloc_temp()?;
+                self.variable(Opcode::Let, temp);
+                self.free_temp();
+            }
+            Instruction::Sequence(s) => {
+                for insn in s {
+                    self.effect(insn)?;
+                }
+            }
+            Instruction::While { condition, body } => {
+                let loop_start = self.w.label();
+                let loop_end = self.w.label();
+                self.w.place(loop_start);
+                self.value(condition)?;
+                self.jump(Opcode::JumpIfNot, loop_end);
+                self.effect(body)?;
+                self.jump(Opcode::Jump, loop_start);
+                self.w.place(loop_end);
+            }
+            Instruction::Try {
+                body,
+                variable,
+                handler,
+            } => self.try_catch(body, *variable, handler, false)?,
+            Instruction::Throw(value) => {
+                self.value(value)?;
+                self.emit(Opcode::Throw, 1, 0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles an instruction, leaving its value on top of the stack.
+    fn value(&mut self, insn: &Instruction) -> Result<(), CompileError> {
+        match insn {
+            Instruction::Int(i) => self.int(*i),
+            Instruction::Float(f) => {
+                self.emit(Opcode::Float, 0, 1);
+                self.w.write_u64(f.to_bits());
+            }
+            Instruction::Var(v) => self.variable(Opcode::Var, *v),
+            Instruction::Let { variable, value } => {
+                self.value(value)?;
+                self.variable(Opcode::Let, *variable);
+                self.variable(Opcode::Var, *variable);
+            }
+            Instruction::LessEq(a, b) => self.binary(Opcode::LessEq, a, b)?,
+            Instruction::Add(a, b) => self.binary(Opcode::Add, a, b)?,
+            Instruction::Multiply(a, b) => self.binary(Opcode::Multiply, a, b)?,
+            Instruction::Subtract(a, b) => self.binary(Opcode::Subtract, a, b)?,
+            Instruction::Divide(a, b) => self.binary(Opcode::Divide, a, b)?,
+            Instruction::Remainder(a, b) => self.binary(Opcode::Remainder, a, b)?,
+            Instruction::FLess(a, b) => self.binary(Opcode::FLess, a, b)?,
+            Instruction::FAdd(a, b) => self.binary(Opcode::FAdd, a, b)?,
+            Instruction::FMultiply(a, b) => self.binary(Opcode::FMultiply, a, b)?,
+            Instruction::FSubtract(a, b) => self.binary(Opcode::FSubtract, a, b)?,
+            Instruction::FDivide(a, b) => self.binary(Opcode::FDivide, a, b)?,
+            Instruction::IntToFloat(a) => {
+                self.value(a)?;
+                self.emit(Opcode::IntToFloat, 1, 1);
+            }
+            Instruction::FloatToInt(a) => {
+                self.value(a)?;
+                self.emit(Opcode::FloatToInt, 1, 1);
+            }
+            Instruction::Index { width, address } => {
+                self.value(address)?;
+                self.emit(load_opcode(*width), 1, 1);
+            }
+            Instruction::StoreIndex {
+                width,
+                address,
+                value,
+            } => {
+                // The store consumes the value, so a copy is kept in a temporary.
+                let temp = self.alloc_temp()?;
+                self.value(address)?;
+                self.value(value)?;
+                self.variable(Opcode::Let, temp);
+                self.variable(Opcode::Var, temp);
+                self.emit(store_opcode(*width), 2, 0);
+                self.variable(Opcode::Var, temp);
+                self.free_temp();
+            }
+            Instruction::NewArray(len) => {
+                self.value(len)?;
+                self.emit(Opcode::NewArray, 1, 1);
+            }
+            Instruction::NewRecord(fields) => {
+                self.emit(Opcode::NewRecord, 0, 1);
+                self.w.write_u16(*fields);
+            }
+            Instruction::NewString(s) => {
+                let k = u16::try_from(heap::add_string_constant(&mut self.w.constants, s))
+                    .map_err(|_| CompileError::TooManyConstants)?;
+                self.emit(Opcode::NewString, 0, 1);
+                self.w.write_u16(k);
+            }
+            Instruction::Get(object, index) => self.binary(Opcode::Get, object, index)?,
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => {
+                // Like a store, with the value kept in a temporary.
+                let temp = self.alloc_temp()?;
+                self.value(object)?;
+                self.value(index)?;
+                self.value(value)?;
+                self.variable(Opcode::Let, temp);
+                self.variable(Opcode::Var, temp);
+                self.emit(Opcode::Set, 3, 0);
+                self.variable(Opcode::Var, temp);
+                self.free_temp();
+            }
+            Instruction::Len(object) => {
+                self.value(object)?;
+                self.emit(Opcode::Len, 1, 1);
+            }
+            Instruction::CallNative {
+                function,
+                arguments,
+            } => {
+                let argc =
+                    u8::try_from(arguments.len()).map_err(|_| CompileError::TooManyArguments)?;
+                for argument in arguments {
+                    self.value(argument)?;
+                }
+                self.emit(Opcode::CallNative, argc as usize, 1);
+                self.w.write_u16(*function);
+                self.w.write_u8(argc);
+            }
+            Instruction::Yield(value) => {
+                self.value(value)?;
+                self.emit(Opcode::Yield, 1, 1);
+            }
+            Instruction::Coroutine(body) => {
+                // The body runs on top of the stack as it was when the coroutine was created.
+                let start = self.w.label();
+                let end = self.w.label();
+                self.w.write_new_coroutine(end);
+                self.w.place(start);
+                self.handlers.enter_coroutine();
+                self.value(body)?;
+                self.emit(Opcode::Halt, 1, 0);
+                self.handlers.leave_coroutine(start, end);
+                self.w.place(end);
+                self.depth += 1;
+            }
+            Instruction::Resume(coroutine, value) => {
+                self.binary(Opcode::Resume, coroutine, value)?
+            }
+            Instruction::Sequence(s) => match s.split_last() {
+                Some((last, init)) => {
+                    for insn in init {
+                        self.effect(insn)?;
+                    }
+                    self.value(last)?;
+                }
+                None => self.int(0),
+            },
+            Instruction::While { condition, body } => {
+                // The value of a loop is the value of its body in the last iteration, which is
+                // kept in a temporary.
+                let result = self.alloc_temp()?;
+                self.int(0);
+                self.variable(Opcode::Let, result);
+
+                let loop_start = self.w.label();
+                let loop_end = self.w.label();
+                self.w.place(loop_start);
+                self.value(condition)?;
+                self.jump(Opcode::JumpIfNot, loop_end);
+                self.value(body)?;
+                self.variable(Opcode::Let, result);
+                self.jump(Opcode::Jump, loop_start);
+                self.w.place(loop_end);
+
+                self.variable(Opcode::Var, result);
+                self.free_temp();
+            }
+            Instruction::Try {
+                body,
+                variable,
+                handler,
+            } => self.try_catch(body, *variable, handler, true)?,
+            Instruction::Throw(value) => {
+                self.value(value)?;
+                self.emit(Opcode::Throw, 1, 0);
+                // Nothing runs after the throw, but the code after it expects a value.
+                self.depth += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles a `Try`, leaving its value on top of the stack if `keep` is set. The handler
+    /// starts out with the thrown value where the body's value would be, and assigns it to the
+    /// variable.
+    fn try_catch(
+        &mut self,
+        body: &Instruction,
+        variable: u16,
+        handler: &Instruction,
+        keep: bool,
+    ) -> Result<(), CompileError> {
+        let [start, end, target, done] = [(); 4].map(|()| self.w.label());
+        let mark = self.handlers.mark();
+        let depth = self.depth;
+        self.w.place(start);
+        if keep {
+            self.value(body)?;
+        } else {
+            self.effect(body)?;
+        }
+        self.w.place(end);
+        self.jump(Opcode::Jump, done);
+
+        self.w.place(target);
+        self.handlers.add(mark, start, end, target, depth as u32);
+        self.depth = depth + 1;
+        self.max_depth = self.max_depth.max(self.depth);
+        self.variable(Opcode::Let, variable);
+        if keep {
+            self.value(handler)?;
+        } else {
+            self.effect(handler)?;
+        }
+        self.w.place(done);
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        opcode: Opcode,
+        a: &Instruction,
+        b: &Instruction,
+    ) -> Result<(), CompileError> {
+        self.value(a)?;
+        self.value(b)?;
+        self.emit(opcode, 2, 1);
+        Ok(())
+    }
+}
+
+fn load_opcode(width: Width) -> Opcode {
+    match width {
+        Width::U8 => Opcode::Load8,
+        Width::U16 => Opcode::Load16,
+        Width::U32 => Opcode::Load32,
+    }
+}
+
+fn store_opcode(width: Width) -> Opcode {
+    match width {
+        Width::U8 => Opcode::Store8,
+        Width::U16 => Opcode::Store16,
+        Width::U32 => Opcode::Store32,
+    }
+}
+
+/// Compiles a program to bytecode that [`execute`] can run. The variables used by the program
+/// are allocated at the bottom of the stack, followed by temporaries and then the operands.
+pub fn compile(program: &Instruction) -> Result<Chunk, CompileError> {
+    let variables = program.variable_count();
+    let mut c = Compiler {
+        w: Writer::default(),
+        temps: variables,
+        max_temps: variables,
+        depth: 0,
+        max_depth: 0,
+        handlers: exception::Table::default(),
+    };
+
+    c.value(program)?;
+    c.emit(Opcode::Halt, 0, 0);
+
+    let locals = u16::try_from(c.max_temps).map_err(|_| CompileError::TooManyVariables)?;
+    let slots = locals as usize + c.max_depth;
+    if slots > 1 << 16 {
+        return Err(CompileError::ExpressionTooDeep);
+    }
+
+    // The number of locals is only known now, but since jumps are relative, the `Reserve` can
+    // simply be put in front of the rest of the code.
+    let mut code = match u8::try_from(locals) {
+        Ok(locals) => vec![Opcode::Reserve as u8, locals],
+        Err(_) => {
+            let [lo, hi] = locals.to_le_bytes();
+            vec![Opcode::Wide as u8, Opcode::Reserve as u8, lo, hi]
+        }
+    };
+    let prefix = code.len() as u32;
+    let linked = c.w.link()?;
+    code.extend(&linked.code);
+    if code.len() > u32::MAX as usize {
+        return Err(CompileError::CodeTooLarge);
+    }
+    // Handlers cut the stack back to a depth above the locals.
+    let handlers = c
+        .handlers
+        .resolve(|label| prefix + linked.position(label), locals as u32);
+    Ok(Chunk {
+        code,
+        constants: c.w.constants,
+        slots,
+        handlers,
+    })
 }
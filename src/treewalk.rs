@@ -1,15 +1,104 @@
+use crate::{
+    bytecode::with_slots,
+    exception::{Thrown, DIVISION_BY_ZERO},
+    fuel::VmError,
+    heap::{Heap, Object},
+    host::{Call, Host},
+    memory::{self, Width},
+    value::Value,
+};
+
+/// Integer instructions expect integer operands and float instructions float ones, except for
+/// `Add`, which takes either, and heap instructions expect references to objects of the right
+/// kind. What any other operand does is unspecified.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Int(u32),
+    Float(f64),
 
-    Var(u8),
+    Var(u16),
     Let {
-        variable: u8,
+        variable: u16,
         value: Box<Instruction>,
     },
 
     LessEq(Box<Instruction>, Box<Instruction>),
+    /// Adds two integers, wrapping around, or two floats.
     Add(Box<Instruction>, Box<Instruction>),
     Multiply(Box<Instruction>, Box<Instruction>),
+    Subtract(Box<Instruction>, Box<Instruction>),
+    /// Unsigned division, which throws [`DIVISION_BY_ZERO`] if the divisor is zero.
+    Divide(Box<Instruction>, Box<Instruction>),
+    /// The remainder of unsigned division, which throws [`DIVISION_BY_ZERO`] if the divisor is
+    /// zero.
+    Remainder(Box<Instruction>, Box<Instruction>),
+
+    FLess(Box<Instruction>, Box<Instruction>),
+    FAdd(Box<Instruction>, Box<Instruction>),
+    FMultiply(Box<Instruction>, Box<Instruction>),
+    FSubtract(Box<Instruction>, Box<Instruction>),
+    FDivide(Box<Instruction>, Box<Instruction>),
+    /// Converts an unsigned integer to the nearest float.
+    IntToFloat(Box<Instruction>),
+    /// Converts a float to an integer, rounding toward zero and saturating at the bounds of
+    /// `u32`. NaN becomes zero.
+    FloatToInt(Box<Instruction>),
+
+    /// Loads an unsigned integer from linear memory at a byte address.
+    Index {
+        width: Width,
+        address: Box<Instruction>,
+    },
+    /// Stores the low bytes of an integer in linear memory at a byte address, and evaluates to
+    /// the integer.
+    StoreIndex {
+        width: Width,
+        address: Box<Instruction>,
+        value: Box<Instruction>,
+    },
+
+    /// Allocates an array of the given length on the heap.
+    NewArray(Box<Instruction>),
+    /// Allocates a record with the given number of fields on the heap.
+    NewRecord(u16),
+    /// Allocates a copy of a string on the heap.
+    NewString(Box<[u8]>),
+    /// Reads an element of an array, a field of a record or a byte of a string.
+    Get(Box<Instruction>, Box<Instruction>),
+    /// Writes an element of an array or a field of a record, and evaluates to the value written.
+    Set {
+        object: Box<Instruction>,
+        index: Box<Instruction>,
+        value: Box<Instruction>,
+    },
+    /// The length of an array, record or string.
+    Len(Box<Instruction>),
+
+    /// Calls a function of the [`Host`] by index, with the arguments evaluated left to right.
+    CallNative {
+        function: u16,
+        arguments: Vec<Instruction>,
+    },
+    /// Stops a metered run of a bytecode backend with the value, which it evaluates to once the
+    /// run is resumed. Other runs carry straight on.
+    Yield(Box<Instruction>),
+    /// Creates a [coroutine][crate::coroutine] that runs the body when it's resumed, and in
+    /// which `Yield` hands a value back to whoever resumed it. The tree walker cannot suspend
+    /// an evaluation, so it panics on this and `Resume`.
+    Coroutine(Box<Instruction>),
+    /// Resumes a coroutine with a value, and evaluates to the value it yields or returns.
+    Resume(Box<Instruction>, Box<Instruction>),
+
+    /// Evaluates to the value of `body`, unless it throws, in which case the thrown value is
+    /// assigned to `variable` and it evaluates to `handler` instead. See
+    /// [exceptions][crate::exception].
+    Try {
+        body: Box<Instruction>,
+        variable: u16,
+        handler: Box<Instruction>,
+    },
+    /// Throws a value to the innermost `Try` around it.
+    Throw(Box<Instruction>),
 
     Sequence(Vec<Instruction>),
     While {
@@ -18,17 +107,146 @@ pub enum Instruction {
     },
 }
 
-struct Frame {
-    variables: [u32; 256],
+impl Instruction {
+    /// The number of variable slots the instruction needs, ie. one more than the highest
+    /// variable it reads or writes.
+    pub fn variable_count(&self) -> usize {
+        match self {
+            Instruction::Int(_)
+            | Instruction::Float(_)
+            | Instruction::NewRecord(_)
+            | Instruction::NewString(_) => 0,
+            Instruction::Var(v) => *v as usize + 1,
+            Instruction::Let { variable, value } => {
+                (*variable as usize + 1).max(value.variable_count())
+            }
+            Instruction::LessEq(a, b)
+            | Instruction::Add(a, b)
+            | Instruction::Multiply(a, b)
+            | Instruction::Subtract(a, b)
+            | Instruction::Divide(a, b)
+            | Instruction::Remainder(a, b)
+            | Instruction::FLess(a, b)
+            | Instruction::FAdd(a, b)
+            | Instruction::FMultiply(a, b)
+            | Instruction::FSubtract(a, b)
+            | Instruction::FDivide(a, b)
+            | Instruction::StoreIndex {
+                address: a,
+                value: b,
+                ..
+            }
+            | Instruction::Get(a, b)
+            | Instruction::Resume(a, b) => a.variable_count().max(b.variable_count()),
+            Instruction::IntToFloat(a)
+            | Instruction::FloatToInt(a)
+            | Instruction::Index { address: a, .. }
+            | Instruction::NewArray(a)
+            | Instruction::Len(a)
+            | Instruction::Yield(a)
+            | Instruction::Coroutine(a)
+            | Instruction::Throw(a) => a.variable_count(),
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => object
+                .variable_count()
+                .max(index.variable_count())
+                .max(value.variable_count()),
+            Instruction::Sequence(s) | Instruction::CallNative { arguments: s, .. } => {
+                s.iter().map(|i| i.variable_count()).max().unwrap_or(0)
+            }
+            Instruction::While { condition, body } => {
+                condition.variable_count().max(body.variable_count())
+            }
+            Instruction::Try {
+                body,
+                variable,
+                handler,
+            } => (*variable as usize + 1)
+                .max(body.variable_count())
+                .max(handler.variable_count()),
+        }
+    }
+
+    /// Whether evaluating the instruction may assign to the given variable.
+    pub fn assigns(&self, variable: u16) -> bool {
+        match self {
+            Instruction::Int(_)
+            | Instruction::Float(_)
+            | Instruction::Var(_)
+            | Instruction::NewRecord(_)
+            | Instruction::NewString(_) => false,
+            // A coroutine's body assigns to its own copies of the variables.
+            Instruction::Coroutine(_) => false,
+            Instruction::Let { variable: v, value } => *v == variable || value.assigns(variable),
+            Instruction::LessEq(a, b)
+            | Instruction::Add(a, b)
+            | Instruction::Multiply(a, b)
+            | Instruction::Subtract(a, b)
+            | Instruction::Divide(a, b)
+            | Instruction::Remainder(a, b)
+            | Instruction::FLess(a, b)
+            | Instruction::FAdd(a, b)
+            | Instruction::FMultiply(a, b)
+            | Instruction::FSubtract(a, b)
+            | Instruction::FDivide(a, b)
+            | Instruction::StoreIndex {
+                address: a,
+                value: b,
+                ..
+            }
+            | Instruction::Get(a, b)
+            | Instruction::Resume(a, b) => a.assigns(variable) || b.assigns(variable),
+            Instruction::IntToFloat(a)
+            | Instruction::FloatToInt(a)
+            | Instruction::Index { address: a, .. }
+            | Instruction::NewArray(a)
+            | Instruction::Len(a)
+            | Instruction::Yield(a)
+            | Instruction::Throw(a) => a.assigns(variable),
+            Instruction::Set {
+                object,
+                index,
+                value,
+            } => object.assigns(variable) || index.assigns(variable) || value.assigns(variable),
+            Instruction::Sequence(s) | Instruction::CallNative { arguments: s, .. } => {
+                s.iter().any(|i| i.assigns(variable))
+            }
+            Instruction::While { condition, body } => {
+                condition.assigns(variable) || body.assigns(variable)
+            }
+            Instruction::Try {
+                body,
+                variable: v,
+                handler,
+            } => *v == variable || body.assigns(variable) || handler.assigns(variable),
+        }
+    }
+}
+
+struct Frame<'v> {
+    variables: &'v mut [Value],
+    memory: &'v mut [u8],
+    heap: &'v mut Heap,
+    host: &'v Host,
+    /// Values that are not in variables but still needed, such as an object whose index is
+    /// being evaluated, which the garbage collector must keep alive.
+    roots: Vec<Value>,
 }
 
-impl Frame {
-    fn var(&self, i: u8) -> u32 {
+impl Frame<'_> {
+    fn alloc(&mut self, object: Object) -> Value {
+        self.heap.alloc(object, &[self.variables, &self.roots])
+    }
+
+    fn var(&self, i: u16) -> Value {
         debug_assert!((i as usize) < self.variables.len());
         unsafe { *self.variables.get_unchecked(i as usize) }
     }
 
-    fn set_var(&mut self, i: u8, val: u32) {
+    fn set_var(&mut self, i: u16, val: Value) {
         debug_assert!((i as usize) < self.variables.len());
         unsafe {
             *self.variables.get_unchecked_mut(i as usize) = val;
@@ -36,41 +254,162 @@ impl Frame {
     }
 }
 
-fn interpret(frame: &mut Frame, code: &Instruction) -> u32 {
-    match code {
-        Instruction::Int(i) => *i,
+fn int(frame: &mut Frame, code: &Instruction) -> Result<u32, Thrown> {
+    Ok(interpret(frame, code)?.as_int())
+}
+
+fn float(frame: &mut Frame, code: &Instruction) -> Result<f64, Thrown> {
+    Ok(interpret(frame, code)?.as_float())
+}
+
+/// Evaluates `code`, or returns what it threw to the innermost `Try` around it.
+fn interpret(frame: &mut Frame, code: &Instruction) -> Result<Value, Thrown> {
+    Ok(match code {
+        Instruction::Int(i) => Value::int(*i),
+        Instruction::Float(f) => Value::float(*f),
 
         Instruction::Var(v) => frame.var(*v),
         Instruction::Let { variable, value } => {
-            let value = interpret(frame, value);
+            let value = interpret(frame, value)?;
             frame.set_var(*variable, value);
             value
         }
 
-        Instruction::LessEq(a, b) => (interpret(frame, a) <= interpret(frame, b)) as u32,
-        Instruction::Add(a, b) => interpret(frame, a) + interpret(frame, b),
-        Instruction::Multiply(a, b) => interpret(frame, a) * interpret(frame, b),
+        Instruction::LessEq(a, b) => Value::int((int(frame, a)? <= int(frame, b)?) as u32),
+        Instruction::Add(a, b) => {
+            let a = interpret(frame, a)?;
+            a + interpret(frame, b)?
+        }
+        Instruction::Multiply(a, b) => Value::int(int(frame, a)?.wrapping_mul(int(frame, b)?)),
+        Instruction::Subtract(a, b) => Value::int(int(frame, a)?.wrapping_sub(int(frame, b)?)),
+        Instruction::Divide(a, b) => divide(int(frame, a)?.checked_div(int(frame, b)?))?,
+        Instruction::Remainder(a, b) => divide(int(frame, a)?.checked_rem(int(frame, b)?))?,
+
+        Instruction::FLess(a, b) => Value::int((float(frame, a)? < float(frame, b)?) as u32),
+        Instruction::FAdd(a, b) => Value::float(float(frame, a)? + float(frame, b)?),
+        Instruction::FMultiply(a, b) => Value::float(float(frame, a)? * float(frame, b)?),
+        Instruction::FSubtract(a, b) => Value::float(float(frame, a)? - float(frame, b)?),
+        Instruction::FDivide(a, b) => Value::float(float(frame, a)? / float(frame, b)?),
+        Instruction::IntToFloat(a) => Value::float(int(frame, a)? as f64),
+        Instruction::FloatToInt(a) => Value::int(float(frame, a)? as u32),
+
+        Instruction::Index { width, address } => {
+            let address = int(frame, address)?;
+            Value::int(memory::load(frame.memory, address, *width))
+        }
+        Instruction::StoreIndex {
+            width,
+            address,
+            value,
+        } => {
+            let address = int(frame, address)?;
+            let value = int(frame, value)?;
+            memory::store(frame.memory, address, *width, value);
+            Value::int(value)
+        }
+
+        Instruction::NewArray(len) => {
+            let len = int(frame, len)?;
+            frame.alloc(Object::Array(vec![Value::int(0); len as usize]))
+        }
+        Instruction::NewRecord(fields) => {
+            frame.alloc(Object::Record(vec![Value::int(0); *fields as usize].into()))
+        }
+        Instruction::NewString(s) => frame.alloc(Object::String(s.clone())),
+        Instruction::Get(object, index) => {
+            let object = interpret(frame, object)?;
+            frame.roots.push(object);
+            let index = int(frame, index)?;
+            frame.roots.pop();
+            frame.heap.get(object, index)
+        }
+        Instruction::Set {
+            object,
+            index,
+            value,
+        } => {
+            let object = interpret(frame, object)?;
+            frame.roots.push(object);
+            let index = int(frame, index)?;
+            let value = interpret(frame, value)?;
+            frame.roots.pop();
+            frame.heap.set(object, index, value);
+            value
+        }
+        Instruction::Len(object) => {
+            let object = interpret(frame, object)?;
+            Value::int(frame.heap.len(object))
+        }
+
+        Instruction::CallNative {
+            function,
+            arguments,
+        } => {
+            // The arguments are roots until the call is over.
+            let base = frame.roots.len();
+            for argument in arguments {
+                let argument = interpret(frame, argument)?;
+                frame.roots.push(argument);
+            }
+            let (roots, args) = frame.roots.split_at(base);
+            let roots = [&*frame.variables, roots];
+            let mut call = Call::new(args, frame.heap, &roots);
+            let result = frame.host.call(*function, &mut call);
+            frame.roots.truncate(base);
+            result
+        }
+        // The tree walker keeps its state on the Rust stack, so it can't stop.
+        Instruction::Yield(value) => interpret(frame, value)?,
+        Instruction::Coroutine(_) | Instruction::Resume(..) => {
+            panic!("the tree walker cannot run coroutines")
+        }
+
+        // What threw returned early without popping its roots, so they're cut back to where
+        // they were.
+        Instruction::Try {
+            body,
+            variable,
+            handler,
+        } => {
+            let roots = frame.roots.len();
+            match interpret(frame, body) {
+                Ok(value) => value,
+                Err(Thrown(thrown)) => {
+                    frame.roots.truncate(roots);
+                    frame.set_var(*variable, thrown);
+                    interpret(frame, handler)?
+                }
+            }
+        }
+        Instruction::Throw(value) => return Err(Thrown(interpret(frame, value)?)),
 
         Instruction::Sequence(s) => {
-            let mut last = 0;
+            let mut last = Value::int(0);
             for insn in s {
-                last = interpret(frame, insn);
+                last = interpret(frame, insn)?;
             }
             last
         }
         Instruction::While { condition, body } => {
-            let mut last = 0;
-            while interpret(frame, condition) != 0 {
-                last = interpret(frame, body);
+            // The value of the last iteration stays alive while the condition is evaluated.
+            let last = frame.roots.len();
+            frame.roots.push(Value::int(0));
+            while interpret(frame, condition)?.is_truthy() {
+                frame.roots[last] = interpret(frame, body)?;
             }
-            last
+            frame.roots.pop().unwrap()
         }
-    }
+    })
 }
 
-const VAR_N: u8 = 0;
-const VAR_I: u8 = 1;
-const VAR_X: u8 = 2;
+/// The quotient or remainder of a division, or a throw if the divisor was zero.
+fn divide(result: Option<u32>) -> Result<Value, Thrown> {
+    result.map(Value::int).ok_or(Thrown(DIVISION_BY_ZERO))
+}
+
+const VAR_N: u16 = 0;
+const VAR_I: u16 = 1;
+const VAR_X: u16 = 2;
 
 pub fn code() -> Instruction {
     use Instruction::*;
@@ -109,10 +448,39 @@ pub fn code() -> Instruction {
 }
 
 pub fn run(code: &Instruction) -> u32 {
+    let mut variables = [Value::int(0); 256];
     let mut frame = Frame {
-        variables: [0; 256],
+        variables: &mut variables,
+        memory: &mut [],
+        heap: &mut Heap::new(),
+        host: &Host::new(),
+        roots: vec![],
     };
-    frame.variables[VAR_N as usize] = 10;
-    interpret(&mut frame, &code);
-    frame.variables[VAR_X as usize]
+    frame.variables[VAR_N as usize] = Value::int(10);
+    interpret(&mut frame, code).expect("the factorial doesn't throw");
+    frame.variables[VAR_X as usize].as_int()
+}
+
+/// Runs a program with the given initial variables, linear memory, heap and host functions, and
+/// returns its value. Afterwards `variables` holds the final values of the variables. Variables
+/// the program uses beyond the end of `variables` start out as zero. An exception that nothing
+/// catches ends the run with [`VmError::Uncaught`].
+pub fn execute(
+    code: &Instruction,
+    variables: &mut [Value],
+    memory: &mut [u8],
+    heap: &mut Heap,
+    host: &Host,
+) -> Result<Value, VmError> {
+    let slots = code.variable_count().max(variables.len());
+    with_slots(slots, variables, |variables| {
+        let mut frame = Frame {
+            variables,
+            memory,
+            heap,
+            host,
+            roots: vec![],
+        };
+        interpret(&mut frame, code).map_err(|Thrown(value)| VmError::Uncaught(value))
+    })
 }
@@ -0,0 +1,260 @@
+//! Dynamically typed values, as used by the interpreters: integers, floats, booleans, nil and
+//! references to objects on a heap.
+//!
+//! By default, values are NaN-boxed into 64 bits. Floats are stored as themselves, and every
+//! other kind of value hides in the payload of a quiet NaN that no arithmetic produces:
+//!
+//! ```text
+//! 0 11111111111 11 xxxxxxxxxxxxxxx tttt pppppppppppppppppppppppppppppppp
+//!   exponent    quiet NaN          tag  32-bit payload
+//! ```
+//!
+//! With the `tagged-values` feature, values are a Rust enum instead, which is twice as large
+//! but doesn't need any bit twiddling. Both representations have the same interface, so the
+//! cost of either can be measured on the same interpreters.
+
+use std::{fmt, ops};
+
+#[cfg(not(feature = "tagged-values"))]
+pub use nan_boxed::Value;
+#[cfg(feature = "tagged-values")]
+pub use tagged::Value;
+
+/// A value taken apart into its kind and payload, independently of the representation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unpacked {
+    Nil,
+    Bool(bool),
+    Int(u32),
+    Float(f64),
+    /// A reference to an object, identified by its index in the heap.
+    Ref(u32),
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::NIL
+    }
+}
+
+impl From<Unpacked> for Value {
+    fn from(unpacked: Unpacked) -> Self {
+        match unpacked {
+            Unpacked::Nil => Value::NIL,
+            Unpacked::Bool(b) => Value::bool(b),
+            Unpacked::Int(i) => Value::int(i),
+            Unpacked::Float(f) => Value::float(f),
+            Unpacked::Ref(r) => Value::reference(r),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.unpack().fmt(f)
+    }
+}
+
+/// Floats always show a decimal point or an exponent, so that they can be told apart from
+/// integers.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unpack() {
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Bool(b) => write!(f, "{b}"),
+            Unpacked::Int(i) => write!(f, "{i}"),
+            Unpacked::Float(x) => write!(f, "{x:?}"),
+            Unpacked::Ref(r) => write!(f, "<object {r}>"),
+        }
+    }
+}
+
+impl Value {
+    /// Whether a condition with this value holds. Nil, `false` and the integer zero are false,
+    /// and everything else is true.
+    #[inline(always)]
+    pub fn is_truthy(self) -> bool {
+        self != Value::NIL && self != Value::bool(false) && self != Value::int(0)
+    }
+}
+
+/// Adds two integers, wrapping around, or two floats. Adding an integer and a float is
+/// unspecified.
+impl ops::Add for Value {
+    type Output = Value;
+
+    #[inline(always)]
+    fn add(self, other: Value) -> Value {
+        if self.is_int() {
+            Value::int(self.as_int().wrapping_add(other.as_int()))
+        } else {
+            Value::float(self.as_float() + other.as_float())
+        }
+    }
+}
+
+#[cfg(not(feature = "tagged-values"))]
+mod nan_boxed {
+    use super::Unpacked;
+
+    /// The bits set in every boxed value: all of the exponent, the quiet bit, and the bit after
+    /// it, which keeps boxed values apart from the NaN produced by arithmetic.
+    const BOXED: u64 = 0x7ffc_0000_0000_0000;
+    const TAG_SHIFT: u32 = 32;
+    const TAG_MASK: u64 = 0xf << TAG_SHIFT;
+
+    const TAG_NIL: u64 = 1 << TAG_SHIFT;
+    const TAG_BOOL: u64 = 2 << TAG_SHIFT;
+    const TAG_INT: u64 = 3 << TAG_SHIFT;
+    const TAG_REF: u64 = 4 << TAG_SHIFT;
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub const NIL: Value = Value(BOXED | TAG_NIL);
+
+        #[inline(always)]
+        pub const fn int(i: u32) -> Value {
+            Value(BOXED | TAG_INT | i as u64)
+        }
+
+        /// NaNs are all stored as the same NaN, so that they can't be mistaken for boxed values.
+        #[inline(always)]
+        pub fn float(f: f64) -> Value {
+            if f.is_nan() {
+                Value(f64::NAN.to_bits())
+            } else {
+                Value(f.to_bits())
+            }
+        }
+
+        #[inline(always)]
+        pub const fn bool(b: bool) -> Value {
+            Value(BOXED | TAG_BOOL | b as u64)
+        }
+
+        #[inline(always)]
+        pub const fn reference(index: u32) -> Value {
+            Value(BOXED | TAG_REF | index as u64)
+        }
+
+        #[inline(always)]
+        pub fn is_int(self) -> bool {
+            self.0 & (BOXED | TAG_MASK) == BOXED | TAG_INT
+        }
+
+        /// The integer this value holds. The result is unspecified for other kinds of values.
+        #[inline(always)]
+        pub fn as_int(self) -> u32 {
+            debug_assert!(self.is_int(), "{self:?} is not an integer");
+            self.0 as u32
+        }
+
+        #[inline(always)]
+        pub fn is_float(self) -> bool {
+            self.0 & BOXED != BOXED
+        }
+
+        /// The float this value holds. The result is unspecified for other kinds of values.
+        #[inline(always)]
+        pub fn as_float(self) -> f64 {
+            debug_assert!(self.is_float(), "{self:?} is not a float");
+            f64::from_bits(self.0)
+        }
+
+        pub fn unpack(self) -> Unpacked {
+            if self.is_float() {
+                return Unpacked::Float(self.as_float());
+            }
+            let payload = self.0 as u32;
+            match self.0 & TAG_MASK {
+                TAG_NIL => Unpacked::Nil,
+                TAG_BOOL => Unpacked::Bool(payload != 0),
+                TAG_INT => Unpacked::Int(payload),
+                TAG_REF => Unpacked::Ref(payload),
+                _ => unreachable!("invalid tag in {:#x}", self.0),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tagged-values")]
+mod tagged {
+    use super::Unpacked;
+
+    #[derive(Clone, Copy)]
+    pub struct Value(Unpacked);
+
+    /// Floats are equal when their bits are, as NaN-boxed values are, so a NaN equals itself and
+    /// `0.0` doesn't equal `-0.0`.
+    impl PartialEq for Value {
+        fn eq(&self, other: &Self) -> bool {
+            match (self.0, other.0) {
+                (Unpacked::Float(a), Unpacked::Float(b)) => a.to_bits() == b.to_bits(),
+                (a, b) => a == b,
+            }
+        }
+    }
+
+    impl Eq for Value {}
+
+    impl Value {
+        pub const NIL: Value = Value(Unpacked::Nil);
+
+        #[inline(always)]
+        pub const fn int(i: u32) -> Value {
+            Value(Unpacked::Int(i))
+        }
+
+        /// NaNs are all stored as the same NaN, as they are when NaN-boxed.
+        #[inline(always)]
+        pub fn float(f: f64) -> Value {
+            Value(Unpacked::Float(if f.is_nan() { f64::NAN } else { f }))
+        }
+
+        #[inline(always)]
+        pub const fn bool(b: bool) -> Value {
+            Value(Unpacked::Bool(b))
+        }
+
+        #[inline(always)]
+        pub const fn reference(index: u32) -> Value {
+            Value(Unpacked::Ref(index))
+        }
+
+        #[inline(always)]
+        pub fn is_int(self) -> bool {
+            matches!(self.0, Unpacked::Int(_))
+        }
+
+        /// The integer this value holds. The result is unspecified for other kinds of values.
+        #[inline(always)]
+        pub fn as_int(self) -> u32 {
+            debug_assert!(self.is_int(), "{self:?} is not an integer");
+            match self.0 {
+                Unpacked::Int(i) => i,
+                _ => 0,
+            }
+        }
+
+        #[inline(always)]
+        pub fn is_float(self) -> bool {
+            matches!(self.0, Unpacked::Float(_))
+        }
+
+        /// The float this value holds. The result is unspecified for other kinds of values.
+        #[inline(always)]
+        pub fn as_float(self) -> f64 {
+            debug_assert!(self.is_float(), "{self:?} is not a float");
+            match self.0 {
+                Unpacked::Float(f) => f,
+                _ => 0.0,
+            }
+        }
+
+        pub fn unpack(self) -> Unpacked {
+            self.0
+        }
+    }
+}
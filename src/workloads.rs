@@ -0,0 +1,132 @@
+//! Programs benchmarked in addition to the factorial, which is too short to say much beyond the
+//! cost of setting up a run. Each is written in the language of [`parser`] and has a native
+//! implementation in [`native`] to compare against.
+
+use crate::{native, parser, treewalk::Instruction, value::Value};
+
+pub struct Workload {
+    pub name: &'static str,
+    pub source: &'static str,
+    /// The arguments the workload is benchmarked with.
+    pub arguments: &'static [u32],
+    /// The number of bytes of linear memory the workload needs.
+    pub memory: usize,
+    /// Whether the workload allocates on the heap, so that it's also benchmarked with each
+    /// collector.
+    pub heap: bool,
+    /// Whether the workload uses coroutines, so that it only runs on the stack and register
+    /// backends.
+    pub coroutines: bool,
+    pub native: fn(&[u32]) -> u32,
+}
+
+impl Workload {
+    pub fn program(&self) -> Instruction {
+        match parser::parse(self.source) {
+            Ok(program) => program.code,
+            Err(error) => panic!("workload {} does not parse: {error}", self.name),
+        }
+    }
+
+    /// The initial variables of a run, with the arguments in the first slots.
+    pub fn variables(&self) -> [Value; 256] {
+        let mut variables = [Value::int(0); 256];
+        for (variable, &argument) in variables.iter_mut().zip(self.arguments) {
+            *variable = Value::int(argument);
+        }
+        variables
+    }
+
+    /// The initial linear memory of a run, which is zeroed.
+    pub fn memory(&self) -> Vec<u8> {
+        vec![0; self.memory]
+    }
+
+    pub fn run_native(&self) -> Value {
+        Value::int((self.native)(self.arguments))
+    }
+}
+
+pub const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "fib",
+        source: include_str!("../programs/fib.dsp"),
+        arguments: &[25],
+        memory: 0,
+        heap: false,
+        coroutines: false,
+        native: |a| native::fib(a[0]),
+    },
+    Workload {
+        name: "collatz",
+        source: include_str!("../programs/collatz.dsp"),
+        arguments: &[1000],
+        memory: 0,
+        heap: false,
+        coroutines: false,
+        native: |a| native::collatz(a[0]),
+    },
+    Workload {
+        name: "nested loops",
+        source: include_str!("../programs/nested_loops.dsp"),
+        arguments: &[200],
+        memory: 0,
+        heap: false,
+        coroutines: false,
+        native: |a| native::nested_loops(a[0]),
+    },
+    Workload {
+        name: "mandelbrot",
+        source: include_str!("../programs/mandelbrot.dsp"),
+        arguments: &[32, 50],
+        memory: 0,
+        heap: false,
+        coroutines: false,
+        native: |a| native::mandelbrot(a[0], a[1]),
+    },
+    Workload {
+        name: "mandelbrot (float)",
+        source: include_str!("../programs/mandelbrot_float.dsp"),
+        arguments: &[32, 50],
+        memory: 0,
+        heap: false,
+        coroutines: false,
+        native: |a| native::mandelbrot_float(a[0], a[1]),
+    },
+    Workload {
+        name: "sieve",
+        source: include_str!("../programs/sieve.dsp"),
+        arguments: &[10000],
+        memory: 10001,
+        heap: false,
+        coroutines: false,
+        native: |a| native::sieve(a[0]),
+    },
+    Workload {
+        name: "bubble sort",
+        source: include_str!("../programs/bubble_sort.dsp"),
+        arguments: &[300, 1],
+        memory: 600,
+        heap: false,
+        coroutines: false,
+        native: |a| native::bubble_sort(a[0], a[1]),
+    },
+    Workload {
+        name: "linked list",
+        source: include_str!("../programs/linked_list.dsp"),
+        arguments: &[100, 1000],
+        memory: 0,
+        heap: true,
+        coroutines: false,
+        native: |a| native::linked_list(a[0], a[1]),
+    },
+    Workload {
+        name: "generator",
+        source: include_str!("../programs/generator.dsp"),
+        arguments: &[10000],
+        memory: 0,
+        heap: false,
+        coroutines: true,
+        native: |a| native::generator(a[0]),
+    },
+];
@@ -0,0 +1,268 @@
+//! Running programs on every backend, for the integration tests and the benchmarks.
+
+// Each test crate uses only some of this.
+#![allow(dead_code)]
+
+use dispatchers::{
+    bytecode::Chunk,
+    fuel::{Fuel, Suspended, VmError},
+    heap::{Collector, Heap},
+    host::Host,
+    treewalk::Instruction,
+    value::Value,
+    *,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Treewalk,
+    CompactTreewalkDtable,
+    CompactTreewalkSwitch,
+    StackDtable,
+    StackSwitch,
+    /// `stack (switch)` running the code [`stack_switch::optimize`] made.
+    StackOptimized,
+    RegisterDtable,
+    RegisterSwitch,
+    RegisterFixed,
+}
+
+/// Every backend.
+pub const ALL: &[Backend] = &[
+    Backend::Treewalk,
+    Backend::CompactTreewalkDtable,
+    Backend::CompactTreewalkSwitch,
+    Backend::StackDtable,
+    Backend::StackSwitch,
+    Backend::StackOptimized,
+    Backend::RegisterDtable,
+    Backend::RegisterSwitch,
+    Backend::RegisterFixed,
+];
+
+/// The stack and register backends, which are the ones with coroutines and exception tables.
+pub const BYTECODE: &[Backend] = &[
+    Backend::StackDtable,
+    Backend::StackSwitch,
+    Backend::StackOptimized,
+    Backend::RegisterDtable,
+    Backend::RegisterSwitch,
+    Backend::RegisterFixed,
+];
+
+/// The backends with [`Compiled::run_metered`], running the code as it was compiled, so that
+/// pcs from its disassembly hold.
+pub const METERED: &[Backend] = &[
+    Backend::StackDtable,
+    Backend::StackSwitch,
+    Backend::RegisterDtable,
+    Backend::RegisterSwitch,
+    Backend::RegisterFixed,
+];
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Treewalk => "treewalk",
+            Backend::CompactTreewalkDtable => "compact treewalk (dtable)",
+            Backend::CompactTreewalkSwitch => "compact treewalk (switch)",
+            Backend::StackDtable => "stack (dtable)",
+            Backend::StackSwitch => "stack (switch)",
+            Backend::StackOptimized => "stack (optimized)",
+            Backend::RegisterDtable => "register (dtable)",
+            Backend::RegisterSwitch => "register (switch)",
+            Backend::RegisterFixed => "register (fixed)",
+        }
+    }
+}
+
+/// A program compiled and verified for one backend. The `switch` backends keep the code they
+/// quicken, so later runs start out with it.
+pub struct Compiled {
+    pub name: &'static str,
+    code: Code,
+}
+
+enum Code {
+    Treewalk(Instruction),
+    CompactTreewalkDtable(Chunk),
+    CompactTreewalkSwitch(Chunk),
+    StackDtable(Chunk),
+    StackSwitch(Chunk),
+    RegisterDtable(Chunk),
+    RegisterSwitch(Chunk),
+    RegisterFixed(register_fixed::Program),
+}
+
+/// Compiles and verifies `code` for each of `backends`, which must all be able to run it.
+pub fn compile(code: &Instruction, backends: &[Backend]) -> Vec<Compiled> {
+    let compact = || {
+        let chunk = compact_treewalk_switch::compile(code).unwrap();
+        compact_treewalk_switch::verify(&chunk).unwrap();
+        chunk
+    };
+    let stack = || {
+        let chunk = stack_switch::compile(code).unwrap();
+        stack_switch::verify(&chunk).unwrap();
+        chunk
+    };
+    let register = || {
+        let chunk = register_switch::compile(code).unwrap();
+        register_switch::verify(&chunk).unwrap();
+        chunk
+    };
+    backends
+        .iter()
+        .map(|&backend| {
+            let code = match backend {
+                Backend::Treewalk => Code::Treewalk(code.clone()),
+                Backend::CompactTreewalkDtable => Code::CompactTreewalkDtable(compact()),
+                Backend::CompactTreewalkSwitch => Code::CompactTreewalkSwitch(compact()),
+                Backend::StackDtable => Code::StackDtable(stack()),
+                Backend::StackSwitch => Code::StackSwitch(stack()),
+                Backend::StackOptimized => {
+                    let chunk = stack_switch::optimize(&stack()).unwrap();
+                    stack_switch::verify(&chunk).unwrap();
+                    Code::StackSwitch(chunk)
+                }
+                Backend::RegisterDtable => Code::RegisterDtable(register()),
+                Backend::RegisterSwitch => Code::RegisterSwitch(register()),
+                Backend::RegisterFixed => {
+                    let program = register_fixed::compile(code).unwrap();
+                    register_fixed::verify(&program).unwrap();
+                    Code::RegisterFixed(program)
+                }
+            };
+            Compiled {
+                name: backend.name(),
+                code,
+            }
+        })
+        .collect()
+}
+
+impl Compiled {
+    pub fn run(
+        &mut self,
+        variables: &mut [Value],
+        memory: &mut [u8],
+        heap: &mut Heap,
+        host: &Host,
+    ) -> Result<Value, VmError> {
+        match &mut self.code {
+            Code::Treewalk(code) => treewalk::execute(code, variables, memory, heap, host),
+            // The compact tree walkers have no exceptions.
+            Code::CompactTreewalkDtable(code) => Ok(compact_treewalk_dtable::execute(
+                code, variables, memory, heap, host,
+            )),
+            Code::CompactTreewalkSwitch(code) => Ok(compact_treewalk_switch::execute(
+                code, variables, memory, heap, host,
+            )),
+            Code::StackDtable(code) => stack_dtable::execute(code, variables, memory, heap, host),
+            Code::StackSwitch(code) => stack_switch::execute(code, variables, memory, heap, host),
+            Code::RegisterDtable(code) => {
+                register_dtable::execute(code, variables, memory, heap, host)
+            }
+            Code::RegisterSwitch(code) => {
+                register_switch::execute(code, variables, memory, heap, host)
+            }
+            Code::RegisterFixed(program) => {
+                register_fixed::execute(program, variables, memory, heap, host)
+            }
+        }
+    }
+
+    /// Runs with `fuel`, from the start or from where `suspended` stopped.
+    pub fn run_metered(
+        &mut self,
+        suspended: Option<Suspended>,
+        variables: &mut [Value],
+        memory: &mut [u8],
+        heap: &mut Heap,
+        host: &Host,
+        fuel: &mut Fuel,
+    ) -> Result<Value, VmError> {
+        let (v, m, h) = (variables, memory, heap);
+        match (&mut self.code, suspended) {
+            (Code::StackDtable(code), None) => {
+                stack_dtable::execute_metered(code, v, m, h, host, fuel)
+            }
+            (Code::StackDtable(code), Some(s)) => {
+                stack_dtable::resume(code, s, v, m, h, host, fuel)
+            }
+            (Code::StackSwitch(code), None) => {
+                stack_switch::execute_metered(code, v, m, h, host, fuel)
+            }
+            (Code::StackSwitch(code), Some(s)) => {
+                stack_switch::resume(code, s, v, m, h, host, fuel)
+            }
+            (Code::RegisterDtable(code), None) => {
+                register_dtable::execute_metered(code, v, m, h, host, fuel)
+            }
+            (Code::RegisterDtable(code), Some(s)) => {
+                register_dtable::resume(code, s, v, m, h, host, fuel)
+            }
+            (Code::RegisterSwitch(code), None) => {
+                register_switch::execute_metered(code, v, m, h, host, fuel)
+            }
+            (Code::RegisterSwitch(code), Some(s)) => {
+                register_switch::resume(code, s, v, m, h, host, fuel)
+            }
+            (Code::RegisterFixed(program), None) => {
+                register_fixed::execute_metered(program, v, m, h, host, fuel)
+            }
+            (Code::RegisterFixed(program), Some(s)) => {
+                register_fixed::resume(program, s, v, m, h, host, fuel)
+            }
+            _ => panic!("{} isn't metered", self.name),
+        }
+    }
+}
+
+/// Small enough that programs' heaps are collected on almost every allocation, with either
+/// collector.
+pub const HEAP_THRESHOLD: usize = 64;
+
+/// A run of a program on one backend with one collector.
+pub struct Run {
+    pub name: &'static str,
+    pub result: Result<Value, VmError>,
+    pub variables: [Value; 256],
+    pub memory: Vec<u8>,
+    pub heap: Heap,
+}
+
+/// Runs `code` on each of `backends`, once with each collector, with `arguments` in its first
+/// variables, `memory` bytes of linear memory and the functions of `host`.
+pub fn run_each(
+    code: &Instruction,
+    backends: &[Backend],
+    arguments: &[Value],
+    memory: usize,
+    host: &Host,
+) -> Vec<Run> {
+    let collectors = [
+        Collector::MarkSweep,
+        Collector::Generational {
+            nursery: HEAP_THRESHOLD,
+        },
+    ];
+    let mut runs = vec![];
+    for mut compiled in compile(code, backends) {
+        for collector in collectors {
+            let mut variables = [Value::int(0); 256];
+            variables[..arguments.len()].copy_from_slice(arguments);
+            let mut memory = vec![0; memory];
+            let mut heap = Heap::with_collector(collector, HEAP_THRESHOLD);
+            let result = compiled.run(&mut variables, &mut memory, &mut heap, host);
+            runs.push(Run {
+                name: compiled.name,
+                result,
+                variables,
+                memory,
+                heap,
+            });
+        }
+    }
+    runs
+}
@@ -0,0 +1,626 @@
+use std::{cell::Cell, panic, rc::Rc};
+
+mod common;
+
+use dispatchers::{
+    bytecode::{Chunk, CompileError, Disassembly},
+    heap::{Heap, Object},
+    host::Host,
+    parser,
+    treewalk::Instruction,
+    value::Value,
+    *,
+};
+
+const FACTORIAL: &str = include_str!("../programs/factorial.dsp");
+
+/// The size of the linear memory that programs are run with.
+const MEMORY: usize = 16;
+
+type Results = Vec<(&'static str, Value, [Value; 256], Vec<u8>)>;
+
+fn run_all(code: &Instruction, arguments: &[Value]) -> Results {
+    run_all_with(code, arguments, &Host::new())
+}
+
+/// Runs a program on every backend, with both collectors, and calling functions from `host`.
+fn run_all_with(code: &Instruction, arguments: &[Value], host: &Host) -> Results {
+    common::run_each(code, common::ALL, arguments, MEMORY, host)
+        .into_iter()
+        .map(|run| (run.name, run.result.unwrap(), run.variables, run.memory))
+        .collect()
+}
+
+#[test]
+fn factorial() {
+    let program = parser::parse(FACTORIAL).unwrap();
+    assert_eq!(program.variables, ["n", "i", "x"]);
+    assert_eq!(program.arguments, 1);
+    for (name, result, variables, _) in run_all(&program.code, &[Value::int(10)]) {
+        assert_eq!(result, Value::int(3628800), "{name}");
+        assert_eq!(
+            variables[..3],
+            [Value::int(10), Value::int(11), Value::int(3628800)],
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn compiles_benchmark() {
+    let code = treewalk::code();
+    for (name, _, variables, _) in run_all(&code, &[Value::int(10)]) {
+        assert_eq!(variables[2], Value::int(3628800), "{name}");
+    }
+}
+
+#[test]
+fn loops_as_values() {
+    let program = parser::parse("(let a (while (<= i 3) (let i (+ i 1)))) (+ a (do 1 2))").unwrap();
+    assert_eq!(
+        compact_treewalk_switch::compile(&program.code),
+        Err(CompileError::StatementInExpression)
+    );
+    // A loop's value is the value of the last iteration of its body.
+    let program = parser::parse("(let a (while (<= i 3) (let i (+ i 1)))) (+ a i)").unwrap();
+    for (name, result, _, _) in run_all(&program.code, &[]) {
+        assert_eq!(result, Value::int(8), "{name}");
+    }
+}
+
+#[test]
+fn operands_assigned_by_other_operand() {
+    // The right operand overwrites `x` after the left operand has been evaluated.
+    let program = parser::parse("(let x 2) (+ x (let x 5))").unwrap();
+    for (name, result, _, _) in run_all(&program.code, &[]) {
+        assert_eq!(result, Value::int(7), "{name}");
+    }
+}
+
+#[test]
+fn floats() {
+    let program = parser::parse(
+        "(args a) (let x 0.5) (let y (f* x (float a))) (let z (int (f- 0.0 1.0))) \
+         (let i (int (f+ y 1e10))) (+ (f< x y) z)",
+    )
+    .unwrap();
+    for (name, result, variables, _) in run_all(&program.code, &[Value::int(3)]) {
+        assert_eq!(result, Value::int(1), "{name}");
+        assert_eq!(variables[2], Value::float(1.5), "{name}");
+        // Conversions to integers saturate.
+        assert_eq!(variables[3], Value::int(0), "{name}");
+        assert_eq!(variables[4], Value::int(u32::MAX), "{name}");
+    }
+
+    let stack = stack_switch::compile(&program.code).unwrap();
+    assert!(stack_switch::disassemble(&stack)
+        .iter()
+        .any(|(_, text)| text == "Float 0.5"));
+    assert!(parser::parse("(+ 1.5.0 1)").is_err());
+}
+
+#[test]
+fn quickening() {
+    // `(+ x x)` sees an integer, then floats, so it's quickened, deoptimised and quickened again.
+    let program = parser::parse(
+        "(let x 1) (while (<= n 3) (let y (+ x x)) (let x 0.5) (let n (+ n 1))) (+ y 0.25)",
+    )
+    .unwrap();
+    let expected = Value::float(1.25);
+
+    let mut stack = stack_switch::compile(&program.code).unwrap();
+    let mut register = register_switch::compile(&program.code).unwrap();
+    let (cold_stack, cold_register) = (stack.clone(), register.clone());
+    for _ in 0..2 {
+        assert_eq!(
+            stack_switch::execute(&mut stack, &mut [], &mut [], &mut Heap::new(), &Host::new())
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            register_switch::execute(
+                &mut register,
+                &mut [],
+                &mut [],
+                &mut Heap::new(),
+                &Host::new()
+            )
+            .unwrap(),
+            expected
+        );
+    }
+    // The backends sharing the encoding run quickened code too.
+    assert_eq!(
+        stack_dtable::execute(&stack, &mut [], &mut [], &mut Heap::new(), &Host::new()).unwrap(),
+        expected
+    );
+    assert_eq!(
+        register_dtable::execute(&register, &mut [], &mut [], &mut Heap::new(), &Host::new())
+            .unwrap(),
+        expected
+    );
+
+    let count = |disassembly: &Disassembly, opcode: &str| {
+        disassembly
+            .iter()
+            .filter(|(_, text)| text.split_whitespace().any(|word| word == opcode))
+            .count()
+    };
+    for (before, after) in [
+        (
+            stack_switch::disassemble(&cold_stack),
+            stack_switch::disassemble(&stack),
+        ),
+        (
+            register_switch::disassemble(&cold_register),
+            register_switch::disassemble(&register),
+        ),
+    ] {
+        assert_eq!(count(&before, "Add"), 3);
+        assert_eq!(count(&after, "AddInt"), 1);
+        assert_eq!(count(&after, "AddFloat"), 2);
+    }
+    stack_switch::verify(&stack).unwrap();
+    register_switch::verify(&register).unwrap();
+}
+
+#[test]
+fn linear_memory() {
+    // Integers are little-endian and unaligned, and stores truncate them but evaluate to them.
+    let program = parser::parse(
+        "(store32 0 305419896) (let a (load8 1)) (let b (load16 2)) (let c (load32 1)) \
+         (store8 4 (load8 0)) (let d (store16 7 131071)) (+ (load16 7) (load8 9))",
+    )
+    .unwrap();
+    let mut expected_memory = [0; MEMORY];
+    expected_memory[..9].copy_from_slice(&[0x78, 0x56, 0x34, 0x12, 0x78, 0, 0, 0xff, 0xff]);
+    for (name, result, variables, memory) in run_all(&program.code, &[]) {
+        assert_eq!(result, Value::int(0xffff), "{name}");
+        assert_eq!(
+            variables[..4],
+            [0x56, 0x1234, 0x123456, 0x1ffff].map(Value::int),
+            "{name}"
+        );
+        assert_eq!(memory, expected_memory, "{name}");
+    }
+
+    // Accesses that run past the end of memory panic, even when their value isn't used.
+    type Run<'a> = &'a dyn Fn(&mut [u8]) -> Value;
+    for source in [
+        "(load32 13)",
+        "(do (load8 16) 0)",
+        "(store16 15 0)",
+        "(load8 4294967295)",
+    ] {
+        let code = parser::parse(source).unwrap().code;
+        let compact = compact_treewalk_switch::compile(&code).unwrap();
+        let stack = stack_switch::compile(&code).unwrap();
+        let register = register_switch::compile(&code).unwrap();
+        let fixed = register_fixed::compile(&code).unwrap();
+        let backends: [(&str, Run); 8] = [
+            ("treewalk", &|m| {
+                treewalk::execute(&code, &mut [], m, &mut Heap::new(), &Host::new()).unwrap()
+            }),
+            ("compact treewalk (dtable)", &|m| {
+                compact_treewalk_dtable::execute(
+                    &compact,
+                    &mut [],
+                    m,
+                    &mut Heap::new(),
+                    &Host::new(),
+                )
+            }),
+            ("compact treewalk (switch)", &|m| {
+                compact_treewalk_switch::execute(
+                    &compact,
+                    &mut [],
+                    m,
+                    &mut Heap::new(),
+                    &Host::new(),
+                )
+            }),
+            ("stack (dtable)", &|m| {
+                stack_dtable::execute(&stack, &mut [], m, &mut Heap::new(), &Host::new()).unwrap()
+            }),
+            ("stack (switch)", &|m| {
+                stack_switch::execute(
+                    &mut stack.clone(),
+                    &mut [],
+                    m,
+                    &mut Heap::new(),
+                    &Host::new(),
+                )
+                .unwrap()
+            }),
+            ("register (dtable)", &|m| {
+                register_dtable::execute(&register, &mut [], m, &mut Heap::new(), &Host::new())
+                    .unwrap()
+            }),
+            ("register (switch)", &|m| {
+                register_switch::execute(
+                    &mut register.clone(),
+                    &mut [],
+                    m,
+                    &mut Heap::new(),
+                    &Host::new(),
+                )
+                .unwrap()
+            }),
+            ("register (fixed)", &|m| {
+                register_fixed::execute(&fixed, &mut [], m, &mut Heap::new(), &Host::new()).unwrap()
+            }),
+        ];
+        for (name, execute) in backends {
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| execute(&mut [0; MEMORY])));
+            assert!(result.is_err(), "{name} accepts {source}");
+        }
+    }
+}
+
+#[test]
+fn heap_objects() {
+    // Objects live as long as a variable, or an object reachable from one, refers to them. The
+    // array is promoted before the record is written to it, which the remembered set keeps alive.
+    let program = parser::parse(
+        "(let s \"hi\\n\") (let a (array 3)) (set a 0 (record 2)) (set (get a 0) 1 s) \
+         (let i 0) (while (<= i 20) (let g (array 10)) (let i (+ i 1))) \
+         (set a 1 (len (get (get a 0) 1))) \
+         (+ (get a 1) (+ (get s 2) (+ (get a 2) (len (record 5)))))",
+    )
+    .unwrap();
+    for (name, result, _, _) in run_all(&program.code, &[]) {
+        assert_eq!(result, Value::int(3 + 10 + 5), "{name}");
+    }
+
+    // So do objects that are operands of an operation still being evaluated.
+    let program = parser::parse(
+        "(+ (get (array 2) (- (len (array 10)) 9)) \
+            (set (record 3) (- (len (record 10)) 8) (len \"abc\")))",
+    )
+    .unwrap();
+    for (name, result, _, _) in run_all(&program.code, &[]) {
+        assert_eq!(result, Value::int(3), "{name}");
+    }
+
+    let stack = stack_switch::compile(&program.code).unwrap();
+    assert!(stack_switch::disassemble(&stack)
+        .iter()
+        .any(|(_, text)| text.ends_with("(\"abc\")")));
+}
+
+#[test]
+fn host_functions() {
+    let calls = Rc::new(Cell::new(0));
+    let mut host = Host::new();
+    host.register("sum3", 3, |call| {
+        let args = call.args();
+        Value::int(args[0].as_int() * 100 + args[1].as_int() * 10 + args[2].as_int())
+    });
+    let counter = calls.clone();
+    host.register("tick", 0, move |_| {
+        counter.set(counter.get() + 1);
+        Value::int(0)
+    });
+    // The arguments must survive the collection that allocating the pair may cause.
+    host.register("pair", 2, |call| {
+        let args = call.args().to_vec();
+        call.alloc(Object::Array(args))
+    });
+
+    // Arguments are evaluated in order, calls are kept even if their value isn't used, and
+    // they may be nested.
+    let program = parser::parse_with(
+        "(let x 1) (tick) (let y (sum3 x (let x 2) (sum3 x 0 (tick)))) \
+         (+ y (len (get (pair (array 3) (array 4)) 1)))",
+        &host,
+    )
+    .unwrap();
+    let results = run_all_with(&program.code, &[], &host);
+    for (name, result, _, _) in &results {
+        assert_eq!(*result, Value::int(120 + 200 + 4), "{name}");
+    }
+    assert_eq!(calls.get(), 2 * results.len());
+
+    let stack = stack_switch::compile(&program.code).unwrap();
+    assert!(stack_switch::disassemble(&stack)
+        .iter()
+        .any(|(_, text)| text.ends_with("CallNative 0, 3")));
+
+    // Function indices and registers past the first 256 need wide operands.
+    let mut host = Host::new();
+    for i in 0..300 {
+        host.register(format!("f{i}"), 1, move |call| {
+            Value::int(call.args()[0].as_int() + i)
+        });
+    }
+    let mut source: String = (0..300).map(|i| format!("(let v{i} {i})")).collect();
+    source += "(f299 (f1 v299))";
+    let wide = parser::parse_with(&source, &host).unwrap();
+    for (name, result, _, _) in run_all_with(&wide.code, &[], &host) {
+        assert_eq!(result, Value::int(599), "{name}");
+    }
+
+    // A program must be run with the functions it was compiled against.
+    let result = panic::catch_unwind(|| {
+        treewalk::execute(
+            &program.code,
+            &mut [],
+            &mut [],
+            &mut Heap::new(),
+            &Host::new(),
+        )
+        .unwrap()
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn verify_rejects_bad_jumps() {
+    let mut code = stack_switch::compile(&treewalk::code()).unwrap();
+    let (jump, _) = stack_switch::disassemble(&code)
+        .into_iter()
+        .find(|(_, text)| text.starts_with("Jump8 "))
+        .unwrap();
+    code.code[jump as usize + 1] += 1;
+    assert!(stack_switch::verify(&code).is_err());
+}
+
+#[test]
+fn constant_pool() {
+    // Integers that fit in a byte are immediates, and the others are stored in the pool once.
+    let program = parser::parse("(let a 70000) (let b (+ 70000 255)) (* a (* b 256))").unwrap();
+    let chunks = [
+        compact_treewalk_switch::compile(&program.code).unwrap(),
+        stack_switch::compile(&program.code).unwrap(),
+        register_switch::compile(&program.code).unwrap(),
+    ];
+    for chunk in &chunks {
+        assert_eq!(chunk.constants, [70000, 256]);
+    }
+
+    // Past the first 256 constants, the pool is indexed with two bytes.
+    let source: String = (0..300)
+        .map(|i| format!("(let x (+ x {}))", 1000 + i))
+        .collect();
+    let program = parser::parse(&source).unwrap();
+    let expected = treewalk::execute(
+        &program.code,
+        &mut [Value::int(0); 256],
+        &mut [],
+        &mut Heap::new(),
+        &Host::new(),
+    )
+    .unwrap();
+    for (name, result, _, _) in run_all(&program.code, &[]) {
+        assert_eq!(result, expected, "{name}");
+    }
+
+    let mut chunk = stack_switch::compile(&program.code).unwrap();
+    chunk.constants.pop();
+    assert!(stack_switch::verify(&chunk).is_err());
+}
+
+#[test]
+fn fixed_width_constants() {
+    // Constants are shared, and those past the first 256 are loaded into registers.
+    let source: String = (0..300)
+        .map(|i| format!("(let x (+ x {}))", i % 260))
+        .collect();
+    let program = parser::parse(&source).unwrap();
+    let fixed = register_fixed::compile(&program.code).unwrap();
+    register_fixed::verify(&fixed).unwrap();
+    assert_eq!(fixed.constants.len(), 260);
+    let mut variables = [Value::int(0); 256];
+    let expected = treewalk::execute(
+        &program.code,
+        &mut [Value::int(0); 256],
+        &mut [],
+        &mut Heap::new(),
+        &Host::new(),
+    )
+    .unwrap();
+    assert_eq!(
+        register_fixed::execute(
+            &fixed,
+            &mut variables,
+            &mut [],
+            &mut Heap::new(),
+            &Host::new()
+        )
+        .unwrap(),
+        expected
+    );
+
+    let module = fixed.to_module();
+    assert_eq!(
+        register_fixed::Program::from_module(&module).unwrap(),
+        fixed
+    );
+}
+
+#[test]
+fn many_variables() {
+    // Variables past the first 256 need wide operands, as do temporaries past them.
+    for count in [255, 256, 257, 300] {
+        let mut source: String = (0..count).map(|i| format!("(let v{i} {i})")).collect();
+        let last = count - 1;
+        source += &format!(
+            "(while (<= v{last} {}) (let v{last} (+ v{last} v1)))",
+            last + 2
+        );
+        source += &format!("(+ v{last} (- v{last} v{}))", last - 1);
+        let program = parser::parse(&source).unwrap();
+        let expected = treewalk::execute(
+            &program.code,
+            &mut [Value::int(0); 256],
+            &mut [],
+            &mut Heap::new(),
+            &Host::new(),
+        )
+        .unwrap();
+        assert_eq!(expected, Value::int(last as u32 + 3 + 4));
+        for (name, result, _, _) in run_all(&program.code, &[]) {
+            assert_eq!(result, expected, "{name} with {count} variables");
+        }
+
+        // Every operand must be within the slots the code declares.
+        let mut compact = compact_treewalk_switch::compile(&program.code).unwrap();
+        let mut stack = stack_switch::compile(&program.code).unwrap();
+        let mut register = register_switch::compile(&program.code).unwrap();
+        let mut fixed = register_fixed::compile(&program.code).unwrap();
+        compact.slots -= 1;
+        stack.slots -= 1;
+        register.slots -= 1;
+        fixed.slots -= 1;
+        assert!(compact_treewalk_switch::verify(&compact).is_err());
+        assert!(stack_switch::verify(&stack).is_err());
+        assert!(register_switch::verify(&register).is_err());
+        assert!(register_fixed::verify(&fixed).is_err());
+    }
+
+    let program = parser::parse("(let a 1) (let b (+ a 1))").unwrap();
+    let stack = stack_switch::compile(&program.code).unwrap();
+    assert!(stack_switch::disassemble(&stack)
+        .iter()
+        .all(|(_, text)| !text.starts_with("Wide")));
+    let source: String = (0..300).map(|i| format!("(let v{i} {i})")).collect();
+    let program = parser::parse(&source).unwrap();
+    let stack = stack_switch::compile(&program.code).unwrap();
+    let disassembly = stack_switch::disassemble(&stack);
+    assert_eq!(disassembly[0].1, "Wide Reserve 300");
+    assert!(disassembly.iter().any(|(_, text)| text == "Wide Let 299"));
+}
+
+#[test]
+fn large_programs() {
+    // Long enough that jumps need four-byte targets in the byte encodings, and more than the
+    // 131071 instructions that `sBx` can reach in the fixed-width one.
+    let body = "(let x (+ x 3))".repeat(140_000);
+    let source = format!("(while (<= i 1) {body} (let i (+ i 1))) x");
+    let program = parser::parse(&source).unwrap();
+    for (name, result, _, _) in run_all(&program.code, &[]) {
+        assert_eq!(result, Value::int(2 * 3 * 140_000), "{name}");
+    }
+
+    let compact = compact_treewalk_switch::compile(&program.code).unwrap();
+    let stack = stack_switch::compile(&program.code).unwrap();
+    let register = register_switch::compile(&program.code).unwrap();
+    for code in [&compact, &stack, &register] {
+        assert!(code.code.len() > u16::MAX as usize);
+    }
+    for disassembly in [
+        compact_treewalk_switch::disassemble(&compact),
+        stack_switch::disassemble(&stack),
+        register_switch::disassemble(&register),
+    ] {
+        assert!(disassembly
+            .iter()
+            .any(|(_, text)| text.starts_with("Wide Jump ")));
+    }
+    let fixed = register_fixed::compile(&program.code).unwrap();
+    let jumps = register_fixed::disassemble(&fixed)
+        .into_iter()
+        .filter(|(_, text)| text.starts_with("Wide Jump"))
+        .count();
+    assert_eq!(jumps, 2);
+}
+
+#[test]
+fn relative_jumps() {
+    // Each jump gets the shortest offset that reaches its target.
+    let short = parser::parse("(while (<= i 3) (let i (+ i 1)))").unwrap();
+    let body = "(let x (+ x 1000))".repeat(20);
+    let long = parser::parse(&format!("(while (<= i 3) {body} (let i (+ i 1)))")).unwrap();
+    for (program, jump) in [(&short, "Jump8 "), (&long, "Jump ")] {
+        let compact = compact_treewalk_switch::compile(&program.code).unwrap();
+        let stack = stack_switch::compile(&program.code).unwrap();
+        let register = register_switch::compile(&program.code).unwrap();
+        for disassembly in [
+            compact_treewalk_switch::disassemble(&compact),
+            stack_switch::disassemble(&stack),
+            register_switch::disassemble(&register),
+        ] {
+            assert!(disassembly.iter().any(|(_, text)| text.starts_with(jump)));
+        }
+    }
+
+    // Code can be moved without patching its jumps, so chunks can be put one after the other.
+    let first = parser::parse("(let i 0) (let x 5)").unwrap();
+    let second = parser::parse("(while (<= i 3) (let x (+ x i)) (let i (+ i 1))) x").unwrap();
+    let concatenate = |first: Chunk, second: Chunk| {
+        assert!(first.constants.is_empty() && second.constants.is_empty());
+        let mut code = first.code;
+        assert_eq!(code.pop(), second.code.last().copied(), "ends with `Halt`");
+        code.extend(second.code);
+        Chunk {
+            code,
+            constants: vec![],
+            slots: first.slots.max(second.slots),
+            handlers: vec![],
+        }
+    };
+
+    let compact = concatenate(
+        compact_treewalk_switch::compile(&first.code).unwrap(),
+        compact_treewalk_switch::compile(&second.code).unwrap(),
+    );
+    compact_treewalk_switch::verify(&compact).unwrap();
+    assert_eq!(
+        compact_treewalk_switch::execute(
+            &compact,
+            &mut [],
+            &mut [],
+            &mut Heap::new(),
+            &Host::new()
+        ),
+        Value::int(11)
+    );
+
+    let mut stack = concatenate(
+        stack_switch::compile(&first.code).unwrap(),
+        stack_switch::compile(&second.code).unwrap(),
+    );
+    stack_switch::verify(&stack).unwrap();
+    assert_eq!(
+        stack_switch::execute(&mut stack, &mut [], &mut [], &mut Heap::new(), &Host::new())
+            .unwrap(),
+        Value::int(11)
+    );
+
+    let mut register = concatenate(
+        register_switch::compile(&first.code).unwrap(),
+        register_switch::compile(&second.code).unwrap(),
+    );
+    register_switch::verify(&register).unwrap();
+    assert_eq!(
+        register_switch::execute(
+            &mut register,
+            &mut [],
+            &mut [],
+            &mut Heap::new(),
+            &Host::new()
+        )
+        .unwrap(),
+        Value::int(11)
+    );
+}
+
+#[test]
+fn parse_errors() {
+    let error = parser::parse("(let x 1)\n(let y (+ x))").unwrap_err();
+    assert_eq!((error.line, error.column), (2, 8));
+    assert!(parser::parse("(let x 1").is_err());
+    assert!(parser::parse("(x 1)").is_err());
+    assert!(parser::parse("(let while 1)").is_err());
+    assert!(parser::parse("(let x 1) (args x)").is_err());
+    assert!(parser::parse("(len \"abc)").is_err());
+    assert!(parser::parse("(len \"\\a\")").is_err());
+    assert!(parser::parse("(record x)").is_err());
+
+    let mut host = Host::new();
+    host.register("print", 1, |call| call.args()[0]);
+    assert!(parser::parse_with("(print 1)", &host).is_ok());
+    assert!(parser::parse("(print 1)").is_err());
+    let error = parser::parse_with("(print 1 2)", &host).unwrap_err();
+    assert!(error.message.contains("takes 1 arguments"), "{error}");
+}
@@ -0,0 +1,149 @@
+use std::panic;
+
+mod common;
+
+use dispatchers::{
+    bytecode::CompileError,
+    coroutine::Status,
+    heap::{Heap, Object},
+    host::Host,
+    parser,
+    treewalk::Instruction,
+    value::Value,
+    *,
+};
+
+/// Runs a program on every backend that has coroutines, with both collectors, and checks that
+/// they agree on its result. Returns the result, variables and heap of the first.
+fn run_all(code: &Instruction, arguments: &[Value]) -> (Value, [Value; 256], Heap) {
+    let mut runs = common::run_each(code, common::BYTECODE, arguments, 0, &Host::new());
+    let first = runs.remove(0);
+    for run in runs {
+        assert_eq!(run.result, first.result, "{}", run.name);
+    }
+    (first.result.unwrap(), first.variables, first.heap)
+}
+
+fn status(heap: &Heap, coroutine: Value) -> Status {
+    match heap.object(coroutine) {
+        Object::Coroutine(coroutine) => coroutine.status(),
+        object => panic!("{object:?} is not a coroutine"),
+    }
+}
+
+#[test]
+fn generators() {
+    let program = parser::parse(
+        "(args n) \
+         (let squares (coroutine \
+           (let i 0) (while (<= (+ i 1) n) (let i (+ i 1)) (yield (* i i))) \
+           1000)) \
+         (let sum 0) (let k 0) \
+         (while (<= (+ k 1) n) (let sum (+ sum (resume squares 0))) (let k (+ k 1))) \
+         (+ sum (resume squares 0))",
+    )
+    .unwrap();
+    let (result, variables, heap) = run_all(&program.code, &[Value::int(10)]);
+    assert_eq!(result, Value::int(385 + 1000));
+    // The coroutine's variables are its own.
+    assert_eq!(variables[2], Value::int(0));
+    assert_eq!(status(&heap, variables[1]), Status::Dead);
+
+    let stack = stack_switch::compile(&program.code).unwrap();
+    assert!(stack_switch::disassemble(&stack)
+        .iter()
+        .any(|(_, text)| text.starts_with("NewCoroutine")));
+    let register = register_switch::compile(&program.code).unwrap();
+    assert!(register_switch::disassemble(&register)
+        .iter()
+        .any(|(_, text)| text.starts_with("%1 = NewCoroutine")));
+}
+
+#[test]
+fn resumes_with_values() {
+    // A `yield` evaluates to what its coroutine is next resumed with, and coroutines may resume
+    // others.
+    let program = parser::parse(
+        "(let a (coroutine (yield 1) (yield 2) 3)) \
+         (let b (coroutine (let x (resume a 0)) (let y (yield (* x 10))) (+ y (resume a 0)))) \
+         (let p (resume b 0)) \
+         (let q (resume b 5)) \
+         (+ (* p 1000) q)",
+    )
+    .unwrap();
+    let (result, variables, heap) = run_all(&program.code, &[]);
+    assert_eq!(result, Value::int(10 * 1000 + 5 + 2));
+    assert_eq!(status(&heap, variables[0]), Status::Suspended);
+    assert_eq!(status(&heap, variables[1]), Status::Dead);
+
+    // A coroutine starts out with a copy of its creator's variables, and neither sees the
+    // other's assignments.
+    let program = parser::parse(
+        "(let x 1) (let c (coroutine (let y (+ x 1)) (let x 5) (yield y) x)) \
+         (let x 10) (let y (resume c 0)) (+ (* y 100) (+ (resume c 0) x))",
+    )
+    .unwrap();
+    let (result, variables, heap) = run_all(&program.code, &[]);
+    assert_eq!(result, Value::int(2 * 100 + 5 + 10));
+    assert_eq!(variables[0], Value::int(10));
+    assert_eq!(status(&heap, variables[1]), Status::Dead);
+}
+
+#[test]
+fn collections() {
+    // Each coroutine allocates before and after it yields, while its frame is on the heap.
+    let program = parser::parse(
+        "(args n) (let total 0) (let j 0) \
+         (while (<= (+ j 1) n) \
+           (let g (coroutine \
+             (let a (array 10)) (set a 5 7) (yield (get a 5)) \
+             (let b (array 5)) (+ (get a 5) (len b)))) \
+           (let total (+ total (+ (resume g 0) (resume g 0)))) \
+           (let j (+ j 1))) \
+         total",
+    )
+    .unwrap();
+    let (result, _, heap) = run_all(&program.code, &[Value::int(100)]);
+    assert_eq!(result, Value::int(100 * (7 + 7 + 5)));
+    assert!(heap.collections() > 0);
+    // Finished coroutines drop their frames, and the arrays in them.
+    assert!(heap.live() < 100, "{} objects are live", heap.live());
+}
+
+#[test]
+fn wide_operands() {
+    // A coroutine in a register past the first 256, and a body too long for a two-byte offset.
+    let mut source: String = (0..300).map(|i| format!("(let v{i} {i})")).collect();
+    source += "(let v299 (coroutine (let v1 0)";
+    source += &"(let v1 (+ v1 1))".repeat(10_000);
+    source += "(+ v1 v298)))";
+    source += "(resume v299 0)";
+    let program = parser::parse(&source).unwrap();
+    let (result, _, _) = run_all(&program.code, &[]);
+    assert_eq!(result, Value::int(10_000 + 298));
+
+    let stack = stack_switch::compile(&program.code).unwrap();
+    assert!(stack_switch::disassemble(&stack)
+        .iter()
+        .any(|(_, text)| text.starts_with("Wide NewCoroutine")));
+}
+
+#[test]
+fn dead_coroutines_cannot_be_resumed() {
+    let program = parser::parse("(let c (coroutine 1)) (resume c 0) (resume c 0)").unwrap();
+    for mut compiled in common::compile(&program.code, common::BYTECODE) {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            compiled.run(&mut [], &mut [], &mut Heap::new(), &Host::new())
+        }));
+        assert!(result.is_err(), "{}", compiled.name);
+    }
+}
+
+#[test]
+fn tree_walkers_reject_coroutines() {
+    let program = parser::parse("(let c (coroutine (yield 1))) (resume c 0)").unwrap();
+    assert_eq!(
+        compact_treewalk_switch::compile(&program.code).unwrap_err(),
+        CompileError::CoroutinesUnsupported
+    );
+}
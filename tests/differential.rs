@@ -0,0 +1,754 @@
+//! Differential testing: random programs are run on every backend, and folded, which must agree
+//! with the treewalk interpreter on the result and on the final values of the variables and
+//! memory, including those assigned before an exception was caught. Failing programs are shrunk
+//! before being reported.
+//!
+//! Set `DIFFERENTIAL_SEED` to reproduce a failure and `DIFFERENTIAL_CASES` to run more cases.
+
+use std::{env, fmt, panic};
+
+use dispatchers::{
+    bytecode::{Chunk, CompileError},
+    heap::Heap,
+    host::Host,
+    memory::Width,
+    treewalk::Instruction,
+    value::{Unpacked, Value},
+    *,
+};
+
+/// Variables that the programs read and assign freely. They start out with random values, the
+/// first half integers and the second half floats.
+const VARIABLES: u8 = 8;
+const FLOAT_VARIABLES: std::ops::Range<u8> = VARIABLES / 2..VARIABLES;
+/// Loop counters take the slots after the variables, one for each level of nesting.
+const MAX_LOOP_DEPTH: u8 = 3;
+const MAX_DEPTH: u32 = 5;
+/// The size of the linear memory, which starts out with random bytes.
+const MEMORY: u32 = 32;
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        (self.next() % n as u64) as u32
+    }
+
+    fn int(&mut self) -> u32 {
+        match self.below(4) {
+            0 => self.next() as u32,
+            1 => u32::MAX - self.below(4),
+            _ => self.below(10),
+        }
+    }
+
+    fn float(&mut self) -> f64 {
+        match self.below(4) {
+            0 => 0.1,
+            1 => 1e300,
+            _ => self.below(16) as f64 / 4.0,
+        }
+    }
+
+    fn value(&mut self, ty: Type) -> Node {
+        match ty {
+            Type::Int => Node::Int(self.int()),
+            Type::Float => Node::Float(self.float()),
+        }
+    }
+
+    fn ty(&mut self) -> Type {
+        [Type::Int, Type::Float][self.below(2) as usize]
+    }
+}
+
+/// Programs are typed, so that no instruction gets an operand of the wrong type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Int,
+    Float,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    LessEq,
+    Add,
+    Multiply,
+    Subtract,
+    Divide,
+    Remainder,
+    FLess,
+    FAdd,
+    FMultiply,
+    FSubtract,
+    FDivide,
+}
+
+impl Op {
+    /// `Add` takes either type, and gives the type of its operands.
+    fn operands(self, result: Type) -> Type {
+        match self {
+            Op::Add => result,
+            Op::LessEq | Op::Multiply | Op::Subtract | Op::Divide | Op::Remainder => Type::Int,
+            _ => Type::Float,
+        }
+    }
+
+    fn result(self, operands: Type) -> Type {
+        match self {
+            Op::Add => operands,
+            Op::FAdd | Op::FMultiply | Op::FSubtract | Op::FDivide => Type::Float,
+            _ => Type::Int,
+        }
+    }
+}
+
+/// A program whose loops are known to terminate. Shrinking works on this representation, so
+/// that it can't break the loop counters.
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Int(u32),
+    Float(f64),
+    Var(u8),
+    Let(u8, Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+    IntToFloat(Box<Node>),
+    FloatToInt(Box<Node>),
+    Load(Width, Box<Node>),
+    Store(Width, Box<Node>, Box<Node>),
+    Sequence(Vec<Node>),
+    /// Runs the body `count` times, counting in the given slot.
+    Loop {
+        counter: u8,
+        count: u32,
+        body: Box<Node>,
+    },
+    /// Catches integers, into an integer variable.
+    Try {
+        body: Box<Node>,
+        variable: u8,
+        handler: Box<Node>,
+    },
+    /// Throws an integer, in place of a value of the given type.
+    Throw(Type, Box<Node>),
+}
+
+impl Node {
+    /// Throws are only generated where a `Try` catches them, since an uncaught exception ends
+    /// the program early.
+    fn generate(rng: &mut Rng, depth: u32, loop_depth: u8, catching: bool, ty: Type) -> Node {
+        let var = |rng: &mut Rng| match ty {
+            // Loop counters are integers too.
+            Type::Int => {
+                let v = rng.below((FLOAT_VARIABLES.start + loop_depth) as u32) as u8;
+                if FLOAT_VARIABLES.contains(&v) {
+                    v + FLOAT_VARIABLES.len() as u8
+                } else {
+                    v
+                }
+            }
+            Type::Float => FLOAT_VARIABLES.start + rng.below(FLOAT_VARIABLES.len() as u32) as u8,
+        };
+        if depth == 0 {
+            return match rng.below(2) {
+                0 => rng.value(ty),
+                _ => Node::Var(var(rng)),
+            };
+        }
+        let child =
+            |rng: &mut Rng, ty| Box::new(Node::generate(rng, depth - 1, loop_depth, catching, ty));
+        // Mostly stay in bounds of memory, since an out-of-bounds access ends the program early.
+        let address = |rng: &mut Rng| match rng.below(3) {
+            0 => child(rng, Type::Int),
+            _ => Box::new(Node::Int(rng.below(MEMORY))),
+        };
+        let width = |rng: &mut Rng| [Width::U8, Width::U16, Width::U32][rng.below(3) as usize];
+        match (rng.below(16), ty) {
+            (0, _) => rng.value(ty),
+            (1, _) => Node::Var(var(rng)),
+            (2 | 3, _) => {
+                let variable = match ty {
+                    Type::Int => rng.below(FLOAT_VARIABLES.start as u32) as u8,
+                    Type::Float => var(rng),
+                };
+                Node::Let(variable, child(rng, ty))
+            }
+            (4 | 5, _) => {
+                let op = match ty {
+                    Type::Int => [Op::LessEq, Op::Add, Op::Multiply, Op::Subtract, Op::FLess]
+                        [rng.below(5) as usize],
+                    Type::Float => [Op::Add, Op::FAdd, Op::FMultiply, Op::FSubtract, Op::FDivide]
+                        [rng.below(5) as usize],
+                };
+                let operands = op.operands(ty);
+                Node::Binary(op, child(rng, operands), child(rng, operands))
+            }
+            (6, Type::Int) => {
+                // Mostly avoid dividing by zero, which ends the program early.
+                let op = [Op::Divide, Op::Remainder][rng.below(2) as usize];
+                let divisor = match rng.below(3) {
+                    0 => child(rng, ty),
+                    _ => Box::new(Node::Int(1 + rng.below(9))),
+                };
+                Node::Binary(op, child(rng, ty), divisor)
+            }
+            (6, Type::Float) => Node::Binary(Op::FDivide, child(rng, ty), child(rng, ty)),
+            (7, Type::Int) => Node::FloatToInt(child(rng, Type::Float)),
+            (7, Type::Float) => Node::IntToFloat(child(rng, Type::Int)),
+            (10, Type::Int) => Node::Load(width(rng), address(rng)),
+            (11, Type::Int) => Node::Store(width(rng), address(rng), child(rng, Type::Int)),
+            (12, _) => {
+                let body = Box::new(Node::generate(rng, depth - 1, loop_depth, true, ty));
+                // Sometimes divide by zero before the rest of the body.
+                let body = match rng.below(3) {
+                    0 => {
+                        let op = [Op::Divide, Op::Remainder][rng.below(2) as usize];
+                        let dividend = child(rng, Type::Int);
+                        let divide = Node::Binary(op, dividend, Box::new(Node::Int(0)));
+                        Box::new(Node::Sequence(vec![divide, *body]))
+                    }
+                    _ => body,
+                };
+                Node::Try {
+                    body,
+                    variable: rng.below(FLOAT_VARIABLES.start as u32) as u8,
+                    handler: child(rng, ty),
+                }
+            }
+            (13 | 14, _) if catching => Node::Throw(ty, child(rng, Type::Int)),
+            (8 | 9, _) => {
+                let mut nodes = vec![];
+                for _ in 0..rng.below(3) {
+                    let ty = rng.ty();
+                    nodes.push(*child(rng, ty));
+                }
+                nodes.push(*child(rng, ty));
+                Node::Sequence(nodes)
+            }
+            (_, Type::Int) if loop_depth < MAX_LOOP_DEPTH => {
+                let (count, body) = (rng.below(4), rng.ty());
+                Node::Loop {
+                    counter: VARIABLES + loop_depth,
+                    count,
+                    body: Box::new(Node::generate(
+                        rng,
+                        depth - 1,
+                        loop_depth + 1,
+                        catching,
+                        body,
+                    )),
+                }
+            }
+            (_, Type::Int) => Node::Sequence(vec![]),
+            (_, Type::Float) => rng.value(ty),
+        }
+    }
+
+    fn ty(&self) -> Type {
+        match self {
+            Node::Int(_)
+            | Node::FloatToInt(_)
+            | Node::Load(..)
+            | Node::Store(..)
+            | Node::Loop { .. } => Type::Int,
+            Node::Float(_) | Node::IntToFloat(_) => Type::Float,
+            Node::Var(v) if FLOAT_VARIABLES.contains(v) => Type::Float,
+            Node::Var(_) => Type::Int,
+            Node::Let(_, value) => value.ty(),
+            Node::Try { body, .. } => body.ty(),
+            Node::Throw(ty, _) => *ty,
+            Node::Binary(op, a, _) => op.result(a.ty()),
+            Node::Sequence(nodes) => nodes.last().map_or(Type::Int, Node::ty),
+        }
+    }
+
+    fn lower(&self) -> Instruction {
+        match self {
+            Node::Int(i) => Instruction::Int(*i),
+            Node::Float(f) => Instruction::Float(*f),
+            Node::Var(v) => Instruction::Var((*v).into()),
+            Node::Let(v, value) => Instruction::Let {
+                variable: (*v).into(),
+                value: Box::new(value.lower()),
+            },
+            Node::Binary(op, a, b) => {
+                let (a, b) = (Box::new(a.lower()), Box::new(b.lower()));
+                match op {
+                    Op::LessEq => Instruction::LessEq(a, b),
+                    Op::Add => Instruction::Add(a, b),
+                    Op::Multiply => Instruction::Multiply(a, b),
+                    Op::Subtract => Instruction::Subtract(a, b),
+                    Op::Divide => Instruction::Divide(a, b),
+                    Op::Remainder => Instruction::Remainder(a, b),
+                    Op::FLess => Instruction::FLess(a, b),
+                    Op::FAdd => Instruction::FAdd(a, b),
+                    Op::FMultiply => Instruction::FMultiply(a, b),
+                    Op::FSubtract => Instruction::FSubtract(a, b),
+                    Op::FDivide => Instruction::FDivide(a, b),
+                }
+            }
+            Node::IntToFloat(a) => Instruction::IntToFloat(Box::new(a.lower())),
+            Node::FloatToInt(a) => Instruction::FloatToInt(Box::new(a.lower())),
+            Node::Load(width, address) => Instruction::Index {
+                width: *width,
+                address: Box::new(address.lower()),
+            },
+            Node::Store(width, address, value) => Instruction::StoreIndex {
+                width: *width,
+                address: Box::new(address.lower()),
+                value: Box::new(value.lower()),
+            },
+            Node::Sequence(nodes) => Instruction::Sequence(nodes.iter().map(Node::lower).collect()),
+            Node::Loop {
+                counter,
+                count,
+                body,
+            } => {
+                let counter_plus_one = || {
+                    Box::new(Instruction::Add(
+                        Box::new(Instruction::Var((*counter).into())),
+                        Box::new(Instruction::Int(1)),
+                    ))
+                };
+                Instruction::Sequence(vec![
+                    Instruction::Let {
+                        variable: (*counter).into(),
+                        value: Box::new(Instruction::Int(0)),
+                    },
+                    Instruction::While {
+                        condition: Box::new(Instruction::LessEq(
+                            counter_plus_one(),
+                            Box::new(Instruction::Int(*count)),
+                        )),
+                        body: Box::new(Instruction::Sequence(vec![
+                            body.lower(),
+                            Instruction::Let {
+                                variable: (*counter).into(),
+                                value: counter_plus_one(),
+                            },
+                        ])),
+                    },
+                ])
+            }
+            Node::Try {
+                body,
+                variable,
+                handler,
+            } => Instruction::Try {
+                body: Box::new(body.lower()),
+                variable: (*variable).into(),
+                handler: Box::new(handler.lower()),
+            },
+            Node::Throw(_, value) => Instruction::Throw(Box::new(value.lower())),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Node::Int(_) | Node::Float(_) | Node::Var(_) => 1,
+            Node::Let(_, value)
+            | Node::IntToFloat(value)
+            | Node::FloatToInt(value)
+            | Node::Load(_, value)
+            | Node::Throw(_, value) => 1 + value.size(),
+            Node::Binary(_, a, b)
+            | Node::Store(_, a, b)
+            | Node::Try {
+                body: a,
+                handler: b,
+                ..
+            } => 1 + a.size() + b.size(),
+            Node::Sequence(nodes) => 1 + nodes.iter().map(Node::size).sum::<usize>(),
+            Node::Loop { body, .. } => 1 + body.size(),
+        }
+    }
+
+    /// Programs that are one step simpler than this one and of the same type, simplest first.
+    fn shrink(&self) -> Vec<Node> {
+        let mut candidates = vec![];
+        match self {
+            Node::Int(0) => {}
+            Node::Int(i) => {
+                candidates.push(Node::Int(0));
+                candidates.push(Node::Int(i / 2));
+            }
+            Node::Float(f) if *f == 0.0 => {}
+            Node::Float(_) => candidates.push(Node::Float(0.0)),
+            Node::Var(_) => match self.ty() {
+                Type::Int => candidates.push(Node::Int(0)),
+                Type::Float => candidates.push(Node::Float(0.0)),
+            },
+            Node::IntToFloat(a) | Node::FloatToInt(a) => {
+                for a in a.shrink() {
+                    candidates.push(match self {
+                        Node::IntToFloat(_) => Node::IntToFloat(Box::new(a)),
+                        _ => Node::FloatToInt(Box::new(a)),
+                    });
+                }
+            }
+            Node::Load(width, address) => {
+                candidates.push(Node::Int(0));
+                for address in address.shrink() {
+                    candidates.push(Node::Load(*width, Box::new(address)));
+                }
+            }
+            Node::Store(width, address, value) => {
+                candidates.push(*value.clone());
+                for address in address.shrink() {
+                    candidates.push(Node::Store(*width, Box::new(address), value.clone()));
+                }
+                for value in value.shrink() {
+                    candidates.push(Node::Store(*width, address.clone(), Box::new(value)));
+                }
+            }
+            Node::Let(v, value) => {
+                candidates.push(*value.clone());
+                for value in value.shrink() {
+                    candidates.push(Node::Let(*v, Box::new(value)));
+                }
+            }
+            Node::Binary(op, a, b) => {
+                candidates.push(*a.clone());
+                candidates.push(*b.clone());
+                for a in a.shrink() {
+                    candidates.push(Node::Binary(*op, Box::new(a), b.clone()));
+                }
+                for b in b.shrink() {
+                    candidates.push(Node::Binary(*op, a.clone(), Box::new(b)));
+                }
+            }
+            Node::Sequence(nodes) => {
+                candidates.extend(nodes.iter().cloned());
+                for i in 0..nodes.len() {
+                    let mut without = nodes.clone();
+                    without.remove(i);
+                    candidates.push(Node::Sequence(without));
+                }
+                for (i, node) in nodes.iter().enumerate() {
+                    for node in node.shrink() {
+                        let mut nodes = nodes.clone();
+                        nodes[i] = node;
+                        candidates.push(Node::Sequence(nodes));
+                    }
+                }
+            }
+            Node::Loop {
+                counter,
+                count,
+                body,
+            } => {
+                candidates.push(*body.clone());
+                if *count > 0 {
+                    candidates.push(Node::Loop {
+                        counter: *counter,
+                        count: count - 1,
+                        body: body.clone(),
+                    });
+                }
+                for body in body.shrink() {
+                    candidates.push(Node::Loop {
+                        counter: *counter,
+                        count: *count,
+                        body: Box::new(body),
+                    });
+                }
+            }
+            Node::Try {
+                body,
+                variable,
+                handler,
+            } => {
+                candidates.push(*body.clone());
+                candidates.push(*handler.clone());
+                for body in body.shrink() {
+                    candidates.push(Node::Try {
+                        body: Box::new(body),
+                        variable: *variable,
+                        handler: handler.clone(),
+                    });
+                }
+                for handler in handler.shrink() {
+                    candidates.push(Node::Try {
+                        body: body.clone(),
+                        variable: *variable,
+                        handler: Box::new(handler),
+                    });
+                }
+            }
+            Node::Throw(ty, value) => {
+                candidates.push(match ty {
+                    Type::Int => Node::Int(0),
+                    Type::Float => Node::Float(0.0),
+                });
+                for value in value.shrink() {
+                    candidates.push(Node::Throw(*ty, Box::new(value)));
+                }
+            }
+        }
+        candidates.retain(|candidate| candidate.ty() == self.ty());
+        candidates
+    }
+}
+
+/// Prints the program in the syntax accepted by the `dispatchers` binary.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Int(i) => write!(f, "{i}"),
+            Node::Float(x) => write!(f, "{x:?}"),
+            Node::Var(v) => write!(f, "v{v}"),
+            Node::Let(v, value) => write!(f, "(let v{v} {value})"),
+            Node::Binary(op, a, b) => {
+                let op = match op {
+                    Op::LessEq => "<=",
+                    Op::Add => "+",
+                    Op::Multiply => "*",
+                    Op::Subtract => "-",
+                    Op::Divide => "/",
+                    Op::Remainder => "%",
+                    Op::FLess => "f<",
+                    Op::FAdd => "f+",
+                    Op::FMultiply => "f*",
+                    Op::FSubtract => "f-",
+                    Op::FDivide => "f/",
+                };
+                write!(f, "({op} {a} {b})")
+            }
+            Node::IntToFloat(a) => write!(f, "(float {a})"),
+            Node::FloatToInt(a) => write!(f, "(int {a})"),
+            Node::Load(width, address) => write!(f, "(load{} {address})", width.bytes() * 8),
+            Node::Store(width, address, value) => {
+                write!(f, "(store{} {address} {value})", width.bytes() * 8)
+            }
+            Node::Sequence(nodes) => {
+                write!(f, "(do")?;
+                for node in nodes {
+                    write!(f, " {node}")?;
+                }
+                write!(f, ")")
+            }
+            Node::Loop {
+                counter: c,
+                count,
+                body,
+            } => write!(
+                f,
+                "(do (let v{c} 0) (while (<= (+ v{c} 1) {count}) {body} (let v{c} (+ v{c} 1))))"
+            ),
+            Node::Try {
+                body,
+                variable,
+                handler,
+            } => write!(f, "(try {body} (catch v{variable} {handler}))"),
+            Node::Throw(_, value) => write!(f, "(throw {value})"),
+        }
+    }
+}
+
+type Compile = fn(&Instruction) -> Result<Chunk, CompileError>;
+type Execute = fn(&mut Chunk, &mut [Value], &mut [u8]) -> Value;
+
+const BACKENDS: &[(&str, Compile, Execute)] = &[
+    (
+        "compact treewalk (dtable)",
+        compact_treewalk_dtable::compile,
+        |code, v, m| compact_treewalk_dtable::execute(code, v, m, &mut Heap::new(), &Host::new()),
+    ),
+    (
+        "compact treewalk (switch)",
+        compact_treewalk_switch::compile,
+        |code, v, m| compact_treewalk_switch::execute(code, v, m, &mut Heap::new(), &Host::new()),
+    ),
+    ("stack (dtable)", stack_dtable::compile, |code, v, m| {
+        stack_dtable::execute(code, v, m, &mut Heap::new(), &Host::new()).unwrap()
+    }),
+    ("stack (switch)", stack_switch::compile, |code, v, m| {
+        stack_switch::execute(code, v, m, &mut Heap::new(), &Host::new()).unwrap()
+    }),
+    (
+        "stack (optimized)",
+        |code| {
+            let chunk = stack_switch::optimize(&stack_switch::compile(code)?).unwrap();
+            stack_switch::verify(&chunk).unwrap();
+            Ok(chunk)
+        },
+        |code, v, m| stack_switch::execute(code, v, m, &mut Heap::new(), &Host::new()).unwrap(),
+    ),
+    (
+        "register (dtable)",
+        register_dtable::compile,
+        |code, v, m| register_dtable::execute(code, v, m, &mut Heap::new(), &Host::new()).unwrap(),
+    ),
+    (
+        "register (switch)",
+        register_switch::compile,
+        |code, v, m| register_switch::execute(code, v, m, &mut Heap::new(), &Host::new()).unwrap(),
+    ),
+];
+
+/// Runs the program, returning `None` if it panicked, which happens when dividing by zero or
+/// accessing memory out of bounds. Programs don't use the heap, since which objects references
+/// point to depends on when each backend collects garbage.
+fn run(
+    execute: impl FnOnce(&mut [Value; 256], &mut [u8]) -> Value,
+    variables: &mut [Value; 256],
+    memory: &mut [u8],
+) -> Option<Value> {
+    panic::catch_unwind(panic::AssertUnwindSafe(|| execute(variables, memory))).ok()
+}
+
+/// Compares floats by their bits, so that NaNs produced the same way are equal.
+fn same(a: &[Value], b: &[Value]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| match (a.unpack(), b.unpack()) {
+                (Unpacked::Float(a), Unpacked::Float(b)) => a.to_bits() == b.to_bits(),
+                _ => a == b,
+            })
+}
+
+/// Runs the program on every backend, describing the first disagreement with treewalk.
+fn check(program: &Node, variables: &[Value; 256], memory: &[u8]) -> Result<(), String> {
+    let code = program.lower();
+    // Backends may use the slots after the program's variables for temporaries.
+    let used = code.variable_count();
+
+    let mut expected_variables = *variables;
+    let mut expected_memory = memory.to_vec();
+    let expected = run(
+        |v, m| treewalk::execute(&code, v, m, &mut Heap::new(), &Host::new()).unwrap(),
+        &mut expected_variables,
+        &mut expected_memory,
+    );
+
+    let compare = |name: &str, execute: &mut dyn FnMut(&mut [Value; 256], &mut [u8]) -> Value| {
+        let mut actual_variables = *variables;
+        let mut actual_memory = memory.to_vec();
+        let actual = run(execute, &mut actual_variables, &mut actual_memory);
+
+        if !same(actual.as_slice(), expected.as_slice()) {
+            return Err(format!("{name}: result {actual:?}, expected {expected:?}"));
+        }
+        // The state of the variables after a panic is unspecified.
+        if actual.is_some() && !same(&actual_variables[..used], &expected_variables[..used]) {
+            return Err(format!(
+                "{name}: variables {:?}, expected {:?}",
+                &actual_variables[..used],
+                &expected_variables[..used]
+            ));
+        }
+        if actual.is_some() && actual_memory != expected_memory {
+            return Err(format!(
+                "{name}: memory {actual_memory:?}, expected {expected_memory:?}"
+            ));
+        }
+        Ok(())
+    };
+
+    for &(name, compile, execute) in BACKENDS {
+        let mut bytecode = match compile(&code) {
+            Ok(bytecode) => bytecode,
+            // The compact encoding can't express every program, nor exceptions.
+            Err(CompileError::StatementInExpression | CompileError::ExceptionsUnsupported)
+                if name.starts_with("compact") =>
+            {
+                continue
+            }
+            Err(error) => return Err(format!("{name}: {error}")),
+        };
+        // The second run starts out with the code quickened by the first.
+        compare(name, &mut |v, m| execute(&mut bytecode, v, m))?;
+        compare(name, &mut |v, m| execute(&mut bytecode, v, m))?;
+    }
+
+    let fixed = register_fixed::compile(&code).map_err(|e| format!("register (fixed): {e}"))?;
+    compare("register (fixed)", &mut |v, m| {
+        register_fixed::execute(&fixed, v, m, &mut Heap::new(), &Host::new()).unwrap()
+    })?;
+
+    let folded = fold::fold(&code);
+    compare("folded", &mut |v, m| {
+        treewalk::execute(&folded, v, m, &mut Heap::new(), &Host::new()).unwrap()
+    })
+}
+
+/// Greedily replaces the program with simpler ones that still fail.
+fn shrink(mut program: Node, fails: impl Fn(&Node) -> bool) -> Node {
+    'outer: loop {
+        for candidate in program.shrink() {
+            if fails(&candidate) {
+                program = candidate;
+                continue 'outer;
+            }
+        }
+        return program;
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[test]
+fn backends_agree_with_treewalk() {
+    let seed = env_or("DIFFERENTIAL_SEED", 0x5eed_u64);
+    let cases = env_or("DIFFERENTIAL_CASES", 2000_u32);
+
+    let mut rng = Rng(seed | 1);
+    for case in 0..cases {
+        let depth = 1 + rng.below(MAX_DEPTH);
+        let ty = rng.ty();
+        let program = Node::generate(&mut rng, depth, 0, false, ty);
+        let mut variables = [Value::int(0); 256];
+        for (v, variable) in variables[..VARIABLES as usize].iter_mut().enumerate() {
+            *variable = match FLOAT_VARIABLES.contains(&(v as u8)) {
+                false => Value::int(rng.int()),
+                true => Value::float(rng.float()),
+            };
+        }
+        let memory: Vec<u8> = (0..MEMORY).map(|_| rng.next() as u8).collect();
+
+        if check(&program, &variables, &memory).is_err() {
+            let original_size = program.size();
+            let program = shrink(program, |p| check(p, &variables, &memory).is_err());
+            panic!(
+                "case {case} of seed {seed:#x} failed: {}\n\
+                 shrunk from {original_size} to {} nodes:\n  {program}\n\
+                 initial variables: {:?}\n\
+                 initial memory: {memory:?}",
+                check(&program, &variables, &memory).unwrap_err(),
+                program.size(),
+                &variables[..VARIABLES as usize],
+            );
+        }
+    }
+}
+
+#[test]
+fn shrinking_preserves_failure() {
+    // Shrink against a stand-in for a bug, that multiplication by a variable is miscompiled.
+    let mut rng = Rng(1);
+    let program = loop {
+        let program = Node::generate(&mut rng, MAX_DEPTH, 0, false, Type::Int);
+        if program.size() > 20 && program.to_string().contains("(* v") {
+            break program;
+        }
+    };
+    let fails = |node: &Node| node.to_string().contains("(* v");
+    let shrunk = shrink(program, fails);
+    assert!(fails(&shrunk));
+    // `(* v 0)` is as small as it gets.
+    assert_eq!(shrunk.size(), 3, "{shrunk}");
+}
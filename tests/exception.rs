@@ -0,0 +1,245 @@
+mod common;
+
+use common::Backend;
+use dispatchers::{
+    bytecode::CompileError,
+    exception::DIVISION_BY_ZERO,
+    fuel::{Fuel, VmError},
+    heap::Heap,
+    host::Host,
+    parser,
+    treewalk::Instruction,
+    value::Value,
+    *,
+};
+
+/// Runs a program on each of `backends`, with both collectors, and checks that they agree on its
+/// result and variables. Returns the result and variables of the first.
+fn run_on(
+    backends: &[Backend],
+    code: &Instruction,
+    arguments: &[Value],
+) -> (Result<Value, VmError>, [Value; 256]) {
+    let mut runs = common::run_each(code, backends, arguments, 0, &Host::new());
+    let first = runs.remove(0);
+    let variable_count = code.variable_count().min(256);
+    for run in runs {
+        assert_eq!(run.result, first.result, "{}", run.name);
+        assert_eq!(
+            run.variables[..variable_count],
+            first.variables[..variable_count],
+            "{}",
+            run.name
+        );
+    }
+    (first.result, first.variables)
+}
+
+/// Runs a program on every backend that has exception tables, like [`run_on`].
+fn run_bytecode(code: &Instruction, arguments: &[Value]) -> (Value, [Value; 256]) {
+    let (result, variables) = run_on(common::BYTECODE, code, arguments);
+    (result.unwrap(), variables)
+}
+
+/// Like [`run_bytecode`], but also checks that the tree walker agrees.
+fn run_all(source: &str, arguments: &[Value]) -> Value {
+    let program = parser::parse(source).unwrap();
+    let backends = [&[Backend::Treewalk], common::BYTECODE].concat();
+    run_on(&backends, &program.code, arguments).0.unwrap()
+}
+
+#[test]
+fn catches_division_by_zero() {
+    assert_eq!(
+        run_all(
+            "(args n) (try (/ 10 n) (catch e (+ e 100)))",
+            &[Value::int(0)]
+        ),
+        Value::int(DIVISION_BY_ZERO.as_int() + 100)
+    );
+    assert_eq!(
+        run_all("(args n) (try (% 10 n) (catch e 7))", &[Value::int(0)]),
+        Value::int(7)
+    );
+    // Without an exception, the handler doesn't run.
+    assert_eq!(
+        run_all("(args n) (try (/ 10 n) (catch e 7))", &[Value::int(2)]),
+        Value::int(5)
+    );
+}
+
+#[test]
+fn nested_handlers() {
+    // A handler may throw again, to the `try` around it.
+    assert_eq!(
+        run_all(
+            "(try (try (throw 7) (catch e (throw (+ e 1)))) (catch f (* f 10)))",
+            &[]
+        ),
+        Value::int(80)
+    );
+    // The innermost `try` catches, even when the throw is in a loop in it.
+    assert_eq!(
+        run_all(
+            "(let i 0) \
+             (try \
+               (+ 1000 (try (while 1 (let i (+ i 1)) (while (<= 5 i) (throw i))) \
+                 (catch e (* e 2)))) \
+               (catch e 0))",
+            &[]
+        ),
+        Value::int(1010)
+    );
+}
+
+#[test]
+fn unwinds_partial_expressions() {
+    // Exceptions thrown halfway through an expression leave nothing behind on the stack, so
+    // the loop can throw any number of times.
+    assert_eq!(
+        run_all(
+            "(args n) (let s 0) (let i 0) \
+             (while (<= (+ i 1) n) \
+               (let s (+ s (try (* 2 (+ i (throw i))) (catch e e)))) \
+               (let i (+ i 1))) \
+             (+ 1 (try (+ 2 (/ s 0)) (catch e s)))",
+            &[Value::int(1000)]
+        ),
+        Value::int(1 + 999 * 1000 / 2)
+    );
+}
+
+#[test]
+fn keeps_assignments_before_the_throw() {
+    assert_eq!(
+        run_all(
+            "(let x 1) (let y 0) \
+             (try (let x 2) (throw 3) (let x 4) (catch e (let y (+ x e)))) \
+             (+ (* x 10) y)",
+            &[]
+        ),
+        Value::int(2 * 10 + 5)
+    );
+}
+
+#[test]
+fn coroutines() {
+    // An exception escaping a coroutine is thrown again by the `resume`.
+    let program = parser::parse(
+        "(let c (coroutine (yield 1) (throw 42))) \
+         (let a (resume c 0)) \
+         (let b (try (resume c 0) (catch e e))) \
+         (+ a b)",
+    )
+    .unwrap();
+    assert_eq!(run_bytecode(&program.code, &[]).0, Value::int(1 + 42));
+
+    // A coroutine's body isn't covered by a `try` around where it was created.
+    let program = parser::parse(
+        "(let c 0) (try (let c (coroutine (throw 9))) (catch e 100)) \
+         (try (resume c 0) (catch e (+ e 1)))",
+    )
+    .unwrap();
+    assert_eq!(run_bytecode(&program.code, &[]).0, Value::int(10));
+
+    // Exceptions caught inside a coroutine stay there.
+    let program = parser::parse(
+        "(let c (coroutine (let x (try (/ 1 0) (catch e (yield e)))) (+ x 5))) \
+         (let a (resume c 0)) \
+         (+ (* a 100) (resume c 10))",
+    )
+    .unwrap();
+    assert_eq!(
+        run_bytecode(&program.code, &[]).0,
+        Value::int(DIVISION_BY_ZERO.as_int() * 100 + 15)
+    );
+}
+
+#[test]
+fn wide_operands() {
+    // A handler variable past the first 256 registers, and a body too long for a two-byte offset.
+    let mut source: String = (0..300).map(|i| format!("(let v{i} {i})")).collect();
+    source += "(try (let v1 0)";
+    source += &"(let v1 (+ v1 1))".repeat(40_000);
+    source += "(throw (+ v1 v298)) (catch v299 (+ v299 1)))";
+    let program = parser::parse(&source).unwrap();
+    let (result, variables) = run_bytecode(&program.code, &[]);
+    assert_eq!(result, Value::int(40_000 + 298 + 1));
+    assert_eq!(variables[1], Value::int(40_000));
+}
+
+#[test]
+fn uncaught_exceptions_end_the_run() {
+    // The second throws from the last instruction, past which the switch methods can't halt.
+    for source in ["(args n) (+ 1 (throw (+ n 1)))", "(args n) (throw (+ n 1))"] {
+        let program = parser::parse(source).unwrap();
+        let backends = [&[Backend::Treewalk], common::BYTECODE].concat();
+        let (result, _) = run_on(&backends, &program.code, &[Value::int(5)]);
+        assert_eq!(result, Err(VmError::Uncaught(Value::int(6))));
+
+        let (heap, host) = (&mut Heap::new(), &Host::new());
+        for mut compiled in common::compile(&program.code, common::METERED) {
+            let mut fuel = Fuel::new(10);
+            let result =
+                compiled.run_metered(None, &mut [Value::int(5)], &mut [], heap, host, &mut fuel);
+            assert_eq!(
+                result,
+                Err(VmError::Uncaught(Value::int(6))),
+                "{}",
+                compiled.name
+            );
+            // The switch methods put back the instruction the `Halt` that ended it went over.
+            let result = compiled.run(&mut [Value::int(0)], &mut [], heap, host);
+            assert_eq!(
+                result,
+                Err(VmError::Uncaught(Value::int(1))),
+                "{}",
+                compiled.name
+            );
+        }
+    }
+}
+
+#[test]
+fn verifiers_check_handlers() {
+    let program = parser::parse("(try (/ 1 0) (catch e e))").unwrap();
+
+    let stack = stack_switch::compile(&program.code).unwrap();
+    assert_eq!(stack.handlers.len(), 1);
+    let mut bad = stack.clone();
+    bad.handlers[0].end = bad.code.len() as u32 + 1;
+    assert!(stack_switch::verify(&bad).is_err());
+    let mut bad = stack.clone();
+    bad.handlers[0].target = bad.code.len() as u32;
+    assert!(stack_switch::verify(&bad).is_err());
+    // Inside the body's first instruction, which has an operand.
+    let mut bad = stack.clone();
+    bad.handlers[0].start += 1;
+    assert!(stack_switch::verify(&bad).is_err());
+
+    let register = register_switch::compile(&program.code).unwrap();
+    let mut bad = register.clone();
+    bad.handlers[0].depth = bad.slots as u32;
+    assert!(register_switch::verify(&bad).is_err());
+    let mut bad = register.clone();
+    bad.handlers[0].end -= 1;
+    assert!(register_switch::verify(&bad).is_err());
+
+    let fixed = register_fixed::compile(&program.code).unwrap();
+    assert_eq!(
+        register_fixed::Program::from_module(&fixed.to_module()).unwrap(),
+        fixed
+    );
+    let mut bad = fixed.clone();
+    bad.handlers[0].target = bad.code.len() as u32;
+    assert!(register_fixed::verify(&bad).is_err());
+}
+
+#[test]
+fn compact_tree_walker_rejects_exceptions() {
+    let program = parser::parse("(try (throw 1) (catch e e))").unwrap();
+    assert_eq!(
+        compact_treewalk_switch::compile(&program.code).unwrap_err(),
+        CompileError::ExceptionsUnsupported
+    );
+}
@@ -0,0 +1,81 @@
+use dispatchers::{
+    exception::DIVISION_BY_ZERO, fold::fold, heap::Heap, host::Host, parser, treewalk::Instruction,
+    value::Value, workloads::WORKLOADS, *,
+};
+
+fn folded(source: &str) -> Instruction {
+    fold(&parser::parse(source).unwrap().code)
+}
+
+#[test]
+fn folds_constants() {
+    assert_eq!(folded("(+ (* 6 7) (- 10 4))"), Instruction::Int(48));
+    assert_eq!(folded("(- 0 1)"), Instruction::Int(u32::MAX));
+    assert_eq!(folded("(<= (/ 7 2) (% 7 4))"), Instruction::Int(1));
+    assert_eq!(folded("(f+ 0.5 (float 2))"), Instruction::Float(2.5));
+    assert_eq!(folded("(int (f/ 7.0 2.0))"), Instruction::Int(3));
+}
+
+#[test]
+fn simplifies_identities() {
+    assert_eq!(
+        folded("(args x) (- (/ (+ 0 (* 1 (+ x 0))) 1) 0)"),
+        Instruction::Var(0)
+    );
+    assert_eq!(folded("(args x) (* x (- 2 2))"), Instruction::Int(0));
+    // Multiplying by zero still has the other operand's effects.
+    assert_eq!(
+        folded("(args x) (* (let x 5) 0)"),
+        parser::parse("(args x) (* (let x 5) 0)").unwrap().code
+    );
+    // Float identities don't hold for negative zero.
+    assert_eq!(
+        folded("(args x) (f+ x 0.0)"),
+        parser::parse("(args x) (f+ x 0.0)").unwrap().code
+    );
+}
+
+#[test]
+fn removes_dead_code() {
+    assert_eq!(
+        folded("(args n) (while (<= 2 1) (let n 5)) 1 n"),
+        Instruction::Var(0)
+    );
+    assert_eq!(
+        folded("(args n) (let n 5) (while 0 n)"),
+        folded("(let n 5) 0")
+    );
+    // A `try` around code that can't throw is its body.
+    assert_eq!(folded("(try (+ 1 2) (catch e e))"), Instruction::Int(3));
+}
+
+#[test]
+fn keeps_exceptions() {
+    // Dividing by a constant zero still throws when it's run.
+    let program = parser::parse("(try (/ 1 0) (catch e (+ e 1)))").unwrap();
+    let code = fold(&program.code);
+    assert!(matches!(code, Instruction::Try { .. }));
+    assert_eq!(
+        treewalk::execute(&code, &mut [], &mut [], &mut Heap::new(), &Host::new()).unwrap(),
+        Value::int(DIVISION_BY_ZERO.as_int() + 1)
+    );
+}
+
+#[test]
+fn workloads_match_native() {
+    for workload in WORKLOADS {
+        let program = fold(&workload.program());
+        let mut variables = workload.variables();
+        let mut heap = Heap::new();
+        let host = &Host::new();
+        let memory = &mut workload.memory();
+        let result = match workload.coroutines {
+            false => treewalk::execute(&program, &mut variables, memory, &mut heap, host).unwrap(),
+            true => {
+                let mut chunk = stack_switch::compile(&program).unwrap();
+                stack_switch::execute(&mut chunk, &mut variables, memory, &mut heap, host).unwrap()
+            }
+        };
+        assert_eq!(result, workload.run_native(), "{}", workload.name);
+    }
+}
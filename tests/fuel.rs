@@ -0,0 +1,454 @@
+use std::{panic, thread, time::Duration};
+
+mod common;
+
+use dispatchers::{
+    fuel::{Fuel, Suspended, VmError},
+    heap::Heap,
+    host::Host,
+    parser,
+    snapshot::SnapshotError,
+    treewalk::Instruction,
+    value::Value,
+    *,
+};
+
+type Run = Box<dyn FnMut(Option<Suspended>, &mut [Value], &mut Fuel) -> Result<Value, VmError>>;
+
+/// Runs `code` on each metered backend, from the start or from where it was suspended. Each
+/// backend keeps its heap between runs, for the objects a suspended run refers to.
+fn metered(code: &Instruction) -> Vec<(&'static str, Run)> {
+    common::compile(code, common::METERED)
+        .into_iter()
+        .map(|mut compiled| {
+            let mut heap = Heap::new();
+            let name = compiled.name;
+            let run: Run = Box::new(move |s, v, fuel| {
+                compiled.run_metered(s, v, &mut [], &mut heap, &Host::new(), fuel)
+            });
+            (name, run)
+        })
+        .collect()
+}
+
+#[test]
+fn runs_out_of_fuel_and_resumes() {
+    // Every iteration of a loop costs one unit, and nothing else does.
+    let program = parser::parse(
+        "(let i 0) (let x 0) \
+         (while (<= i 99) (let x (+ x i)) (let i (+ i 1))) \
+         (let j 0) (while (<= j 9) (let j (+ j 1))) \
+         (+ x 7)",
+    )
+    .unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let mut fuel = Fuel::new(110);
+        let mut variables = [Value::int(0); 3];
+        assert_eq!(
+            run(None, &mut variables, &mut fuel),
+            Ok(Value::int(4957)),
+            "{name}"
+        );
+        assert_eq!(fuel.remaining(), 0, "{name}");
+
+        // Runs stop at a backward jump, and carry on where they stopped.
+        let mut fuel = Fuel::new(7);
+        let mut variables = [Value::int(0); 3];
+        let mut suspended = None;
+        let mut stops = 0;
+        let result = loop {
+            match run(suspended.take(), &mut variables, &mut fuel) {
+                Ok(result) => break result,
+                Err(VmError::OutOfFuel(s)) => {
+                    // The variables show how far the run has come.
+                    assert!(variables[0].as_int() <= 100, "{name}");
+                    stops += 1;
+                    fuel.refuel(7);
+                    suspended = Some(s);
+                }
+                Err(error) => panic!("{name}: {error}"),
+            }
+        };
+        assert_eq!(result, Value::int(4957), "{name}");
+        assert_eq!(
+            variables[..2],
+            [Value::int(100), Value::int(4950)],
+            "{name}"
+        );
+        assert_eq!(stops, 110 / 7, "{name}");
+    }
+}
+
+#[test]
+fn straight_line_code_is_free() {
+    let program = parser::parse("(let x 2) (+ x (* x 3))").unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let result = run(None, &mut [], &mut Fuel::new(0));
+        assert_eq!(result, Ok(Value::int(8)), "{name}");
+    }
+}
+
+#[test]
+fn interrupts() {
+    let program = parser::parse("(let x 0) (while 1 (let x (+ x 1)))").unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let mut fuel = Fuel::unlimited();
+        let interrupter = fuel.interrupter();
+        let interrupting = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            interrupter.interrupt();
+        });
+        let mut variables = [Value::int(0)];
+        let error = run(None, &mut variables, &mut fuel).unwrap_err();
+        interrupting.join().unwrap();
+        assert!(matches!(error, VmError::Interrupted(_)), "{name}: {error}");
+        let x = variables[0].as_int();
+        assert!(x > 0, "{name}");
+
+        // The interruption is cleared once it has stopped a run.
+        let mut fuel = Fuel::new(5);
+        let error = run(
+            Some(error.into_suspended().unwrap()),
+            &mut variables,
+            &mut fuel,
+        )
+        .unwrap_err();
+        assert!(matches!(error, VmError::OutOfFuel(_)), "{name}: {error}");
+        assert_eq!(variables[0].as_int(), x + 5, "{name}");
+    }
+}
+
+#[test]
+fn yields_and_resumes() {
+    let program = parser::parse(
+        "(let i 0) (let s 0) \
+         (while (<= i 9) (let s (+ s (yield (* i i)))) (let i (+ i 1))) \
+         s",
+    )
+    .unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let mut fuel = Fuel::new(100);
+        let mut variables = [Value::int(0); 2];
+        let mut suspended = None;
+        let mut yielded = vec![];
+        let result = loop {
+            match run(suspended.take(), &mut variables, &mut fuel) {
+                Ok(result) => break result,
+                Err(VmError::Yielded(value, s)) => {
+                    yielded.push(value.as_int());
+                    suspended = Some(s);
+                }
+                Err(error) => panic!("{name}: {error}"),
+            }
+        };
+        assert_eq!(result, Value::int(285), "{name}");
+        assert_eq!(
+            yielded,
+            (0..10).map(|i| i * i).collect::<Vec<_>>(),
+            "{name}"
+        );
+        // Yields are free, so only the loop's jumps were paid for.
+        assert_eq!(fuel.remaining(), 90, "{name}");
+    }
+
+    // Unmetered runs carry on through yields.
+    let mut variables = [Value::int(0); 2];
+    let result = treewalk::execute(
+        &program.code,
+        &mut variables,
+        &mut [],
+        &mut Heap::new(),
+        &Host::new(),
+    )
+    .unwrap();
+    assert_eq!(result, Value::int(285));
+}
+
+/// The pc of the first instruction of `code` whose text in `name`'s disassembly has `opcode`.
+fn pc_of(name: &str, code: &Instruction, opcode: &str) -> u32 {
+    let disassembly = match name {
+        "register (fixed)" => register_fixed::disassemble(&register_fixed::compile(code).unwrap()),
+        _ if name.starts_with("stack") => {
+            stack_switch::disassemble(&stack_switch::compile(code).unwrap())
+        }
+        _ => register_switch::disassemble(&register_switch::compile(code).unwrap()),
+    };
+    let (pc, _) = disassembly
+        .into_iter()
+        .find(|(_, text)| text.contains(opcode))
+        .unwrap();
+    pc
+}
+
+#[test]
+fn stops_at_breakpoints() {
+    let program = parser::parse(
+        "(let i 0) (let s 0) \
+         (while (<= i 9) (let s (+ s i)) (let i (+ i 1))) \
+         s",
+    )
+    .unwrap();
+    for (name, mut run) in metered(&program.code) {
+        // The first `Add` is the one in `(+ s i)`.
+        let pc = pc_of(name, &program.code, "Add");
+        for refuel in [100, 3] {
+            let mut fuel = Fuel::new(refuel);
+            fuel.set_breakpoint(pc);
+            let mut variables = [Value::int(0); 2];
+            let mut suspended = None;
+            let mut stopped_at = vec![];
+            let mut paid = refuel;
+            let result = loop {
+                match run(suspended.take(), &mut variables, &mut fuel) {
+                    Ok(result) => break result,
+                    Err(VmError::Breakpoint(s)) => {
+                        // Before the instruction runs.
+                        assert_eq!(s.pc(), pc, "{name}");
+                        stopped_at.push(variables[0].as_int());
+                        // It carries on past the breakpoint even when saved and loaded.
+                        let bytes = s.to_bytes().unwrap();
+                        suspended = Some(Suspended::from_bytes(&bytes).unwrap());
+                    }
+                    Err(VmError::OutOfFuel(s)) => {
+                        fuel.refuel(refuel);
+                        paid += refuel;
+                        suspended = Some(s);
+                    }
+                    Err(error) => panic!("{name}: {error}"),
+                }
+            };
+            assert_eq!(result, Value::int(45), "{name}");
+            // Once in every iteration, as the loop jumps back.
+            assert_eq!(stopped_at, (0..10).collect::<Vec<_>>(), "{name}");
+            // Breakpoints are free, so only the loop's jumps were paid for.
+            assert_eq!(paid - fuel.remaining(), 10, "{name}");
+        }
+
+        // Without the breakpoint, the run goes on to the end.
+        let mut fuel = Fuel::new(100);
+        fuel.set_breakpoint(pc);
+        let mut variables = [Value::int(0); 2];
+        let error = run(None, &mut variables, &mut fuel).unwrap_err();
+        fuel.clear_breakpoint(pc);
+        let result = run(
+            Some(error.into_suspended().unwrap()),
+            &mut variables,
+            &mut fuel,
+        );
+        assert_eq!(result, Ok(Value::int(45)), "{name}");
+    }
+}
+
+#[test]
+fn stops_at_breakpoints_in_coroutines() {
+    // The creator resumes the coroutine three times without jumping back.
+    let program = parser::parse(
+        "(let c (coroutine (let i 0) (while 1 (let i (+ i 1)) (yield (* i i))))) \
+         (+ (resume c 0) (+ (resume c 0) (resume c 0)))",
+    )
+    .unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let mut fuel = Fuel::new(100);
+        fuel.set_breakpoint(pc_of(name, &program.code, "Mul"));
+        let mut suspended = None;
+        let mut stops = 0;
+        let result = loop {
+            match run(suspended.take(), &mut [Value::int(0)], &mut fuel) {
+                Ok(result) => break result,
+                Err(VmError::Breakpoint(s)) => {
+                    stops += 1;
+                    suspended = Some(s);
+                }
+                Err(error) => panic!("{name}: {error}"),
+            }
+        };
+        assert_eq!(result, Value::int(14), "{name}");
+        assert_eq!(stops, 3, "{name}");
+    }
+}
+
+#[test]
+fn breakpoints_are_removed_when_a_run_panics() {
+    // The memory is empty, so `load8` panics unless `x` is 0.
+    let program = parser::parse("(while x (load8 0)) (+ x 300)").unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let mut fuel = Fuel::new(100);
+        fuel.set_breakpoint(pc_of(name, &program.code, "Add"));
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            run(None, &mut [Value::int(1)], &mut fuel)
+        }));
+        assert!(result.is_err(), "{name}");
+        // The `switch` methods wrote the breakpoint into the code they're given.
+        let result = run(None, &mut [Value::int(0)], &mut Fuel::new(100));
+        assert_eq!(result, Ok(Value::int(300)), "{name}");
+    }
+}
+
+#[test]
+fn breakpoints_must_start_instructions() {
+    let program = parser::parse("(let x 2) (+ x 300)").unwrap();
+    for (name, mut run) in metered(&program.code) {
+        // Past the end of the code.
+        let mut fuel = Fuel::new(0);
+        fuel.set_breakpoint(1000);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            run(None, &mut [Value::int(0)], &mut fuel)
+        }));
+        assert!(result.is_err(), "{name}");
+    }
+}
+
+#[test]
+fn stops_inside_coroutines() {
+    // The meter stops the run in the middle of the coroutine's loop as well as the creator's,
+    // and a metered `yield` inside a coroutine goes to its resumer rather than stopping the run.
+    let program = parser::parse(
+        "(let c (coroutine (let i 0) (while 1 (let i (+ i 1)) (yield i)))) \
+         (let s 0) (let j 0) \
+         (while (<= j 19) (let s (+ s (resume c 0))) (let j (+ j 1))) \
+         s",
+    )
+    .unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let mut fuel = Fuel::new(3);
+        let mut variables = [Value::int(0); 4];
+        let mut suspended = None;
+        let mut stops = 0;
+        let result = loop {
+            match run(suspended.take(), &mut variables, &mut fuel) {
+                Ok(result) => break result,
+                Err(VmError::OutOfFuel(s)) => {
+                    stops += 1;
+                    fuel.refuel(3);
+                    suspended = Some(s);
+                }
+                Err(error) => panic!("{name}: {error}"),
+            }
+        };
+        assert_eq!(result, Value::int(210), "{name}");
+        // 20 iterations of the creator's loop and 19 jumps back in the coroutine's.
+        assert_eq!(stops, (20 + 19 - 1) / 3, "{name}");
+
+        // Coroutines live on the heap, so a run stopped inside one cannot be saved.
+        let mut variables = [Value::int(0); 4];
+        let error = run(None, &mut variables, &mut Fuel::new(2)).unwrap_err();
+        assert!(
+            matches!(
+                error.into_suspended().unwrap().to_bytes(),
+                Err(SnapshotError::HeapReference)
+            ),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn snapshots() {
+    let program = parser::parse(
+        "(let i 0) (let x 0.5) \
+         (while (<= i 99) (let x (f* x 1.01)) (let i (+ i 1))) \
+         (int (f* x 100.0))",
+    )
+    .unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let expected = run(None, &mut [Value::int(0); 2], &mut Fuel::unlimited());
+
+        // A snapshot taken part of the way through resumes to the same result.
+        let mut variables = [Value::int(0); 2];
+        let error = run(None, &mut variables, &mut Fuel::new(30)).unwrap_err();
+        let suspended = error.into_suspended().unwrap();
+        let bytes = suspended.to_bytes().unwrap();
+        let loaded = Suspended::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, suspended, "{name}");
+        let mut variables = [Value::int(0); 2];
+        let result = run(Some(loaded), &mut variables, &mut Fuel::unlimited());
+        assert_eq!(result, expected, "{name}");
+        assert_eq!(variables[0], Value::int(100), "{name}");
+
+        let mut corrupted = bytes.clone();
+        corrupted[12] ^= 1;
+        assert!(
+            matches!(
+                Suspended::from_bytes(&corrupted),
+                Err(SnapshotError::ChecksumMismatch { .. })
+            ),
+            "{name}"
+        );
+        assert!(matches!(
+            Suspended::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            Suspended::from_bytes(b"DSPM"),
+            Err(SnapshotError::BadMagic)
+        ));
+    }
+
+    // Objects live on the embedder's heap, which a snapshot doesn't hold.
+    let program = parser::parse("(let a (array 3)) (yield 1) (len a)").unwrap();
+    for (name, mut run) in metered(&program.code) {
+        let error = run(None, &mut [Value::int(0)], &mut Fuel::unlimited()).unwrap_err();
+        assert!(
+            matches!(
+                error.into_suspended().unwrap().to_bytes(),
+                Err(SnapshotError::HeapReference)
+            ),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn snapshots_check_their_code() {
+    let program = |step| {
+        let source = format!("(let i 0) (while (<= i 99) (let i (+ i {step}))) i");
+        parser::parse(&source).unwrap().code
+    };
+    let host = &Host::new();
+    let stop = |run: &mut dyn FnMut(&mut Fuel) -> Result<Value, VmError>| {
+        run(&mut Fuel::new(10))
+            .unwrap_err()
+            .into_suspended()
+            .unwrap()
+    };
+
+    let (a, b) = (program(1), program(2));
+    let mut stack = stack_switch::compile(&a).unwrap();
+    let other = stack_switch::compile(&b).unwrap();
+    let checksum = stack_switch::checksum(&stack);
+    let suspended = stop(&mut |fuel| {
+        stack_switch::execute_metered(&mut stack, &mut [], &mut [], &mut Heap::new(), host, fuel)
+    });
+    // Quickening `Add` doesn't change the checksum.
+    assert_eq!(stack_switch::checksum(&stack), checksum);
+    assert_eq!(suspended.code(), checksum);
+    assert!(suspended.check_code(checksum).is_ok());
+    assert!(matches!(
+        suspended.check_code(stack_switch::checksum(&other)),
+        Err(SnapshotError::DifferentCode { .. })
+    ));
+    let resumed = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let (heap, fuel) = (&mut Heap::new(), &mut Fuel::unlimited());
+        stack_dtable::resume(&other, suspended, &mut [], &mut [], heap, host, fuel)
+    }));
+    assert!(resumed.is_err());
+
+    let mut register = register_switch::compile(&a).unwrap();
+    let other = register_switch::compile(&b).unwrap();
+    let checksum = register_switch::checksum(&register);
+    let suspended = stop(&mut |fuel| {
+        let heap = &mut Heap::new();
+        register_switch::execute_metered(&mut register, &mut [], &mut [], heap, host, fuel)
+    });
+    assert_eq!(register_switch::checksum(&register), checksum);
+    assert_eq!(suspended.code(), checksum);
+    assert_ne!(register_switch::checksum(&other), checksum);
+
+    let fixed = register_fixed::compile(&a).unwrap();
+    let other = register_fixed::compile(&b).unwrap();
+    let suspended = stop(&mut |fuel| {
+        register_fixed::execute_metered(&fixed, &mut [], &mut [], &mut Heap::new(), host, fuel)
+    });
+    assert_eq!(suspended.code(), register_fixed::checksum(&fixed));
+    assert_ne!(register_fixed::checksum(&other), suspended.code());
+}
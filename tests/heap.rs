@@ -0,0 +1,138 @@
+use std::panic;
+
+use dispatchers::{
+    heap::{Collector, Heap, Object},
+    host::Host,
+    parser, stack_dtable, stack_switch, treewalk,
+    value::Value,
+};
+
+#[test]
+fn collects_unreachable_objects() {
+    let mut heap = Heap::new();
+    let a = heap.alloc(Object::Array(vec![Value::int(1)]), &[]);
+    let b = heap.alloc(Object::Record(vec![a, Value::int(0)].into()), &[]);
+    let c = heap.alloc(Object::String(b"garbage".as_slice().into()), &[]);
+    assert_eq!(heap.live(), 3);
+
+    // `a` is reachable through `b`.
+    heap.collect(&[&[b]]);
+    assert_eq!(heap.live(), 2);
+    assert_eq!(heap.get(heap.get(b, 0), 0), Value::int(1));
+
+    // The slot of a freed object is reused.
+    let d = heap.alloc(Object::String(b"new".as_slice().into()), &[]);
+    assert_eq!(d, c);
+    heap.collect(&[]);
+    assert_eq!(heap.live(), 0);
+    assert_eq!(heap.collections(), 2);
+}
+
+#[test]
+fn collects_cycles() {
+    let mut heap = Heap::new();
+    let a = heap.alloc(Object::Record(vec![Value::int(0)].into()), &[]);
+    let b = heap.alloc(Object::Record(vec![a].into()), &[]);
+    heap.set(a, 0, b);
+    heap.collect(&[&[a]]);
+    assert_eq!(heap.live(), 2);
+    heap.collect(&[]);
+    assert_eq!(heap.live(), 0);
+}
+
+#[test]
+fn collects_when_allocating() {
+    let program = parser::parse(include_str!("../programs/linked_list.dsp")).unwrap();
+    let mut heap = Heap::with_threshold(4096);
+    let mut variables = [Value::int(0); 256];
+    variables[..2].copy_from_slice(&[Value::int(10), Value::int(100)]);
+    let result = treewalk::execute(
+        &program.code,
+        &mut variables,
+        &mut [],
+        &mut heap,
+        &Host::new(),
+    )
+    .unwrap();
+    assert_eq!(
+        result,
+        Value::int(dispatchers::native::linked_list(10, 100))
+    );
+    assert!(heap.collections() > 0);
+    // Past the first collection, only about a list's worth of records is live at once.
+    assert!(heap.live() < 300, "{} objects are live", heap.live());
+}
+
+#[test]
+fn accesses_are_checked() {
+    let mut heap = Heap::new();
+    let array = heap.alloc(Object::Array(vec![Value::int(0); 2]), &[]);
+    let string = heap.alloc(Object::String(b"ab".as_slice().into()), &[]);
+    assert_eq!(heap.get(string, 1), Value::int(b'b' as u32));
+    assert_eq!(heap.len(array), 2);
+
+    let mut accesses: [&mut dyn FnMut(&mut Heap); 4] = [
+        &mut |heap| {
+            heap.get(array, 2);
+        },
+        &mut |heap| heap.set(array, 2, Value::int(0)),
+        &mut |heap| heap.set(string, 0, Value::int(0)),
+        &mut |heap| {
+            heap.len(Value::int(0));
+        },
+    ];
+    for access in &mut accesses {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| access(&mut heap)));
+        assert!(result.is_err());
+    }
+}
+
+#[test]
+fn generational_collections() {
+    let mut heap = Heap::generational(64);
+    let old = heap.alloc(Object::Array(vec![Value::int(0); 2]), &[]);
+    heap.alloc(Object::Array(vec![]), &[]);
+    // The nursery is full, so this promotes `old` and frees the other array.
+    let young = heap.alloc(Object::Array(vec![]), &[&[old]]);
+    assert_eq!(heap.live(), 2);
+
+    // `young` is only reachable from an old object, through the remembered set.
+    heap.set(old, 0, young);
+    heap.alloc(Object::Array(vec![Value::int(0); 8]), &[&[old]]);
+    heap.alloc(Object::Array(vec![]), &[&[old]]);
+    assert_eq!(heap.live(), 3);
+    assert_eq!(heap.len(heap.get(old, 0)), 0);
+
+    // Nursery collections don't free old objects, however unreachable.
+    heap.alloc(Object::Array(vec![Value::int(0); 8]), &[]);
+    heap.alloc(Object::Array(vec![]), &[]);
+    assert_eq!(heap.live(), 3);
+    heap.collect(&[]);
+    assert_eq!(heap.live(), 0);
+
+    let stats = heap.stats();
+    assert_eq!(stats.objects, 7);
+    assert_eq!(stats.pauses.iter().filter(|p| p.full).count(), 1);
+    assert_eq!(stats.pauses.len(), 4);
+    assert_eq!(stats.pauses.iter().map(|p| p.freed).sum::<usize>(), 7);
+}
+
+#[test]
+fn collectors_agree() {
+    let program = parser::parse(include_str!("../programs/linked_list.dsp")).unwrap();
+    let expected = Value::int(dispatchers::native::linked_list(20, 100));
+    for collector in [
+        Collector::MarkSweep,
+        Collector::Generational { nursery: 1024 },
+    ] {
+        let mut heap = Heap::with_collector(collector, 4096);
+        let mut variables = [Value::int(0); 256];
+        variables[..2].copy_from_slice(&[Value::int(20), Value::int(100)]);
+        let code = stack_switch::compile(&program.code).unwrap();
+        let result =
+            stack_dtable::execute(&code, &mut variables, &mut [], &mut heap, &Host::new()).unwrap();
+        assert_eq!(result, expected, "{collector:?}");
+        assert_eq!(heap.stats().objects, 20 * 100);
+        assert!(heap.stats().max_pause() <= heap.stats().total_pause());
+    }
+}
@@ -0,0 +1,106 @@
+use dispatchers::{
+    exception::Handler,
+    module::{Backend, EntryPoint, LoadError, Module, VERSION},
+    *,
+};
+
+fn sample() -> Module {
+    Module {
+        backend: Backend::Stack,
+        slots: 300,
+        constants: vec![1, 10, 3628800],
+        entry_points: vec![
+            EntryPoint {
+                name: "main".into(),
+                pc: 0,
+            },
+            EntryPoint {
+                name: "loop".into(),
+                pc: 10,
+            },
+        ],
+        handlers: vec![Handler {
+            start: 2,
+            end: 10,
+            target: 14,
+            depth: 3,
+        }],
+        bytecode: stack_switch::code(),
+    }
+}
+
+#[test]
+fn roundtrip() {
+    let module = sample();
+    let mut file = vec![];
+    module.save(&mut file).unwrap();
+    let loaded = Module::load(&file[..]).unwrap();
+    assert_eq!(loaded, module);
+    assert_eq!(loaded.entry_point("loop"), Some(10));
+    assert_eq!(stack_dtable::run(&loaded.bytecode), 3628800);
+}
+
+#[test]
+fn rejects_bad_magic() {
+    let mut bytes = sample().to_bytes();
+    bytes[0] = b'X';
+    assert!(matches!(
+        Module::from_bytes(&bytes),
+        Err(LoadError::BadMagic)
+    ));
+    assert!(matches!(Module::from_bytes(b""), Err(LoadError::BadMagic)));
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut bytes = sample().to_bytes();
+    bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert!(matches!(
+        Module::from_bytes(&bytes),
+        Err(LoadError::UnsupportedVersion(v)) if v == VERSION + 1
+    ));
+}
+
+#[test]
+fn rejects_corruption() {
+    let mut bytes = sample().to_bytes();
+    let last_code_byte = bytes.len() - 5;
+    bytes[last_code_byte] ^= 0xFF;
+    assert!(matches!(
+        Module::from_bytes(&bytes),
+        Err(LoadError::ChecksumMismatch { .. })
+    ));
+
+    let bytes = sample().to_bytes();
+    assert!(Module::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn rejects_unaddressable_slots() {
+    let mut module = sample();
+    module.slots = (1 << 16) + 1;
+    assert!(matches!(
+        Module::from_bytes(&module.to_bytes()),
+        Err(LoadError::Malformed(_))
+    ));
+}
+
+#[test]
+fn rejects_entry_points_outside_of_bytecode() {
+    let mut module = sample();
+    module.entry_points[1].pc = module.bytecode.len() as u32;
+    assert!(matches!(
+        Module::from_bytes(&module.to_bytes()),
+        Err(LoadError::Malformed(_))
+    ));
+}
+
+#[test]
+fn rejects_handlers_outside_of_bytecode() {
+    let mut module = sample();
+    module.handlers[0].target = module.bytecode.len() as u32;
+    assert!(matches!(
+        Module::from_bytes(&module.to_bytes()),
+        Err(LoadError::Malformed(_))
+    ));
+}
@@ -0,0 +1,157 @@
+use dispatchers::{
+    bytecode::Chunk, heap::Heap, host::Host, parser, value::Value, workloads::WORKLOADS, *,
+};
+
+/// Compiles a program for the stack backends, with and without optimizing it, and checks that
+/// both versions agree on its result. Returns the optimized chunk.
+fn optimized(source: &str, arguments: &[Value]) -> Chunk {
+    let program = parser::parse(source).unwrap();
+    let chunk = stack_switch::compile(&program.code).unwrap();
+    let optimized = stack_switch::optimize(&chunk).unwrap();
+    stack_switch::verify(&optimized).unwrap();
+    assert!(optimized.code.len() <= chunk.code.len());
+    // Optimizing again finds nothing left to do.
+    assert_eq!(stack_switch::optimize(&optimized).unwrap(), optimized);
+
+    let run = |chunk: &Chunk| {
+        let mut variables = [Value::int(0); 256];
+        variables[..arguments.len()].copy_from_slice(arguments);
+        let result = stack_dtable::execute(
+            chunk,
+            &mut variables,
+            &mut [],
+            &mut Heap::new(),
+            &Host::new(),
+        )
+        .unwrap();
+        (result, variables)
+    };
+    assert_eq!(run(&optimized), run(&chunk), "{source}");
+    optimized
+}
+
+fn disassembly(chunk: &Chunk) -> Vec<String> {
+    stack_switch::disassemble(chunk)
+        .into_iter()
+        .map(|(_, text)| text)
+        .collect()
+}
+
+/// The instruction each jump lands on.
+fn jump_targets(chunk: &Chunk) -> Vec<String> {
+    let disassembly = stack_switch::disassemble(chunk);
+    disassembly
+        .iter()
+        .filter(|(_, text)| text.contains("Jump"))
+        .map(|(_, text)| {
+            let target: u32 = text.rsplit(' ').next().unwrap().parse().unwrap();
+            let (_, insn) = disassembly.iter().find(|(pc, _)| *pc == target).unwrap();
+            insn.clone()
+        })
+        .collect()
+}
+
+#[test]
+fn merges_stores_and_loads() {
+    let chunk = optimized(
+        "(args n) (let n n) (let x (+ n 1)) (+ x (let x (* x 2)))",
+        &[Value::int(5)],
+    );
+    let text = disassembly(&chunk);
+    // Both the statement and the `let` used as a value.
+    assert_eq!(text.iter().filter(|text| *text == "Tee 1").count(), 2);
+    assert!(!text
+        .windows(2)
+        .any(|pair| pair[0] == "Let 1" && pair[1] == "Var 1"));
+    // `(let n n)` is gone.
+    assert!(!text.contains(&"Let 0".to_string()), "{text:?}");
+
+    // Variables past the first 256 are merged in their wide form.
+    let mut source: String = (0..300).map(|i| format!("(let v{i} {i})")).collect();
+    source += "(+ v1 (let v299 (+ v298 1)))";
+    let text = disassembly(&optimized(&source, &[]));
+    assert!(text.contains(&"Wide Tee 299".to_string()));
+}
+
+#[test]
+fn keeps_loads_that_jumps_land_on() {
+    // The loop's condition starts by loading `i`, right after `i` is initialized.
+    let chunk = optimized(
+        "(args n) (let i 0) (while (<= i n) (let i (+ i 1))) i",
+        &[Value::int(10)],
+    );
+    let text = disassembly(&chunk);
+    assert!(text
+        .windows(2)
+        .any(|pair| pair[0] == "Let 1" && pair[1] == "Var 1"));
+}
+
+#[test]
+fn threads_jumps() {
+    // The inner loop exits to the outer loop's backward jump.
+    let source = "(args n) (let t 0) (let i 0) \
+                  (while (<= i n) \
+                    (let i (+ i 1)) (let j 0) \
+                    (while (<= j i) (let j (+ j 1)) (let t (+ t j)))) \
+                  t";
+    let program = parser::parse(source).unwrap();
+    let chunk = stack_switch::compile(&program.code).unwrap();
+    assert!(jump_targets(&chunk)
+        .iter()
+        .any(|insn| insn.starts_with("Jump")));
+    let chunk = optimized(source, &[Value::int(20)]);
+    assert!(!jump_targets(&chunk)
+        .iter()
+        .any(|insn| insn.starts_with("Jump")));
+}
+
+#[test]
+fn removes_unreachable_code() {
+    // The jump over the handler follows a throw, and so does the rest of the body.
+    let chunk = optimized(
+        "(args n) (try (throw (+ n 1)) (let n 7) (catch e (* e 2)))",
+        &[Value::int(4)],
+    );
+    let text = disassembly(&chunk);
+    assert!(
+        !text.iter().any(|text| text.starts_with("Jump")),
+        "{text:?}"
+    );
+    assert!(!text.contains(&"SmallInt 7".to_string()), "{text:?}");
+    // The handler moved with the code it covers.
+    assert_eq!(chunk.handlers.len(), 1);
+    let pcs: Vec<u32> = stack_switch::disassemble(&chunk)
+        .iter()
+        .map(|(pc, _)| *pc)
+        .collect();
+    let handler = chunk.handlers[0];
+    assert!(pcs.contains(&handler.start) && pcs.contains(&handler.target));
+    assert!(handler.end <= handler.target);
+}
+
+#[test]
+fn rejects_unverified_code() {
+    let chunk = Chunk {
+        code: vec![0xff],
+        ..Chunk::default()
+    };
+    assert!(stack_switch::optimize(&chunk).is_err());
+}
+
+#[test]
+fn workloads_match_native() {
+    for workload in WORKLOADS {
+        let chunk = stack_switch::compile(&workload.program()).unwrap();
+        let mut chunk = stack_switch::optimize(&chunk).unwrap();
+        let mut variables = workload.variables();
+        let result = stack_switch::execute(
+            &mut chunk,
+            &mut variables,
+            &mut workload.memory(),
+            &mut Heap::new(),
+            &Host::new(),
+        )
+        .unwrap();
+        assert_eq!(result, workload.run_native(), "{}", workload.name);
+    }
+}
@@ -0,0 +1,89 @@
+#![cfg(feature = "profile")]
+
+use dispatchers::{profile::Profile, *};
+
+fn count(profile: &Profile, opcode: &str) -> u64 {
+    profile
+        .opcodes
+        .iter()
+        .find(|o| o.name == opcode)
+        .map(|o| o.count)
+        .unwrap_or(0)
+}
+
+fn all_profiles() -> Vec<Profile> {
+    vec![
+        compact_treewalk_dtable::profile(&compact_treewalk_dtable::code()),
+        compact_treewalk_switch::profile(&compact_treewalk_switch::code()),
+        stack_dtable::profile(&stack_dtable::code()),
+        stack_switch::profile(&mut stack_switch::code()),
+        register_dtable::profile(&register_dtable::code()),
+        register_switch::profile(&mut register_switch::code()),
+        register_fixed::profile(&register_fixed::code()),
+    ]
+}
+
+#[test]
+fn opcode_counts() {
+    for profile in all_profiles() {
+        assert_eq!(count(&profile, "Multiply"), 10, "{}", profile.backend);
+        // The switch backends quicken `Add` into `AddInt` the first time it runs.
+        let adds = count(&profile, "Add") + count(&profile, "AddInt");
+        assert_eq!(adds, 10, "{}", profile.backend);
+        assert_eq!(count(&profile, "LessEq"), 11, "{}", profile.backend);
+        assert_eq!(count(&profile, "JumpIfNot"), 11, "{}", profile.backend);
+        assert_eq!(count(&profile, "Jump"), 10, "{}", profile.backend);
+    }
+}
+
+#[test]
+fn pc_counts_add_up() {
+    for profile in all_profiles() {
+        let pc_total: u64 = profile.pcs.iter().map(|pc| pc.count).sum();
+        assert_eq!(pc_total, profile.dispatches(), "{}", profile.backend);
+    }
+}
+
+#[test]
+fn switch_dispatches_halt() {
+    let switch = stack_switch::profile(&mut stack_switch::code());
+    let dtable = stack_dtable::profile(&stack_dtable::code());
+    assert_eq!(count(&switch, "Halt"), 1);
+    assert_eq!(count(&dtable, "Halt"), 0);
+    assert_eq!(switch.dispatches(), dtable.dispatches() + 1);
+}
+
+#[test]
+fn work_counts() {
+    let stack = stack_switch::profile(&mut stack_switch::code()).counts;
+    let register = register_switch::profile(&mut register_switch::code()).counts;
+
+    // Each loop iteration reads `i` three times and `x` and `n` once, plus the final `i <= n`
+    // check and the read of `x` after the loop. The register machine additionally reads its
+    // temporaries.
+    assert_eq!(stack.variable_reads, 10 * 5 + 2 + 1);
+    assert_eq!(stack.variable_writes, 20);
+    // `n`, `i`, `x` and the result are pushed but never popped.
+    assert_eq!(stack.stack_writes, stack.stack_reads + 4);
+
+    assert_eq!(register.variable_reads, 10 * 7 + 2 + 1);
+    assert_eq!(register.variable_writes, 12 + 11 + 20);
+    assert_eq!(register.stack_reads + register.stack_writes, 0);
+    assert!(register.bytecode_bytes < stack.bytecode_bytes);
+}
+
+#[test]
+fn fixed_width_work_counts() {
+    let switch = register_switch::profile(&mut register_switch::code());
+    let fixed = register_fixed::profile(&register_fixed::code());
+
+    // Adding a constant directly saves loading it into a temporary on every iteration.
+    assert_eq!(count(&switch, "Int"), 12);
+    assert_eq!(count(&fixed, "LoadK"), 2);
+    assert_eq!(fixed.dispatches() + 10, switch.dispatches());
+    // Every instruction is a word, and the constant 1 is read from the table.
+    assert_eq!(
+        fixed.counts.bytecode_bytes,
+        4 * (fixed.dispatches() + 2 + 10)
+    );
+}
@@ -38,8 +38,8 @@ fn stack_dtable_test() {
 
 #[test]
 fn stack_switch_test() {
-    let code = stack_switch::code();
-    let result = stack_switch::run(&code);
+    let mut code = stack_switch::code();
+    let result = stack_switch::run(&mut code);
     assert_eq!(result, REFERENCE);
 }
 
@@ -52,7 +52,14 @@ fn register_dtable_test() {
 
 #[test]
 fn register_switch_test() {
-    let code = register_switch::code();
-    let result = register_switch::run(&code);
+    let mut code = register_switch::code();
+    let result = register_switch::run(&mut code);
+    assert_eq!(result, REFERENCE);
+}
+
+#[test]
+fn register_fixed_test() {
+    let code = register_fixed::code();
+    let result = register_fixed::run(&code);
     assert_eq!(result, REFERENCE);
 }
@@ -0,0 +1,66 @@
+use dispatchers::value::{Unpacked, Value};
+
+#[test]
+fn round_trips() {
+    let values = [
+        Unpacked::Nil,
+        Unpacked::Bool(false),
+        Unpacked::Bool(true),
+        Unpacked::Int(0),
+        Unpacked::Int(u32::MAX),
+        Unpacked::Float(0.0),
+        Unpacked::Float(-1.5),
+        Unpacked::Float(f64::INFINITY),
+        Unpacked::Float(f64::NEG_INFINITY),
+        Unpacked::Float(f64::MIN_POSITIVE / 2.0),
+        Unpacked::Ref(0),
+        Unpacked::Ref(u32::MAX),
+    ];
+    for unpacked in values {
+        let value = Value::from(unpacked);
+        assert_eq!(value.unpack(), unpacked);
+        assert_eq!(value.is_int(), matches!(unpacked, Unpacked::Int(_)));
+        assert_eq!(value.is_float(), matches!(unpacked, Unpacked::Float(_)));
+    }
+    assert_eq!(Value::int(7).as_int(), 7);
+    assert_eq!(Value::float(0.25).as_float(), 0.25);
+}
+
+#[test]
+fn nans_stay_floats() {
+    // A NaN whose bits look like a boxed integer must not turn into one.
+    for nan in [f64::NAN, -f64::NAN, f64::from_bits(0x7ffc_0003_0000_0005)] {
+        let value = Value::float(nan);
+        assert!(value.is_float());
+        assert!(value.as_float().is_nan());
+    }
+}
+
+#[test]
+fn floats_are_equal_when_their_bits_are() {
+    assert_eq!(Value::float(f64::NAN), Value::float(-f64::NAN));
+    assert_ne!(Value::float(0.0), Value::float(-0.0));
+    assert_eq!(Value::float(1.5), Value::float(1.5));
+    assert_ne!(Value::float(1.0), Value::int(1));
+}
+
+#[test]
+fn truthiness() {
+    assert!(!Value::NIL.is_truthy());
+    assert!(!Value::bool(false).is_truthy());
+    assert!(!Value::int(0).is_truthy());
+    assert!(Value::bool(true).is_truthy());
+    assert!(Value::int(1).is_truthy());
+    assert!(Value::float(0.0).is_truthy());
+    assert!(Value::reference(0).is_truthy());
+}
+
+#[test]
+fn size() {
+    let size = if cfg!(feature = "tagged-values") {
+        16
+    } else {
+        8
+    };
+    assert_eq!(std::mem::size_of::<Value>(), size);
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use dispatchers::{heap::Heap, host::Host, workloads::WORKLOADS, *};
+
+#[test]
+fn workloads_match_native() {
+    for workload in WORKLOADS {
+        let expected = workload.run_native();
+        // The tree walkers have no coroutines.
+        let backends = if workload.coroutines {
+            common::BYTECODE
+        } else {
+            common::ALL
+        };
+        for mut compiled in common::compile(&workload.program(), backends) {
+            // The second run starts out with the code quickened by the first.
+            for _ in 0..2 {
+                let result = compiled.run(
+                    &mut workload.variables(),
+                    &mut workload.memory(),
+                    &mut Heap::new(),
+                    &Host::new(),
+                );
+                assert_eq!(
+                    result,
+                    Ok(expected),
+                    "{} ({})",
+                    workload.name,
+                    compiled.name
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn native_results() {
+    assert_eq!(native::fib(25), 75025);
+    // The sequences starting from 2 to 10 take 1, 7, 2, 5, 8, 16, 3, 19 and 6 steps.
+    assert_eq!(native::collatz(10), 67);
+    assert_eq!(native::nested_loops(3), 9);
+    // About a quarter of the grid's area.
+    assert_eq!(native::mandelbrot(32, 50), 279);
+    assert_eq!(native::mandelbrot_float(32, 50), 279);
+    assert_eq!(native::sieve(100), 25);
+    // The first three integers from seed 0 are 0, 54236 and 42756.
+    assert_eq!(native::bubble_sort(3, 0), 42756 * 2 + 54236 * 3);
+    assert_eq!(native::generator(3), 1 + 4 + 9);
+}